@@ -0,0 +1,52 @@
+use aws_mfa::config::credentials::{ConfigFile, Credential};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::path::PathBuf;
+
+// A credentials file of generated profiles, as org-wide scripts write them.
+fn credentials(profiles: usize) -> String {
+    (0..profiles)
+        .map(|i| {
+            format!(
+                "[account-{i:05}]\n# aws-mfa: source_profile=org\naws_access_key_id=AKIAIOSFODNN7{i:07}\naws_secret_access_key=wJalrXUtnFEMI/K7MDENG/bPxRfiCY{i:010}\nregion = ap-northeast-1"
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n\n")
+}
+
+fn temp_file(content: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("aws-mfa-bench-{}", std::process::id()));
+    std::fs::write(&path, content).unwrap();
+    path
+}
+
+fn bench_credentials(c: &mut Criterion) {
+    let mut group = c.benchmark_group("credentials");
+
+    for profiles in [100, 1000, 5000] {
+        let content = credentials(profiles);
+        let path = temp_file(&content);
+        let file = ConfigFile::from_path(&path).unwrap();
+
+        group.bench_with_input(BenchmarkId::new("from_path", profiles), &path, |b, path| {
+            b.iter(|| ConfigFile::from_path(path).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("to_string", profiles), &file, |b, file| {
+            b.iter(|| file.to_string())
+        });
+        // A refresh rewriting the session of one profile
+        group.bench_with_input(BenchmarkId::new("patch", profiles), &path, |b, path| {
+            b.iter(|| {
+                let session = Credential::new("account-00042", &["aws_session_token=token".into()]);
+                ConfigFile::patch(path, vec![session]).unwrap()
+            })
+        });
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_credentials);
+criterion_main!(benches);
@@ -0,0 +1,16 @@
+#![no_main]
+
+use aws_mfa::config::credentials::ConfigFile;
+use libfuzzer_sys::fuzz_target;
+
+// Whatever parses must render to a file that parses to the same, since a
+// refresh rewrites the credentials file from what it parsed.
+fuzz_target!(|content: &str| {
+    if let Ok(file) = content.parse::<ConfigFile>() {
+        let rendered = file.to_string();
+        let reparsed = rendered
+            .parse::<ConfigFile>()
+            .unwrap_or_else(|e| panic!("rendered file no longer parses: {}", e));
+        assert_eq!(reparsed.to_string(), rendered);
+    }
+});
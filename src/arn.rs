@@ -0,0 +1,247 @@
+use crate::Result;
+
+use anyhow::anyhow;
+use std::str::FromStr;
+
+// arn:partition:service:region:account-id:resource
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Arn {
+    pub partition: Partition,
+    pub service: String,
+    pub region: String,
+    pub account_id: String,
+    pub resource: String,
+}
+
+impl FromStr for Arn {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.splitn(6, ':').collect();
+        match parts.as_slice() {
+            ["arn", partition, service, region, account_id, resource]
+                if !service.is_empty() && !resource.is_empty() =>
+            {
+                Ok(Self {
+                    partition: partition.parse()?,
+                    service: service.to_string(),
+                    region: region.to_string(),
+                    account_id: account_id.to_string(),
+                    resource: resource.to_string(),
+                })
+            }
+            _ => Err(anyhow!("Invalid ARN: {}", s)),
+        }
+    }
+}
+
+impl Arn {
+    // The usual name of an IAM user's virtual MFA device, mfa/<user name>, or the
+    // root user's, from the ARN get-caller-identity returns.
+    pub fn virtual_device(&self) -> Result<String> {
+        let device = match (self.service.as_str(), self.resource.as_str()) {
+            ("iam", "root") => "root-account-mfa-device",
+            // A user with a path, like user/admins/tanaka, still has mfa/tanaka.
+            ("iam", resource) if resource.starts_with("user/") => {
+                resource.rsplit('/').next().unwrap_or_default()
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Cannot guess the MFA device of {}: only IAM users have one",
+                    self
+                ))
+            }
+        };
+
+        Ok(format!(
+            "arn:{}:iam::{}:mfa/{}",
+            self.partition.name(),
+            self.account_id,
+            device
+        ))
+    }
+}
+
+impl std::fmt::Display for Arn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "arn:{}:{}:{}:{}:{}",
+            self.partition.name(),
+            self.service,
+            self.region,
+            self.account_id,
+            self.resource
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Partition {
+    Aws,
+    AwsCn,
+    AwsUsGov,
+    AwsIso,
+    AwsIsoB,
+}
+
+impl Partition {
+    // Hardware MFA devices are identified by a serial number instead of an ARN,
+    // and those only exist in the commercial partition.
+    pub fn of_device(serial: &str) -> Result<Self> {
+        if serial.starts_with("arn:") {
+            serial.parse::<Arn>().map(|arn| arn.partition)
+        } else {
+            Ok(Self::Aws)
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Aws => "aws",
+            Self::AwsCn => "aws-cn",
+            Self::AwsUsGov => "aws-us-gov",
+            Self::AwsIso => "aws-iso",
+            Self::AwsIsoB => "aws-iso-b",
+        }
+    }
+
+    // Region whose STS endpoint serves the partition, when the global one doesn't.
+    pub fn default_region(&self) -> Option<&'static str> {
+        match self {
+            Self::Aws => None,
+            Self::AwsCn => Some("cn-north-1"),
+            Self::AwsUsGov => Some("us-gov-west-1"),
+            Self::AwsIso => Some("us-iso-east-1"),
+            Self::AwsIsoB => Some("us-isob-east-1"),
+        }
+    }
+
+    pub fn of_region(region: &str) -> Self {
+        if region.starts_with("cn-") {
+            Self::AwsCn
+        } else if region.starts_with("us-gov-") {
+            Self::AwsUsGov
+        } else if region.starts_with("us-isob-") {
+            Self::AwsIsoB
+        } else if region.starts_with("us-iso-") {
+            Self::AwsIso
+        } else {
+            Self::Aws
+        }
+    }
+
+    pub fn dns_suffix(&self) -> &'static str {
+        match self {
+            Self::Aws | Self::AwsUsGov => "amazonaws.com",
+            Self::AwsCn => "amazonaws.com.cn",
+            Self::AwsIso => "c2s.ic.gov",
+            Self::AwsIsoB => "sc2s.sgov.gov",
+        }
+    }
+
+    pub fn sts_endpoint(&self, region: &str) -> Option<String> {
+        match self {
+            Self::Aws => None,
+            _ => Some(format!("https://sts.{}.{}", region, self.dns_suffix())),
+        }
+    }
+}
+
+impl FromStr for Partition {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "aws" => Ok(Self::Aws),
+            "aws-cn" => Ok(Self::AwsCn),
+            "aws-us-gov" => Ok(Self::AwsUsGov),
+            "aws-iso" => Ok(Self::AwsIso),
+            "aws-iso-b" => Ok(Self::AwsIsoB),
+            _ => Err(anyhow!("Unknown partition: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod arn {
+        use super::*;
+
+        #[test]
+        fn it_parses_device_arn() {
+            let arn: Arn = "arn:aws-us-gov:iam::012345678901:mfa/tanaka"
+                .parse()
+                .unwrap();
+            assert_eq!(arn.partition, Partition::AwsUsGov);
+            assert_eq!(arn.service, "iam");
+            assert_eq!(arn.region, "");
+            assert_eq!(arn.account_id, "012345678901");
+            assert_eq!(arn.resource, "mfa/tanaka");
+        }
+
+        #[test]
+        fn it_returns_virtual_device_of_caller() {
+            let device = |caller: &str| caller.parse::<Arn>().unwrap().virtual_device();
+
+            assert_eq!(
+                device("arn:aws:iam::012345678901:user/tanaka").unwrap(),
+                "arn:aws:iam::012345678901:mfa/tanaka"
+            );
+            assert_eq!(
+                device("arn:aws-cn:iam::012345678901:user/admins/tanaka").unwrap(),
+                "arn:aws-cn:iam::012345678901:mfa/tanaka"
+            );
+            assert_eq!(
+                device("arn:aws:iam::012345678901:root").unwrap(),
+                "arn:aws:iam::012345678901:mfa/root-account-mfa-device"
+            );
+            assert!(device("arn:aws:sts::012345678901:assumed-role/deploy/tanaka").is_err());
+        }
+
+        #[test]
+        fn it_fails_with_invalid_arn() {
+            assert!("arn:aws:iam".parse::<Arn>().is_err());
+            assert!("arn:foo:iam::012345678901:mfa/tanaka"
+                .parse::<Arn>()
+                .is_err());
+        }
+    }
+
+    mod partition {
+        use super::*;
+
+        #[test]
+        fn it_returns_partition_of_device() {
+            let partition = Partition::of_device("arn:aws-cn:iam::012345678901:mfa/tanaka");
+            assert_eq!(partition.unwrap(), Partition::AwsCn);
+            assert_eq!(
+                Partition::of_device("GAHT12345678").unwrap(),
+                Partition::Aws
+            );
+        }
+
+        #[test]
+        fn it_returns_sts_endpoint() {
+            assert!(Partition::Aws.sts_endpoint("us-east-1").is_none());
+            assert_eq!(
+                Partition::AwsCn.sts_endpoint("cn-north-1").unwrap(),
+                "https://sts.cn-north-1.amazonaws.com.cn"
+            );
+            assert_eq!(
+                Partition::AwsUsGov.sts_endpoint("us-gov-west-1").unwrap(),
+                "https://sts.us-gov-west-1.amazonaws.com"
+            );
+        }
+
+        #[test]
+        fn it_returns_partition_of_region() {
+            assert_eq!(Partition::of_region("ap-northeast-1"), Partition::Aws);
+            assert_eq!(Partition::of_region("cn-northwest-1"), Partition::AwsCn);
+            assert_eq!(Partition::of_region("us-gov-east-1"), Partition::AwsUsGov);
+            assert_eq!(Partition::of_region("us-isob-east-1"), Partition::AwsIsoB);
+        }
+    }
+}
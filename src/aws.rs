@@ -0,0 +1,612 @@
+use crate::arn::Partition;
+use crate::config::credentials::SsoProfile;
+use crate::process::{aws_command, output_with_timeout, TempFile};
+use crate::{redact, Credentials, Result, SessionTokens};
+
+use anyhow::anyhow;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Deserialize;
+use serde_json::Value;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use std::str::FromStr;
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+// Package managers `codeartifact-login` configures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodeArtifactTool {
+    Pip,
+    Npm,
+    Cargo,
+}
+
+impl CodeArtifactTool {
+    pub const VARIANTS: [&'static str; 3] = ["pip", "npm", "cargo"];
+}
+
+impl FromStr for CodeArtifactTool {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pip" => Ok(Self::Pip),
+            "npm" => Ok(Self::Npm),
+            "cargo" => Ok(Self::Cargo),
+            _ => Err(anyhow!("Unknown tool: {}", s)),
+        }
+    }
+}
+
+pub struct CodeArtifact<'a> {
+    pub domain: &'a str,
+    pub repository: &'a str,
+    pub domain_owner: Option<&'a str>,
+    pub region: Option<&'a str>,
+}
+
+// The client.authentication.k8s.io ExecCredential for the cluster, wrapping a
+// presigned GetCallerIdentity URL like aws-iam-authenticator does.
+pub fn eks_token(
+    cluster: &str,
+    region: Option<&str>,
+    session: &SessionTokens,
+    timeout: Duration,
+) -> Result<String> {
+    let mut args = vec![
+        "eks",
+        "get-token",
+        "--cluster-name",
+        cluster,
+        "--output",
+        "json",
+    ];
+    if let Some(region) = region {
+        args.extend(["--region", region]);
+    }
+
+    let stdout = String::from_utf8(aws(&args, session, timeout)?)?;
+    let credential: Value = serde_json::from_str(&stdout)?;
+    if credential["kind"] != "ExecCredential" {
+        return Err(anyhow!(
+            "Unexpected output of aws eks get-token: {}",
+            stdout
+        ));
+    }
+    Ok(stdout.trim().to_string())
+}
+
+// The region given on the command line, else in the environment.
+pub fn region(region: Option<&str>) -> Result<String> {
+    region
+        .map(str::to_string)
+        .or_else(|| std::env::var("AWS_REGION").ok())
+        .or_else(|| std::env::var("AWS_DEFAULT_REGION").ok())
+        .filter(|r| !r.is_empty())
+        .ok_or_else(|| anyhow!("Not Found region: pass --region or set AWS_REGION"))
+}
+
+pub fn caller_account(session: &SessionTokens, timeout: Duration) -> Result<String> {
+    let args = [
+        "sts",
+        "get-caller-identity",
+        "--query",
+        "Account",
+        "--output",
+        "text",
+    ];
+    Ok(String::from_utf8(aws(&args, session, timeout)?)?
+        .trim()
+        .to_string())
+}
+
+pub fn ecr_registry(account: &str, region: &str) -> String {
+    format!(
+        "{}.dkr.ecr.{}.{}",
+        account,
+        region,
+        Partition::of_region(region).dns_suffix()
+    )
+}
+
+pub fn ecr_password(region: &str, session: &SessionTokens, timeout: Duration) -> Result<String> {
+    let args = ["ecr", "get-login-password", "--region", region];
+    let password = String::from_utf8(aws(&args, session, timeout)?)?
+        .trim()
+        .to_string();
+    redact::register(&password);
+    Ok(password)
+}
+
+// Passes the password on stdin, where other users can't see it in the process list.
+pub fn docker_login(registry: &str, password: &str) -> Result<()> {
+    let mut child = Command::new("docker")
+        .args(["login", "--username", "AWS", "--password-stdin", registry])
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("{}: docker", e))?;
+
+    child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("Error writing to docker login"))?
+        .write_all(password.as_bytes())?;
+
+    if child.wait()?.success() {
+        Ok(())
+    } else {
+        Err(anyhow!("docker login to {} failed", registry))
+    }
+}
+
+impl CodeArtifact<'_> {
+    // The aws CLI configures pip and npm itself, but not cargo.
+    pub fn login(
+        &self,
+        tool: CodeArtifactTool,
+        session: &SessionTokens,
+        timeout: Duration,
+    ) -> Result<()> {
+        let tool_name = match tool {
+            CodeArtifactTool::Pip => "pip",
+            CodeArtifactTool::Npm => "npm",
+            CodeArtifactTool::Cargo => return self.cargo_login(session, timeout),
+        };
+
+        let mut args = vec!["codeartifact", "login", "--tool", tool_name];
+        args.extend(["--repository", self.repository]);
+        args.extend(self.args());
+
+        let stdout = aws(&args, session, timeout)?;
+        eprint!("{}", String::from_utf8_lossy(&stdout));
+        Ok(())
+    }
+
+    // Registers the repository in ~/.cargo/config.toml once and stores the token
+    // with `cargo login`.
+    fn cargo_login(&self, session: &SessionTokens, timeout: Duration) -> Result<()> {
+        let mut args = vec![
+            "codeartifact",
+            "get-repository-endpoint",
+            "--format",
+            "cargo",
+        ];
+        args.extend(["--repository", self.repository]);
+        args.extend(["--query", "repositoryEndpoint", "--output", "text"]);
+        args.extend(self.args());
+        let endpoint = String::from_utf8(aws(&args, session, timeout)?)?
+            .trim()
+            .to_string();
+
+        let mut args = vec!["codeartifact", "get-authorization-token"];
+        args.extend(["--query", "authorizationToken", "--output", "text"]);
+        args.extend(self.args());
+        let token = String::from_utf8(aws(&args, session, timeout)?)?
+            .trim()
+            .to_string();
+        redact::register(&token);
+
+        let path = cargo_config_path();
+        let config = std::fs::read_to_string(&path).unwrap_or_default();
+        if !has_cargo_registry(&config, self.repository) {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)?;
+            write!(file, "{}", cargo_registry(self.repository, &endpoint))?;
+            eprintln!("Added registry {} to {}", self.repository, path.display());
+        }
+
+        let mut child = Command::new("cargo")
+            .args(["login", "--registry", self.repository])
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow!("{}: cargo", e))?;
+        child
+            .stdin
+            .take()
+            .ok_or_else(|| anyhow!("Error writing to cargo login"))?
+            .write_all(token.as_bytes())?;
+
+        if child.wait()?.success() {
+            Ok(())
+        } else {
+            Err(anyhow!("cargo login to {} failed", self.repository))
+        }
+    }
+
+    fn args(&self) -> Vec<&str> {
+        let mut args = vec!["--domain", self.domain];
+        if let Some(owner) = self.domain_owner {
+            args.extend(["--domain-owner", owner]);
+        }
+        if let Some(region) = self.region {
+            args.extend(["--region", region]);
+        }
+        args
+    }
+}
+
+fn cargo_config_path() -> PathBuf {
+    let home = std::env::var("CARGO_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| crate::config::expand_home("~/.cargo"));
+    home.join("config.toml")
+}
+
+fn has_cargo_registry(config: &str, name: &str) -> bool {
+    let header = format!("[registries.{}]", name);
+    config.lines().any(|line| line.trim() == header)
+}
+
+fn cargo_registry(name: &str, endpoint: &str) -> String {
+    format!(
+        "\n[registries.{}]\nindex = \"sparse+{}\"\ncredential-provider = \"cargo:token\"\n",
+        name, endpoint
+    )
+}
+
+// Calls that hand out credentials, as CloudTrail names them.
+pub const TOKEN_REQUESTS: [&str; 2] = ["GetSessionToken", "AssumeRole"];
+
+// A call of TOKEN_REQUESTS that CloudTrail recorded for a user.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenRequest {
+    pub event_time: DateTime<Utc>,
+    pub event_name: String,
+    #[serde(rename = "sourceIPAddress", default)]
+    pub source_ip_address: String,
+    #[serde(default)]
+    pub user_agent: String,
+    pub error_code: Option<String>,
+}
+
+// The token requests of the IAM user since the time, oldest first. CloudTrail
+// logs calls to the global STS endpoint in us-east-1.
+pub fn token_requests(
+    user: &str,
+    since: DateTime<Utc>,
+    region: &str,
+    profile: &str,
+    timeout: Duration,
+) -> Result<Vec<TokenRequest>> {
+    let args = [
+        "cloudtrail",
+        "lookup-events",
+        "--lookup-attributes",
+        &format!("AttributeKey=Username,AttributeValue={}", user),
+        "--start-time",
+        &since.to_rfc3339_opts(SecondsFormat::Secs, true),
+        "--region",
+        region,
+        "--query",
+        "Events[].CloudTrailEvent",
+        "--output",
+        "json",
+    ];
+    parse_token_requests(&aws_with_profile(&args, profile, timeout)?)
+}
+
+// lookup-events gives each event as a JSON document in a string.
+fn parse_token_requests(stdout: &[u8]) -> Result<Vec<TokenRequest>> {
+    let events: Vec<String> = serde_json::from_slice(stdout)?;
+
+    let mut requests = events
+        .iter()
+        .filter_map(|event| serde_json::from_str::<TokenRequest>(event).ok())
+        .filter(|request| TOKEN_REQUESTS.contains(&request.event_name.as_str()))
+        .collect::<Vec<_>>();
+    requests.sort_by_key(|request| request.event_time);
+    Ok(requests)
+}
+
+// The role credentials of the SSO profile, for an access token of its session.
+pub fn sso_role_credentials(
+    sso: &SsoProfile,
+    access_token: &str,
+    timeout: Duration,
+) -> Result<SessionTokens> {
+    // Given as a file, as the command line shows in the process list.
+    let input = Zeroizing::new(
+        serde_json::json!({
+            "roleName": sso.role_name,
+            "accountId": sso.account_id,
+            "accessToken": access_token,
+        })
+        .to_string(),
+    );
+    let input = TempFile::write("sso", input.as_bytes())?;
+
+    let Output {
+        status,
+        stdout,
+        stderr,
+    } = output_with_timeout(
+        aws_command()
+            .args(["sso", "get-role-credentials", "--region", &sso.region])
+            .arg("--cli-input-json")
+            .arg(format!("file://{}", input.0.display()))
+            .args(["--output", "json"])
+            .env_remove("AWS_PROFILE"),
+        timeout,
+    )?;
+    if !status.success() {
+        return Err(anyhow!("{}", String::from_utf8(stderr)?));
+    }
+
+    let tokens = parse_role_credentials(&stdout)?;
+    redact::register_tokens(&tokens);
+    Ok(tokens)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RoleCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: String,
+    // Milliseconds since the epoch
+    expiration: i64,
+}
+
+fn parse_role_credentials(stdout: &[u8]) -> Result<SessionTokens> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Response {
+        role_credentials: RoleCredentials,
+    }
+
+    let Response {
+        role_credentials: credentials,
+    } = serde_json::from_slice(stdout)
+        .map_err(|e| anyhow!("Parse error: cannot parse output of aws sso: {}", e))?;
+    let expiration = DateTime::from_timestamp_millis(credentials.expiration)
+        .ok_or_else(|| anyhow!("Invalid expiration in output of aws sso"))?;
+
+    Ok(SessionTokens {
+        credentials: Credentials {
+            access_key_id: credentials.access_key_id,
+            secret_access_key: credentials.secret_access_key,
+            session_token: credentials.session_token,
+            expiration,
+        },
+    })
+}
+
+// A virtual MFA device just created, not yet enabled for its user.
+pub struct VirtualMfaDevice {
+    pub arn: String,
+    // base32, as shown in the QR code of the console
+    pub seed: Zeroizing<String>,
+}
+
+pub fn create_virtual_mfa_device(
+    name: &str,
+    profile: &str,
+    timeout: Duration,
+) -> Result<VirtualMfaDevice> {
+    // The CLI only hands out the seed in a file.
+    let outfile = TempFile::write("seed", b"")?;
+    let args = [
+        "iam",
+        "create-virtual-mfa-device",
+        "--virtual-mfa-device-name",
+        name,
+        "--bootstrap-method",
+        "Base32StringSeed",
+        "--outfile",
+        outfile.0.to_str().unwrap(),
+        "--query",
+        "VirtualMFADevice.SerialNumber",
+        "--output",
+        "text",
+    ];
+    let arn = String::from_utf8(aws_with_profile(&args, profile, timeout)?)?
+        .trim()
+        .to_string();
+
+    let seed = Zeroizing::new(std::fs::read_to_string(&outfile.0)?.trim().to_string());
+    redact::register(&seed);
+    Ok(VirtualMfaDevice { arn, seed })
+}
+
+// Associates the device with the user, proving it works with two consecutive codes.
+pub fn enable_mfa_device(
+    user: &str,
+    device_arn: &str,
+    codes: [&str; 2],
+    profile: &str,
+    timeout: Duration,
+) -> Result<()> {
+    let args = [
+        "iam",
+        "enable-mfa-device",
+        "--user-name",
+        user,
+        "--serial-number",
+        device_arn,
+        "--authentication-code1",
+        codes[0],
+        "--authentication-code2",
+        codes[1],
+    ];
+    aws_with_profile(&args, profile, timeout).map(|_| ())
+}
+
+// Tells IAM the codes the device shows now, after its clock drifted.
+pub fn resync_mfa_device(
+    user: &str,
+    device_arn: &str,
+    codes: [&str; 2],
+    profile: &str,
+    timeout: Duration,
+) -> Result<()> {
+    let args = [
+        "iam",
+        "resync-mfa-device",
+        "--user-name",
+        user,
+        "--serial-number",
+        device_arn,
+        "--authentication-code1",
+        codes[0],
+        "--authentication-code2",
+        codes[1],
+    ];
+    aws_with_profile(&args, profile, timeout).map(|_| ())
+}
+
+pub fn deactivate_mfa_device(
+    user: &str,
+    device_arn: &str,
+    profile: &str,
+    timeout: Duration,
+) -> Result<()> {
+    let args = [
+        "iam",
+        "deactivate-mfa-device",
+        "--user-name",
+        user,
+        "--serial-number",
+        device_arn,
+    ];
+    aws_with_profile(&args, profile, timeout).map(|_| ())
+}
+
+pub fn delete_virtual_mfa_device(device_arn: &str, profile: &str, timeout: Duration) -> Result<()> {
+    let args = [
+        "iam",
+        "delete-virtual-mfa-device",
+        "--serial-number",
+        device_arn,
+    ];
+    aws_with_profile(&args, profile, timeout).map(|_| ())
+}
+
+fn aws_with_profile(args: &[&str], profile: &str, timeout: Duration) -> Result<Vec<u8>> {
+    let Output {
+        status,
+        stdout,
+        stderr,
+    } = output_with_timeout(
+        aws_command().args(args).args(["--profile", profile]),
+        timeout,
+    )?;
+
+    if status.success() {
+        Ok(stdout)
+    } else {
+        Err(anyhow!("{}", String::from_utf8(stderr)?))
+    }
+}
+
+// Runs the aws command with the session instead of a profile.
+fn aws(args: &[&str], session: &SessionTokens, timeout: Duration) -> Result<Vec<u8>> {
+    let Output {
+        status,
+        stdout,
+        stderr,
+    } = output_with_timeout(
+        aws_command()
+            .args(args)
+            .envs(session.env_vars())
+            .env_remove("AWS_PROFILE"),
+        timeout,
+    )?;
+
+    if status.success() {
+        Ok(stdout)
+    } else {
+        Err(anyhow!("{}", String::from_utf8(stderr)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod parse_role_credentials {
+        use super::*;
+
+        #[test]
+        fn it_reads_role_credentials() {
+            let stdout = br#"{"roleCredentials": {"accessKeyId": "ASIAEXAMPLE",
+                "secretAccessKey": "secret", "sessionToken": "token",
+                "expiration": 1893456000000}}"#;
+            let tokens = parse_role_credentials(stdout).unwrap();
+            assert_eq!(tokens.credentials.access_key_id, "ASIAEXAMPLE");
+            assert_eq!(
+                tokens.expiration().to_rfc3339(),
+                "2030-01-01T00:00:00+00:00"
+            );
+        }
+    }
+
+    mod parse_token_requests {
+        use super::*;
+
+        #[test]
+        fn it_keeps_token_requests_oldest_first() {
+            let event = |time: &str, name: &str| {
+                json_string(&format!(
+                    r#"{{"eventTime":"{}","eventName":"{}","sourceIPAddress":"192.0.2.1","userAgent":"aws-cli/1.45","errorCode":null}}"#,
+                    time, name
+                ))
+            };
+            let stdout = format!(
+                "[{},{},{}]",
+                event("2024-05-01T10:00:00Z", "AssumeRole"),
+                event("2024-05-01T09:00:00Z", "GetSessionToken"),
+                event("2024-05-01T09:30:00Z", "ListBuckets"),
+            );
+
+            let requests = parse_token_requests(stdout.as_bytes()).unwrap();
+            assert_eq!(requests.len(), 2);
+            assert_eq!(requests[0].event_name, "GetSessionToken");
+            assert_eq!(requests[0].source_ip_address, "192.0.2.1");
+            assert_eq!(requests[1].event_name, "AssumeRole");
+        }
+
+        fn json_string(value: &str) -> String {
+            serde_json::to_string(value).unwrap()
+        }
+    }
+
+    mod cargo_registry {
+        use super::*;
+
+        #[test]
+        fn it_returns_sparse_registry() {
+            let section = cargo_registry(
+                "crates",
+                "https://d-012345678901.d.codeartifact.us-east-1.amazonaws.com/cargo/crates/",
+            );
+            assert!(has_cargo_registry(&section, "crates"));
+            assert!(!has_cargo_registry(&section, "other"));
+            assert!(section.contains(
+                "index = \"sparse+https://d-012345678901.d.codeartifact.us-east-1.amazonaws.com/cargo/crates/\""
+            ));
+        }
+    }
+
+    mod ecr_registry {
+        use super::*;
+
+        #[test]
+        fn it_returns_registry_host() {
+            assert_eq!(
+                ecr_registry("012345678901", "ap-northeast-1"),
+                "012345678901.dkr.ecr.ap-northeast-1.amazonaws.com"
+            );
+            assert_eq!(
+                ecr_registry("012345678901", "cn-north-1"),
+                "012345678901.dkr.ecr.cn-north-1.amazonaws.com.cn"
+            );
+        }
+    }
+}
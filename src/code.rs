@@ -0,0 +1,291 @@
+use crate::config::mfa::CodeSource;
+use crate::config::secrets::{self, Secrets};
+use crate::config::Paths;
+use crate::{redact, totp, tty, Result};
+
+use anyhow::anyhow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Command, Output};
+
+// A source of MFA one time pass codes.
+pub trait CodeProvider {
+    fn code(&self) -> Result<String>;
+}
+
+pub fn provider(paths: &Paths, source: &CodeSource, digits: u32) -> Box<dyn CodeProvider> {
+    match source {
+        CodeSource::Prompt => Box::new(Prompt),
+        CodeSource::Totp { seed } => Box::new(Totp {
+            seed: seed.clone(),
+            digits,
+        }),
+        CodeSource::Op { item } => Box::new(External::new("op", &["item", "get", item, "--otp"])),
+        CodeSource::Pass { entry } => Box::new(External::new("pass", &["otp", entry])),
+        CodeSource::Ykman { account } => Box::new(External::new(
+            "ykman",
+            &["oath", "accounts", "code", "--single", account],
+        )),
+        CodeSource::Command { run } => Box::new(External::new("sh", &["-c", run])),
+        CodeSource::Secret { name } => Box::new(StoredTotp {
+            paths: paths.clone(),
+            name: name.clone(),
+            digits,
+        }),
+    }
+}
+
+// Checks that the code is a number of the device's length, before spending an attempt on it.
+pub fn validate(code: &str, digits: u32) -> Result<()> {
+    if code.len() == digits as usize && code.chars().all(|c| c.is_ascii_digit()) {
+        Ok(())
+    } else {
+        Err(anyhow!("Invalid MFA code: expected {} digits", digits))
+    }
+}
+
+pub struct Prompt;
+
+impl CodeProvider for Prompt {
+    fn code(&self) -> Result<String> {
+        tty::require_interactive("prompt for the MFA code (pass it as an argument)")?;
+
+        eprint!("MFA code: ");
+        std::io::stderr().flush()?;
+
+        let mut line = String::new();
+        std::io::stdin().lock().read_line(&mut line)?;
+        Ok(line.trim().to_string())
+    }
+}
+
+// Asks on the controlling terminal, for when stdin belongs to a running command.
+pub struct TtyPrompt;
+
+impl CodeProvider for TtyPrompt {
+    fn code(&self) -> Result<String> {
+        let mut tty = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/tty")
+            .map_err(|e| anyhow!("Cannot prompt for the MFA code: no terminal: {}", e))?;
+
+        write!(tty, "MFA code to renew the session: ")?;
+        tty.flush()?;
+
+        let mut line = String::new();
+        BufReader::new(tty).read_line(&mut line)?;
+        Ok(line.trim().to_string())
+    }
+}
+
+pub struct Totp {
+    seed: String,
+    digits: u32,
+}
+
+impl CodeProvider for Totp {
+    fn code(&self) -> Result<String> {
+        redact::register(&self.seed);
+        totp::generate_digits(&self.seed, totp::fresh_timestamp(), self.digits)
+    }
+}
+
+// A TOTP from a seed in the encrypted secrets store.
+pub struct StoredTotp {
+    paths: Paths,
+    name: String,
+    digits: u32,
+}
+
+impl CodeProvider for StoredTotp {
+    fn code(&self) -> Result<String> {
+        let secrets = Secrets::read(&self.paths, &secrets::passphrase(&self.paths, false)?)?;
+        let seed = secrets
+            .seed(&self.name)
+            .ok_or_else(|| anyhow!("Not Found secret: {}", self.name))?;
+        totp::generate_digits(seed, totp::fresh_timestamp(), self.digits)
+    }
+}
+
+// Codes printed on stdout by another program such as `op` or `ykman`.
+pub struct External {
+    program: String,
+    args: Vec<String>,
+}
+
+impl External {
+    pub fn new(program: &str, args: &[&str]) -> Self {
+        Self {
+            program: program.to_string(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+}
+
+impl CodeProvider for External {
+    fn code(&self) -> Result<String> {
+        // These compute a TOTP from the clock too, so they get the same wait.
+        totp::fresh_timestamp();
+
+        let Output {
+            status,
+            stdout,
+            stderr,
+        } = Command::new(&self.program)
+            .args(&self.args)
+            .output()
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => {
+                    anyhow!("Not Found code provider command: {}", self.program)
+                }
+                _ => anyhow!("{}: {}", e, self.program),
+            })?;
+
+        if !status.success() {
+            return Err(anyhow!(
+                "Code provider {} failed: {}",
+                self.program,
+                String::from_utf8_lossy(&stderr).trim()
+            ));
+        }
+
+        let code = String::from_utf8(stdout)?.trim().to_string();
+        if code.is_empty() {
+            return Err(anyhow!("Code provider {} returned no code", self.program));
+        }
+
+        Ok(code)
+    }
+}
+
+// The codes taken during a --group refresh by device, so that the profiles of
+// one device share a code while its TOTP window lasts.
+#[derive(Default)]
+pub struct CodeBatch {
+    codes: RefCell<HashMap<String, (u64, String)>>,
+}
+
+impl CodeBatch {
+    pub fn get(&self, device: &str) -> Option<String> {
+        self.get_at(device, totp::now())
+    }
+
+    pub fn insert(&self, device: &str, code: &str) {
+        self.insert_at(device, code, totp::now());
+    }
+
+    // None once the window of the code is over or about to be.
+    fn get_at(&self, device: &str, timestamp: u64) -> Option<String> {
+        if totp::remaining(timestamp) < totp::MIN_REMAINING {
+            return None;
+        }
+        self.codes
+            .borrow()
+            .get(device)
+            .filter(|(window, _)| *window == timestamp / totp::STEP)
+            .map(|(_, code)| code.clone())
+    }
+
+    fn insert_at(&self, device: &str, code: &str, timestamp: u64) {
+        self.codes.borrow_mut().insert(
+            device.to_string(),
+            (timestamp / totp::STEP, code.to_string()),
+        );
+    }
+}
+
+// The codes stay out of logs and panics.
+impl std::fmt::Debug for CodeBatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CodeBatch")
+            .field("devices", &self.codes.borrow().keys().collect::<Vec<_>>())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod code_batch {
+        use super::*;
+
+        const DEVICE: &str = "arn:aws:iam::012345678901:mfa/tanaka";
+
+        #[test]
+        fn it_reuses_code_within_its_window() {
+            let batch = CodeBatch::default();
+            batch.insert_at(DEVICE, "123456", 1_700_000_010);
+
+            assert_eq!(
+                batch.get_at(DEVICE, 1_700_000_020),
+                Some("123456".to_owned())
+            );
+            assert_eq!(
+                batch.get_at("arn:aws:iam::012345678901:mfa/suzuki", 1_700_000_020),
+                None
+            );
+        }
+
+        #[test]
+        fn it_drops_code_of_past_window() {
+            let batch = CodeBatch::default();
+            batch.insert_at(DEVICE, "123456", 1_700_000_010);
+
+            // The window of the code is 1_700_000_010 to 1_700_000_039
+            assert_eq!(batch.get_at(DEVICE, 1_700_000_039), None);
+            assert_eq!(batch.get_at(DEVICE, 1_700_000_040), None);
+        }
+
+        #[test]
+        fn it_leaves_codes_out_of_debug_output() {
+            let batch = CodeBatch::default();
+            batch.insert(DEVICE, "987654");
+            assert!(!format!("{:?}", batch).contains("987654"));
+        }
+    }
+
+    mod validate {
+        use super::*;
+
+        #[test]
+        fn it_accepts_codes_of_the_length() {
+            assert!(validate("123456", 6).is_ok());
+            assert!(validate("12345678", 8).is_ok());
+        }
+
+        #[test]
+        fn it_rejects_other_codes() {
+            assert!(validate("12345678", 6).is_err());
+            assert!(validate("123456", 8).is_err());
+            assert!(validate("12345a", 6).is_err());
+            assert!(validate("", 6).is_err());
+        }
+    }
+
+    mod external {
+        use super::*;
+
+        #[test]
+        fn it_returns_trimmed_stdout() {
+            let provider = External::new("sh", &["-c", "echo ' 123456 '"]);
+            assert_eq!(provider.code().unwrap(), "123456");
+        }
+
+        #[test]
+        fn it_fails_when_command_fails() {
+            let provider = External::new("sh", &["-c", "echo oops >&2; exit 1"]);
+            let err = provider.code().unwrap_err();
+            assert_eq!(err.to_string(), "Code provider sh failed: oops");
+        }
+
+        #[test]
+        fn it_fails_when_command_not_found() {
+            let provider = External::new("aws-mfa-no-such-command", &[]);
+            assert!(provider.code().is_err());
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use super::Paths;
+use crate::{redact, Result};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+// One line of the audit log per refresh, successful or not.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Entry {
+    pub time: DateTime<Utc>,
+    pub profile: String,
+    pub mfa_profile: String,
+    pub device: String,
+    pub duration: u32,
+    pub success: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Entry {
+    pub fn new<T>(
+        profile: &str,
+        mfa_profile: &str,
+        device: &str,
+        duration: u32,
+        result: &Result<T>,
+    ) -> Self {
+        Self {
+            time: Utc::now(),
+            profile: profile.to_string(),
+            mfa_profile: mfa_profile.to_string(),
+            device: device.to_string(),
+            duration,
+            success: result.is_ok(),
+            error: result
+                .as_ref()
+                .err()
+                .map(|e| redact::redact(e.to_string().trim())),
+        }
+    }
+}
+
+// How far apart a refresh here and the call CloudTrail recorded may be.
+pub const MATCH_TOLERANCE_SECONDS: i64 = 120;
+
+// Whether the refreshes explain a call STS recorded at the time, i.e. one of
+// them was made around then. Failed refreshes count, CloudTrail logs them too.
+pub fn made_here(time: DateTime<Utc>, entries: &[Entry]) -> bool {
+    entries
+        .iter()
+        .any(|entry| (entry.time - time).num_seconds().abs() <= MATCH_TOLERANCE_SECONDS)
+}
+
+pub fn append(paths: &Paths, entry: &Entry) -> Result<()> {
+    append_to_path(&paths.audit, entry)
+}
+
+fn append_to_path<P: AsRef<Path>>(path: P, entry: &Entry) -> Result<()> {
+    if let Some(dir) = path.as_ref().parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    writeln!(file, "{}", serde_json::to_string(entry)?)
+        .map_err(|e| anyhow!("Error writing to audit log: {}", e))
+}
+
+// Entries oldest first. Lines that can't be parsed are skipped.
+pub fn read(paths: &Paths) -> Result<Vec<Entry>> {
+    read_from_path(&paths.audit)
+}
+
+fn read_from_path<P: AsRef<Path>>(path: P) -> Result<Vec<Entry>> {
+    if !path.as_ref().exists() {
+        return Ok(Vec::new());
+    }
+
+    let content = std::fs::read_to_string(&path)?;
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod made_here {
+        use super::*;
+
+        #[test]
+        fn it_matches_refreshes_around_the_time() {
+            let ok: Result<()> = Ok(());
+            let entry = Entry::new("tanaka", "mfa", "device", 900, &ok);
+            let entries = vec![entry.clone()];
+
+            assert!(made_here(
+                entry.time + chrono::Duration::seconds(90),
+                &entries
+            ));
+            assert!(made_here(
+                entry.time - chrono::Duration::seconds(90),
+                &entries
+            ));
+            assert!(!made_here(
+                entry.time + chrono::Duration::minutes(10),
+                &entries
+            ));
+            assert!(!made_here(entry.time, &[]));
+        }
+    }
+
+    mod append {
+        use super::*;
+
+        #[test]
+        fn it_appends_and_reads_entries() {
+            let path = std::env::temp_dir()
+                .join(format!("aws-mfa-audit-{}/audit.log", std::process::id()));
+            let ok: Result<()> = Ok(());
+            let err: Result<()> = Err(anyhow!("aws_session_token=secret"));
+
+            append_to_path(&path, &Entry::new("tanaka", "mfa", "device", 900, &ok)).unwrap();
+            append_to_path(&path, &Entry::new("suzuki", "mfa", "device", 3600, &err)).unwrap();
+
+            let entries = read_from_path(&path).unwrap();
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[0].profile, "tanaka");
+            assert!(entries[0].success);
+            assert!(entries[0].error.is_none());
+            assert_eq!(entries[1].duration, 3600);
+            assert!(!entries[1].success);
+            assert_eq!(entries[1].error, Some("aws_session_token=****".to_owned()));
+        }
+
+        #[test]
+        fn it_returns_no_entries_when_file_not_found() {
+            assert!(read_from_path("mock/not-found.log").unwrap().is_empty());
+        }
+    }
+}
@@ -0,0 +1,469 @@
+use super::Paths;
+use crate::{log, redact, Result, SessionTokens};
+use crate::{CODE_FAILURE_WINDOW_MINUTES, STS_ERROR_CACHE_SECONDS};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+// Metadata of the sessions aws-mfa has written, keyed by mfa profile.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Cache {
+    #[serde(default)]
+    sessions: BTreeMap<String, Session>,
+    // When invalid codes were rejected, keyed by device
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    failures: BTreeMap<String, Vec<DateTime<Utc>>>,
+    // The last error STS answered that another try won't fix, keyed by device
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    sts_errors: BTreeMap<String, StsError>,
+    // Source profile of the latest refresh
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_profile: Option<String>,
+}
+
+impl Cache {
+    pub fn read(paths: &Paths) -> Result<Self> {
+        Self::from_path(cache_path(paths))
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        if !path.as_ref().exists() {
+            return Ok(Self::default());
+        }
+
+        let content = std::fs::read_to_string(&path)?;
+        serde_json::from_str(&content)
+            .map_err(|e| anyhow!("{}: {}", e, path.as_ref().to_str().unwrap()))
+    }
+
+    pub fn write(&self, paths: &Paths) -> Result<()> {
+        self.write_to_path(cache_path(paths))
+    }
+
+    pub fn write_to_path<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        if let Some(dir) = path.as_ref().parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let content = serde_json::to_string_pretty(self)?;
+        std::fs::write(&path, content).map_err(|e| anyhow!("Error writing to cache: {}", e))?;
+        log::file_written(path.as_ref());
+        Ok(())
+    }
+
+    pub fn session(&self, mfa_profile: &str) -> Option<&Session> {
+        self.sessions.get(mfa_profile)
+    }
+
+    pub fn set_session(&mut self, mfa_profile: &str, session: Session) {
+        self.sessions.insert(mfa_profile.to_string(), session);
+    }
+
+    pub fn last_profile(&self) -> Option<&str> {
+        self.last_profile.as_deref()
+    }
+
+    pub fn set_last_profile(&mut self, profile: &str) {
+        self.last_profile = Some(profile.to_string());
+    }
+
+    // Failures of the device since the given time.
+    pub fn failures_since(&self, device: &str, since: DateTime<Utc>) -> usize {
+        self.failures
+            .get(device)
+            .map(|times| times.iter().filter(|t| **t >= since).count())
+            .unwrap_or_default()
+    }
+
+    // Records a failure and forgets those older than `since`.
+    pub fn add_failure(&mut self, device: &str, at: DateTime<Utc>, since: DateTime<Utc>) {
+        let times = self.failures.entry(device.to_string()).or_default();
+        times.retain(|t| *t >= since);
+        times.push(at);
+    }
+
+    pub fn clear_failures(&mut self, device: &str) {
+        self.failures.remove(device);
+    }
+
+    pub fn sts_error_since(&self, device: &str, since: DateTime<Utc>) -> Option<&StsError> {
+        self.sts_errors.get(device).filter(|e| e.at >= since)
+    }
+
+    pub fn set_sts_error(&mut self, device: &str, at: DateTime<Utc>, message: &str) {
+        let error = StsError {
+            at,
+            message: message.to_string(),
+        };
+        self.sts_errors.insert(device.to_string(), error);
+    }
+
+    pub fn clear_sts_error(&mut self, device: &str) {
+        self.sts_errors.remove(device);
+    }
+
+    // Forgets expired sessions, and failures and errors no longer looked at.
+    // Returns how many entries were removed.
+    pub fn prune(&mut self, now: DateTime<Utc>) -> usize {
+        let before = self.len();
+
+        self.sessions.retain(|_, session| session.expiration > now);
+
+        let since = now - Duration::minutes(CODE_FAILURE_WINDOW_MINUTES);
+        self.failures.retain(|_, times| {
+            times.retain(|t| *t >= since);
+            !times.is_empty()
+        });
+
+        let since = now - Duration::seconds(STS_ERROR_CACHE_SECONDS);
+        self.sts_errors.retain(|_, error| error.at >= since);
+
+        before - self.len()
+    }
+
+    fn len(&self) -> usize {
+        self.sessions.len() + self.failures.len() + self.sts_errors.len()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct StsError {
+    pub at: DateTime<Utc>,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Session {
+    pub source_profile: String,
+    pub expiration: DateTime<Utc>,
+}
+
+impl Session {
+    pub fn new(source_profile: &str, expiration: DateTime<Utc>) -> Self {
+        Self {
+            source_profile: source_profile.to_string(),
+            expiration,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.expiration > Utc::now()
+    }
+}
+
+// MFA sessions used for assuming roles, keyed by the profile they were obtained with.
+pub fn read_mfa_session(paths: &Paths, profile: &str) -> Result<Option<SessionTokens>> {
+    read_mfa_session_from(mfa_session_path(paths, profile))
+}
+
+fn read_mfa_session_from<P: AsRef<Path>>(path: P) -> Result<Option<SessionTokens>> {
+    if !path.as_ref().exists() {
+        return Ok(None);
+    }
+
+    let content = std::fs::read_to_string(&path)?;
+    let tokens = serde_json::from_str(&content)
+        .map_err(|e| anyhow!("{}: {}", e, path.as_ref().to_str().unwrap()))?;
+    redact::register_tokens(&tokens);
+    Ok(Some(tokens))
+}
+
+pub fn write_mfa_session(paths: &Paths, profile: &str, tokens: &SessionTokens) -> Result<()> {
+    write_mfa_session_to(mfa_session_path(paths, profile), tokens)
+}
+
+fn write_mfa_session_to<P: AsRef<Path>>(path: P, tokens: &SessionTokens) -> Result<()> {
+    if let Some(dir) = path.as_ref().parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    // Unlike cache.json these are credentials, so only the owner may read them,
+    // however the file was left before.
+    let content = serde_json::to_string_pretty(tokens)?;
+    super::replace(path.as_ref(), content.as_bytes())
+        .map_err(|e| anyhow!("Error writing to session cache: {}", e))?;
+    log::file_written(path.as_ref());
+    Ok(())
+}
+
+// Wipes cache.json and the MFA sessions.
+pub fn clear(paths: &Paths) -> Result<()> {
+    for path in [cache_path(paths), sessions_dir(paths)] {
+        let result = if path.is_dir() {
+            std::fs::remove_dir_all(&path)
+        } else if path.exists() {
+            std::fs::remove_file(&path)
+        } else {
+            Ok(())
+        };
+        result.map_err(|e| anyhow!("Error removing {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+// Prunes cache.json and removes expired or unreadable MFA sessions. Returns how
+// many entries were removed.
+pub fn gc(paths: &Paths, now: DateTime<Utc>) -> Result<usize> {
+    let mut cache = Cache::read(paths)?;
+    let mut removed = cache.prune(now);
+    if removed > 0 {
+        cache.write(paths)?;
+    }
+
+    let dir = sessions_dir(paths);
+    if dir.is_dir() {
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let stale = match read_mfa_session_from(&path) {
+                Ok(Some(tokens)) => tokens.expiration() <= now,
+                _ => true,
+            };
+            if stale {
+                std::fs::remove_file(&path)?;
+                removed += 1;
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
+fn sessions_dir(paths: &Paths) -> PathBuf {
+    paths.cache.join("sessions")
+}
+
+fn mfa_session_path(paths: &Paths, profile: &str) -> PathBuf {
+    sessions_dir(paths).join(format!("{}.json", file_name(profile)))
+}
+
+// Percent-encodes what could take a profile name out of the sessions directory,
+// e.g. "/" and a leading ".", keeping names of different profiles apart.
+fn file_name(profile: &str) -> String {
+    let mut name = String::with_capacity(profile.len());
+    for (i, byte) in profile.bytes().enumerate() {
+        match byte {
+            b'.' if i > 0 => name.push('.'),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => name.push(byte as char),
+            _ => name.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    name
+}
+
+pub fn cache_path(paths: &Paths) -> PathBuf {
+    paths.cache.join("cache.json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod cache {
+        use super::*;
+
+        #[test]
+        fn it_returns_empty_cache_when_file_not_found() {
+            let cache = Cache::from_path("mock/not-found.json").unwrap();
+            assert!(cache.sessions.is_empty());
+        }
+
+        #[test]
+        fn it_writes_and_reads_sessions() {
+            let expiration = "2022-01-01T00:00:00Z".parse().unwrap();
+            let mut cache = Cache::default();
+            cache.set_session("mfa", Session::new("tanaka", expiration));
+
+            let path = "mock/test-cache.json";
+            cache.write_to_path(path).unwrap();
+
+            let cache = Cache::from_path(path).unwrap();
+            assert!(cache.last_profile().is_none());
+            let session = cache.session("mfa").unwrap();
+            assert_eq!(session.source_profile, "tanaka");
+            assert_eq!(session.expiration, expiration);
+            assert!(cache.session("tanaka").is_none());
+        }
+    }
+
+    mod clear {
+        use super::*;
+        use crate::test_util::TestHome;
+
+        #[test]
+        fn it_removes_cache_of_given_paths() {
+            let home = TestHome::new().unwrap();
+            let paths = home.paths();
+            Cache::default().write(&paths).unwrap();
+            assert!(cache_path(&paths).exists());
+
+            clear(&paths).unwrap();
+            assert!(!cache_path(&paths).exists());
+        }
+    }
+
+    mod last_profile {
+        use super::*;
+
+        #[test]
+        fn it_remembers_last_profile() {
+            let mut cache = Cache::default();
+            cache.set_last_profile("tanaka");
+            cache.set_last_profile("suzuki");
+
+            let json = serde_json::to_string(&cache).unwrap();
+            let cache: Cache = serde_json::from_str(&json).unwrap();
+            assert_eq!(cache.last_profile(), Some("suzuki"));
+        }
+    }
+
+    mod failures {
+        use super::*;
+
+        #[test]
+        fn it_counts_failures_in_window() {
+            let now = Utc::now();
+            let since = now - Duration::minutes(15);
+            let mut cache = Cache::default();
+
+            cache.add_failure(
+                "device",
+                now - Duration::minutes(30),
+                now - Duration::hours(1),
+            );
+            cache.add_failure("device", now - Duration::minutes(5), since);
+            cache.add_failure("device", now, since);
+            assert_eq!(cache.failures_since("device", since), 2);
+            assert_eq!(cache.failures["device"].len(), 2);
+            assert_eq!(cache.failures_since("other", since), 0);
+
+            cache.clear_failures("device");
+            assert_eq!(cache.failures_since("device", since), 0);
+        }
+    }
+
+    mod sts_errors {
+        use super::*;
+
+        #[test]
+        fn it_returns_errors_in_window() {
+            let now = Utc::now();
+            let mut cache = Cache::default();
+
+            cache.set_sts_error("device", now - Duration::minutes(5), "AccessDenied");
+            assert!(cache
+                .sts_error_since("device", now - Duration::minutes(1))
+                .is_none());
+
+            cache.set_sts_error("device", now, "AccessDenied");
+            let error = cache
+                .sts_error_since("device", now - Duration::minutes(1))
+                .unwrap();
+            assert_eq!(error.message, "AccessDenied");
+
+            cache.clear_sts_error("device");
+            assert!(cache.sts_error_since("device", now).is_none());
+        }
+    }
+
+    mod prune {
+        use super::*;
+
+        #[test]
+        fn it_removes_stale_entries() {
+            let now = Utc::now();
+            let mut cache = Cache::default();
+            cache.set_session("old", Session::new("default", now - Duration::minutes(1)));
+            cache.set_session("mfa", Session::new("default", now + Duration::hours(1)));
+            cache.add_failure("old", now - Duration::hours(1), now - Duration::days(1));
+            cache.add_failure("device", now, now - Duration::minutes(15));
+            cache.set_sts_error("old", now - Duration::hours(1), "AccessDenied");
+
+            assert_eq!(cache.prune(now), 3);
+            assert!(cache.session("old").is_none());
+            assert!(cache.session("mfa").is_some());
+            assert_eq!(cache.failures_since("device", now), 1);
+            assert_eq!(cache.prune(now), 0);
+        }
+    }
+
+    mod session {
+        use super::*;
+
+        #[test]
+        fn it_is_valid_before_expiration() {
+            let session = Session::new("tanaka", Utc::now() + Duration::minutes(10));
+            assert!(session.is_valid());
+        }
+
+        #[test]
+        fn it_is_invalid_after_expiration() {
+            let session = Session::new("tanaka", Utc::now() - Duration::minutes(10));
+            assert!(!session.is_valid());
+        }
+    }
+
+    mod mfa_session {
+        use super::*;
+
+        #[test]
+        fn it_returns_none_when_file_not_found() {
+            assert!(read_mfa_session_from("mock/not-found.json")
+                .unwrap()
+                .is_none());
+        }
+
+        #[test]
+        fn it_writes_and_reads_session_tokens() {
+            let tokens: SessionTokens = serde_json::from_str(
+                r#"{"Credentials": {"AccessKeyId": "foo", "SecretAccessKey": "bar", "SessionToken": "baz", "Expiration": "2022-01-01T00:00:00Z"}}"#,
+            )
+            .unwrap();
+
+            let path = std::env::temp_dir().join(format!(
+                "aws-mfa-sessions-{}/tanaka.json",
+                std::process::id()
+            ));
+            write_mfa_session_to(&path, &tokens).unwrap();
+
+            let read = read_mfa_session_from(&path).unwrap().unwrap();
+            assert_eq!(read.env_vars(), tokens.env_vars());
+            assert_eq!(read.expiration(), tokens.expiration());
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+                assert_eq!(mode & 0o777, 0o600);
+
+                // Loosened since, e.g. by a backup tool restoring it.
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+                write_mfa_session_to(&path, &tokens).unwrap();
+                let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+                assert_eq!(mode & 0o777, 0o600);
+            }
+        }
+
+        #[test]
+        fn it_keeps_session_files_in_sessions_dir() {
+            let paths = Paths::under(Path::new("/home/me/.aws"));
+            let dir = sessions_dir(&paths);
+
+            for profile in ["../credentials", "a/b", "..", ".hidden", "/etc/passwd"] {
+                let path = mfa_session_path(&paths, profile);
+                assert_eq!(path.parent(), Some(dir.as_path()), "{}", profile);
+                assert!(!path.file_name().unwrap().to_string_lossy().starts_with('.'));
+            }
+            assert_eq!(
+                mfa_session_path(&paths, "tanaka.prod"),
+                dir.join("tanaka.prod.json")
+            );
+            assert_ne!(
+                mfa_session_path(&paths, "a/b"),
+                mfa_session_path(&paths, "a%2Fb")
+            );
+        }
+    }
+}
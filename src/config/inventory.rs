@@ -0,0 +1,151 @@
+use crate::Result;
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use std::path::Path;
+
+// Kinds of device GetSessionToken accepts a code of. FIDO security keys
+// aren't, so their rows are skipped.
+const TOTP_KINDS: [&str; 3] = ["virtual", "hardware", "totp"];
+
+// A row of a device inventory, e.g. exported from IAM by an admin.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Entry {
+    pub profile: String,
+    pub arn: String,
+    #[serde(default)]
+    pub kind: Option<String>,
+}
+
+impl Entry {
+    // Rows without a kind are taken to be virtual devices.
+    pub fn is_totp(&self) -> bool {
+        match self.kind.as_deref().map(str::trim) {
+            None | Some("") => true,
+            Some(kind) => TOTP_KINDS.contains(&kind.to_lowercase().as_str()),
+        }
+    }
+}
+
+// A JSON array of {profile, arn, kind} for a .json file, else CSV with a
+// header naming the columns.
+pub fn read(path: &Path) -> Result<Vec<Entry>> {
+    let content =
+        std::fs::read_to_string(path).map_err(|e| anyhow!("{}: {}", e, path.to_str().unwrap()))?;
+
+    if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str(&content).map_err(|e| anyhow!("{}: {}", e, path.to_str().unwrap()))
+    } else {
+        parse_csv(&content).map_err(|e| anyhow!("{}: {}", path.to_str().unwrap(), e))
+    }
+}
+
+fn parse_csv(content: &str) -> Result<Vec<Entry>> {
+    let mut rows = content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty());
+
+    let header = rows
+        .next()
+        .map(|(_, line)| split_row(line))
+        .ok_or_else(|| anyhow!("empty inventory"))?;
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|h| h.eq_ignore_ascii_case(name))
+            .ok_or_else(|| anyhow!("missing column: {}", name))
+    };
+    let profile = column("profile")?;
+    let arn = column("arn")?;
+    let kind = column("kind").ok();
+
+    rows.map(|(i, line)| {
+        let fields = split_row(line);
+        let field = |index: usize| {
+            fields
+                .get(index)
+                .filter(|f| !f.is_empty())
+                .cloned()
+                .ok_or_else(|| anyhow!("line {}: missing {}", i + 1, header[index]))
+        };
+
+        Ok(Entry {
+            profile: field(profile)?,
+            arn: field(arn)?,
+            kind: kind.and_then(|k| fields.get(k).cloned()),
+        })
+    })
+    .collect()
+}
+
+// Fields separated by commas, optionally in double quotes with "" for a quote.
+fn split_row(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field).trim().to_string()),
+            c => field.push(c),
+        }
+    }
+    fields.push(field.trim().to_string());
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod parse_csv {
+        use super::*;
+
+        #[test]
+        fn it_maps_columns_by_header() {
+            let content = "arn,Profile,kind,user\n\
+                arn:aws:iam::012345678901:mfa/tanaka,tanaka,virtual,tanaka\n\
+                \n\
+                \"arn:aws:iam::012345678901:mfa/satoh\",\"satoh, jr\",U2F,satoh\n";
+
+            let entries = parse_csv(content).unwrap();
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[0].profile, "tanaka");
+            assert_eq!(entries[0].arn, "arn:aws:iam::012345678901:mfa/tanaka");
+            assert!(entries[0].is_totp());
+            assert_eq!(entries[1].profile, "satoh, jr");
+            assert!(!entries[1].is_totp());
+        }
+
+        #[test]
+        fn it_fails_without_arn_column() {
+            let err = parse_csv("profile,kind\ntanaka,virtual\n").unwrap_err();
+            assert_eq!(err.to_string(), "missing column: arn");
+        }
+
+        #[test]
+        fn it_fails_with_empty_field() {
+            let err = parse_csv("profile,arn\ntanaka,\n").unwrap_err();
+            assert_eq!(err.to_string(), "line 2: missing arn");
+        }
+    }
+
+    mod split_row {
+        use super::*;
+
+        #[test]
+        fn it_unquotes_fields() {
+            assert_eq!(
+                split_row(r#"a, "b,c" ,"say ""hi""""#),
+                vec!["a", "b,c", r#"say "hi""#]
+            );
+        }
+    }
+}
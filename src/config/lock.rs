@@ -0,0 +1,317 @@
+use crate::Result;
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+// How long to wait for another aws-mfa to finish writing
+const LOCK_WAIT_SECONDS: u64 = 10;
+// A lock file older than this was left behind by a process that died
+const STALE_LOCK_SECONDS: u64 = 120;
+// How often the holder touches its lock file, so that a write waiting on S3 or
+// STS doesn't look stale
+const HEARTBEAT_SECONDS: u64 = STALE_LOCK_SECONDS / 4;
+
+// How concurrent writes of the credentials file are kept apart. flock(2) is
+// unreliable on some NFS and SMB home directories, where a lock file created
+// with O_EXCL still works.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LockStrategy {
+    #[default]
+    Flock,
+    Lockfile,
+}
+
+// Held until dropped.
+#[derive(Debug)]
+pub enum Lock {
+    Flock(File),
+    Lockfile(PathBuf, Heartbeat),
+}
+
+// Takes the lock on `path`, e.g. the credentials file, through `path.lock`.
+pub fn acquire(path: &Path, strategy: LockStrategy) -> Result<Lock> {
+    acquire_within(path, strategy, Duration::from_secs(LOCK_WAIT_SECONDS))
+}
+
+fn acquire_within(path: &Path, strategy: LockStrategy, wait: Duration) -> Result<Lock> {
+    let lock_path = lock_path(path);
+
+    match strategy {
+        LockStrategy::Flock => {
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&lock_path)
+                .map_err(|e| anyhow!("Error opening {}: {}", lock_path.display(), e))?;
+            let started = SystemTime::now();
+            loop {
+                match file.try_lock() {
+                    Ok(()) => return Ok(Lock::Flock(file)),
+                    Err(TryLockError::WouldBlock) => {}
+                    Err(TryLockError::Error(e)) => {
+                        return Err(anyhow!("Error locking {}: {}", lock_path.display(), e))
+                    }
+                }
+
+                if started.elapsed().unwrap_or_default() >= wait {
+                    return Err(anyhow!(
+                        "Timed out waiting for {} held by another aws-mfa",
+                        lock_path.display()
+                    ));
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        }
+        LockStrategy::Lockfile => {
+            let started = SystemTime::now();
+            loop {
+                match create_lockfile(&lock_path) {
+                    Ok(()) => {
+                        let heartbeat =
+                            Heartbeat::start(&lock_path, Duration::from_secs(HEARTBEAT_SECONDS));
+                        return Ok(Lock::Lockfile(lock_path, heartbeat));
+                    }
+                    Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+                    Err(e) => return Err(anyhow!("Error creating {}: {}", lock_path.display(), e)),
+                }
+
+                if is_stale(&lock_path) {
+                    if remove_stale(&lock_path) {
+                        eprintln!("Removed stale lock {}", lock_path.display());
+                    }
+                    continue;
+                }
+
+                if started.elapsed().unwrap_or_default() >= wait {
+                    let holder = std::fs::read_to_string(&lock_path).unwrap_or_default();
+                    return Err(anyhow!(
+                        "Timed out waiting for {} held by {}",
+                        lock_path.display(),
+                        holder.trim()
+                    ));
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        match self {
+            Self::Flock(file) => {
+                let _ = file.unlock();
+            }
+            Self::Lockfile(path, heartbeat) => {
+                heartbeat.stop();
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+// Keeps the mtime of a held lock file recent until stopped.
+#[derive(Debug)]
+pub struct Heartbeat {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Heartbeat {
+    fn start(path: &Path, every: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let path = path.to_path_buf();
+        let thread = std::thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(every) {
+                // Opened without create, so a lock removed meanwhile isn't recreated.
+                if let Ok(file) = OpenOptions::new().write(true).open(&path) {
+                    let _ = file.set_modified(SystemTime::now());
+                }
+            }
+        });
+        Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    fn stop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn lock_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".lock");
+    path.with_file_name(name)
+}
+
+// O_EXCL, which NFSv3 and later honor, recording who holds the lock.
+fn create_lockfile(path: &Path) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    let host = std::env::var("HOSTNAME").unwrap_or_default();
+    writeln!(file, "pid {} on {}", std::process::id(), host)
+}
+
+// Moves the lock aside under a name of its own before removing it, so that of the
+// waiters finding it stale only one takes it over. One that moved a lock taken by
+// another in the meantime puts it back.
+fn remove_stale(lock_path: &Path) -> bool {
+    static TAKEOVERS: AtomicUsize = AtomicUsize::new(0);
+
+    let mut name = lock_path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(
+        ".stale.{}.{}",
+        std::process::id(),
+        TAKEOVERS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let aside = lock_path.with_file_name(name);
+
+    if std::fs::rename(lock_path, &aside).is_err() {
+        return false;
+    }
+    let stale = is_stale(&aside);
+    if !stale {
+        // Fails instead of replacing a lock created since.
+        let _ = std::fs::hard_link(&aside, lock_path);
+    }
+    let _ = std::fs::remove_file(&aside);
+    stale
+}
+
+fn is_stale(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age > Duration::from_secs(STALE_LOCK_SECONDS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod acquire {
+        use super::*;
+
+        fn path(name: &str) -> PathBuf {
+            let dir = std::env::temp_dir().join(format!("aws-mfa-lock-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            dir.join(name)
+        }
+
+        #[test]
+        fn it_excludes_second_lockfile_until_released() {
+            let path = path("credentials");
+            let wait = Duration::from_millis(200);
+
+            let lock = acquire_within(&path, LockStrategy::Lockfile, wait).unwrap();
+            assert!(lock_path(&path).exists());
+            assert!(acquire_within(&path, LockStrategy::Lockfile, wait).is_err());
+
+            drop(lock);
+            assert!(!lock_path(&path).exists());
+            assert!(acquire_within(&path, LockStrategy::Lockfile, wait).is_ok());
+        }
+
+        #[test]
+        fn it_takes_over_stale_lockfile() {
+            let path = path("stale");
+            let file = File::create(lock_path(&path)).unwrap();
+            file.set_modified(SystemTime::now() - Duration::from_secs(STALE_LOCK_SECONDS + 1))
+                .unwrap();
+
+            let lock = acquire_within(&path, LockStrategy::Lockfile, Duration::ZERO);
+            assert!(lock.is_ok());
+        }
+
+        #[test]
+        fn it_lets_one_of_two_waiters_take_over_stale_lockfile() {
+            let path = path("contended");
+            let wait = Duration::from_millis(50);
+
+            for _ in 0..20 {
+                let file = File::create(lock_path(&path)).unwrap();
+                file.set_modified(SystemTime::now() - Duration::from_secs(STALE_LOCK_SECONDS + 1))
+                    .unwrap();
+
+                let barrier = std::sync::Barrier::new(2);
+                let locks: Vec<Result<Lock>> = std::thread::scope(|s| {
+                    let waiters: Vec<_> = (0..2)
+                        .map(|_| {
+                            s.spawn(|| {
+                                barrier.wait();
+                                acquire_within(&path, LockStrategy::Lockfile, wait)
+                            })
+                        })
+                        .collect();
+                    waiters.into_iter().map(|w| w.join().unwrap()).collect()
+                });
+                assert_eq!(locks.iter().filter(|lock| lock.is_ok()).count(), 1);
+            }
+        }
+
+        #[test]
+        fn it_keeps_lockfile_taken_over_by_another_waiter() {
+            let path = path("taken-over");
+            // Taken over and locked again after this waiter found it stale.
+            let lock = acquire_within(&path, LockStrategy::Lockfile, Duration::ZERO).unwrap();
+
+            assert!(!remove_stale(&lock_path(&path)));
+            assert!(lock_path(&path).exists());
+            assert!(acquire_within(&path, LockStrategy::Lockfile, Duration::ZERO).is_err());
+            drop(lock);
+        }
+
+        #[test]
+        fn it_locks_with_flock() {
+            let path = path("flock");
+            let lock = acquire_within(&path, LockStrategy::Flock, Duration::ZERO).unwrap();
+            assert!(matches!(lock, Lock::Flock(_)));
+        }
+
+        #[test]
+        fn it_times_out_waiting_for_flock() {
+            let path = path("flock-held");
+            let wait = Duration::from_millis(200);
+
+            let lock = acquire_within(&path, LockStrategy::Flock, wait).unwrap();
+            assert!(acquire_within(&path, LockStrategy::Flock, wait).is_err());
+
+            drop(lock);
+            assert!(acquire_within(&path, LockStrategy::Flock, wait).is_ok());
+        }
+    }
+
+    mod heartbeat {
+        use super::*;
+
+        #[test]
+        fn it_keeps_held_lockfile_from_going_stale() {
+            let dir = std::env::temp_dir().join(format!("aws-mfa-lock-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("heartbeat.lock");
+            let file = File::create(&path).unwrap();
+            file.set_modified(SystemTime::now() - Duration::from_secs(STALE_LOCK_SECONDS + 1))
+                .unwrap();
+            assert!(is_stale(&path));
+
+            let mut heartbeat = Heartbeat::start(&path, Duration::from_millis(20));
+            std::thread::sleep(Duration::from_millis(200));
+            heartbeat.stop();
+            assert!(!is_stale(&path));
+        }
+    }
+}
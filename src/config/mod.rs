@@ -1,16 +1,261 @@
-use lazy_static::lazy_static;
+use crate::Result;
+
+use anyhow::anyhow;
+use std::fs::OpenOptions;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
 
+pub mod audit;
+pub mod cache;
 pub mod credentials;
+pub mod inventory;
+pub mod lock;
 pub mod mfa;
+pub mod secrets;
+pub mod snapshot;
+pub mod sso;
+pub mod users;
+
+// Where aws-mfa reads and writes its files, ~/.aws unless set otherwise, e.g.
+// to fixed locations in a sandbox or a temporary directory in tests. Resolved
+// once and passed to whatever reads or writes them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Paths {
+    // Where mfa.yml is looked for, and backups go by default
+    pub aws_dir: PathBuf,
+    pub credentials: PathBuf,
+    // The aws CLI's config, with the profiles' settings
+    pub config: PathBuf,
+    // cache.json and the MFA sessions
+    pub cache: PathBuf,
+    pub audit: PathBuf,
+    // aws-mfa's other files: secrets, snapshots, shell rc files
+    pub data: PathBuf,
+}
+
+impl Paths {
+    // The files laid out as in ~/.aws, under another directory.
+    pub fn under(aws_dir: &Path) -> Self {
+        let data = aws_dir.join("aws-mfa");
+        Self {
+            aws_dir: aws_dir.to_path_buf(),
+            credentials: aws_dir.join("credentials"),
+            config: aws_dir.join("config"),
+            cache: data.clone(),
+            audit: data.join("audit.log"),
+            data,
+        }
+    }
+
+    // A workspace of its own mfa.yml, credentials, cache and audit log, kept
+    // apart from the others under ~/.aws/aws-mfa/workspaces. The aws CLI's
+    // config stays shared.
+    pub fn workspace(name: &str) -> Result<Self> {
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(anyhow!("Invalid workspace name: {}", name));
+        }
 
-lazy_static! {
-    static ref CONF_DIR: String = {
+        let default = Self::default();
+        let dir = default.data.join("workspaces").join(name);
+        Ok(Self {
+            aws_dir: dir.clone(),
+            credentials: dir.join("credentials"),
+            config: default.config,
+            cache: dir.clone(),
+            audit: dir.join("audit.log"),
+            data: dir,
+        })
+    }
+
+    // The aws CLI's own files, found the way the CLI and the SDK find them, for
+    // the embedded STS clients standing in for the CLI.
+    pub fn aws_cli() -> Self {
+        let mut paths = Self::default();
+        if let Some(path) = std::env::var_os("AWS_SHARED_CREDENTIALS_FILE") {
+            paths.credentials = PathBuf::from(path);
+        }
+        if let Some(path) = std::env::var_os("AWS_CONFIG_FILE") {
+            paths.config = PathBuf::from(path);
+        }
+        paths
+    }
+}
+
+impl Default for Paths {
+    fn default() -> Self {
         let home = std::env::var("HOME").expect("env HOME is required");
-        format!("{}/.aws", home)
+        Self::under(&Path::new(&home).join(".aws"))
+    }
+}
+
+// Expands a leading "~/" to $HOME, as paths in mfa.yml aren't seen by a shell.
+pub fn expand_home(path: &str) -> PathBuf {
+    match path.strip_prefix("~/") {
+        Some(rest) => Path::new(&std::env::var("HOME").expect("env HOME is required")).join(rest),
+        None => PathBuf::from(path),
+    }
+}
+
+// Writes a file next to `path` readable by the owner only and renames it over
+// `path`, so a failed or interrupted write leaves the old file as it was
+// instead of truncated. A symlink, e.g. to a dotfiles repo, is written through.
+pub fn replace(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
     };
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = dir.join(format!(".{}.{}.tmp", name, std::process::id()));
+
+    // Left by a run killed mid-write with the same pid.
+    let _ = std::fs::remove_file(&tmp);
+    let result = write_synced(&tmp, content).and_then(|_| {
+        std::fs::rename(&tmp, &path)?;
+        #[cfg(unix)]
+        std::fs::File::open(dir)?.sync_all()?;
+        Ok(())
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
 }
 
-fn config_file(filename: &str) -> PathBuf {
-    Path::new(&*CONF_DIR).join(filename)
+// Creates the file readable by the owner only, and flushes it to disk.
+fn write_synced(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(path)?;
+    file.write_all(content)?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod paths {
+        use super::*;
+
+        #[test]
+        fn it_lays_out_files_under_dir() {
+            let paths = Paths::under(Path::new("/srv/aws"));
+            assert_eq!(paths.credentials, PathBuf::from("/srv/aws/credentials"));
+            assert_eq!(paths.config, PathBuf::from("/srv/aws/config"));
+            assert_eq!(paths.cache, PathBuf::from("/srv/aws/aws-mfa"));
+            assert_eq!(paths.audit, PathBuf::from("/srv/aws/aws-mfa/audit.log"));
+            assert_eq!(paths.data, PathBuf::from("/srv/aws/aws-mfa"));
+        }
+
+        #[test]
+        fn it_keeps_workspace_apart_but_aws_config() {
+            let home = Path::new(&std::env::var("HOME").unwrap()).join(".aws");
+            let dir = home.join("aws-mfa/workspaces/client-a");
+
+            let paths = Paths::workspace("client-a").unwrap();
+            assert_eq!(paths.aws_dir, dir);
+            assert_eq!(paths.credentials, dir.join("credentials"));
+            assert_eq!(paths.config, home.join("config"));
+            assert_eq!(paths.cache, dir);
+            assert_eq!(paths.audit, dir.join("audit.log"));
+        }
+
+        #[test]
+        fn it_refuses_workspace_names_of_paths() {
+            assert!(Paths::workspace("").is_err());
+            assert!(Paths::workspace("..").is_err());
+            assert!(Paths::workspace("a/b").is_err());
+        }
+
+        #[test]
+        fn it_defaults_to_dot_aws_in_home() {
+            let home = std::env::var("HOME").unwrap();
+            assert_eq!(
+                Paths::default(),
+                Paths::under(&Path::new(&home).join(".aws"))
+            );
+        }
+    }
+
+    mod replace {
+        use super::*;
+
+        fn dir(name: &str) -> PathBuf {
+            let dir = std::env::temp_dir().join(format!(
+                "aws-mfa-replace-{}-{}",
+                std::process::id(),
+                name
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            dir
+        }
+
+        #[test]
+        fn it_replaces_content_without_leaving_temp_file() {
+            let dir = dir("content");
+            let path = dir.join("credentials");
+            std::fs::write(&path, "old content, longer than the new").unwrap();
+
+            replace(&path, b"new").unwrap();
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+            assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn it_makes_file_readable_by_owner_only() {
+            use std::os::unix::fs::PermissionsExt;
+
+            let path = dir("mode").join("credentials");
+            std::fs::write(&path, "old").unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+            replace(&path, b"new").unwrap();
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn it_writes_through_symlink() {
+            let dir = dir("symlink");
+            let target = dir.join("dotfiles-credentials");
+            let link = dir.join("credentials");
+            std::fs::write(&target, "old").unwrap();
+            let _ = std::fs::remove_file(&link);
+            std::os::unix::fs::symlink(&target, &link).unwrap();
+
+            replace(&link, b"new").unwrap();
+            assert!(std::fs::symlink_metadata(&link)
+                .unwrap()
+                .file_type()
+                .is_symlink());
+            assert_eq!(std::fs::read_to_string(&target).unwrap(), "new");
+        }
+    }
+
+    mod expand_home {
+        use super::*;
+
+        #[test]
+        fn it_expands_tilde() {
+            let home = std::env::var("HOME").unwrap();
+            assert_eq!(
+                expand_home("~/Backups/aws"),
+                Path::new(&home).join("Backups/aws")
+            );
+        }
+
+        #[test]
+        fn it_keeps_other_paths() {
+            assert_eq!(expand_home("/mnt/backup"), PathBuf::from("/mnt/backup"));
+            assert_eq!(expand_home("backup"), PathBuf::from("backup"));
+        }
+    }
 }
@@ -0,0 +1,318 @@
+use super::Paths;
+use crate::fido2::Enrollment;
+use crate::{log, prompt, redact, Result};
+
+use anyhow::anyhow;
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use data_encoding::BASE64;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use zeroize::{Zeroize, Zeroizing};
+
+// Unlocks the store without asking, e.g. in scripts.
+pub const ENV_PASSPHRASE: &str = "AWS_MFA_PASSPHRASE";
+
+// What the store is unlocked with, as taken by `secret unlock`.
+pub const UNLOCK_METHODS: [&str; 2] = ["passphrase", "key"];
+
+const VERSION: u32 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+// TOTP seeds by name, kept encrypted with a passphrase for platforms without a
+// usable OS keychain. Long-term access keys imported with `key import` are kept
+// by profile apart from the seeds.
+#[derive(Default, Deserialize, Serialize)]
+pub struct Secrets {
+    seeds: BTreeMap<String, String>,
+    #[serde(default)]
+    keys: BTreeMap<String, String>,
+}
+
+// Only the names, in case the store gets logged.
+impl std::fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Secrets")
+            .field("names", &self.names())
+            .field("key_profiles", &self.key_profiles())
+            .finish()
+    }
+}
+
+// Overwrites the seeds before the memory is freed.
+impl Drop for Secrets {
+    fn drop(&mut self) {
+        for secret in self.seeds.values_mut().chain(self.keys.values_mut()) {
+            secret.zeroize();
+        }
+    }
+}
+
+// secrets.enc: the seeds as JSON, encrypted with XChaCha20-Poly1305 under a key
+// derived from the passphrase with Argon2id.
+#[derive(Debug, Deserialize, Serialize)]
+struct Sealed {
+    version: u32,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+impl Secrets {
+    pub fn read(paths: &Paths, passphrase: &str) -> Result<Self> {
+        Self::from_path(secrets_path(paths), passphrase)
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P, passphrase: &str) -> Result<Self> {
+        if !path.as_ref().exists() {
+            return Ok(Self::default());
+        }
+
+        let content = std::fs::read_to_string(&path)?;
+        let sealed: Sealed = serde_json::from_str(&content)
+            .map_err(|e| anyhow!("{}: {}", e, path.as_ref().to_str().unwrap()))?;
+        let secrets = sealed.open(passphrase)?;
+
+        for secret in secrets.seeds.values().chain(secrets.keys.values()) {
+            redact::register(secret);
+        }
+        Ok(secrets)
+    }
+
+    pub fn write(&self, paths: &Paths, passphrase: &str) -> Result<()> {
+        self.write_to_path(secrets_path(paths), passphrase)
+    }
+
+    // Written to a file next to the store and renamed over it, so a failed write
+    // leaves the store as it was instead of truncated.
+    pub fn write_to_path<P: AsRef<Path>>(&self, path: P, passphrase: &str) -> Result<()> {
+        let path = path.as_ref();
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        std::fs::create_dir_all(dir)?;
+
+        let sealed = Sealed::seal(self, passphrase)?;
+        let content = serde_json::to_string_pretty(&sealed)?;
+
+        super::replace(path, content.as_bytes())
+            .map_err(|e| anyhow!("Error writing to secrets: {}", e))?;
+        log::file_written(path);
+        Ok(())
+    }
+
+    pub fn seed(&self, name: &str) -> Option<&str> {
+        self.seeds.get(name).map(String::as_str)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.seeds.keys().map(String::as_str).collect()
+    }
+
+    pub fn insert(&mut self, name: &str, seed: &str) {
+        self.seeds.insert(name.to_string(), seed.to_string());
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.seeds.remove(name).map(Zeroizing::new).is_some()
+    }
+
+    pub fn keys(&self, profile: &str) -> Option<&str> {
+        self.keys.get(profile).map(String::as_str)
+    }
+
+    pub fn key_profiles(&self) -> Vec<&str> {
+        self.keys.keys().map(String::as_str).collect()
+    }
+
+    pub fn insert_keys(&mut self, profile: &str, keys: &str) {
+        self.keys.insert(profile.to_string(), keys.to_string());
+    }
+
+    pub fn remove_keys(&mut self, profile: &str) -> bool {
+        self.keys.remove(profile).map(Zeroizing::new).is_some()
+    }
+}
+
+impl Sealed {
+    fn seal(secrets: &Secrets, passphrase: &str) -> Result<Self> {
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        getrandom::getrandom(&mut salt).map_err(|e| anyhow!("{}", e))?;
+        getrandom::getrandom(&mut nonce).map_err(|e| anyhow!("{}", e))?;
+
+        let cipher = cipher(passphrase, &salt)?;
+        let plaintext = Zeroizing::new(serde_json::to_vec(secrets)?);
+        let ciphertext = cipher
+            .encrypt(XNonce::from_slice(&nonce), plaintext.as_slice())
+            .map_err(|_| anyhow!("Error encrypting secrets"))?;
+
+        Ok(Self {
+            version: VERSION,
+            salt: BASE64.encode(&salt),
+            nonce: BASE64.encode(&nonce),
+            ciphertext: BASE64.encode(&ciphertext),
+        })
+    }
+
+    fn open(&self, passphrase: &str) -> Result<Secrets> {
+        if self.version != VERSION {
+            return Err(anyhow!("Unsupported secrets version: {}", self.version));
+        }
+
+        let decode = |value: &str| {
+            BASE64
+                .decode(value.as_bytes())
+                .map_err(|e| anyhow!("Invalid secrets file: {}", e))
+        };
+        let nonce = decode(&self.nonce)?;
+        if nonce.len() != NONCE_LEN {
+            return Err(anyhow!("Invalid secrets file: bad nonce"));
+        }
+
+        let plaintext = cipher(passphrase, &decode(&self.salt)?)?
+            .decrypt(
+                XNonce::from_slice(&nonce),
+                decode(&self.ciphertext)?.as_slice(),
+            )
+            .map(Zeroizing::new)
+            .map_err(|_| anyhow!("Wrong passphrase for secrets, or the file is corrupt"))?;
+        serde_json::from_slice(&plaintext).map_err(anyhow::Error::new)
+    }
+}
+
+fn cipher(passphrase: &str, salt: &[u8]) -> Result<XChaCha20Poly1305> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, key.as_mut())
+        .map_err(|e| anyhow!("Error deriving key from passphrase: {}", e))?;
+    Ok(XChaCha20Poly1305::new(key.as_ref().into()))
+}
+
+// The hmac-secret of the security key once one is enrolled, else a typed
+// passphrase.
+pub fn passphrase(paths: &Paths, new_store: bool) -> Result<Zeroizing<String>> {
+    match Enrollment::read(paths)? {
+        Some(enrollment) => enrollment.secret(),
+        None => typed_passphrase(new_store),
+    }
+}
+
+// From AWS_MFA_PASSPHRASE, else asked on the terminal. A new store asks twice.
+pub fn typed_passphrase(new_store: bool) -> Result<Zeroizing<String>> {
+    if let Some(p) = std::env::var(ENV_PASSPHRASE).ok().filter(|p| !p.is_empty()) {
+        return Ok(Zeroizing::new(p));
+    }
+
+    let passphrase = Zeroizing::new(prompt::secret("Passphrase for secrets")?);
+    if new_store && *Zeroizing::new(prompt::secret("Repeat passphrase")?) != *passphrase {
+        return Err(anyhow!("Passphrases don't match"));
+    }
+    Ok(passphrase)
+}
+
+pub fn secrets_path(paths: &Paths) -> PathBuf {
+    paths.data.join("secrets.enc")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod secrets {
+        use super::*;
+
+        fn path(name: &str) -> PathBuf {
+            std::env::temp_dir().join(format!(
+                "aws-mfa-secrets-{}/{}.enc",
+                std::process::id(),
+                name
+            ))
+        }
+
+        #[test]
+        fn it_writes_and_reads_seeds() {
+            let path = path("roundtrip");
+            let mut secrets = Secrets::default();
+            secrets.insert("work", "JBSWY3DPEHPK3PXP");
+            secrets.write_to_path(&path, "passphrase").unwrap();
+
+            let content = std::fs::read_to_string(&path).unwrap();
+            assert!(!content.contains("JBSWY3DPEHPK3PXP"));
+
+            let secrets = Secrets::from_path(&path, "passphrase").unwrap();
+            assert_eq!(secrets.seed("work"), Some("JBSWY3DPEHPK3PXP"));
+            assert_eq!(secrets.names(), vec!["work"]);
+        }
+
+        #[test]
+        fn it_keeps_keys_apart_from_seeds() {
+            let path = path("keys");
+            let mut secrets = Secrets::default();
+            secrets.insert("work", "JBSWY3DPEHPK3PXP");
+            secrets.insert_keys("work", "{}");
+            secrets.write_to_path(&path, "passphrase").unwrap();
+
+            let mut secrets = Secrets::from_path(&path, "passphrase").unwrap();
+            assert_eq!(secrets.names(), vec!["work"]);
+            assert_eq!(secrets.key_profiles(), vec!["work"]);
+            assert_eq!(secrets.keys("work"), Some("{}"));
+            assert!(secrets.remove_keys("work"));
+            assert_eq!(secrets.seed("work"), Some("JBSWY3DPEHPK3PXP"));
+        }
+
+        #[test]
+        fn it_replaces_store_without_leaving_temp_file() {
+            let path = path("replace");
+            let mut secrets = Secrets::default();
+            secrets.insert("work", "JBSWY3DPEHPK3PXP");
+            secrets.write_to_path(&path, "passphrase").unwrap();
+            secrets.insert("home", "GEZDGNBVGY3TQOJQ");
+            secrets.write_to_path(&path, "passphrase").unwrap();
+
+            let secrets = Secrets::from_path(&path, "passphrase").unwrap();
+            assert_eq!(secrets.names(), vec!["home", "work"]);
+
+            let dir = path.parent().unwrap();
+            let leftover = std::fs::read_dir(dir)
+                .unwrap()
+                .filter_map(|entry| entry.ok())
+                .any(|entry| entry.file_name().to_string_lossy().ends_with(".tmp"));
+            assert!(!leftover);
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+                assert_eq!(mode & 0o777, 0o600);
+            }
+        }
+
+        #[test]
+        fn it_fails_with_wrong_passphrase() {
+            let path = path("wrong");
+            let mut secrets = Secrets::default();
+            secrets.insert("work", "JBSWY3DPEHPK3PXP");
+            secrets.write_to_path(&path, "passphrase").unwrap();
+
+            let err = Secrets::from_path(&path, "other").unwrap_err();
+            assert!(err.to_string().starts_with("Wrong passphrase"));
+        }
+
+        #[test]
+        fn it_leaves_seeds_out_of_debug_output() {
+            let mut secrets = Secrets::default();
+            secrets.insert("work", "JBSWY3DPEHPK3PXP");
+            let debug = format!("{:?}", secrets);
+            assert!(debug.contains("work"));
+            assert!(!debug.contains("JBSWY3DPEHPK3PXP"));
+        }
+
+        #[test]
+        fn it_returns_empty_secrets_when_file_not_found() {
+            let secrets = Secrets::from_path("mock/not-found.enc", "passphrase").unwrap();
+            assert!(secrets.names().is_empty());
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use super::lock::{self, LockStrategy};
+use super::Paths;
+use crate::Result;
+
+use anyhow::anyhow;
+use chrono::Utc;
+use std::path::PathBuf;
+
+// Files restored together, since config and mfa.yml refer to credentials profiles.
+pub const FILES: [&str; 4] = ["credentials", "config", "mfa.yml", "mfa.yaml"];
+
+// Copies the files in FILES into a new snapshot directory and returns its id.
+pub fn create(paths: &Paths) -> Result<String> {
+    let id = new_id(paths);
+    let dir = snapshot_dir(paths, &id);
+    std::fs::create_dir_all(&dir)?;
+
+    for file in FILES {
+        let src = file_path(paths, file);
+        if src.exists() {
+            std::fs::copy(&src, dir.join(file))
+                .map_err(|e| anyhow!("Error copying {} to snapshot: {}", file, e))?;
+        }
+    }
+
+    Ok(id)
+}
+
+// Puts the files back as they were in the snapshot: those saved in it are
+// replaced through temp files and the others removed, all under the lock of the
+// credentials file. Returns the files restored and those removed.
+pub fn restore(
+    paths: &Paths,
+    id: &str,
+    strategy: LockStrategy,
+) -> Result<(Vec<String>, Vec<String>)> {
+    let dir = snapshot_dir(paths, id);
+    if id.is_empty() || id.contains('/') || !dir.is_dir() {
+        return Err(anyhow!("Not Found snapshot: {}", id));
+    }
+
+    let _lock = lock::acquire(&paths.credentials, strategy)?;
+
+    let mut restored = Vec::new();
+    let mut removed = Vec::new();
+    for file in FILES {
+        let src = dir.join(file);
+        let dest = file_path(paths, file);
+        if src.exists() {
+            std::fs::read(&src)
+                .and_then(|content| super::replace(&dest, &content))
+                .map_err(|e| anyhow!("Error restoring {}: {}", file, e))?;
+            restored.push(file.to_string());
+        } else if dest.exists() {
+            std::fs::remove_file(&dest)
+                .map_err(|e| anyhow!("Error removing {}: {}", dest.display(), e))?;
+            removed.push(file.to_string());
+        }
+    }
+
+    Ok((restored, removed))
+}
+
+// Snapshot ids, oldest first.
+pub fn list(paths: &Paths) -> Result<Vec<String>> {
+    let root = snapshots_root(paths);
+    if !root.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut ids = std::fs::read_dir(root)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect::<Vec<String>>();
+    ids.sort();
+    Ok(ids)
+}
+
+fn new_id(paths: &Paths) -> String {
+    let base = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let mut id = base.clone();
+    let mut n = 1;
+    while snapshot_dir(paths, &id).exists() {
+        id = format!("{}-{}", base, n);
+        n += 1;
+    }
+    id
+}
+
+// Where the file of FILES lives.
+fn file_path(paths: &Paths, file: &str) -> PathBuf {
+    match file {
+        "credentials" => paths.credentials.clone(),
+        "config" => paths.config.clone(),
+        _ => paths.aws_dir.join(file),
+    }
+}
+
+fn snapshots_root(paths: &Paths) -> PathBuf {
+    paths.data.join("snapshots")
+}
+
+fn snapshot_dir(paths: &Paths, id: &str) -> PathBuf {
+    snapshots_root(paths).join(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod restore {
+        use super::*;
+
+        fn paths(name: &str) -> Paths {
+            let dir = std::env::temp_dir().join(format!(
+                "aws-mfa-snapshot-{}-{}",
+                std::process::id(),
+                name
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            Paths::under(&dir)
+        }
+
+        #[test]
+        fn it_puts_files_back_as_they_were() {
+            let paths = paths("files");
+            std::fs::write(&paths.credentials, "[default]\n").unwrap();
+            let id = create(&paths).unwrap();
+
+            std::fs::write(&paths.credentials, "[changed]\n").unwrap();
+            std::fs::write(paths.aws_dir.join("mfa.yml"), "devices: []\n").unwrap();
+
+            let (restored, removed) = restore(&paths, &id, LockStrategy::default()).unwrap();
+            assert_eq!(restored, vec!["credentials"]);
+            assert_eq!(removed, vec!["mfa.yml"]);
+            assert_eq!(
+                std::fs::read_to_string(&paths.credentials).unwrap(),
+                "[default]\n"
+            );
+            assert!(!paths.aws_dir.join("mfa.yml").exists());
+        }
+
+        #[test]
+        fn it_refuses_ids_of_paths() {
+            let paths = paths("ids");
+            let err = restore(&paths, "../..", LockStrategy::default()).unwrap_err();
+            assert!(err.to_string().starts_with("Not Found snapshot"));
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use super::credentials::SsoProfile;
+use super::Paths;
+use crate::{redact, Result};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+// An access token `aws sso login` cached in ~/.aws/sso/cache, in a file named
+// after the SHA-1 of the session name, or of the start URL for legacy profiles.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CachedToken {
+    access_token: String,
+    expires_at: String,
+}
+
+// The cached access token of the profile's SSO session, while it is valid.
+pub fn access_token(paths: &Paths, sso: &SsoProfile, profile: &str) -> Result<Zeroizing<String>> {
+    read_token(&token_path(paths, sso), Utc::now()).map_err(|e| {
+        anyhow!(
+            "{}: sign in with aws sso login --profile {} first",
+            e,
+            profile
+        )
+    })
+}
+
+pub fn token_path(paths: &Paths, sso: &SsoProfile) -> PathBuf {
+    let digest = Sha1::digest(sso.cache_key().as_bytes());
+    let name: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    paths
+        .aws_dir
+        .join("sso")
+        .join("cache")
+        .join(format!("{}.json", name))
+}
+
+fn read_token(path: &Path, now: DateTime<Utc>) -> Result<Zeroizing<String>> {
+    let content = Zeroizing::new(
+        std::fs::read_to_string(path)
+            .map_err(|_| anyhow!("Not Found cached SSO token {}", path.display()))?,
+    );
+    let token: CachedToken =
+        serde_json::from_str(&content).map_err(|e| anyhow!("{}: {}", e, path.display()))?;
+
+    // Older versions of the aws CLI v2 end it with "UTC" instead of "Z".
+    let expires_at = token.expires_at.replace("UTC", "Z");
+    let expiration: DateTime<Utc> = expires_at
+        .parse()
+        .map_err(|e| anyhow!("{}: expiresAt of {}", e, path.display()))?;
+    if expiration <= now {
+        return Err(anyhow!("SSO session expired at {}", expiration));
+    }
+
+    redact::register(&token.access_token);
+    Ok(Zeroizing::new(token.access_token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_token(name: &str, content: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("aws-mfa-sso-{}-{}.json", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    mod token_path {
+        use super::*;
+
+        #[test]
+        fn it_names_file_like_aws_sso_login() {
+            let sso = SsoProfile {
+                account_id: "123456789012".to_owned(),
+                role_name: "ReadOnly".to_owned(),
+                start_url: "https://example.awsapps.com/start".to_owned(),
+                region: "us-east-1".to_owned(),
+                session: Some("my-sso".to_owned()),
+            };
+            assert!(token_path(&Paths::default(), &sso)
+                .ends_with("sso/cache/0ad374308c5a4e22f723adf10145eafad7c4031c.json"));
+        }
+    }
+
+    mod read_token {
+        use super::*;
+
+        #[test]
+        fn it_reads_valid_token() {
+            let path = write_token(
+                "valid",
+                r#"{"startUrl": "https://example.awsapps.com/start", "region": "us-east-1",
+                    "accessToken": "aoaTOKEN", "expiresAt": "2030-01-01T00:00:00Z"}"#,
+            );
+            let token = read_token(&path, Utc::now()).unwrap();
+            assert_eq!(*token, "aoaTOKEN");
+            std::fs::remove_file(path).unwrap();
+        }
+
+        #[test]
+        fn it_reads_expiration_in_utc() {
+            let path = write_token(
+                "utc",
+                r#"{"accessToken": "aoaTOKEN", "expiresAt": "2030-01-01T00:00:00UTC"}"#,
+            );
+            assert!(read_token(&path, Utc::now()).is_ok());
+            std::fs::remove_file(path).unwrap();
+        }
+
+        #[test]
+        fn it_fails_with_expired_token() {
+            let path = write_token(
+                "expired",
+                r#"{"accessToken": "aoaTOKEN", "expiresAt": "2020-01-01T00:00:00Z"}"#,
+            );
+            let err = read_token(&path, Utc::now()).unwrap_err();
+            assert!(err.to_string().starts_with("SSO session expired"));
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
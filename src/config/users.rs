@@ -0,0 +1,74 @@
+use crate::Result;
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use std::path::Path;
+
+// Profile of the user's own keys when users.yml doesn't name one.
+const DEFAULT_PROFILE: &str = "default";
+
+// An IAM user to give a virtual device under `users:` of users.yml.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct User {
+    pub user: String,
+    // Profile the user's mfa.yml snippet is for
+    #[serde(default = "default_profile")]
+    pub profile: String,
+}
+
+#[derive(Deserialize)]
+struct Users {
+    users: Vec<User>,
+}
+
+fn default_profile() -> String {
+    DEFAULT_PROFILE.to_string()
+}
+
+pub fn read(path: &Path) -> Result<Vec<User>> {
+    let content =
+        std::fs::read_to_string(path).map_err(|e| anyhow!("{}: {}", e, path.to_str().unwrap()))?;
+    parse(&content).map_err(|e| anyhow!("{}: {}", path.to_str().unwrap(), e))
+}
+
+fn parse(content: &str) -> Result<Vec<User>> {
+    let users: Users = serde_yaml::from_str(content)?;
+    if let Some(user) = users.users.iter().find(|u| u.user.trim().is_empty()) {
+        return Err(anyhow!("empty user name, with profile {}", user.profile));
+    }
+    Ok(users.users)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod parse {
+        use super::*;
+
+        #[test]
+        fn it_reads_users_with_default_profile() {
+            let users =
+                parse("users:\n  - user: tanaka\n  - user: sato\n    profile: work\n").unwrap();
+            assert_eq!(
+                users,
+                vec![
+                    User {
+                        user: "tanaka".into(),
+                        profile: "default".into()
+                    },
+                    User {
+                        user: "sato".into(),
+                        profile: "work".into()
+                    },
+                ]
+            );
+        }
+
+        #[test]
+        fn it_fails_without_user_name() {
+            assert!(parse("users:\n  - profile: work\n").is_err());
+            assert!(parse("users:\n  - user: ''\n").is_err());
+        }
+    }
+}
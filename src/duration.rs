@@ -0,0 +1,234 @@
+use crate::Result;
+
+use anyhow::anyhow;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Duration, Local, Utc};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+lazy_static! {
+    // "90m", "1h30m", "2h 15m 10s"
+    static ref RE_UNITS: Regex = Regex::new(r"^(?:(\d+)d)?\s*(?:(\d+)h)?\s*(?:(\d+)m)?\s*(?:(\d+)s)?$").unwrap();
+    // ISO 8601 durations without years and months, whose length varies: "PT12H", "P1DT6H"
+    static ref RE_ISO8601: Regex = Regex::new(r"^P(?:(\d+)W)?(?:(\d+)D)?(?:T(?:(\d+)H)?(?:(\d+)M)?(?:(\d+)S)?)?$").unwrap();
+    static ref TIME_FORMAT: RwLock<Option<TimeFormat>> = RwLock::new(None);
+}
+
+// Parses seconds given as "3600", "1h", "90m", "1h30m" or "PT1H".
+pub fn parse_seconds(value: &str) -> Result<u32> {
+    let value = value.trim();
+    let invalid = || anyhow!("Parse error: cannot parse duration: {}", value);
+
+    if let Ok(secs) = value.parse::<u32>() {
+        return Ok(secs);
+    }
+
+    let (caps, units): (_, &[u64]) = if let Some(caps) = RE_ISO8601.captures(value) {
+        (caps, &[604800, 86400, 3600, 60, 1])
+    } else if let Some(caps) = RE_UNITS.captures(value) {
+        (caps, &[86400, 3600, 60, 1])
+    } else {
+        return Err(invalid());
+    };
+
+    // Each number is at group i + 1, and "P", "PT" or "" alone means nothing.
+    let mut matched = false;
+    let mut secs: u64 = 0;
+    for (i, unit) in units.iter().enumerate() {
+        if let Some(n) = caps.get(i + 1) {
+            let n: u64 = n.as_str().parse().map_err(|_| invalid())?;
+            secs = n
+                .checked_mul(*unit)
+                .and_then(|s| s.checked_add(secs))
+                .ok_or_else(invalid)?;
+            matched = true;
+        }
+    }
+
+    if !matched {
+        return Err(invalid());
+    }
+    u32::try_from(secs).map_err(|_| invalid())
+}
+
+// Formats a duration like "1h 5m" or "45s" for humans.
+pub fn humanize(duration: Duration) -> String {
+    let secs = duration.num_seconds().max(0);
+    let (hours, minutes, seconds) = (secs / 3600, secs % 3600 / 60, secs % 60);
+
+    match (hours, minutes) {
+        (0, 0) => format!("{}s", seconds),
+        (0, m) => format!("{}m", m),
+        (h, 0) => format!("{}h", h),
+        (h, m) => format!("{}h {}m", h, m),
+    }
+}
+
+// How times like expirations are shown, time_format in mfa.yml.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimeFormat {
+    // "in 58m" or "3m ago"
+    Relative,
+    // 2024-01-01 21:00:00 +09:00
+    Local,
+    // 2024-01-01 12:00:00 UTC
+    Utc,
+    // A strftime format of the local time, like "%H:%M"
+    Custom(String),
+}
+
+impl TimeFormat {
+    pub const VARIANTS: [&'static str; 3] = ["relative", "local", "utc"];
+
+    pub fn format(&self, time: DateTime<Utc>, now: DateTime<Utc>) -> String {
+        match self {
+            Self::Relative if time >= now => format!("in {}", humanize(time - now)),
+            Self::Relative => format!("{} ago", humanize(now - time)),
+            Self::Local => time
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S %:z")
+                .to_string(),
+            Self::Utc => time.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+            Self::Custom(format) => time.with_timezone(&Local).format(format).to_string(),
+        }
+    }
+}
+
+impl FromStr for TimeFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "relative" => Ok(Self::Relative),
+            "local" => Ok(Self::Local),
+            "utc" => Ok(Self::Utc),
+            _ if s.contains('%') => {
+                // chrono panics on formatting with an invalid one.
+                if StrftimeItems::new(s).any(|item| item == Item::Error) {
+                    return Err(anyhow!("Invalid strftime format in time_format: {}", s));
+                }
+                Ok(Self::Custom(s.to_string()))
+            }
+            _ => Err(anyhow!(
+                "Unknown time format: {}, expected {} or a strftime format",
+                s,
+                Self::VARIANTS.join(", ")
+            )),
+        }
+    }
+}
+
+// Set from time_format in mfa.yml, before any time is shown.
+pub fn set_time_format(format: TimeFormat) {
+    *TIME_FORMAT.write().unwrap() = Some(format);
+}
+
+pub fn time_format() -> Option<TimeFormat> {
+    TIME_FORMAT.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod parse_seconds {
+        use super::*;
+
+        #[test]
+        fn it_parses_integer_seconds() {
+            assert_eq!(parse_seconds("900").unwrap(), 900);
+        }
+
+        #[test]
+        fn it_parses_unit_suffixes() {
+            assert_eq!(parse_seconds("45s").unwrap(), 45);
+            assert_eq!(parse_seconds("90m").unwrap(), 5400);
+            assert_eq!(parse_seconds("12h").unwrap(), 43200);
+            assert_eq!(parse_seconds("1d").unwrap(), 86400);
+            assert_eq!(parse_seconds("1h30m").unwrap(), 5400);
+            assert_eq!(parse_seconds("1h 30m 15s").unwrap(), 5415);
+        }
+
+        #[test]
+        fn it_parses_iso8601_durations() {
+            assert_eq!(parse_seconds("PT12H").unwrap(), 43200);
+            assert_eq!(parse_seconds("PT15M").unwrap(), 900);
+            assert_eq!(parse_seconds("PT1H30M").unwrap(), 5400);
+            assert_eq!(parse_seconds("P1DT6H").unwrap(), 108000);
+            assert_eq!(parse_seconds("P1W").unwrap(), 604800);
+        }
+
+        #[test]
+        fn it_rejects_invalid_durations() {
+            for value in [
+                "",
+                "P",
+                "PT",
+                "1x",
+                "-900",
+                "P1M",
+                "P1Y",
+                "PT1.5H",
+                "h",
+                "99999999999",
+            ] {
+                assert!(parse_seconds(value).is_err(), "{}", value);
+            }
+        }
+    }
+
+    mod humanize {
+        use super::*;
+
+        #[test]
+        fn it_formats_durations() {
+            assert_eq!(humanize(Duration::seconds(45)), "45s");
+            assert_eq!(humanize(Duration::seconds(125)), "2m");
+            assert_eq!(humanize(Duration::hours(2)), "2h");
+            assert_eq!(humanize(Duration::minutes(65)), "1h 5m");
+        }
+
+        #[test]
+        fn it_formats_negative_durations_as_zero() {
+            assert_eq!(humanize(Duration::seconds(-10)), "0s");
+        }
+    }
+
+    mod time_format {
+        use super::*;
+
+        #[test]
+        fn it_parses_names_and_strftime() {
+            assert_eq!(
+                "relative".parse::<TimeFormat>().unwrap(),
+                TimeFormat::Relative
+            );
+            assert_eq!("utc".parse::<TimeFormat>().unwrap(), TimeFormat::Utc);
+            assert_eq!(
+                "%H:%M".parse::<TimeFormat>().unwrap(),
+                TimeFormat::Custom("%H:%M".into())
+            );
+            assert!("%Q".parse::<TimeFormat>().is_err());
+            assert!("soon".parse::<TimeFormat>().is_err());
+        }
+
+        #[test]
+        fn it_formats_relative_to_now() {
+            let now = "2024-01-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+            let format = TimeFormat::Relative;
+            assert_eq!(format.format(now + Duration::minutes(58), now), "in 58m");
+            assert_eq!(format.format(now - Duration::minutes(3), now), "3m ago");
+        }
+
+        #[test]
+        fn it_formats_utc() {
+            let time = "2024-01-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+            assert_eq!(
+                TimeFormat::Utc.format(time, time),
+                "2024-01-01 12:00:00 UTC"
+            );
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use crate::config::credentials::ParseError;
+use crate::interrupt::Interrupted;
+use crate::process::Timeout;
+use crate::{redact, Result};
+
+use anyhow::anyhow;
+use serde_json::{json, Value};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+// How a failure is reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    // The message on stderr
+    Text,
+    // {"error": {"code": ..., "message": ..., "hint": ...}} on stdout
+    Json,
+}
+
+impl ErrorFormat {
+    pub const VARIANTS: [&'static str; 2] = ["text", "json"];
+}
+
+impl FromStr for ErrorFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(anyhow!("Unknown error format: {}", s)),
+        }
+    }
+}
+
+pub fn init(format: ErrorFormat) {
+    JSON.store(format == ErrorFormat::Json, Ordering::Relaxed);
+}
+
+pub fn report(err: &anyhow::Error) {
+    if JSON.load(Ordering::Relaxed) {
+        println!("{}", to_json(err));
+    } else {
+        eprintln!("{}", redact::redact(&err.to_string()));
+    }
+}
+
+fn to_json(err: &anyhow::Error) -> Value {
+    let code = code(err);
+    json!({
+        "error": {
+            "code": code,
+            "message": redact::redact(&err.to_string()),
+            "hint": hint(&code),
+        }
+    })
+}
+
+// A stable name of the failure to branch on. AWS errors keep the code the aws
+// CLI printed, like AccessDenied or ExpiredToken.
+pub fn code(err: &anyhow::Error) -> String {
+    if err.is::<Timeout>() {
+        return "Timeout".to_string();
+    }
+    if err.is::<Interrupted>() {
+        return "Interrupted".to_string();
+    }
+    if err.is::<ParseError>() {
+        return "ParseError".to_string();
+    }
+
+    let message = err.to_string();
+    if message.contains("invalid MFA one time pass code") {
+        return "InvalidMfaCode".to_string();
+    }
+    if let Some(code) = aws_error_code(&message) {
+        return code.to_string();
+    }
+
+    let code = match message.split(':').next().unwrap_or_default() {
+        m if m.starts_with("Not Found mfa device") || m.starts_with("Not Found device") => {
+            "DeviceNotFound"
+        }
+        m if m.starts_with("Not Found mfa profile") || m.starts_with("Not Found session") => {
+            "SessionNotFound"
+        }
+        m if m.starts_with("Not Found secret") => "SecretNotFound",
+        m if m.starts_with("Not Found group") => "GroupNotFound",
+        m if m.starts_with("Not Found aws CLI") => "AwsCliNotFound",
+        m if m.starts_with("Not Found") => "NotFound",
+        m if m.starts_with("Session for mfa profile") && message.contains("expired at") => {
+            "SessionExpired"
+        }
+        m if m.starts_with("Invalid MFA code") => "InvalidMfaCode",
+        _ => "Error",
+    };
+    code.to_string()
+}
+
+// The code of "An error occurred (AccessDenied) when calling ...".
+fn aws_error_code(message: &str) -> Option<&str> {
+    let (_, rest) = message.split_once("An error occurred (")?;
+    let (code, _) = rest.split_once(')')?;
+    Some(code).filter(|code| !code.is_empty() && !code.contains(' '))
+}
+
+pub fn hint(code: &str) -> Option<&'static str> {
+    match code {
+        "DeviceNotFound" => {
+            Some("add the device of the profile to mfa.yml, or run aws-mfa device provision")
+        }
+        "SessionNotFound" | "SessionExpired" | "ExpiredToken" => {
+            Some("refresh the session with aws-mfa -p PROFILE")
+        }
+        "InvalidMfaCode" => Some("wait for the next code, or resync the device"),
+        "AccessDenied" => Some("check the IAM permissions of the profile's user"),
+        "InvalidClientTokenId" | "SignatureDoesNotMatch" => {
+            Some("the long-term keys of the profile are wrong or deactivated")
+        }
+        "Timeout" => Some("raise --timeout, or check the network"),
+        "AwsCliNotFound" if cfg!(any(feature = "sdk", feature = "sigv4")) => Some("install the aws CLI"),
+        "AwsCliNotFound" => {
+            Some("install the aws CLI, or build aws-mfa with --features sdk or sigv4 to refresh without it")
+        }
+        "ParseError" => Some("fix the line named, or pass --lenient to skip the section"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod code {
+        use super::*;
+
+        #[test]
+        fn it_names_failures() {
+            let code_of = |message: &str| code(&anyhow!(message.to_string()));
+            assert_eq!(
+                code_of("Not Found mfa device arn for profile: work"),
+                "DeviceNotFound"
+            );
+            assert_eq!(
+                code_of("An error occurred (ExpiredToken) when calling the AssumeRole operation: expired"),
+                "ExpiredToken"
+            );
+            assert_eq!(
+                code_of("An error occurred (AccessDenied) when calling the GetSessionToken operation: MultiFactorAuthentication failed with invalid MFA one time pass code."),
+                "InvalidMfaCode"
+            );
+            assert_eq!(code_of("Not Found group: ops"), "GroupNotFound");
+            assert_eq!(code_of("something else"), "Error");
+        }
+
+        #[test]
+        fn it_names_typed_failures() {
+            let err = anyhow::Error::new(Interrupted);
+            assert_eq!(code(&err), "Interrupted");
+        }
+    }
+
+    mod to_json {
+        use super::*;
+
+        #[test]
+        fn it_nests_code_message_and_hint() {
+            let value = to_json(&anyhow!("Not Found mfa device arn for profile: work"));
+            assert_eq!(value["error"]["code"], "DeviceNotFound");
+            assert_eq!(
+                value["error"]["message"],
+                "Not Found mfa device arn for profile: work"
+            );
+            assert!(value["error"]["hint"].is_string());
+
+            let value = to_json(&anyhow!("something else"));
+            assert!(value["error"]["hint"].is_null());
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use crate::config::Paths;
+use crate::{log, Result};
+
+use anyhow::anyhow;
+use data_encoding::BASE64;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use zeroize::Zeroizing;
+
+// A FIDO2 security key unlocks the secrets store with its hmac-secret in place
+// of a passphrase, through fido2-token, fido2-cred and fido2-assert of libfido2.
+// Each unlock asks for a touch, so no seed is read without someone at the key.
+
+const RP_ID: &str = "aws-mfa";
+const RANDOM_LEN: usize = 32;
+
+// The credential made on the key, and the salt its hmac-secret is asked for.
+// Neither is secret: the key alone turns them into the passphrase.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Enrollment {
+    credential_id: String,
+    salt: String,
+}
+
+impl Enrollment {
+    // None when the store is unlocked with a passphrase.
+    pub fn read(paths: &Paths) -> Result<Option<Self>> {
+        let path = enrollment_path(paths);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = std::fs::read_to_string(&path)?;
+        serde_json::from_str(&content)
+            .map(Some)
+            .map_err(|e| anyhow!("{}: {}", e, path.display()))
+    }
+
+    // Makes a credential with the hmac-secret extension on the first key found.
+    pub fn create() -> Result<Self> {
+        let device = device()?;
+        eprintln!("Touch the security key to register it");
+        let input = [random()?, RP_ID.to_string(), RP_ID.to_string(), random()?].join("\n");
+        let output = run("fido2-cred", &["-M", "-h", &device], Some(&input))?;
+
+        Ok(Self {
+            credential_id: credential_id(&output)?,
+            salt: random()?,
+        })
+    }
+
+    pub fn write(&self, paths: &Paths) -> Result<()> {
+        let path = enrollment_path(paths);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&path, serde_json::to_string_pretty(self)?)?;
+        log::file_written(&path);
+        Ok(())
+    }
+
+    pub fn remove(paths: &Paths) -> Result<()> {
+        std::fs::remove_file(enrollment_path(paths))?;
+        Ok(())
+    }
+
+    // The key's hmac-secret for the salt, which the store is encrypted with.
+    pub fn secret(&self) -> Result<Zeroizing<String>> {
+        let device = device()?;
+        eprintln!("Touch the security key to unlock secrets");
+        let input = [
+            random()?,
+            RP_ID.to_string(),
+            self.credential_id.clone(),
+            self.salt.clone(),
+        ]
+        .join("\n");
+        let output = Zeroizing::new(run(
+            "fido2-assert",
+            &["-G", "-h", "-p", &device],
+            Some(&input),
+        )?);
+        hmac_secret(&output)
+    }
+}
+
+pub fn enrollment_path(paths: &Paths) -> PathBuf {
+    paths.data.join("secrets.fido2")
+}
+
+// The first key plugged in.
+fn device() -> Result<String> {
+    let list = run("fido2-token", &["-L"], None)?;
+    first_device(&list).ok_or_else(|| anyhow!("Not Found security key: plug it in"))
+}
+
+// Runs a libfido2 tool with its input on stdin. Its stderr stays on the
+// terminal, where it asks for the PIN if the key has one.
+fn run(program: &str, args: &[&str], input: Option<&str>) -> Result<String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => anyhow!(
+                "Not Found {}: install the tools of libfido2 to use a security key",
+                program
+            ),
+            _ => anyhow!("{}: {}", e, program),
+        })?;
+    if let Some(input) = input {
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(format!("{}\n", input).as_bytes())?;
+    }
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!("{} failed with {}", program, output.status));
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+fn random() -> Result<String> {
+    let mut bytes = [0u8; RANDOM_LEN];
+    getrandom::getrandom(&mut bytes).map_err(|e| anyhow!("{}", e))?;
+    Ok(BASE64.encode(&bytes))
+}
+
+// "/dev/hidraw0: vendor=0x1050, product=0x0407 (Yubico YubiKey OTP+FIDO+CCID)"
+fn first_device(list: &str) -> Option<String> {
+    list.lines()
+        .find_map(|line| line.split_once(": "))
+        .map(|(device, _)| device.to_string())
+}
+
+// fido2-cred -M prints the client data hash, the relying party, the format, the
+// authenticator data and then the credential id.
+fn credential_id(output: &str) -> Result<String> {
+    output
+        .lines()
+        .nth(4)
+        .filter(|id| !id.is_empty())
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("No credential id in output of fido2-cred"))
+}
+
+// fido2-assert -G -h prints the hmac-secret last, after the client data hash,
+// the relying party, the authenticator data and the signature.
+fn hmac_secret(output: &str) -> Result<Zeroizing<String>> {
+    let lines: Vec<&str> = output.lines().collect();
+    match lines.as_slice() {
+        [_, _, _, _, .., secret] if !secret.is_empty() => Ok(Zeroizing::new(secret.to_string())),
+        _ => Err(anyhow!(
+            "No hmac-secret in output of fido2-assert: the key may not support it"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod first_device {
+        use super::*;
+
+        #[test]
+        fn it_takes_path_of_first_key() {
+            let list = concat!(
+                "/dev/hidraw3: vendor=0x1050, product=0x0407 (Yubico YubiKey OTP+FIDO+CCID)\n",
+                "/dev/hidraw5: vendor=0x096e, product=0x0858 (FT U2F)\n",
+            );
+            assert_eq!(first_device(list), Some("/dev/hidraw3".to_owned()));
+            assert_eq!(first_device(""), None);
+        }
+    }
+
+    mod parse_output {
+        use super::*;
+
+        #[test]
+        fn it_reads_credential_id() {
+            let output = "aGFzaA==\naws-mfa\npacked\nYXV0aA==\nY3JlZA==\nc2ln\nY2VydA==\n";
+            assert_eq!(credential_id(output).unwrap(), "Y3JlZA==");
+            assert!(credential_id("aGFzaA==\naws-mfa\n").is_err());
+        }
+
+        #[test]
+        fn it_reads_hmac_secret() {
+            let output = "aGFzaA==\naws-mfa\nYXV0aA==\nc2ln\nc2VjcmV0\n";
+            assert_eq!(*hmac_secret(output).unwrap(), "c2VjcmV0");
+            // Without -h support the secret line is missing.
+            assert!(hmac_secret("aGFzaA==\naws-mfa\nYXV0aA==\nc2ln\n").is_err());
+        }
+    }
+}
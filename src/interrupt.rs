@@ -0,0 +1,106 @@
+use crate::Result;
+
+use anyhow::anyhow;
+use lazy_static::lazy_static;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+// Same as a shell reports for a process killed by SIGINT.
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    // Held while a guarded section runs, so Ctrl-C waits for it to roll back.
+    static ref GUARDED: Mutex<()> = Mutex::new(());
+}
+
+// Returned from a guarded section that was interrupted and rolled back.
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Interrupted: restored the files it was writing")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+pub fn install() -> Result<()> {
+    ctrlc::set_handler(on_interrupt)
+        .map_err(|e| anyhow!("Error installing the interrupt handler: {}", e))
+}
+
+fn on_interrupt() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+
+    let _lock = GUARDED.lock().unwrap_or_else(PoisonError::into_inner);
+
+    // A guarded section clears the flag when it handles the interrupt itself.
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        eprintln!("Interrupted");
+        std::process::exit(EXIT_INTERRUPTED);
+    }
+}
+
+// Runs `f` to completion even if Ctrl-C arrives meanwhile, then calls `rollback`
+// and fails with Interrupted if it did.
+pub fn guarded<T, R, F>(rollback: R, f: F) -> Result<T>
+where
+    R: FnOnce() -> Result<()>,
+    F: FnOnce() -> Result<T>,
+{
+    let _lock = GUARDED.lock().unwrap_or_else(PoisonError::into_inner);
+    let result = f();
+
+    if INTERRUPTED.swap(false, Ordering::SeqCst) {
+        rollback()?;
+        return Err(Interrupted.into());
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    mod guarded {
+        use super::*;
+
+        #[test]
+        fn it_returns_result_without_rollback() {
+            let rolled_back = Cell::new(false);
+            let result = guarded(
+                || {
+                    rolled_back.set(true);
+                    Ok(())
+                },
+                || Ok(1),
+            );
+            assert_eq!(result.unwrap(), 1);
+            assert!(!rolled_back.get());
+        }
+
+        #[test]
+        fn it_rolls_back_when_interrupted() {
+            let rolled_back = Cell::new(false);
+            let result = guarded(
+                || {
+                    rolled_back.set(true);
+                    Ok(())
+                },
+                || {
+                    INTERRUPTED.store(true, Ordering::SeqCst);
+                    Ok(1)
+                },
+            );
+            assert!(result.unwrap_err().is::<Interrupted>());
+            assert!(rolled_back.get());
+            assert!(!INTERRUPTED.load(Ordering::SeqCst));
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use crate::Result;
+
+use anyhow::anyhow;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use zeroize::Zeroizing;
+
+// The OS keychain: macOS `security`, or the freedesktop Secret Service via
+// `secret-tool` elsewhere. Items are found by service and profile.
+
+pub fn store(service: &str, profile: &str, secret: &str) -> Result<()> {
+    let (mut command, stdin) = if cfg!(target_os = "macos") {
+        let mut command = Command::new("security");
+        command.args(["add-generic-password", "-U", "-s", service, "-a"]);
+        command.args([profile, "-w", secret]);
+        (command, None)
+    } else {
+        let mut command = Command::new("secret-tool");
+        command.args(["store", "--label", &format!("aws-mfa: {}", profile)]);
+        command.args(["service", service, "profile", profile]);
+        (command, Some(secret))
+    };
+
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("Error opening keychain: {}", e))?;
+
+    if let Some(secret) = stdin {
+        child.stdin.take().unwrap().write_all(secret.as_bytes())?;
+    }
+
+    let output = child.wait_with_output()?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Error writing to keychain: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+// false when there was no such item.
+pub fn remove(service: &str, profile: &str) -> Result<bool> {
+    let mut command = if cfg!(target_os = "macos") {
+        let mut command = Command::new("security");
+        command.args(["delete-generic-password", "-s", service, "-a", profile]);
+        command
+    } else {
+        let mut command = Command::new("secret-tool");
+        command.args(["clear", "service", service, "profile", profile]);
+        command
+    };
+
+    let exists = load(service, profile)?.is_some();
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| anyhow!("Error opening keychain: {}", e))?;
+
+    if exists && !output.status.success() {
+        return Err(anyhow!(
+            "Error removing from keychain: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(exists)
+}
+
+// None when there is no such item.
+pub fn load(service: &str, profile: &str) -> Result<Option<Zeroizing<String>>> {
+    let mut command = if cfg!(target_os = "macos") {
+        let mut command = Command::new("security");
+        command.args(["find-generic-password", "-s", service, "-a", profile, "-w"]);
+        command
+    } else {
+        let mut command = Command::new("secret-tool");
+        command.args(["lookup", "service", service, "profile", profile]);
+        command
+    };
+
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| anyhow!("Error opening keychain: {}", e))?;
+    let stdout = Zeroizing::new(output.stdout);
+
+    // Both exit with an error and print nothing for a missing item.
+    if stdout.is_empty() {
+        return Ok(None);
+    }
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Error reading from keychain: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let secret = std::str::from_utf8(&stdout)?.trim_end_matches('\n');
+    Ok(Some(Zeroizing::new(secret.to_string())))
+}
@@ -1,22 +1,201 @@
-use clap::ArgMatches;
-use config::credentials::Credential as AwsCredential;
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use clap::{ArgMatches, ValueSource};
+use code::CodeBatch;
+use config::credentials::{aws_config_value, ConflictPolicy, Credential as AwsCredential};
+use config::lock::LockStrategy;
 use config::mfa::Config;
-use serde::Deserialize;
+use config::Paths;
+use duration::TimeFormat;
+use serde::{Deserialize, Serialize};
+use shell::Shell;
+use std::fmt;
+use std::path::PathBuf;
+use zeroize::Zeroize;
 
 pub use anyhow::Result;
+pub mod arn;
+pub mod aws;
+pub mod code;
 pub mod config;
+pub mod duration;
+pub mod errors;
+pub mod fido2;
+pub mod interrupt;
+pub mod keychain;
+pub mod keys;
+pub mod log;
+pub mod ntp;
+pub mod otpauth;
+pub mod process;
+pub mod prompt;
+pub mod redact;
+pub mod rpc;
+pub mod s3;
+#[cfg(feature = "sdk")]
+pub mod sdk;
+pub mod shell;
+#[cfg(feature = "sigv4")]
+pub mod sigv4;
+pub mod sink;
+pub mod sts;
+pub mod subshell;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+pub mod totp;
+pub mod trace;
+pub mod tty;
 
 pub const ARG_MFA_CODE: &str = "mfa_code";
 pub const ARG_PROFILE: &str = "profile";
-pub const ARG_MFA_PROFILE: &str = "mfa-profile";
+pub const ARG_MFA_PROFILE: &str = "output_profile";
 pub const ARG_DURATION: &str = "duration";
 pub const ARG_BACKUP_FILE: &str = "backup_file";
+pub const ARG_BACKUP_DIR: &str = "backup_dir";
+pub const ARG_CACHE_DIR: &str = "cache_dir";
+pub const ARG_WORKSPACE: &str = "workspace";
+pub const ARG_OFFLINE: &str = "offline";
+pub const ARG_RENEW: &str = "renew";
+pub const ARG_COMMAND: &str = "command";
+pub const ARG_OUTPUT: &str = "output";
+pub const ARG_SHELL: &str = "shell";
+pub const ARG_FORCE: &str = "force";
+pub const ARG_TIMEOUT: &str = "timeout";
+pub const ARG_VERIFY: &str = "verify";
+pub const ARG_ROLE_ARN: &str = "role_arn";
+pub const ARG_SERIAL_NUMBER: &str = "serial_number";
+pub const ARG_GROUP: &str = "group";
+pub const ARG_THEN_ASSUME: &str = "then_assume";
+pub const ARG_ROLE_PROFILE: &str = "role_profile";
+pub const ARG_ROLE_SESSION_NAME: &str = "role_session_name";
+pub const ARG_LENIENT: &str = "lenient";
+pub const ARG_DEDUPE: &str = "dedupe";
+pub const ARG_WATCH: &str = "watch";
+pub const ARG_MIN_REMAINING: &str = "min_remaining";
+pub const ARG_CLUSTER: &str = "cluster";
+pub const ARG_REGION: &str = "region";
+pub const ARG_REGISTRY: &str = "registry";
+pub const ARG_DOMAIN: &str = "domain";
+pub const ARG_DOMAIN_OWNER: &str = "domain_owner";
+pub const ARG_REPOSITORY: &str = "repository";
+pub const ARG_TOOL: &str = "tool";
+pub const ARG_LOG_FORMAT: &str = "log_format";
+pub const ARG_ERRORS: &str = "errors";
+pub const ARG_FROM: &str = "from";
+pub const ARG_LIMIT: &str = "limit";
+pub const ARG_LIST: &str = "list";
+pub const ARG_SNAPSHOT_ID: &str = "snapshot_id";
+pub const ARG_SHELL_NAME: &str = "shell_name";
+pub const ARG_FUNCTION_NAME: &str = "function_name";
+pub const ARG_NAME: &str = "name";
+pub const ARG_STORE: &str = "store";
+pub const ARG_QR: &str = "qr";
+pub const ARG_METHOD: &str = "method";
+pub const ARG_TO: &str = "to";
+pub const ARG_DELETE: &str = "delete";
+pub const ARG_OUT: &str = "out";
+pub const ARG_APPEND: &str = "append";
+pub const ARG_SINCE: &str = "since";
+pub const ARG_USERS: &str = "users";
+pub const ARG_OUT_DIR: &str = "out_dir";
+pub const ARG_FILE: &str = "file";
+pub const ARG_REDACT: &str = "redact";
+pub const ARG_CA_BUNDLE: &str = "ca_bundle";
+pub const ARG_ASSERTION_FILE: &str = "assertion_file";
+pub const ARG_PRINCIPAL_ARN: &str = "principal_arn";
+pub const ARG_WEB_IDENTITY_TOKEN_FILE: &str = "web_identity_token_file";
+
+pub const CMD_EXEC: &str = "exec";
+pub const CMD_EXPORT: &str = "export";
+pub const CMD_ENV: &str = "env";
+pub const CMD_UNSET: &str = "unset";
+pub const CMD_STATUS: &str = "status";
+pub const CMD_SNAPSHOT: &str = "snapshot";
+pub const CMD_ROLLBACK: &str = "rollback";
+pub const CMD_IMPORT_SESSION: &str = "import-session";
+pub const CMD_SHELL_INIT: &str = "shell-init";
+pub const CMD_COUNTDOWN: &str = "countdown";
+pub const CMD_CHECK: &str = "check";
+pub const CMD_EKS_TOKEN: &str = "eks-token";
+pub const CMD_ECR_LOGIN: &str = "ecr-login";
+pub const CMD_CODEARTIFACT_LOGIN: &str = "codeartifact-login";
+pub const CMD_RESTORE: &str = "restore";
+pub const CMD_HISTORY: &str = "history";
+pub const CMD_AUDIT_REMOTE: &str = "audit-remote";
+pub const CMD_INSPECT: &str = "inspect";
+pub const CMD_SECRET: &str = "secret";
+pub const CMD_ADD: &str = "add";
+pub const CMD_LIST: &str = "list";
+pub const CMD_REMOVE: &str = "remove";
+pub const CMD_CACHE: &str = "cache";
+pub const CMD_CLEAR: &str = "clear";
+pub const CMD_GC: &str = "gc";
+pub const CMD_CONFIG: &str = "config";
+pub const CMD_EFFECTIVE: &str = "effective";
+pub const CMD_DEVICE: &str = "device";
+pub const CMD_KEY: &str = "key";
+pub const CMD_IMPORT: &str = "import";
+pub const CMD_UNLOCK: &str = "unlock";
+pub const CMD_PROVISION: &str = "provision";
+pub const CMD_RESYNC: &str = "resync";
+pub const CMD_DEACTIVATE: &str = "deactivate";
+pub const CMD_ADMIN: &str = "admin";
+pub const CMD_BOOTSTRAP: &str = "bootstrap";
+pub const CMD_SAML: &str = "saml";
+pub const CMD_OIDC: &str = "oidc";
+pub const CMD_ASSUME_ROLE: &str = "assume-role";
+pub const CMD_RPC: &str = "rpc";
+
+// Source profile and device recorded for sessions read by import-session
+pub const IMPORTED: &str = "imported";
+
+// Source profile recorded for sessions of `aws-mfa saml`, with the IdP as the device
+pub const SAML: &str = "saml";
+
+// Source profile recorded for sessions of `aws-mfa oidc`, with the token file as the device
+pub const WEB_IDENTITY: &str = "web-identity";
+
+// Source profile name when long-term keys are exported instead of a profile.
+// Devices for them are configured with `profile: env`.
+pub const ENV_CREDENTIALS: &str = "env";
+
+pub const ENV_MFA_CODE: &str = "AWS_MFA_CODE";
+
+// `cli` or `embedded` to pick what calls STS: by default the AWS SDK in builds
+// with the sdk feature, else the aws CLI, or the embedded client without it.
+pub const ENV_STS_CLIENT: &str = "AWS_MFA_STS_CLIENT";
 
 pub const DEFAULT_MFA_PROFILE: &str = "mfa";
 pub const DEFAULT_DURATION: &str = "900";
 pub const DEFAULT_BACKUP_FILE: &str = "credentials_bk";
+pub const DEFAULT_OUTPUT: &str = "credentials-file";
+pub const DEFAULT_TIMEOUT: &str = "30";
+pub const DEFAULT_FUNCTION_NAME: &str = "awsm";
+pub const DEFAULT_HISTORY_LIMIT: &str = "10";
+pub const DEFAULT_AUDIT_SINCE: &str = "24h";
+pub const DEFAULT_BOOTSTRAP_DIR: &str = "mfa-bootstrap";
+// Where CloudTrail logs the calls to the global STS endpoint
+pub const DEFAULT_CLOUDTRAIL_REGION: &str = "us-east-1";
+pub const DEFAULT_ROLE_SESSION_NAME: &str = "aws-mfa";
 
-#[derive(Debug, Deserialize)]
+// Invalid codes tolerated within the window before refusing to try more, since some
+// IAM policies lock accounts after repeated MFA failures
+pub const MAX_CODE_FAILURES: usize = 3;
+pub const CODE_FAILURE_WINDOW_MINUTES: i64 = 15;
+
+// How long an STS error that won't go away by retrying (e.g. a missing
+// permission) is answered from the cache instead of calling STS again
+pub const STS_ERROR_CACHE_SECONDS: i64 = 60;
+
+// exec --renew gets a new session this long before the old one expires, which is
+// when the AWS SDKs start to refresh credentials. Short sessions are renewed
+// halfway instead.
+pub const RENEW_BEFORE_MINUTES: i64 = 15;
+
+// Every role accepts sessions of an hour, whatever its maximum session duration is.
+pub const MAX_ASSUME_ROLE_DURATION: u32 = 3600;
+
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct SessionTokens {
     credentials: Credentials,
@@ -39,34 +218,232 @@ impl SessionTokens {
 
         AwsCredential::new(profile, &lines)
     }
+
+    pub fn from_aws_credential(cred: &AwsCredential, expiration: DateTime<Utc>) -> Result<Self> {
+        let value = |key: &str| {
+            cred.get(key)
+                .map(str::to_string)
+                .ok_or_else(|| anyhow!("Not Found {} in credentials", key))
+        };
+
+        Ok(Self {
+            credentials: Credentials {
+                access_key_id: value("aws_access_key_id")?,
+                secret_access_key: value("aws_secret_access_key")?,
+                session_token: value("aws_session_token")?,
+                expiration,
+            },
+        })
+    }
+
+    pub fn expiration(&self) -> DateTime<Utc> {
+        self.credentials.expiration
+    }
+
+    pub(crate) fn credentials(&self) -> &Credentials {
+        &self.credentials
+    }
+
+    pub fn env_vars(&self) -> Vec<(&'static str, String)> {
+        credential_env_vars(&self.to_aws_credential(""))
+    }
+}
+
+// Keys in the credentials file and the environment variables they map to.
+pub const CREDENTIAL_ENV_VARS: [(&str, &str); 3] = [
+    ("aws_access_key_id", "AWS_ACCESS_KEY_ID"),
+    ("aws_secret_access_key", "AWS_SECRET_ACCESS_KEY"),
+    ("aws_session_token", "AWS_SESSION_TOKEN"),
+];
+
+pub fn credential_env_vars(cred: &AwsCredential) -> Vec<(&'static str, String)> {
+    CREDENTIAL_ENV_VARS
+        .iter()
+        .filter_map(|(key, var)| cred.get(key).map(|value| (*var, value.to_string())))
+        .collect()
 }
 
 // AWS Credentials
-#[derive(Debug, Deserialize)]
+#[derive(Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase")]
-struct Credentials {
-    access_key_id: String,
-    secret_access_key: String,
-    session_token: String,
-    #[allow(dead_code)]
-    expiration: String,
+pub(crate) struct Credentials {
+    pub(crate) access_key_id: String,
+    pub(crate) secret_access_key: String,
+    pub(crate) session_token: String,
+    pub(crate) expiration: DateTime<Utc>,
+}
+
+// The secret and the token are left out, in case a session gets logged.
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("access_key_id", &self.access_key_id)
+            .field("expiration", &self.expiration)
+            .finish_non_exhaustive()
+    }
+}
+
+// Overwrites the keys before the memory is freed.
+impl Drop for Credentials {
+    fn drop(&mut self) {
+        self.access_key_id.zeroize();
+        self.secret_access_key.zeroize();
+        self.session_token.zeroize();
+    }
+}
+
+// The environment variable of an option, read by clap after the command line and
+// before mfa.yml. Each option of the app sets it with `Arg::env`.
+pub fn option_env_var(arg: &str) -> String {
+    format!("AWS_MFA_{}", arg.to_uppercase().replace('-', "_"))
+}
+
+// The value of an option on the command line, else in its environment variable.
+pub fn arg_or_env(matches: &ArgMatches, arg: &str) -> Option<String> {
+    matches
+        .value_of(arg)
+        .filter(|v| !v.is_empty())
+        .map(String::from)
+}
+
+// Where the value of an option came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    CommandLine,
+    Env(String),
+    // mfa.yml, .aws-mfa.yml or the .aws-mfa pin
+    File(PathBuf),
+    // duration_seconds of the profile in ~/.aws/config
+    AwsConfig(String),
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CommandLine => write!(f, "command line"),
+            Self::Env(var) => write!(f, "${}", var),
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::AwsConfig(profile) => write!(f, "~/.aws/config [{}]", profile),
+            Self::Default => write!(f, "default"),
+        }
+    }
 }
 
 // CLI Options
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Options<'a> {
     matches: &'a ArgMatches,
     config: &'a Config,
+    paths: &'a Paths,
+    // The profile of a --group being refreshed, and the position of its code
+    member: Option<(usize, String)>,
+    // The mfa profile picked by conflict_policy: suffix
+    claimed: Option<String>,
+    // The codes shared by the profiles of a --group
+    batch: Option<&'a CodeBatch>,
+    // Set by assume-role, which writes the role next to the MFA session
+    assuming: bool,
+    // Outputs replacing the configured ones, e.g. when a renewal must not
+    // write the credentials file
+    outputs: Option<String>,
+    // Set by rpc, whose stdin carries the requests instead of typed codes
+    no_prompt: bool,
 }
 
 impl<'a> Options<'a> {
-    pub fn new(matches: &'a ArgMatches, config: &'a Config) -> Self {
-        Self { matches, config }
+    pub fn new(matches: &'a ArgMatches, config: &'a Config, paths: &'a Paths) -> Self {
+        Self {
+            matches,
+            config,
+            paths,
+            member: None,
+            claimed: None,
+            batch: None,
+            assuming: false,
+            outputs: None,
+            no_prompt: false,
+        }
+    }
+
+    // Options for the profile at `index` of a group, which takes the code at the
+    // same position on the command line.
+    pub fn member(self, index: usize, profile: &str) -> Self {
+        Self {
+            member: Some((index, profile.to_string())),
+            ..self
+        }
+    }
+
+    // Options sharing the codes of each device with the rest of a group.
+    pub fn batch(self, batch: &'a CodeBatch) -> Self {
+        Self {
+            batch: Some(batch),
+            ..self
+        }
+    }
+
+    pub fn code_batch(&self) -> Option<&'a CodeBatch> {
+        self.batch
+    }
+
+    // Options of assume-role, assuming a role with the MFA session and writing
+    // both like --then-assume.
+    pub fn assuming(self) -> Self {
+        Self {
+            assuming: true,
+            ..self
+        }
+    }
+
+    pub fn assumes_role(&self) -> bool {
+        self.assuming
+    }
+
+    // Options writing the session to another mfa profile than asked for.
+    pub fn claim(self, mfa_profile: &str) -> Self {
+        Self {
+            claimed: Some(mfa_profile.to_string()),
+            ..self
+        }
+    }
+
+    // Options writing the session to these outputs instead of the configured ones.
+    pub fn writing(self, outputs: &str) -> Self {
+        Self {
+            outputs: Some(outputs.to_string()),
+            ..self
+        }
+    }
+
+    // Options failing where the MFA code would be asked for on stdin.
+    pub fn no_prompt(self) -> Self {
+        Self {
+            no_prompt: true,
+            ..self
+        }
+    }
+
+    pub fn prompts(&self) -> bool {
+        !self.no_prompt
+    }
+
+    // The MFA code given on the command line.
+    pub fn mfa_code(&self) -> Option<String> {
+        let index = self.member.as_ref().map(|(i, _)| *i).unwrap_or(0);
+        self.matches
+            .values_of(ARG_MFA_CODE)
+            .and_then(|mut codes| codes.nth(index))
+            .map(str::to_string)
+    }
+
+    fn value(&self, arg: &str) -> Option<String> {
+        arg_or_env(self.matches, arg)
     }
 
     pub fn backup_file(&self) -> String {
-        if let Some(f) = self.matches.value_of(ARG_BACKUP_FILE) {
-            return f.to_string();
+        if let Some(f) = self.value(ARG_BACKUP_FILE) {
+            return f;
         }
 
         if let Some(f) = &self.config.backup_file {
@@ -76,9 +453,126 @@ impl<'a> Options<'a> {
         DEFAULT_BACKUP_FILE.to_string()
     }
 
+    // Where the credentials backup is written, ~/.aws unless a backup_dir is set.
+    pub fn backup_path(&self) -> PathBuf {
+        let dir = self
+            .value(ARG_BACKUP_DIR)
+            .or_else(|| self.config.backup_dir.clone());
+
+        match dir {
+            Some(d) => config::expand_home(&d).join(self.backup_file()),
+            None => self.paths.aws_dir.join(self.backup_file()),
+        }
+    }
+
+    // Where the files are read and written, with the cache_dir of this config.
+    pub fn paths(&self) -> &'a Paths {
+        self.paths
+    }
+
+    pub fn cache_dir(&self) -> Option<PathBuf> {
+        self.value(ARG_CACHE_DIR)
+            .or_else(|| self.config.cache_dir.clone())
+            .map(|d| config::expand_home(&d))
+    }
+
+    pub fn ca_bundle(&self) -> Option<PathBuf> {
+        self.value(ARG_CA_BUNDLE)
+            .or_else(|| self.config.ca_bundle.clone())
+            .map(|path| config::expand_home(&path))
+    }
+
+    // NTP server the clock is checked against before generating a TOTP, only
+    // set in mfa.yml.
+    pub fn ntp_server(&self) -> Option<String> {
+        self.config.ntp_server.clone()
+    }
+
+    // Only set in mfa.yml. None leaves each time as the command shows it by default.
+    pub fn time_format(&self) -> Result<Option<TimeFormat>> {
+        self.config
+            .time_format
+            .as_deref()
+            .map(str::parse)
+            .transpose()
+    }
+
+    // Only set in ~/.aws/mfa.yml, like backup: s3://bucket/prefix/. The whole
+    // credentials file goes there, so .aws-mfa.yml can't set it.
+    pub fn remote_backup(&self) -> Option<String> {
+        self.config.backup.clone()
+    }
+
+    // The long-term profile given with -p or in the config, if any.
+    pub fn profile(&self) -> Option<String> {
+        if let Some((_, p)) = &self.member {
+            return Some(p.to_string());
+        }
+
+        self.value(ARG_PROFILE)
+            .or_else(|| self.config.profile.clone())
+    }
+
+    // The MFA device given with --serial-number, instead of the one configured for the profile.
+    pub fn serial_number(&self) -> Option<String> {
+        self.value(ARG_SERIAL_NUMBER)
+    }
+
+    pub fn role_arn(&self) -> Option<String> {
+        self.value(ARG_ROLE_ARN)
+            .or_else(|| self.config.role_arn.clone())
+    }
+
+    // Role assumed after writing the MFA session, into a profile of its own.
+    pub fn then_assume(&self) -> Option<String> {
+        self.value(ARG_THEN_ASSUME)
+    }
+
+    // The session name given for assuming the role, else the one of its preset
+    // in mfa.yml.
+    pub fn role_session_name(&self, role_arn: &str) -> Option<String> {
+        self.value(ARG_ROLE_SESSION_NAME).or_else(|| {
+            self.config
+                .roles()
+                .values()
+                .find(|role| role.arn == role_arn)
+                .and_then(|role| role.session_name.clone())
+        })
+    }
+
+    // Where --then-assume writes the role credentials, like mfa-role.
+    pub fn role_profile(&self) -> String {
+        self.value(ARG_ROLE_PROFILE)
+            .unwrap_or_else(|| format!("{}-role", self.mfa_profile()))
+    }
+
+    // The output_profile of the device unless -m is given. Else each profile of
+    // a group gets its own, like work-mfa.
     pub fn mfa_profile(&self) -> String {
-        if let Some(p) = self.matches.value_of(ARG_MFA_PROFILE) {
-            return p.to_string();
+        if let Some(p) = &self.claimed {
+            return p.clone();
+        }
+        if self.value(ARG_MFA_PROFILE).is_none() {
+            if let Some(p) = self.output_profile() {
+                return p;
+            }
+        }
+
+        match &self.member {
+            Some((_, profile)) => format!("{}-{}", profile, self.base_mfa_profile()),
+            None => self.base_mfa_profile(),
+        }
+    }
+
+    fn output_profile(&self) -> Option<String> {
+        self.profile()
+            .and_then(|p| self.config.device_for(&p))
+            .and_then(|device| device.output_profile.clone())
+    }
+
+    fn base_mfa_profile(&self) -> String {
+        if let Some(p) = self.value(ARG_MFA_PROFILE) {
+            return p;
         }
 
         if let Some(p) = &self.config.mfa_profile {
@@ -89,8 +583,13 @@ impl<'a> Options<'a> {
     }
 
     pub fn duration(&self) -> String {
-        if let Some(d) = self.matches.value_of(ARG_DURATION) {
-            return d.to_string();
+        if let Some(d) = self.value(ARG_DURATION) {
+            return d;
+        }
+
+        let profile = self.profile().unwrap_or_else(|| "default".to_string());
+        if let Some(d) = aws_config_value(self.paths, &profile, "duration_seconds") {
+            return d;
         }
 
         if let Some(d) = &self.config.duration {
@@ -99,4 +598,451 @@ impl<'a> Options<'a> {
 
         DEFAULT_DURATION.to_string()
     }
+
+    pub fn output(&self) -> String {
+        if let Some(o) = &self.outputs {
+            return o.clone();
+        }
+
+        if let Some(o) = self.value(ARG_OUTPUT) {
+            return o;
+        }
+
+        if let Some(o) = &self.config.output {
+            return o.to_string();
+        }
+
+        DEFAULT_OUTPUT.to_string()
+    }
+
+    pub fn timeout(&self) -> String {
+        if let Some(t) = self.value(ARG_TIMEOUT) {
+            return t;
+        }
+
+        if let Some(t) = &self.config.timeout {
+            return t.to_string();
+        }
+
+        DEFAULT_TIMEOUT.to_string()
+    }
+
+    // Overwrite what is refused otherwise, like a .env others can read.
+    pub fn force(&self) -> bool {
+        self.matches.is_present(ARG_FORCE)
+    }
+
+    // clap leaves the flag out when AWS_MFA_VERIFY is "0" or "false", which
+    // still turns off verify of mfa.yml.
+    pub fn verify(&self) -> bool {
+        if self.matches.is_present(ARG_VERIFY) {
+            return true;
+        }
+
+        if std::env::var(option_env_var(ARG_VERIFY)).is_ok_and(|v| !v.is_empty()) {
+            return false;
+        }
+
+        self.config.verify.unwrap_or(false)
+    }
+
+    // Only set in mfa.yml, since it depends on the filesystem of ~/.aws
+    pub fn lock(&self) -> LockStrategy {
+        self.config.lock.unwrap_or_default()
+    }
+
+    // Only set in mfa.yml. None asks on a terminal and fails elsewhere.
+    pub fn conflict_policy(&self) -> Option<ConflictPolicy> {
+        self.config.conflict_policy
+    }
+
+    pub fn shell(&self) -> Result<Shell> {
+        match self.value(ARG_SHELL) {
+            Some(s) => s.parse(),
+            None => Ok(Shell::detect()),
+        }
+    }
+
+    // Every option with its resolved value and where that came from, for
+    // `config effective`.
+    pub fn effective(&self) -> Result<Vec<(&'static str, Option<String>, Source)>> {
+        let profile = self.profile();
+        let device_key = format!("devices.{}", profile.as_deref().unwrap_or("default"));
+        let device = self.serial_number().or_else(|| {
+            profile
+                .as_deref()
+                .and_then(|p| self.config.device_for(p))
+                .map(|device| device.arn.clone())
+        });
+        let lower = |value: &dyn fmt::Debug| format!("{:?}", value).to_lowercase();
+
+        Ok(vec![
+            ("profile", profile, self.source(ARG_PROFILE, "profile")),
+            (
+                "mfa_profile",
+                Some(self.mfa_profile()),
+                self.mfa_profile_source(&device_key),
+            ),
+            (
+                "serial_number",
+                device,
+                self.source(ARG_SERIAL_NUMBER, &device_key),
+            ),
+            (
+                "role_arn",
+                self.role_arn(),
+                self.source(ARG_ROLE_ARN, "role_arn"),
+            ),
+            (
+                "then_assume",
+                self.then_assume(),
+                self.source(ARG_THEN_ASSUME, "then_assume"),
+            ),
+            (
+                "role_profile",
+                Some(self.role_profile()),
+                self.source(ARG_ROLE_PROFILE, "role_profile"),
+            ),
+            ("duration", Some(self.duration()), self.duration_source()),
+            (
+                "output",
+                Some(self.output()),
+                self.source(ARG_OUTPUT, "output"),
+            ),
+            (
+                "timeout",
+                Some(self.timeout()),
+                self.source(ARG_TIMEOUT, "timeout"),
+            ),
+            (
+                "verify",
+                Some(self.verify().to_string()),
+                self.source(ARG_VERIFY, "verify"),
+            ),
+            (
+                "backup_file",
+                Some(self.backup_file()),
+                self.source(ARG_BACKUP_FILE, "backup_file"),
+            ),
+            (
+                "backup_dir",
+                self.value(ARG_BACKUP_DIR)
+                    .or_else(|| self.config.backup_dir.clone()),
+                self.source(ARG_BACKUP_DIR, "backup_dir"),
+            ),
+            (
+                "cache_dir",
+                self.cache_dir().map(|d| d.display().to_string()),
+                self.source(ARG_CACHE_DIR, "cache_dir"),
+            ),
+            ("backup", self.remote_backup(), self.configured("backup")),
+            (
+                "ca_bundle",
+                self.ca_bundle().map(|p| p.display().to_string()),
+                self.source(ARG_CA_BUNDLE, "ca_bundle"),
+            ),
+            ("lock", Some(lower(&self.lock())), self.configured("lock")),
+            (
+                "conflict_policy",
+                self.conflict_policy().map(|p| lower(&p)),
+                self.configured("conflict_policy"),
+            ),
+            (
+                "ntp_server",
+                self.ntp_server(),
+                self.configured("ntp_server"),
+            ),
+            (
+                "time_format",
+                self.config.time_format.clone(),
+                self.configured("time_format"),
+            ),
+            (
+                "shell",
+                Some(lower(&self.shell()?)),
+                self.source(ARG_SHELL, "shell"),
+            ),
+        ])
+    }
+
+    // The same order as the options are looked up: command line, environment,
+    // config files, default.
+    fn source(&self, arg: &str, key: &str) -> Source {
+        if self.matches.value_source(arg) == Some(ValueSource::CommandLine) {
+            return Source::CommandLine;
+        }
+
+        // Also a flag turned off in the environment, which clap leaves out.
+        let var = option_env_var(arg);
+        if std::env::var(&var).is_ok_and(|v| !v.is_empty()) {
+            return Source::Env(var);
+        }
+
+        self.configured(key)
+    }
+
+    fn configured(&self, key: &str) -> Source {
+        match self.config.origin(key) {
+            Some(path) => Source::File(path.to_path_buf()),
+            None => Source::Default,
+        }
+    }
+
+    fn mfa_profile_source(&self, device_key: &str) -> Source {
+        match self.source(ARG_MFA_PROFILE, "mfa_profile") {
+            source @ (Source::CommandLine | Source::Env(_)) => source,
+            _ if self.output_profile().is_some() => self.configured(device_key),
+            source => source,
+        }
+    }
+
+    // ~/.aws/config comes between the environment and mfa.yml.
+    fn duration_source(&self) -> Source {
+        match self.source(ARG_DURATION, "duration") {
+            source @ (Source::CommandLine | Source::Env(_)) => source,
+            source => {
+                let profile = self.profile().unwrap_or_else(|| "default".to_string());
+                match aws_config_value(self.paths, &profile, "duration_seconds") {
+                    Some(_) => Source::AwsConfig(profile),
+                    None => source,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+
+    // Held by tests setting environment variables, which the whole process sees.
+    fn env_lock() -> MutexGuard<'static, ()> {
+        static ENV: Mutex<()> = Mutex::new(());
+        ENV.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    mod session_tokens {
+        use super::*;
+
+        #[test]
+        fn it_deserializes_sts_output() {
+            let json = r#"{
+                "Credentials": {
+                    "AccessKeyId": "ABCDEFGHIJKLMNOPQRST",
+                    "SecretAccessKey": "secret",
+                    "SessionToken": "token",
+                    "Expiration": "2022-01-01T09:00:00+09:00"
+                }
+            }"#;
+            let tokens: SessionTokens = serde_json::from_str(json).unwrap();
+            assert_eq!(
+                tokens.expiration(),
+                "2022-01-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap()
+            );
+        }
+
+        #[test]
+        fn it_converts_from_aws_credential() {
+            let cred = AwsCredential::new(
+                "mfa",
+                &[
+                    "aws_access_key_id=ABCDEFGHIJKLMNOPQRST".to_owned(),
+                    "aws_secret_access_key=secret".to_owned(),
+                    "aws_session_token=token".to_owned(),
+                ],
+            );
+            let expiration = Utc::now();
+            let tokens = SessionTokens::from_aws_credential(&cred, expiration).unwrap();
+            assert_eq!(tokens.expiration(), expiration);
+            assert_eq!(
+                tokens.env_vars(),
+                vec![
+                    ("AWS_ACCESS_KEY_ID", "ABCDEFGHIJKLMNOPQRST".to_owned()),
+                    ("AWS_SECRET_ACCESS_KEY", "secret".to_owned()),
+                    ("AWS_SESSION_TOKEN", "token".to_owned()),
+                ]
+            );
+        }
+
+        #[test]
+        fn it_returns_env_vars_of_available_keys() {
+            let cred = AwsCredential::new(
+                "tanaka",
+                &[
+                    "aws_access_key_id=foo".to_owned(),
+                    "aws_secret_access_key=bar".to_owned(),
+                    "region=ap-northeast-1".to_owned(),
+                ],
+            );
+            assert_eq!(
+                credential_env_vars(&cred),
+                vec![
+                    ("AWS_ACCESS_KEY_ID", "foo".to_owned()),
+                    ("AWS_SECRET_ACCESS_KEY", "bar".to_owned()),
+                ]
+            );
+        }
+
+        #[test]
+        fn it_leaves_secrets_out_of_debug_output() {
+            let json = r#"{
+                "Credentials": {
+                    "AccessKeyId": "ABCDEFGHIJKLMNOPQRST",
+                    "SecretAccessKey": "secret",
+                    "SessionToken": "token",
+                    "Expiration": "2022-01-01T09:00:00+09:00"
+                }
+            }"#;
+            let tokens: SessionTokens = serde_json::from_str(json).unwrap();
+            let debug = format!("{:?}", tokens);
+            assert!(debug.contains("ABCDEFGHIJKLMNOPQRST"));
+            assert!(!debug.contains("secret"));
+            assert!(!debug.contains("token"));
+        }
+
+        #[test]
+        fn it_fails_to_convert_from_aws_credential_without_session_token() {
+            let cred = AwsCredential::new("mfa", &["aws_access_key_id=foo".to_owned()]);
+            assert!(SessionTokens::from_aws_credential(&cred, Utc::now()).is_err());
+        }
+    }
+
+    mod options {
+        use super::*;
+        use clap::{Arg, Command};
+
+        #[test]
+        fn it_takes_profile_and_code_of_group_member() {
+            let matches = Command::new("aws-mfa")
+                .arg(Arg::new(ARG_MFA_CODE).multiple_values(true))
+                .arg(
+                    Arg::new(ARG_MFA_PROFILE)
+                        .long("mfa-profile")
+                        .takes_value(true),
+                )
+                .get_matches_from(["aws-mfa", "123456", "456789"]);
+            let config = Config::default();
+            let paths = Paths::default();
+
+            let options = Options::new(&matches, &config, &paths);
+            assert_eq!(options.mfa_code(), Some("123456".to_owned()));
+
+            let options = Options::new(&matches, &config, &paths).member(1, "sandbox");
+            assert_eq!(options.profile(), Some("sandbox".to_owned()));
+            assert_eq!(options.mfa_profile(), "sandbox-mfa");
+            assert_eq!(options.mfa_code(), Some("456789".to_owned()));
+
+            let options = Options::new(&matches, &config, &paths).member(2, "prod");
+            assert_eq!(options.mfa_code(), None);
+        }
+
+        #[test]
+        fn it_writes_to_output_profile_of_device() {
+            let command = Command::new("aws-mfa")
+                .arg(Arg::new(ARG_PROFILE).short('p').takes_value(true))
+                .arg(Arg::new(ARG_MFA_PROFILE).short('m').takes_value(true));
+            let config = Config::from_path("mock/test-output-profile.yml").unwrap();
+            let paths = Paths::default();
+
+            let matches = command.clone().get_matches_from(["aws-mfa"]);
+            let options = Options::new(&matches, &config, &paths);
+            assert_eq!(options.mfa_profile(), "tanaka-mfa");
+
+            let matches = command.clone().get_matches_from(["aws-mfa", "-p", "satoh"]);
+            let options = Options::new(&matches, &config, &paths);
+            assert_eq!(options.mfa_profile(), "project");
+
+            let matches = command.get_matches_from(["aws-mfa", "-m", "other"]);
+            let options = Options::new(&matches, &config, &paths);
+            assert_eq!(options.mfa_profile(), "other");
+        }
+    }
+
+    mod source {
+        use super::*;
+        use clap::{Arg, Command};
+
+        fn matches(args: &[&str]) -> ArgMatches {
+            Command::new("aws-mfa")
+                .arg(
+                    Arg::new(ARG_TIMEOUT)
+                        .long("timeout")
+                        .env("AWS_MFA_TIMEOUT")
+                        .takes_value(true),
+                )
+                .arg(Arg::new(ARG_CACHE_DIR).long("cache-dir").takes_value(true))
+                .get_matches_from(args)
+        }
+
+        #[test]
+        fn it_tells_where_each_value_came_from() {
+            let _env = env_lock();
+            let config = config::mfa::Config::from_path("mock/test-config2.yml").unwrap();
+            let paths = Paths::default();
+
+            let m = matches(&["aws-mfa", "--timeout", "5"]);
+            let options = Options::new(&m, &config, &paths);
+            assert_eq!(options.source(ARG_TIMEOUT, "timeout"), Source::CommandLine);
+
+            std::env::set_var("AWS_MFA_TIMEOUT", "20");
+            let m = matches(&["aws-mfa"]);
+            let options = Options::new(&m, &config, &paths);
+            assert_eq!(
+                options.source(ARG_TIMEOUT, "timeout"),
+                Source::Env("AWS_MFA_TIMEOUT".to_owned())
+            );
+
+            std::env::remove_var("AWS_MFA_TIMEOUT");
+            let m = matches(&["aws-mfa"]);
+            let options = Options::new(&m, &config, &paths);
+            assert_eq!(
+                options.source(ARG_TIMEOUT, "timeout"),
+                Source::File(PathBuf::from("mock/test-config2.yml"))
+            );
+            assert_eq!(options.source(ARG_CACHE_DIR, "cache_dir"), Source::Default);
+        }
+    }
+
+    mod arg_or_env {
+        use super::*;
+        use clap::{Arg, Command};
+
+        fn matches(args: &[&str]) -> ArgMatches {
+            Command::new("aws-mfa")
+                .arg(
+                    Arg::new(ARG_BACKUP_DIR)
+                        .long("backup-dir")
+                        .env("AWS_MFA_BACKUP_DIR")
+                        .takes_value(true),
+                )
+                .get_matches_from(args)
+        }
+
+        #[test]
+        fn it_prefers_command_line_to_env() {
+            let _env = env_lock();
+            std::env::set_var("AWS_MFA_BACKUP_DIR", "/from/env");
+
+            let m = matches(&["aws-mfa", "--backup-dir", "/from/cli"]);
+            assert_eq!(arg_or_env(&m, ARG_BACKUP_DIR), Some("/from/cli".to_owned()));
+
+            let m = matches(&["aws-mfa"]);
+            assert_eq!(arg_or_env(&m, ARG_BACKUP_DIR), Some("/from/env".to_owned()));
+
+            std::env::set_var("AWS_MFA_BACKUP_DIR", "");
+            let m = matches(&["aws-mfa"]);
+            assert_eq!(arg_or_env(&m, ARG_BACKUP_DIR), None);
+
+            std::env::remove_var("AWS_MFA_BACKUP_DIR");
+            let m = matches(&["aws-mfa"]);
+            assert_eq!(arg_or_env(&m, ARG_BACKUP_DIR), None);
+        }
+
+        #[test]
+        fn it_names_env_vars_after_the_tool() {
+            assert_eq!(option_env_var(ARG_MFA_PROFILE), "AWS_MFA_OUTPUT_PROFILE");
+            assert_eq!(option_env_var(ARG_CA_BUNDLE), "AWS_MFA_CA_BUNDLE");
+        }
+    }
 }
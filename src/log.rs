@@ -0,0 +1,87 @@
+use crate::{redact, Result};
+
+use anyhow::anyhow;
+use chrono::{SecondsFormat, Utc};
+use serde_json::{json, Map, Value};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    // Only the usual messages for humans
+    Text,
+    // Additionally one JSON object per event on stderr
+    Json,
+}
+
+impl LogFormat {
+    pub const VARIANTS: [&'static str; 2] = ["text", "json"];
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(anyhow!("Unknown log format: {}", s)),
+        }
+    }
+}
+
+pub fn init(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+// Emits an event like {"timestamp":"...","event":"sts_request_started","action":"..."}.
+pub fn event(name: &str, fields: Value) {
+    if JSON.load(Ordering::Relaxed) {
+        eprintln!("{}", redact::redact(&to_line(name, fields)));
+    }
+}
+
+pub fn file_written(path: &Path) {
+    event(
+        "file_written",
+        json!({ "path": path.display().to_string() }),
+    );
+}
+
+fn to_line(name: &str, fields: Value) -> String {
+    let mut object = Map::new();
+    object.insert(
+        "timestamp".to_string(),
+        json!(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)),
+    );
+    object.insert("event".to_string(), json!(name));
+
+    if let Value::Object(fields) = fields {
+        object.extend(fields);
+    }
+
+    Value::Object(object).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod to_line {
+        use super::*;
+
+        #[test]
+        fn it_returns_one_json_object() {
+            let line = to_line("file_written", json!({ "path": "/tmp/credentials" }));
+            assert!(!line.contains('\n'));
+
+            let value: Value = serde_json::from_str(&line).unwrap();
+            assert_eq!(value["event"], "file_written");
+            assert_eq!(value["path"], "/tmp/credentials");
+            assert!(value["timestamp"].is_string());
+        }
+    }
+}
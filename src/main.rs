@@ -1,140 +1,3273 @@
 use anyhow::anyhow;
+use aws_mfa::arn::{Arn, Partition};
+use aws_mfa::aws::{CodeArtifact, CodeArtifactTool};
+use aws_mfa::code::{CodeBatch, CodeProvider};
+use aws_mfa::config::audit;
+use aws_mfa::config::cache::{self, Cache, Session};
 use aws_mfa::config::credentials::{
-    copy_credentials as backup_credentials, credentials_path, ConfigFile as CredFile,
+    self, aws_config_role, aws_config_sso, ConfigFile as CredFile, ConflictPolicy, CredentialKind,
+    Dedupe, Provenance, SsoProfile, Transaction,
 };
-use aws_mfa::config::mfa::Config as MfaConfig;
+use aws_mfa::config::mfa::{CodeSource, Config as MfaConfig};
+use aws_mfa::config::secrets::{self, Secrets};
+use aws_mfa::config::{inventory, users, Paths};
+use aws_mfa::duration::TimeFormat;
+use aws_mfa::errors::{self, ErrorFormat};
+use aws_mfa::fido2::Enrollment;
+use aws_mfa::interrupt::{self, Interrupted, EXIT_INTERRUPTED};
+use aws_mfa::keys::{KeyStore, LongTermKeys};
+use aws_mfa::log::{self, LogFormat};
+use aws_mfa::otpauth::OtpAuth;
+use aws_mfa::process::{self, Timeout};
+use aws_mfa::rpc::{self, RefreshParams, RpcError, StatusParams};
+use aws_mfa::s3::S3Backup;
+use aws_mfa::sink::{CredentialServer, ExportTarget, Output, SinkOptions};
 use aws_mfa::{
-    config, Options, Result, SessionTokens, ARG_BACKUP_FILE, ARG_DURATION, ARG_MFA_CODE,
-    ARG_MFA_PROFILE, ARG_PROFILE, DEFAULT_BACKUP_FILE, DEFAULT_DURATION, DEFAULT_MFA_PROFILE,
+    arg_or_env, aws, code, config, credential_env_vars, duration, ntp, prompt, redact,
+    shell::Shell, sink, sts, subshell, totp, trace, tty, Options, Result, SessionTokens,
+    ARG_APPEND, ARG_ASSERTION_FILE, ARG_BACKUP_DIR, ARG_BACKUP_FILE, ARG_CACHE_DIR, ARG_CA_BUNDLE,
+    ARG_CLUSTER, ARG_COMMAND, ARG_DEDUPE, ARG_DELETE, ARG_DOMAIN, ARG_DOMAIN_OWNER, ARG_DURATION,
+    ARG_ERRORS, ARG_FILE, ARG_FORCE, ARG_FROM, ARG_FUNCTION_NAME, ARG_GROUP, ARG_LENIENT,
+    ARG_LIMIT, ARG_LIST, ARG_LOG_FORMAT, ARG_METHOD, ARG_MFA_CODE, ARG_MFA_PROFILE,
+    ARG_MIN_REMAINING, ARG_NAME, ARG_OFFLINE, ARG_OUT, ARG_OUTPUT, ARG_OUT_DIR, ARG_PRINCIPAL_ARN,
+    ARG_PROFILE, ARG_QR, ARG_REDACT, ARG_REGION, ARG_REGISTRY, ARG_RENEW, ARG_REPOSITORY,
+    ARG_ROLE_ARN, ARG_ROLE_PROFILE, ARG_ROLE_SESSION_NAME, ARG_SERIAL_NUMBER, ARG_SHELL,
+    ARG_SHELL_NAME, ARG_SINCE, ARG_SNAPSHOT_ID, ARG_STORE, ARG_THEN_ASSUME, ARG_TIMEOUT, ARG_TO,
+    ARG_TOOL, ARG_USERS, ARG_VERIFY, ARG_WATCH, ARG_WEB_IDENTITY_TOKEN_FILE, ARG_WORKSPACE,
+    CMD_ADD, CMD_ADMIN, CMD_ASSUME_ROLE, CMD_AUDIT_REMOTE, CMD_BOOTSTRAP, CMD_CACHE, CMD_CHECK,
+    CMD_CLEAR, CMD_CODEARTIFACT_LOGIN, CMD_CONFIG, CMD_COUNTDOWN, CMD_DEACTIVATE, CMD_DEVICE,
+    CMD_ECR_LOGIN, CMD_EFFECTIVE, CMD_EKS_TOKEN, CMD_ENV, CMD_EXEC, CMD_EXPORT, CMD_GC,
+    CMD_HISTORY, CMD_IMPORT, CMD_IMPORT_SESSION, CMD_INSPECT, CMD_KEY, CMD_LIST, CMD_OIDC,
+    CMD_PROVISION, CMD_REMOVE, CMD_RESTORE, CMD_RESYNC, CMD_ROLLBACK, CMD_RPC, CMD_SAML,
+    CMD_SECRET, CMD_SHELL_INIT, CMD_SNAPSHOT, CMD_STATUS, CMD_UNLOCK, CMD_UNSET,
+    CODE_FAILURE_WINDOW_MINUTES, CREDENTIAL_ENV_VARS, DEFAULT_AUDIT_SINCE, DEFAULT_BACKUP_FILE,
+    DEFAULT_BOOTSTRAP_DIR, DEFAULT_CLOUDTRAIL_REGION, DEFAULT_DURATION, DEFAULT_FUNCTION_NAME,
+    DEFAULT_HISTORY_LIMIT, DEFAULT_MFA_PROFILE, DEFAULT_OUTPUT, DEFAULT_ROLE_SESSION_NAME,
+    DEFAULT_TIMEOUT, ENV_CREDENTIALS, ENV_MFA_CODE, IMPORTED, MAX_ASSUME_ROLE_DURATION,
+    MAX_CODE_FAILURES, RENEW_BEFORE_MINUTES, SAML, STS_ERROR_CACHE_SECONDS, WEB_IDENTITY,
 };
-use clap::{app_from_crate, Arg};
-use std::process::{Command, Output};
+use chrono::{DateTime, Local, Utc};
+use clap::{app_from_crate, Arg, ArgMatches, Command as App};
+use serde_json::json;
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Once;
+use std::time::Duration;
+use zeroize::Zeroizing;
 
 fn main() {
-    if let Err(err) = run() {
-        eprintln!("{}", err);
+    let result = run();
+    trace::flush();
+
+    if let Err(err) = result {
+        errors::report(&err);
+
+        // Same as timeout(1), so scripts can tell a hang from a failure.
+        if err.is::<Timeout>() {
+            std::process::exit(124);
+        }
+        if err.is::<Interrupted>() {
+            std::process::exit(EXIT_INTERRUPTED);
+        }
         std::process::exit(1);
     }
+    sink::serve_until_interrupted();
 }
 
-fn run() -> Result<()> {
-    let matches = app_from_crate!()
-        .arg(
-            Arg::new(ARG_MFA_CODE)
-                .value_name("MFA_CODE")
-                .help("MFA one time pass code")
-                .required(true),
-        )
+// Help texts of the options with their defaults, borrowed by the app.
+struct Help {
+    duration: String,
+    mfa_profile: String,
+    backup: String,
+    output: String,
+    timeout: String,
+}
+
+impl Help {
+    fn new() -> Self {
+        Self {
+            duration: format!(
+                "expiration duration in seconds, or like 12h, 1h30m, PT12H [default: {}]",
+                DEFAULT_DURATION
+            ),
+            mfa_profile: format!(
+                "profile name for mfa credentials [default: {}]",
+                DEFAULT_MFA_PROFILE
+            ),
+            backup: format!(
+                "filename for credentials backup [default: {}]",
+                DEFAULT_BACKUP_FILE
+            ),
+            output: format!(
+                "comma separated destinations of the session: {} [default: {}]",
+                Output::VARIANTS.join(", "),
+                DEFAULT_OUTPUT
+            ),
+            timeout: format!(
+                "seconds to wait for STS before giving up [default: {}]",
+                DEFAULT_TIMEOUT
+            ),
+        }
+    }
+}
+
+fn app(help: &Help) -> App<'_> {
+    app_from_crate!()
+        .subcommand_negates_reqs(true)
+        .arg(mfa_code_arg().multiple_values(true))
         .arg(
             Arg::new(ARG_PROFILE)
+                .env("AWS_MFA_PROFILE")
                 .short('p')
                 .long("profile")
                 .takes_value(true)
+                .global(true)
                 .value_name("PROFILE")
                 .help("profile name in AWS CLI credentials"),
         )
         .arg(
             Arg::new(ARG_DURATION)
+                .env("AWS_MFA_DURATION")
                 .short('d')
                 .long("duration-seconds")
                 .takes_value(true)
+                .global(true)
                 .value_name("DURATION")
-                .help(
-                    format!(
-                        "expiration duration(in seconds) [default: {}]",
-                        DEFAULT_DURATION
-                    )
-                    .as_ref(),
-                ),
+                .help(help.duration.as_str()),
         )
         .arg(
             Arg::new(ARG_MFA_PROFILE)
+                .env("AWS_MFA_OUTPUT_PROFILE")
                 .short('m')
                 .long("mfa-profile")
                 .takes_value(true)
+                .global(true)
                 .value_name("MFA_PROFILE")
-                .help(
-                    format!(
-                        "profile name for mfa credentials [default: {}]",
-                        DEFAULT_MFA_PROFILE
-                    )
-                    .as_ref(),
-                ),
+                .help(help.mfa_profile.as_str()),
         )
         .arg(
             Arg::new(ARG_BACKUP_FILE)
+                .env("AWS_MFA_BACKUP_FILE")
                 .short('b')
                 .long("backup")
                 .takes_value(true)
+                .global(true)
                 .value_name("BACKUP FILE")
-                .help(
-                    format!(
-                        "filename for credentials backup [default: {}]",
-                        DEFAULT_BACKUP_FILE
-                    )
-                    .as_ref(),
+                .help(help.backup.as_str()),
+        )
+        .arg(
+            Arg::new(ARG_BACKUP_DIR)
+                .env("AWS_MFA_BACKUP_DIR")
+                .long("backup-dir")
+                .takes_value(true)
+                .global(true)
+                .value_name("DIR")
+                .help("directory for credentials backup [default: ~/.aws]"),
+        )
+        .arg(
+            Arg::new(ARG_CACHE_DIR)
+                .env("AWS_MFA_CACHE_DIR")
+                .long("cache-dir")
+                .takes_value(true)
+                .global(true)
+                .value_name("DIR")
+                .help("directory for session metadata and cached MFA sessions [default: ~/.aws/aws-mfa]"),
+        )
+        .arg(
+            Arg::new(ARG_WORKSPACE)
+                .env("AWS_MFA_WORKSPACE")
+                .long("workspace")
+                .takes_value(true)
+                .global(true)
+                .value_name("NAME")
+                .help("use the mfa.yml, credentials, cache and audit log of this workspace under ~/.aws/aws-mfa/workspaces"),
+        )
+        .arg(
+            Arg::new(ARG_OUTPUT)
+                .env("AWS_MFA_OUTPUT")
+                .short('o')
+                .long("output")
+                .takes_value(true)
+                .global(true)
+                .value_name("OUTPUTS")
+                .help(help.output.as_str()),
+        )
+        .arg(
+            Arg::new(ARG_SHELL)
+                .env("AWS_MFA_SHELL")
+                .long("shell")
+                .takes_value(true)
+                .global(true)
+                .value_name("SHELL")
+                .possible_values(Shell::VARIANTS)
+                .help("shell flavor of printed exports [default: detected from $SHELL]"),
+        )
+        .arg(
+            Arg::new(ARG_TIMEOUT)
+                .env("AWS_MFA_TIMEOUT")
+                .long("timeout")
+                .takes_value(true)
+                .global(true)
+                .value_name("SECONDS")
+                .help(help.timeout.as_str()),
+        )
+        .arg(
+            Arg::new(ARG_CA_BUNDLE)
+                .env("AWS_MFA_CA_BUNDLE")
+                .long("ca-bundle")
+                .takes_value(true)
+                .global(true)
+                .value_name("FILE")
+                .help("PEM certificates to verify AWS endpoints with, passed to the aws CLI"),
+        )
+        .arg(
+            Arg::new(ARG_FORCE)
+                .long("force")
+                .global(true)
+                .help("skip safety checks: overwriting a session from another profile, repeated invalid codes, cached STS errors, an --out file others can read, confirming device deactivate, restoring a backup uploaded from another machine or without some current profiles"),
+        )
+        .arg(
+            Arg::new(ARG_ROLE_ARN)
+                .env("AWS_MFA_ROLE_ARN")
+                .long("role-arn")
+                .takes_value(true)
+                .global(true)
+                .value_name("ROLE_ARN")
+                .help("assume this role with the MFA session and write the role credentials"),
+        )
+        .arg(
+            Arg::new(ARG_THEN_ASSUME)
+                .env("AWS_MFA_THEN_ASSUME")
+                .long("then-assume")
+                .takes_value(true)
+                .global(true)
+                .value_name("ROLE_ARN")
+                .conflicts_with(ARG_ROLE_ARN)
+                .help("also assume this role with the new MFA session and write it to --role-profile"),
+        )
+        .arg(
+            Arg::new(ARG_ROLE_SESSION_NAME)
+                .env("AWS_MFA_ROLE_SESSION_NAME")
+                .long("role-session-name")
+                .takes_value(true)
+                .global(true)
+                .value_name("NAME")
+                .help("session name of the assumed role in CloudTrail [default: aws-mfa-<user>-<timestamp>]"),
+        )
+        .arg(
+            Arg::new(ARG_ROLE_PROFILE)
+                .env("AWS_MFA_ROLE_PROFILE")
+                .long("role-profile")
+                .takes_value(true)
+                .global(true)
+                .value_name("PROFILE")
+                .help("profile name for the --then-assume credentials [default: <mfa profile>-role]"),
+        )
+        .arg(
+            Arg::new(ARG_SERIAL_NUMBER)
+                .env("AWS_MFA_SERIAL_NUMBER")
+                .long("serial-number")
+                .takes_value(true)
+                .global(true)
+                .value_name("ARN")
+                .help("MFA device to use instead of the one configured for the profile"),
+        )
+        .arg(
+            Arg::new(ARG_GROUP)
+                .env("AWS_MFA_GROUP")
+                .long("group")
+                .takes_value(true)
+                .value_name("GROUP")
+                .conflicts_with_all(&[ARG_PROFILE, ARG_SERIAL_NUMBER, ARG_COMMAND])
+                .help("refresh the profiles of a group in mfa.yml, taking the codes in order"),
+        )
+        .arg(
+            Arg::new(ARG_LOG_FORMAT)
+                .env("AWS_MFA_LOG_FORMAT")
+                .long("log-format")
+                .takes_value(true)
+                .global(true)
+                .value_name("FORMAT")
+                .possible_values(LogFormat::VARIANTS)
+                .default_value("text")
+                .help("json also prints one JSON object per event to stderr"),
+        )
+        .arg(
+            Arg::new(ARG_ERRORS)
+                .env("AWS_MFA_ERRORS")
+                .long("errors")
+                .takes_value(true)
+                .global(true)
+                .value_name("FORMAT")
+                .possible_values(ErrorFormat::VARIANTS)
+                .default_value("text")
+                .help("json prints a failure to stdout as {\"error\": {\"code\", \"message\", \"hint\"}}"),
+        )
+        .arg(
+            Arg::new(ARG_LENIENT)
+                .env("AWS_MFA_LENIENT")
+                .long("lenient")
+                .global(true)
+                .help("skip malformed sections of the credentials file instead of failing"),
+        )
+        .arg(
+            Arg::new(ARG_DEDUPE)
+                .env("AWS_MFA_DEDUPE")
+                .long("dedupe")
+                .takes_value(true)
+                .global(true)
+                .value_name("POLICY")
+                .possible_values(Dedupe::VARIANTS)
+                .default_value("error")
+                .help("what to do with a profile appearing twice in the credentials file"),
+        )
+        .arg(
+            Arg::new(ARG_VERIFY)
+                .env("AWS_MFA_VERIFY")
+                .long("verify")
+                .global(true)
+                .help("check the written mfa profile with sts get-caller-identity"),
+        )
+        .arg(
+            Arg::new(ARG_OFFLINE)
+                .env("AWS_MFA_OFFLINE")
+                .long("offline")
+                .conflicts_with(ARG_MFA_CODE)
+                .help("use the cached session only and never call STS (exec and export)"),
+        )
+        .arg(
+            Arg::new(ARG_RENEW)
+                .env("AWS_MFA_RENEW")
+                .long("renew")
+                .conflicts_with(ARG_OFFLINE)
+                .help("serve the session to the command and renew it before it expires (exec)"),
+        )
+        .arg(
+            command_arg().help("run a command with the session like exec, refreshing it if needed"),
+        )
+        .subcommand(
+            App::new(CMD_EXEC)
+                .about("Run a command with the mfa session credentials")
+                .arg(mfa_code_arg())
+                .arg(
+                    command_arg()
+                        .help("command and its arguments to run [default: an interactive $SHELL]"),
+                ),
+        )
+        .subcommand(
+            App::new(CMD_EXPORT)
+                .about("Print shell exports of the mfa session credentials")
+                .arg(mfa_code_arg())
+                .arg(
+                    Arg::new(ARG_TO)
+                        .long("to")
+                        .takes_value(true)
+                        .value_name("TOOL")
+                        .possible_values(ExportTarget::VARIANTS)
+                        .help("also set the variables that tool sets for its sessions"),
+                )
+                .args(out_args()),
+        )
+        .subcommand(
+            App::new(CMD_ENV)
+                .about("Print shell exports of the mfa profile in the credentials file without calling STS")
+                .args(out_args()),
+        )
+        .subcommand(
+            App::new(CMD_STATUS)
+                .about("Show where the mfa profile session came from and when it expires"),
+        )
+        .subcommand(
+            App::new(CMD_COUNTDOWN)
+                .about("Print the remaining time of the mfa profile session and fail once it has expired")
+                .arg(
+                    Arg::new(ARG_WATCH)
+                        .long("watch")
+                        .help("keep updating the remaining time until the session expires"),
+                ),
+        )
+        .subcommand(
+            App::new(CMD_CHECK)
+                .about("Exit with 0 if the mfa profile session has enough time left, 1 otherwise")
+                .arg(
+                    Arg::new(ARG_MIN_REMAINING)
+                        .long("min-remaining")
+                        .takes_value(true)
+                        .value_name("DURATION")
+                        .default_value("0")
+                        .help("time the session must still be valid for, like 600, 10m or PT1H"),
+                ),
+        )
+        .subcommand(
+            App::new(CMD_EKS_TOKEN)
+                .about("Print an ExecCredential for kubectl, refreshing the session if needed")
+                .arg(mfa_code_arg())
+                .arg(
+                    Arg::new(ARG_CLUSTER)
+                        .long("cluster")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("NAME")
+                        .help("name of the EKS cluster"),
+                )
+                .arg(region_arg()),
+        )
+        .subcommand(
+            App::new(CMD_ECR_LOGIN)
+                .about("Log docker in to ECR with the session, refreshing it if needed")
+                .arg(mfa_code_arg())
+                .arg(
+                    Arg::new(ARG_REGISTRY)
+                        .long("registry")
+                        .takes_value(true)
+                        .value_name("ID")
+                        .help("account id of the registry [default: account of the session]"),
+                )
+                .arg(region_arg()),
+        )
+        .subcommand(
+            App::new(CMD_CODEARTIFACT_LOGIN)
+                .about("Configure a package manager for CodeArtifact with the session, refreshing it if needed")
+                .arg(mfa_code_arg())
+                .arg(
+                    Arg::new(ARG_DOMAIN)
+                        .long("domain")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("DOMAIN")
+                        .help("CodeArtifact domain"),
+                )
+                .arg(
+                    Arg::new(ARG_DOMAIN_OWNER)
+                        .long("domain-owner")
+                        .takes_value(true)
+                        .value_name("ACCOUNT")
+                        .help("account owning the domain [default: account of the session]"),
+                )
+                .arg(
+                    Arg::new(ARG_REPOSITORY)
+                        .long("repo")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("REPOSITORY")
+                        .help("CodeArtifact repository"),
+                )
+                .arg(
+                    Arg::new(ARG_TOOL)
+                        .long("tool")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("TOOL")
+                        .possible_values(CodeArtifactTool::VARIANTS)
+                        .help("package manager to configure"),
+                )
+                .arg(region_arg()),
+        )
+        .subcommand(
+            App::new(CMD_SAML)
+                .about("Assume --role-arn with a SAML response of the IdP and write the credentials like a session")
+                .arg(
+                    Arg::new(ARG_ASSERTION_FILE)
+                        .long("assertion-file")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("FILE")
+                        .help("the SAMLResponse, base64 encoded or as XML"),
+                )
+                .arg(
+                    Arg::new(ARG_PRINCIPAL_ARN)
+                        .long("principal-arn")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("ARN")
+                        .help("the SAML provider in IAM"),
+                ),
+        )
+        .subcommand(
+            App::new(CMD_OIDC)
+                .about("Assume --role-arn with an OIDC token and write the credentials like a session")
+                .arg(
+                    Arg::new(ARG_WEB_IDENTITY_TOKEN_FILE)
+                        .long("web-identity-token-file")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("the token of the identity provider [default: $AWS_WEB_IDENTITY_TOKEN_FILE]"),
+                ),
+        )
+        .subcommand(
+            App::new(CMD_ASSUME_ROLE)
+                .about("Assume --role-arn, or role_arn of the device, with the MFA session and write it to --role-profile")
+                .arg(mfa_code_arg()),
+        )
+        .subcommand(App::new(CMD_RPC).about(
+            "Answer JSON-RPC 2.0 requests on stdin, one per line: devices, status and refresh",
+        ))
+        .subcommand(App::new(CMD_IMPORT_SESSION).about(
+            "Write the JSON output of `aws sts get-session-token` or `assume-role` \
+                 read from stdin to the mfa profile (-p records the profile it came from)",
+        ))
+        .subcommand(
+            App::new(CMD_HISTORY)
+                .about("Show recent refreshes from the audit log (-p to filter by profile)")
+                .arg(
+                    Arg::new(ARG_LIMIT)
+                        .short('n')
+                        .takes_value(true)
+                        .value_name("N")
+                        .default_value(DEFAULT_HISTORY_LIMIT)
+                        .help("number of refreshes to show"),
+                ),
+        )
+        .subcommand(
+            App::new(CMD_AUDIT_REMOTE)
+                .about("Compare the token requests CloudTrail recorded for the user of -p with the audit log")
+                .arg(
+                    Arg::new(ARG_SINCE)
+                        .long("since")
+                        .takes_value(true)
+                        .value_name("DURATION")
+                        .default_value(DEFAULT_AUDIT_SINCE)
+                        .help("how far back to look, like 24h or 7d"),
+                )
+                .arg(
+                    Arg::new(ARG_REGION)
+                        .long("region")
+                        .takes_value(true)
+                        .value_name("REGION")
+                        .default_value(DEFAULT_CLOUDTRAIL_REGION)
+                        .help("region of the CloudTrail events, where the STS endpoint called is"),
+                ),
+        )
+        .subcommand(
+            App::new(CMD_INSPECT)
+                .about("List the profiles of a credentials file, what keys they hold and when they expire")
+                .arg(
+                    Arg::new(ARG_FILE)
+                        .value_name("FILE")
+                        .help("the credentials file [default: ~/.aws/credentials]"),
+                ),
+        )
+        .subcommand(
+            App::new(CMD_SNAPSHOT)
+                .about("Save credentials, config and mfa.yml in ~/.aws together")
+                .arg(
+                    Arg::new(ARG_LIST)
+                        .long("list")
+                        .help("list saved snapshots instead of taking one"),
+                ),
+        )
+        .subcommand(
+            App::new(CMD_ROLLBACK)
+                .about("Restore credentials, config and mfa.yml from a snapshot")
+                .arg(
+                    Arg::new(ARG_SNAPSHOT_ID)
+                        .value_name("ID")
+                        .required(true)
+                        .help("snapshot id printed by the snapshot subcommand"),
+                ),
+        )
+        .subcommand(
+            App::new(CMD_SHELL_INIT)
+                .about("Print a shell function that refreshes the session and exports it in the current shell")
+                .arg(
+                    Arg::new(ARG_SHELL_NAME)
+                        .value_name("SHELL")
+                        .possible_values(Shell::VARIANTS)
+                        .help("shell to print the function for [default: from $SHELL]"),
+                )
+                .arg(
+                    Arg::new(ARG_FUNCTION_NAME)
+                        .long("name")
+                        .takes_value(true)
+                        .value_name("NAME")
+                        .default_value(DEFAULT_FUNCTION_NAME)
+                        .help("name of the function"),
+                ),
+        )
+        .subcommand(
+            App::new(CMD_RESTORE)
+                .about("Restore the credentials file from a backup in S3")
+                .arg(
+                    Arg::new(ARG_FROM)
+                        .long("from")
+                        .takes_value(true)
+                        .value_name("URI")
+                        .help("backup object, or a prefix to choose one from [default: backup in mfa.yml]"),
+                ),
+        )
+        .subcommand(
+            App::new(CMD_SECRET)
+                .about("Manage TOTP seeds in the encrypted store ~/.aws/aws-mfa/secrets.enc")
+                .subcommand_required(true)
+                .subcommand(
+                    App::new(CMD_ADD)
+                        .about("Add a seed or otpauth:// URI, read from the terminal or stdin")
+                        .arg(secret_name_arg())
+                        .arg(
+                            Arg::new(ARG_QR)
+                                .long("qr")
+                                .takes_value(true)
+                                .value_name("IMAGE")
+                                .help("read the seed from the QR code of a device setup, with zbarimg"),
+                        ),
+                )
+                .subcommand(App::new(CMD_LIST).about("List the names of the stored seeds"))
+                .subcommand(
+                    App::new(CMD_REMOVE)
+                        .about("Remove a seed")
+                        .arg(secret_name_arg()),
+                )
+                .subcommand(
+                    App::new(CMD_UNLOCK)
+                        .about("Re-encrypt the store to be unlocked with a touch of a FIDO2 security key, or a passphrase")
+                        .arg(
+                            Arg::new(ARG_METHOD)
+                                .value_name("METHOD")
+                                .required(true)
+                                .possible_values(secrets::UNLOCK_METHODS),
+                        ),
+                ),
+        )
+        .subcommand(
+            App::new(CMD_CACHE)
+                .about("Manage session metadata and cached MFA sessions")
+                .subcommand_required(true)
+                .subcommand(App::new(CMD_CLEAR).about("Remove everything in the cache"))
+                .subcommand(
+                    App::new(CMD_GC).about("Remove expired sessions and stale failure records"),
+                ),
+        )
+        .subcommand(
+            App::new(CMD_DEVICE)
+                .about("Manage the MFA devices in mfa.yml")
+                .subcommand_required(true)
+                .subcommand(
+                    App::new(CMD_IMPORT)
+                        .about("Add devices from a CSV or JSON inventory with profile, arn and kind")
+                        .arg(
+                            Arg::new(ARG_FILE)
+                                .required(true)
+                                .value_name("FILE")
+                                .help("CSV with a header row, or a JSON array when named *.json"),
+                        ),
+                )
+                .subcommand(
+                    App::new(CMD_PROVISION)
+                        .about("Create and enable a virtual device for the IAM user of -p, shown as a QR code")
+                        .arg(
+                            Arg::new(ARG_NAME)
+                                .long("name")
+                                .takes_value(true)
+                                .value_name("NAME")
+                                .help("name of the device in IAM [default: the user name]"),
+                        ),
+                )
+                .subcommand(
+                    App::new(CMD_RESYNC)
+                        .about("Resynchronize the device of -p in IAM with two consecutive codes"),
+                )
+                .subcommand(
+                    App::new(CMD_DEACTIVATE)
+                        .about("Deactivate the device of -p in IAM and remove it from mfa.yml")
+                        .arg(
+                            Arg::new(ARG_DELETE)
+                                .long("delete")
+                                .help("also delete the virtual device in IAM"),
+                        ),
+                ),
+        )
+        .subcommand(
+            App::new(CMD_ADMIN)
+                .about("Set up MFA for the IAM users of an organization, with the admin profile of -p")
+                .subcommand_required(true)
+                .subcommand(
+                    App::new(CMD_BOOTSTRAP)
+                        .about("Create and enable a virtual device for each user, writing their mfa.yml snippet and QR code")
+                        .arg(
+                            Arg::new(ARG_USERS)
+                                .long("users")
+                                .takes_value(true)
+                                .required(true)
+                                .value_name("FILE")
+                                .help("YAML with a users: list of user and, optionally, profile"),
+                        )
+                        .arg(
+                            Arg::new(ARG_OUT_DIR)
+                                .long("out-dir")
+                                .takes_value(true)
+                                .value_name("DIR")
+                                .default_value(DEFAULT_BOOTSTRAP_DIR)
+                                .help("where USER.mfa.yml and USER.qr.txt are written, readable by you only"),
+                        ),
+                ),
+        )
+        .subcommand(
+            App::new(CMD_KEY)
+                .about("Manage long-term keys kept out of the credentials file")
+                .subcommand_required(true)
+                .subcommand(
+                    App::new(CMD_IMPORT)
+                        .about("Store the access key pair of -p, read from the terminal or stdin")
+                        .arg(key_store_arg()),
+                )
+                .subcommand(
+                    App::new(CMD_LIST)
+                        .about("List the profiles with a key_store in mfa.yml and their access key ids"),
+                )
+                .subcommand(
+                    App::new(CMD_REMOVE)
+                        .about("Remove the access key pair of -p")
+                        .arg(key_store_arg()),
+                ),
+        )
+        .subcommand(
+            App::new(CMD_CONFIG)
+                .about("Inspect the configuration")
+                .subcommand_required(true)
+                .subcommand(App::new(CMD_EFFECTIVE).about(
+                    "Print the resolved options and whether each came from the command line, the environment, a config file or the default",
+                ))
+                .subcommand(
+                    App::new(CMD_EXPORT)
+                        .about("Print mfa.yml merged with .aws-mfa.yml and the .aws-mfa pin")
+                        .arg(
+                            Arg::new(ARG_REDACT)
+                                .long("redact")
+                                .help("mask TOTP seeds, to share the output"),
+                        )
+                        .args(out_args()),
                 ),
         )
-        .get_matches();
+        .subcommand(
+            App::new(CMD_UNSET).about(
+                "Print shell commands removing the session credentials from the environment",
+            ),
+        )
+}
 
-    let code = matches.value_of(ARG_MFA_CODE).unwrap();
-    let config = MfaConfig::read()?;
-    let options = Options::new(&matches, &config);
+fn run() -> Result<()> {
+    let help = Help::new();
+    let app = app(&help);
+    let matches = app.clone().get_matches();
 
-    let mfa_profile = options.mfa_profile();
-    let backup = options.backup_file();
+    // Validated by clap with a default value
+    log::init(matches.value_of(ARG_LOG_FORMAT).unwrap().parse()?);
+    // Validated by clap with a default value
+    errors::init(matches.value_of(ARG_ERRORS).unwrap().parse()?);
+    credentials::set_lenient(matches.is_present(ARG_LENIENT));
+    // Validated by clap with a default value
+    credentials::set_dedupe(matches.value_of(ARG_DEDUPE).unwrap().parse()?);
+    let mut paths = match arg_or_env(&matches, ARG_WORKSPACE) {
+        Some(name) => use_workspace(&name)?,
+        None => Paths::default(),
+    };
+    interrupt::install()?;
+
+    // These don't need a readable mfa.yml.
+    match matches.subcommand() {
+        Some((CMD_UNSET, sub_matches)) => return unset(sub_matches),
+        Some((CMD_SHELL_INIT, sub_matches)) => return shell_init(sub_matches),
+        Some((CMD_SNAPSHOT, sub_matches)) => return snapshot(sub_matches, &paths),
+        Some((CMD_HISTORY, sub_matches)) => return history(sub_matches, &paths),
+        Some((CMD_INSPECT, sub_matches)) => return inspect(sub_matches, &paths),
+        Some((CMD_ROLLBACK, sub_matches)) => return rollback(sub_matches, &paths),
+        Some((CMD_SECRET, sub_matches)) => return secret(sub_matches, &paths),
+        Some((CMD_DEVICE, sub_matches)) => return device(sub_matches, &paths),
+        Some((CMD_KEY, sub_matches)) => return key(sub_matches, &paths),
+        Some((CMD_ADMIN, sub_matches)) => return admin(sub_matches, &paths),
+        _ => {}
+    }
+
+    let config = match MfaConfig::read(&paths) {
+        Ok(config) => config,
+        Err(e) if config::mfa::config_path(&paths).is_err() => first_run(&matches, &paths, e)?,
+        Err(e) => return Err(e),
+    };
+    // Set from --cache-dir or cache_dir in mfa.yml, before the cache is first read.
+    if let Some(dir) = Options::new(&matches, &config, &paths).cache_dir() {
+        paths.cache = dir;
+    }
+    let options = Options::new(&matches, &config, &paths);
+    if let Some(format) = options.time_format()? {
+        duration::set_time_format(format);
+    }
+    if let Some(path) = options.ca_bundle() {
+        if !path.is_file() {
+            return Err(anyhow!("Not Found CA bundle: {}", path.display()));
+        }
+        process::set_ca_bundle(path);
+    }
+    let offline = matches.is_present(ARG_OFFLINE);
+    let renew = matches.is_present(ARG_RENEW);
+
+    match matches.subcommand() {
+        Some((CMD_EXEC, sub_matches)) => exec(sub_matches, &config, &paths, offline, renew),
+        Some((CMD_EXPORT, sub_matches)) => export(sub_matches, &config, &paths, offline),
+        Some((CMD_ENV, sub_matches)) => env(sub_matches, &config, &paths),
+        Some((CMD_STATUS, sub_matches)) => status(sub_matches, &config, &paths),
+        Some((CMD_COUNTDOWN, sub_matches)) => countdown(sub_matches, &config, &paths),
+        Some((CMD_CHECK, sub_matches)) => check(sub_matches, &config, &paths),
+        Some((CMD_EKS_TOKEN, sub_matches)) => eks_token(sub_matches, &config, &paths, offline),
+        Some((CMD_ECR_LOGIN, sub_matches)) => ecr_login(sub_matches, &config, &paths, offline),
+        Some((CMD_CODEARTIFACT_LOGIN, sub_matches)) => {
+            codeartifact_login(sub_matches, &config, &paths, offline)
+        }
+        Some((CMD_IMPORT_SESSION, sub_matches)) => import_session(sub_matches, &config, &paths),
+        Some((CMD_SAML, sub_matches)) => saml(sub_matches, &config, &paths),
+        Some((CMD_OIDC, sub_matches)) => oidc(sub_matches, &config, &paths),
+        Some((CMD_ASSUME_ROLE, sub_matches)) => assume_role(sub_matches, &config, &paths),
+        Some((CMD_RPC, _)) => serve_rpc(&app, &config, &paths),
+        Some((CMD_RESTORE, sub_matches)) => restore(sub_matches, &config, &paths),
+        Some((CMD_AUDIT_REMOTE, sub_matches)) => audit_remote(sub_matches, &config, &paths),
+        Some((CMD_CACHE, sub_matches)) => manage_cache(sub_matches, &paths),
+        Some((CMD_CONFIG, sub_matches)) => show_config(sub_matches, &config, &paths),
+        _ if matches.is_present(ARG_GROUP) => refresh_group(&matches, &config, &paths),
+        // `aws-mfa -- COMMAND` is a shorthand of `aws-mfa exec -- COMMAND`.
+        _ if matches.is_present(ARG_COMMAND) => exec(&matches, &config, &paths, offline, renew),
+        _ => refresh(&matches, &config, &paths).map(drop),
+    }
+}
+
+// Points aws-mfa and the aws commands it runs, which read the credentials file
+// themselves, at the files of the workspace.
+fn use_workspace(name: &str) -> Result<Paths> {
+    let paths = config::Paths::workspace(name)?;
+    std::fs::create_dir_all(&paths.aws_dir)?;
+    // Before the interrupt handler starts its thread, so the environment is
+    // safe to change.
+    std::env::set_var("AWS_SHARED_CREDENTIALS_FILE", &paths.credentials);
+    Ok(paths)
+}
+
+// Without mfa.yml the device comes from --serial-number, or is looked up with
+// STS and saved when run in a terminal. Otherwise explains how to set it up.
+fn first_run(matches: &ArgMatches, paths: &Paths, err: anyhow::Error) -> Result<MfaConfig> {
+    if arg_or_env(matches, ARG_SERIAL_NUMBER).is_some() {
+        return Ok(MfaConfig::default());
+    }
+
+    let path = config::mfa::save_path(paths);
+    if tty::is_interactive() {
+        eprintln!(
+            "No {} yet, looking up the MFA device of your profile",
+            path.display()
+        );
+        return Ok(MfaConfig::default());
+    }
+
+    let profile = arg_or_env(matches, ARG_PROFILE).unwrap_or_else(|| "default".to_string());
+    Err(anyhow!(
+        r#"{}
+
+To get started, find the MFA device of profile {profile}:
+
+  aws iam list-mfa-devices --profile {profile} --query 'MFADevices[].SerialNumber' --output text
+
+and add it to {path}:
+
+  devices:
+    - profile: {profile}
+      arn: <the device ARN>
+
+Or run aws-mfa in a terminal to have it looked up and saved, or pass --serial-number ARN."#,
+        err,
+        profile = profile,
+        path = path.display()
+    ))
+}
+
+fn command_arg<'a>() -> Arg<'a> {
+    Arg::new(ARG_COMMAND)
+        .value_name("COMMAND")
+        .multiple_values(true)
+        .last(true)
+}
+
+fn region_arg<'a>() -> Arg<'a> {
+    Arg::new(ARG_REGION)
+        .long("region")
+        .takes_value(true)
+        .value_name("REGION")
+        .help("region of the service [default: from the aws CLI configuration]")
+}
+
+fn secret_name_arg<'a>() -> Arg<'a> {
+    Arg::new(ARG_NAME)
+        .value_name("NAME")
+        .required(true)
+        .help("name of the seed, as in `code_source: {type: secret, name: NAME}`")
+}
+
+fn key_store_arg<'a>() -> Arg<'a> {
+    Arg::new(ARG_STORE)
+        .long("store")
+        .takes_value(true)
+        .possible_values(KeyStore::VARIANTS)
+        .help(
+            "where the keys are kept [default: key_store of the device in mfa.yml, else keychain]",
+        )
+}
+
+// Where the printing commands write instead of stdout.
+fn out_args<'a>() -> [Arg<'a>; 2] {
+    [
+        Arg::new(ARG_OUT)
+            .long("out")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("write to FILE, created readable by you only, instead of stdout"),
+        Arg::new(ARG_APPEND)
+            .long("append")
+            .requires(ARG_OUT)
+            .help("append to the --out file instead of overwriting it"),
+    ]
+}
+
+// Prints the content, or writes it to the --out file.
+fn emit(matches: &ArgMatches, content: &str) -> Result<()> {
+    match matches.value_of(ARG_OUT) {
+        Some(path) => sink::write_private(
+            Path::new(path),
+            content,
+            matches.is_present(ARG_APPEND),
+            matches.is_present(ARG_FORCE),
+        ),
+        None => {
+            print!("{}", content);
+            Ok(())
+        }
+    }
+}
+
+fn mfa_code_arg<'a>() -> Arg<'a> {
+    Arg::new(ARG_MFA_CODE)
+        .value_name("MFA_CODE")
+        .help("MFA one time pass code [env: AWS_MFA_CODE]")
+}
+
+// Precedence: argv > device code_source > AWS_MFA_CODE > prompt
+// When renewing a session mid-run the codes given at start are spent, so only the
+// code_source or the terminal can answer.
+fn mfa_code(
+    options: &Options,
+    config: &MfaConfig,
+    profile: &str,
+    renewing: bool,
+) -> Result<String> {
+    let digits = config::mfa::get_code_digits(profile, config)?;
+
+    let code = if let Some(code) = options.mfa_code().filter(|_| !renewing) {
+        code
+    } else if let Some(source) = config::mfa::get_code_source(profile, config) {
+        if matches!(source, CodeSource::Totp { .. } | CodeSource::Secret { .. }) {
+            check_clock(options);
+        }
+        if source == CodeSource::Prompt {
+            prompt_code(options, digits)?
+        } else {
+            code::provider(options.paths(), &source, digits).code()?
+        }
+    } else if renewing {
+        code::TtyPrompt.code()?
+    } else if let Some(code) = env_mfa_code() {
+        code
+    } else {
+        prompt_code(options, digits)?
+    };
+
+    code::validate(&code, digits)?;
+    Ok(code)
+}
+
+// Asks on stdin, unless it carries the requests of rpc.
+fn prompt_code(options: &Options, digits: u32) -> Result<String> {
+    if !options.prompts() {
+        return Err(RpcError::invalid_params(
+            "code is required: a prompt for it would read the requests on stdin",
+        )
+        .into());
+    }
+    code::provider(options.paths(), &CodeSource::Prompt, digits).code()
+}
+
+// Compares the clock with ntp_server once, and generates the codes on the server's
+// time when it is too far off. A failed check only warns.
+fn check_clock(options: &Options) {
+    static CHECK: Once = Once::new();
+
+    let Some(server) = options.ntp_server() else {
+        return;
+    };
+    CHECK.call_once(|| match ntp::offset(&server, Duration::from_secs(3)) {
+        Ok(offset) if offset.abs() > totp::MAX_DRIFT => {
+            eprintln!(
+                "Warning: the clock is {:.1}s {} {}, generating TOTP codes on its time. Sync the clock to stop seeing this.",
+                offset.abs(),
+                if offset > 0.0 { "behind" } else { "ahead of" },
+                server
+            );
+            totp::set_clock_offset(offset.round() as i64);
+        }
+        Ok(_) => {}
+        Err(e) => eprintln!("Warning: could not check the clock against {}: {}", server, e),
+    });
+}
+
+fn env_mfa_code() -> Option<String> {
+    let code = std::env::var(ENV_MFA_CODE).ok().filter(|c| !c.is_empty())?;
+    eprintln!(
+        "Warning: using MFA code from {}. Avoid setting it inline on the command line, where it is kept in shell history.",
+        ENV_MFA_CODE
+    );
+    Some(code)
+}
+
+fn refresh(matches: &ArgMatches, config: &MfaConfig, paths: &Paths) -> Result<SessionTokens> {
+    if matches
+        .values_of(ARG_MFA_CODE)
+        .is_some_and(|codes| codes.count() > 1)
+    {
+        return Err(anyhow!(
+            "Too many MFA codes: more than one is only taken with --group"
+        ));
+    }
+    refresh_session(
+        matches,
+        config,
+        &Options::new(matches, config, paths),
+        false,
+    )
+}
+
+// Refreshes every profile of the group, each into its own mfa profile. A
+// failure doesn't stop the others.
+fn refresh_group(matches: &ArgMatches, config: &MfaConfig, paths: &Paths) -> Result<()> {
+    // Validated by clap as present
+    let group = matches.value_of(ARG_GROUP).unwrap();
+    let profiles = config::mfa::get_group(group, config)?;
+
+    let codes = matches
+        .values_of(ARG_MFA_CODE)
+        .map_or(0, |codes| codes.count());
+    if codes > profiles.len() {
+        return Err(anyhow!(
+            "Too many MFA codes: group {} has {} profiles",
+            group,
+            profiles.len()
+        ));
+    }
+
+    if sink::parse_outputs(&Options::new(matches, config, paths).output())?.contains(&Output::Http)
+    {
+        return Err(anyhow!(
+            "Output http serves a single session and can't be used with --group: set output without it"
+        ));
+    }
+
+    let batch = CodeBatch::default();
+    let mut failed = Vec::new();
+    for (index, profile) in profiles.iter().enumerate() {
+        let options = Options::new(matches, config, paths)
+            .member(index, profile)
+            .batch(&batch);
+        eprintln!("{} -> {}", profile, options.mfa_profile());
+
+        if let Err(e) = refresh_session(matches, config, &options, false) {
+            eprintln!("{}", redact::redact(&e.to_string()));
+            failed.push(profile.as_str());
+        }
+    }
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Failed to refresh {} of group {}",
+            failed.join(", "),
+            group
+        ))
+    }
+}
+
+fn refresh_session(
+    matches: &ArgMatches,
+    config: &MfaConfig,
+    options: &Options,
+    renewing: bool,
+) -> Result<SessionTokens> {
+    let paths = options.paths();
+    let mut span = trace::span("refresh");
+    let resolve_span = trace::span("resolve_options");
 
     // Ref: https://aws.amazon.com/premiumsupport/knowledge-center/authenticate-mfa-cli/?nc1=h_ls
     // root user: 900(15 minutes) <= duration <= 3600(1 hour)
     // other: 900(15 minutes) <= duration <= 129600(36 hours)
-    let duration = options
-        .duration()
-        .parse::<u32>()
-        .map_err(|e| anyhow!("Parse error: cannot parse duration (in seconds): {}", e))?;
-
-    let (use_profile, profile) = match matches.value_of(ARG_PROFILE) {
-        Some(p) => (true, p),
-        None => (false, "default"),
-    };
-
-    let device_arn = config::mfa::get_device_arn(profile, &config)?;
-    let Output {
-        status,
-        stdout,
-        stderr,
-    } = Command::new("aws")
-        .arg("sts")
-        .arg("get-session-token")
-        .args(["--serial-number", &device_arn])
-        .args(["--token-code", code])
-        .args(["--duration-seconds", duration.to_string().as_ref()])
-        .args(profile_args(use_profile, profile))
-        .output()?;
-
-    if status.success() {
-        let tokens: SessionTokens = serde_json::from_slice(&stdout)?;
-
-        backup_credentials(&backup)?;
-        write_mfa_credentials(&mfa_profile, &tokens)
-    } else {
-        Err(anyhow!("{}", String::from_utf8(stderr)?))
+    let duration = duration::parse_seconds(&options.duration())?;
+
+    let timeout = timeout(options)?;
+
+    let (use_profile, requested) = source_profile(options)?;
+
+    // An SSO profile in ~/.aws/config gets the credentials of its role with the
+    // token of `aws sso login` instead, without an MFA code.
+    let sso = match requested.as_str() {
+        ENV_CREDENTIALS => None,
+        p => aws_config_sso(paths, p)?,
+    };
+    if let Some(sso) = sso {
+        drop(resolve_span);
+        span.set("profile", requested.as_str());
+        let result = refresh_sso(matches, options, &requested, &sso, timeout);
+        span.set_status(result.is_ok());
+        return result;
+    }
+
+    // A role profile in ~/.aws/config is refreshed through its source profile,
+    // then its role is assumed like with --role-arn.
+    let role_profile = match requested.as_str() {
+        ENV_CREDENTIALS => None,
+        p => aws_config_role(paths, p)?,
+    };
+    let (use_profile, profile) = match &role_profile {
+        Some(r) => (true, r.source_profile.as_str()),
+        None => (use_profile, requested.as_str()),
+    };
+    let role_arn = arg_or_env(matches, ARG_ROLE_ARN)
+        .or_else(|| role_profile.as_ref().map(|r| r.role_arn.clone()))
+        .or_else(|| {
+            options
+                .assumes_role()
+                .then(|| config::mfa::get_role_arn(profile, config))
+                .flatten()
+        })
+        .or_else(|| options.role_arn());
+    // assume-role writes the role next to the MFA session instead of in its place.
+    let (role_arn, then_assume) = match options.assumes_role() {
+        true => (
+            None,
+            Some(role_arn.ok_or_else(|| {
+                anyhow!(
+                    "assume-role needs a role: pass --role-arn, or set role_arn of the device of profile {} in mfa.yml",
+                    profile
+                )
+            })?),
+        ),
+        false => (role_arn, options.then_assume()),
+    };
+    let session_name = match &role_arn {
+        Some(arn) => role_session_name(
+            options,
+            arn,
+            role_profile
+                .as_ref()
+                .and_then(|r| r.role_session_name.clone()),
+        )?,
+        None => String::new(),
+    };
+    drop(resolve_span);
+    span.set("profile", profile);
+
+    log::event(
+        "options_resolved",
+        json!({
+            "profile": profile,
+            "mfa_profile": options.mfa_profile(),
+            "duration": duration,
+            "output": options.output(),
+            "timeout": timeout.as_secs(),
+            "role_arn": role_arn,
+        }),
+    );
+
+    let claimed = check_overwrite(matches, options, profile)?.map(|p| options.clone().claim(&p));
+    let options = claimed.as_ref().unwrap_or(options);
+
+    let mfa_serial = role_profile.as_ref().and_then(|r| r.mfa_serial.clone());
+    let device_arn = match mfa_serial.filter(|_| options.serial_number().is_none()) {
+        Some(arn) => arn,
+        None => device_arn(options, config, profile, use_profile, timeout)?,
+    };
+    // Keys imported with `key import` sign the request instead of the profile.
+    let keys = match config::mfa::get_key_store(profile, config) {
+        Some(store) => Some(LongTermKeys::load(paths, store, profile)?.ok_or_else(|| {
+            anyhow!(
+                "Not Found long-term keys of profile {}: import them with aws-mfa key import -p {}",
+                profile,
+                profile
+            )
+        })?),
+        None => None,
+    };
+
+    let endpoints = config::mfa::get_sts_regional_endpoints(profile, config);
+
+    let get_session_token = || {
+        check_failures(paths, &device_arn, matches.is_present(ARG_FORCE))?;
+        check_sts_error(paths, &device_arn, matches.is_present(ARG_FORCE))?;
+
+        // A code given for this profile wins over one shared in the group.
+        let code = match options
+            .code_batch()
+            .filter(|_| options.mfa_code().is_none())
+            .and_then(|batch| batch.get(&device_arn))
+        {
+            Some(code) => code,
+            None => mfa_code(options, config, profile, renewing)?,
+        };
+        if let Some(batch) = options.code_batch() {
+            batch.insert(&device_arn, &code);
+        }
+        let result = match &keys {
+            Some(keys) => sts::get_session_token_with_keys(
+                &device_arn,
+                &code,
+                duration,
+                keys,
+                endpoints,
+                timeout,
+            ),
+            None => sts::get_session_token(
+                &device_arn,
+                &code,
+                duration,
+                use_profile.then_some(profile),
+                endpoints,
+                timeout,
+            ),
+        };
+        record_failures(paths, &device_arn, &result)?;
+        result
+    };
+
+    let result = (|| {
+        if let Some(role_arn) = then_assume {
+            // assume-role reuses a cached MFA session, like --role-arn does.
+            let tokens = match options.assumes_role() {
+                true => mfa_session(options, profile, renewing, get_session_token)?,
+                false => {
+                    let tokens = get_session_token()?;
+                    cache::write_mfa_session(options.paths(), profile, &tokens)?;
+                    tokens
+                }
+            };
+            let role_tokens = assume_role_chain(
+                config,
+                &role_arn,
+                &role_session_name(options, &role_arn, None)?,
+                &tokens,
+                duration.min(MAX_ASSUME_ROLE_DURATION),
+                Partition::of_device(&device_arn)?,
+                timeout,
+            )?;
+
+            save_session_and_role(options, profile, &device_arn, &tokens, &role_tokens)?;
+            return Ok(tokens);
+        }
+
+        let tokens = match &role_arn {
+            Some(role_arn) => {
+                let session = mfa_session(options, profile, renewing, get_session_token)?;
+                assume_role_chain(
+                    config,
+                    role_arn,
+                    &session_name,
+                    &session,
+                    duration.min(MAX_ASSUME_ROLE_DURATION),
+                    Partition::of_device(&device_arn)?,
+                    timeout,
+                )?
+            }
+            None => get_session_token()?,
+        };
+
+        save_session(options, profile, &device_arn, &tokens)?;
+        Ok(tokens)
+    })();
+
+    let entry = audit::Entry::new(
+        profile,
+        &options.mfa_profile(),
+        &device_arn,
+        duration,
+        &result,
+    );
+    if let Err(e) = audit::append(paths, &entry) {
+        eprintln!("Warning: {}", e);
     }
+    span.set_status(result.is_ok());
+    let tokens = result?;
+
+    // Exported keys are gone in the next shell, so don't default to them.
+    if requested != ENV_CREDENTIALS {
+        let mut cache = Cache::read(paths)?;
+        cache.set_last_profile(&requested);
+        cache.write(paths)?;
+    }
+
+    Ok(tokens)
 }
 
-fn profile_args(use_profile: bool, profile: &str) -> Vec<&str> {
-    if use_profile {
-        vec!["--profile", profile]
-    } else {
-        vec![]
+// Assumes the roles of the chain to the role in turn, each with the credentials
+// of the one before. Only the role's own credentials are returned.
+fn assume_role_chain(
+    config: &MfaConfig,
+    role_arn: &str,
+    session_name: &str,
+    session: &SessionTokens,
+    duration: u32,
+    partition: Partition,
+    timeout: Duration,
+) -> Result<SessionTokens> {
+    let mut tokens: Option<SessionTokens> = None;
+    for hop in config.role_chain(role_arn) {
+        let credentials = tokens.as_ref().unwrap_or(session);
+        tokens = Some(sts::assume_role(
+            &hop,
+            session_name,
+            credentials,
+            duration,
+            partition,
+            timeout,
+        )?);
+    }
+    // The chain ends with the role, so there is at least one hop.
+    Ok(tokens.unwrap())
+}
+
+// Writes the role credentials of an SSO profile like a session, with the start
+// URL as the device.
+fn refresh_sso(
+    matches: &ArgMatches,
+    options: &Options,
+    profile: &str,
+    sso: &SsoProfile,
+    timeout: Duration,
+) -> Result<SessionTokens> {
+    let paths = options.paths();
+    let claimed = check_overwrite(matches, options, profile)?.map(|p| options.clone().claim(&p));
+    let options = claimed.as_ref().unwrap_or(options);
+
+    let result = (|| {
+        let access_token = config::sso::access_token(paths, sso, profile)?;
+        let tokens = aws::sso_role_credentials(sso, &access_token, timeout)?;
+        save_session(options, profile, &sso.start_url, &tokens)?;
+        Ok(tokens)
+    })();
+
+    // Its duration is the role's, not asked for.
+    let duration = result.as_ref().map_or(0, |tokens| {
+        (tokens.expiration() - Utc::now()).num_seconds().max(0) as u32
+    });
+    let entry = audit::Entry::new(
+        profile,
+        &options.mfa_profile(),
+        &sso.start_url,
+        duration,
+        &result,
+    );
+    if let Err(e) = audit::append(paths, &entry) {
+        eprintln!("Warning: {}", e);
+    }
+    let tokens = result?;
+
+    let mut cache = Cache::read(paths)?;
+    cache.set_last_profile(profile);
+    cache.write(paths)?;
+    Ok(tokens)
+}
+
+// --role-session-name, else the role's preset in mfa.yml, else the one given
+// where the role comes from, else aws-mfa-<user>-<timestamp> with the local
+// user name.
+fn role_session_name(options: &Options, role_arn: &str, given: Option<String>) -> Result<String> {
+    let name = options.role_session_name(role_arn).or(given);
+    if let Some(name) = name {
+        sts::validate_role_session_name(&name)?;
+        return Ok(name);
+    }
+
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_default();
+    Ok(match user.as_str() {
+        "" => DEFAULT_ROLE_SESSION_NAME.to_string(),
+        user => {
+            sts::default_role_session_name(DEFAULT_ROLE_SESSION_NAME, user, Utc::now().timestamp())
+        }
+    })
+}
+
+// Precedence: -p > profile in mfa.yml > AWS_PROFILE > last used > default
+// Also returns whether the aws CLI needs --profile to use it.
+fn source_profile(options: &Options) -> Result<(bool, String)> {
+    let paths = options.paths();
+    if let Some(p) = options.profile() {
+        return Ok((true, p.to_string()));
+    }
+
+    if env_credentials() {
+        return Ok((false, ENV_CREDENTIALS.to_string()));
+    }
+
+    if let Some(p) = std::env::var("AWS_PROFILE").ok().filter(|p| !p.is_empty()) {
+        return Ok((false, p));
+    }
+
+    if let Some(p) = Cache::read(paths)?.last_profile() {
+        eprintln!("Using profile {} (last used; pass -p for another)", p);
+        return Ok((true, p.to_string()));
+    }
+
+    Ok((false, "default".to_string()))
+}
+
+// Precedence: --serial-number > device in mfa.yml > guessed from the caller identity
+fn device_arn(
+    options: &Options,
+    config: &MfaConfig,
+    profile: &str,
+    use_profile: bool,
+    timeout: Duration,
+) -> Result<String> {
+    let paths = options.paths();
+    if let Some(arn) = options.serial_number() {
+        return Ok(arn);
+    }
+
+    let err = match config::mfa::get_device_arn(profile, config) {
+        Ok(arn) => return Ok(arn),
+        Err(e) => e,
+    };
+    if !tty::is_interactive() {
+        return Err(err);
+    }
+
+    // Virtual devices are usually named after the user, so ask STS who that is.
+    eprintln!("No MFA device configured for profile {}", profile);
+    let caller: Arn =
+        sts::get_caller_identity(use_profile.then_some(profile), Partition::Aws, timeout)?
+            .parse()?;
+    let arn = caller.virtual_device()?;
+
+    if !prompt::confirm(&format!("Use MFA device {}?", arn))? {
+        return Err(err);
+    }
+    if prompt::confirm(&format!(
+        "Save it for profile {} in {}?",
+        profile,
+        config::mfa::save_path(paths).display()
+    ))? {
+        config::mfa::save_device(paths, profile, &arn)?;
     }
+    Ok(arn)
+}
+
+// Long-term keys exported in the environment, which the aws CLI prefers over
+// AWS_PROFILE. Temporary credentials (with a session token) can't call
+// GetSessionToken, so they are left alone.
+fn env_credentials() -> bool {
+    let set = |name: &str| std::env::var(name).is_ok_and(|v| !v.is_empty());
+    set("AWS_ACCESS_KEY_ID") && set("AWS_SECRET_ACCESS_KEY") && !set("AWS_SESSION_TOKEN")
 }
 
-fn write_mfa_credentials(mfa_profile: &str, tokens: &SessionTokens) -> Result<()> {
-    let cred = tokens.to_aws_credential(mfa_profile);
-    let config = CredFile::from_path(credentials_path())?;
+fn failure_window_start() -> DateTime<Utc> {
+    Utc::now() - chrono::Duration::minutes(CODE_FAILURE_WINDOW_MINUTES)
+}
+
+// Refuses to spend another code on a device that just rejected several.
+fn check_failures(paths: &Paths, device_arn: &str, force: bool) -> Result<()> {
+    let failures = Cache::read(paths)?.failures_since(device_arn, failure_window_start());
+    if failures < MAX_CODE_FAILURES || force {
+        return Ok(());
+    }
 
-    config
-        .remove_credential(mfa_profile)
-        .set_credential(cred)
-        .write(credentials_path())
+    Err(anyhow!(
+        "Refusing to try another code: {} invalid codes for {} in the last {} minutes may lock the account. Wait or pass --force",
+        failures,
+        device_arn,
+        CODE_FAILURE_WINDOW_MINUTES
+    ))
+}
+
+// Answers from the cache while the last error of the device is recent, so
+// prompt integrations calling aws-mfa on every command don't hammer STS.
+fn check_sts_error(paths: &Paths, device_arn: &str, force: bool) -> Result<()> {
+    let since = Utc::now() - chrono::Duration::seconds(STS_ERROR_CACHE_SECONDS);
+    let cache = Cache::read(paths)?;
+    let error = match cache.sts_error_since(device_arn, since) {
+        Some(e) if !force => e,
+        _ => return Ok(()),
+    };
+
+    Err(anyhow!(
+        "{}\n(cached from {}; STS is called again after {} seconds or with --force)",
+        error.message,
+        local_time(error.at),
+        STS_ERROR_CACHE_SECONDS
+    ))
+}
+
+fn record_failures(paths: &Paths, device_arn: &str, result: &Result<SessionTokens>) -> Result<()> {
+    let mut cache = Cache::read(paths)?;
+    match result {
+        Ok(_) => {
+            cache.clear_failures(device_arn);
+            cache.clear_sts_error(device_arn);
+        }
+        Err(e) if is_invalid_code(e) => {
+            cache.add_failure(device_arn, Utc::now(), failure_window_start())
+        }
+        Err(e) if is_lasting_error(e) => {
+            let message = redact::redact(e.to_string().trim());
+            cache.set_sts_error(device_arn, Utc::now(), &message)
+        }
+        Err(_) => return Ok(()),
+    }
+    cache.write(paths)
+}
+
+fn is_invalid_code(err: &anyhow::Error) -> bool {
+    err.to_string().contains("MultiFactorAuthentication failed")
+}
+
+// Errors about the caller rather than the code or the network, which the next
+// try would get again.
+fn is_lasting_error(err: &anyhow::Error) -> bool {
+    let message = err.to_string();
+    [
+        "AccessDenied",
+        "InvalidClientTokenId",
+        "SignatureDoesNotMatch",
+        "UnrecognizedClientException",
+    ]
+    .iter()
+    .any(|code| message.contains(code))
+}
+
+// The session a role is assumed with is kept apart from the role credentials, so
+// another role can be assumed before it expires without a new MFA code.
+fn mfa_session<F>(
+    options: &Options,
+    profile: &str,
+    renewing: bool,
+    get_session_token: F,
+) -> Result<SessionTokens>
+where
+    F: FnOnce() -> Result<SessionTokens>,
+{
+    // A code given on the command line asks for a new session.
+    if options.mfa_code().is_none() || renewing {
+        if let Some(tokens) = cache::read_mfa_session(options.paths(), profile)? {
+            if tokens.expiration() > Utc::now() {
+                return Ok(tokens);
+            }
+        }
+    }
+
+    let tokens = get_session_token()?;
+    cache::write_mfa_session(options.paths(), profile, &tokens)?;
+    Ok(tokens)
+}
+
+// Assumes --role-arn, or the role of the device, with the MFA session and
+// writes it to --role-profile, keeping the session in the mfa profile.
+fn assume_role(matches: &ArgMatches, config: &MfaConfig, paths: &Paths) -> Result<()> {
+    let options = Options::new(matches, config, paths).assuming();
+    refresh_session(matches, config, &options, false).map(drop)
+}
+
+// Writes an STS response someone else obtained, e.g. on a bastion host.
+fn import_session(matches: &ArgMatches, config: &MfaConfig, paths: &Paths) -> Result<()> {
+    let options = Options::new(matches, config, paths);
+    let profile = options.profile();
+    let profile = profile.as_deref().unwrap_or(IMPORTED);
+
+    let mut input = String::new();
+    std::io::stdin().read_to_string(&mut input)?;
+    let tokens: SessionTokens = serde_json::from_str(&input)
+        .map_err(|e| anyhow!("Parse error: cannot parse STS output from stdin: {}", e))?;
+    redact::register_tokens(&tokens);
+
+    let options = match check_overwrite(matches, &options, profile)? {
+        Some(mfa_profile) => options.claim(&mfa_profile),
+        None => options,
+    };
+    save_session(&options, profile, IMPORTED, &tokens)
+}
+
+fn saml(matches: &ArgMatches, config: &MfaConfig, paths: &Paths) -> Result<()> {
+    let options = Options::new(matches, config, paths);
+    let role_arn = options
+        .role_arn()
+        .ok_or_else(|| anyhow!("saml needs the role to assume with --role-arn"))?;
+    // Validated by clap as required
+    let principal_arn = matches.value_of(ARG_PRINCIPAL_ARN).unwrap();
+    let assertion_file = matches.value_of(ARG_ASSERTION_FILE).unwrap();
+
+    let tokens = sts::assume_role_with_saml(
+        &role_arn,
+        principal_arn,
+        Path::new(assertion_file),
+        duration::parse_seconds(&options.duration())?,
+        timeout(&options)?,
+    )?;
+
+    let options = match check_overwrite(matches, &options, SAML)? {
+        Some(mfa_profile) => options.claim(&mfa_profile),
+        None => options,
+    };
+    save_session(&options, SAML, principal_arn, &tokens)
+}
+
+// Takes the same variables as the aws CLI and SDKs, as set in EKS pods, when
+// the options are not given.
+fn oidc(matches: &ArgMatches, config: &MfaConfig, paths: &Paths) -> Result<()> {
+    let options = Options::new(matches, config, paths);
+    let env = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+
+    let role_arn = options
+        .role_arn()
+        .or_else(|| env("AWS_ROLE_ARN"))
+        .ok_or_else(|| anyhow!("oidc needs the role to assume with --role-arn or AWS_ROLE_ARN"))?;
+    let token_file = matches
+        .value_of(ARG_WEB_IDENTITY_TOKEN_FILE)
+        .map(str::to_string)
+        .or_else(|| env("AWS_WEB_IDENTITY_TOKEN_FILE"))
+        .ok_or_else(|| {
+            anyhow!("oidc needs --web-identity-token-file or AWS_WEB_IDENTITY_TOKEN_FILE")
+        })?;
+    let session_name = role_session_name(&options, &role_arn, env("AWS_ROLE_SESSION_NAME"))?;
+
+    let tokens = sts::assume_role_with_web_identity(
+        &role_arn,
+        &session_name,
+        Path::new(&token_file),
+        duration::parse_seconds(&options.duration())?,
+        timeout(&options)?,
+    )?;
+
+    let options = match check_overwrite(matches, &options, WEB_IDENTITY)? {
+        Some(mfa_profile) => options.claim(&mfa_profile),
+        None => options,
+    };
+    save_session(&options, WEB_IDENTITY, &token_file, &tokens)
+}
+
+fn timeout(options: &Options) -> Result<Duration> {
+    options
+        .timeout()
+        .parse::<u64>()
+        .map(Duration::from_secs)
+        .map_err(|e| anyhow!("Parse error: cannot parse timeout (in seconds): {}", e))
+}
+
+// Some mfa profile to write to instead, picked by conflict_policy: suffix.
+fn check_overwrite(
+    matches: &ArgMatches,
+    options: &Options,
+    profile: &str,
+) -> Result<Option<String>> {
+    let paths = options.paths();
+    let outputs = sink::parse_outputs(&options.output())?;
+    let with_role = options.then_assume().is_some() || options.assumes_role();
+
+    if with_role && outputs != [Output::CredentialsFile] {
+        return Err(anyhow!(
+            "--then-assume and assume-role only write to the credentials file, not --output {}",
+            options.output()
+        ));
+    }
+
+    if !outputs.contains(&Output::CredentialsFile)
+        || matches.is_present(ARG_FORCE)
+        || !paths.credentials.exists()
+    {
+        return Ok(None);
+    }
+
+    let policy = options.conflict_policy();
+    let mfa_profile = options.mfa_profile();
+    let creds = CredFile::from_path(&paths.credentials)?;
+    let mut claimed = None;
+
+    // The role profile of --then-assume is named after the mfa profile, so
+    // only the mfa profile moves aside.
+    if policy == Some(ConflictPolicy::Suffix) && !with_role {
+        let free = creds.unclaimed_profile(&mfa_profile, profile);
+        if free != mfa_profile {
+            eprintln!(
+                "Note: mfa profile {} holds a session from another profile, writing to {}",
+                mfa_profile, free
+            );
+            claimed = Some(free);
+        }
+    } else {
+        confirm_source_profile(&creds, &mfa_profile, profile, policy)?;
+        if with_role {
+            confirm_source_profile(&creds, &options.role_profile(), profile, policy)?;
+        }
+    }
+    Ok(claimed)
+}
+
+fn sink_options(
+    options: &Options,
+    profile: &str,
+    device: &str,
+    tokens: &SessionTokens,
+) -> Result<SinkOptions> {
+    Ok(SinkOptions {
+        credentials: options.paths().credentials.clone(),
+        backup: options.backup_path(),
+        lock: options.lock(),
+        shell: options.shell()?,
+        verify: options.verify(),
+        force: options.force(),
+        timeout: timeout(options)?,
+        s3_backup: options
+            .remote_backup()
+            .map(|uri| {
+                S3Backup::new(
+                    options.paths(),
+                    &uri,
+                    options.profile().as_deref(),
+                    timeout(options)?,
+                )
+            })
+            .transpose()?,
+        provenance: Some(Provenance {
+            source_profile: profile.to_string(),
+            device: device.to_string(),
+            issued: Utc::now(),
+            expiration: tokens.expiration(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        }),
+    })
+}
+
+fn save_session(
+    options: &Options,
+    profile: &str,
+    device: &str,
+    tokens: &SessionTokens,
+) -> Result<()> {
+    let paths = options.paths();
+    let mfa_profile = options.mfa_profile();
+    let sink_options = sink_options(options, profile, device, tokens)?;
+
+    for output in sink::parse_outputs(&options.output())? {
+        let mut span = trace::span("write_session");
+        span.set("output", format!("{:?}", output));
+        let result = sink::sink(output, &sink_options).write(&mfa_profile, tokens);
+        span.set_status(result.is_ok());
+        drop(span);
+        result?;
+
+        if output == Output::CredentialsFile {
+            cache_session(paths, &mfa_profile, profile, tokens)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Writes the MFA session and the role credentials assumed with it together, so a
+// failure leaves neither behind.
+fn save_session_and_role(
+    options: &Options,
+    profile: &str,
+    device: &str,
+    tokens: &SessionTokens,
+    role_tokens: &SessionTokens,
+) -> Result<()> {
+    let paths = options.paths();
+    let mfa_profile = options.mfa_profile();
+    let role_profile = options.role_profile();
+
+    let mut span = trace::span("write_session");
+    span.set("output", "CredentialsFile");
+    let result = sink::credentials_file(&sink_options(options, profile, device, tokens)?)
+        .write_profiles(&[(&mfa_profile, tokens), (&role_profile, role_tokens)]);
+    span.set_status(result.is_ok());
+    drop(span);
+    result?;
+
+    cache_session(paths, &mfa_profile, profile, tokens)?;
+    cache_session(paths, &role_profile, profile, role_tokens)
+}
+
+// Guards against overwriting a session minted from another profile, which
+// likely belongs to another account.
+fn confirm_source_profile(
+    creds: &CredFile,
+    mfa_profile: &str,
+    profile: &str,
+    policy: Option<ConflictPolicy>,
+) -> Result<()> {
+    let current = match creds.owner(mfa_profile, profile) {
+        Some(owner) => owner,
+        None => return Ok(()),
+    };
+
+    eprintln!(
+        "Warning: mfa profile {} holds a session from profile {}, not {}",
+        mfa_profile, current, profile
+    );
+
+    match policy {
+        Some(ConflictPolicy::Overwrite) => return Ok(()),
+        Some(ConflictPolicy::Error) => {
+            return Err(anyhow!(
+                "Refusing to overwrite mfa profile {}: conflict_policy is error",
+                mfa_profile
+            ))
+        }
+        _ => {}
+    }
+
+    if !tty::is_interactive() {
+        return Err(anyhow!(
+            "Refusing to overwrite mfa profile {}: pass --force or use another --mfa-profile",
+            mfa_profile
+        ));
+    }
+
+    if prompt::confirm(&format!("Overwrite mfa profile {}?", mfa_profile))? {
+        Ok(())
+    } else {
+        Err(anyhow!("Aborted"))
+    }
+}
+
+fn exec(
+    matches: &ArgMatches,
+    config: &MfaConfig,
+    paths: &Paths,
+    offline: bool,
+    renew: bool,
+) -> Result<()> {
+    let tokens = session_tokens(matches, config, paths, offline)?;
+
+    let (program, mut command) = match matches.values_of(ARG_COMMAND) {
+        Some(mut values) => {
+            let program = values.next().unwrap().to_string();
+            let mut command = Command::new(&program);
+            command.args(values);
+            (program, command)
+        }
+        // Without a command, start an interactive shell.
+        None => {
+            if !tty::stdin_is_tty() {
+                return Err(anyhow!(
+                    "No command given to exec and stdin is not a terminal for a shell"
+                ));
+            }
+
+            let program = std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string());
+            let prompt = format!(
+                "(aws-mfa:{} until {})",
+                Options::new(matches, config, paths).mfa_profile(),
+                clock_time(tokens.expiration())
+            );
+            (
+                program.clone(),
+                subshell::command(paths, &program, &prompt)?,
+            )
+        }
+    };
+
+    // The command may run for long, so don't hold the spans of the refresh until it exits.
+    trace::flush();
+
+    if renew {
+        return exec_renewing(matches, config, paths, tokens, &program, command);
+    }
+
+    let status = command
+        .envs(tokens.env_vars())
+        .env_remove("AWS_PROFILE")
+        .status()
+        .map_err(|e| anyhow!("{}: {}", e, program))?;
+
+    std::process::exit(status.code().unwrap_or(1))
+}
+
+// Environment variables can't be changed once the command runs, so it reads the
+// session from a local endpoint instead, which gets a new one before the old expires.
+fn exec_renewing(
+    matches: &ArgMatches,
+    config: &MfaConfig,
+    paths: &Paths,
+    tokens: SessionTokens,
+    program: &str,
+    mut command: Command,
+) -> Result<()> {
+    let server = CredentialServer::start(&tokens)?;
+
+    command.envs(server.env_vars()).env_remove("AWS_PROFILE");
+    for (_, var) in CREDENTIAL_ENV_VARS {
+        command.env_remove(var);
+    }
+    let mut child = command.spawn().map_err(|e| anyhow!("{}: {}", e, program))?;
+
+    let options = Options::new(matches, config, paths);
+    let mfa_profile = options.mfa_profile();
+    let outputs = sink::parse_outputs(&options.output())?;
+    // The session last written to the credentials file, to notice another tool
+    // rewriting the mfa profile while the command runs.
+    let mut written = written_access_key(paths, &mfa_profile);
+    let mut detached = false;
+
+    let mut renew_at = renew_time(Utc::now(), tokens.expiration());
+    loop {
+        if let Some(status) = child.try_wait()? {
+            std::process::exit(status.code().unwrap_or(1));
+        }
+
+        if Utc::now() >= renew_at {
+            if !detached
+                && outputs.contains(&Output::CredentialsFile)
+                && written_access_key(paths, &mfa_profile) != written
+            {
+                eprintln!(
+                    "Note: mfa profile {} was changed by another tool, renewing without writing the credentials file",
+                    mfa_profile
+                );
+                detached = true;
+            }
+            let options = if detached {
+                let rest: Vec<String> = outputs
+                    .iter()
+                    .filter(|output| **output != Output::CredentialsFile)
+                    .map(Output::to_string)
+                    .collect();
+                options.clone().writing(&rest.join(","))
+            } else {
+                options.clone()
+            };
+
+            match refresh_session(matches, config, &options, true) {
+                Ok(tokens) => {
+                    server.update(&tokens);
+                    if !detached {
+                        written = written_access_key(paths, &mfa_profile);
+                    }
+                    renew_at = renew_time(Utc::now(), tokens.expiration());
+                    eprintln!("Renewed session until {}", clock_time(tokens.expiration()));
+                }
+                Err(e) => {
+                    eprintln!("Warning: failed to renew session: {}", e);
+                    renew_at = Utc::now() + chrono::Duration::minutes(1);
+                }
+            }
+        }
+
+        std::thread::sleep(Duration::from_secs(1));
+    }
+}
+
+// The access key id of the profile in the credentials file.
+fn written_access_key(paths: &Paths, profile: &str) -> Option<String> {
+    CredFile::from_path(&paths.credentials)
+        .ok()?
+        .credential(profile)?
+        .get("aws_access_key_id")
+        .map(str::to_string)
+}
+
+fn renew_time(obtained: DateTime<Utc>, expiration: DateTime<Utc>) -> DateTime<Utc> {
+    let margin = chrono::Duration::minutes(RENEW_BEFORE_MINUTES).min((expiration - obtained) / 2);
+    expiration - margin
+}
+
+// Meant as the exec command of a kubeconfig user, so stdout is only the credential.
+fn eks_token(matches: &ArgMatches, config: &MfaConfig, paths: &Paths, offline: bool) -> Result<()> {
+    let tokens = session_tokens(matches, config, paths, offline)?;
+    // Validated by clap as required
+    let cluster = matches.value_of(ARG_CLUSTER).unwrap();
+    let timeout = timeout(&Options::new(matches, config, paths))?;
+
+    let credential = aws::eks_token(cluster, matches.value_of(ARG_REGION), &tokens, timeout)?;
+    println!("{}", credential);
+    Ok(())
+}
+
+fn ecr_login(matches: &ArgMatches, config: &MfaConfig, paths: &Paths, offline: bool) -> Result<()> {
+    let tokens = session_tokens(matches, config, paths, offline)?;
+    let timeout = timeout(&Options::new(matches, config, paths))?;
+    let region = aws::region(matches.value_of(ARG_REGION))?;
+
+    let account = match matches.value_of(ARG_REGISTRY) {
+        Some(id) => id.to_string(),
+        None => aws::caller_account(&tokens, timeout)?,
+    };
+    let registry = aws::ecr_registry(&account, &region);
+
+    let password = aws::ecr_password(&region, &tokens, timeout)?;
+    aws::docker_login(&registry, &password)
+}
+
+fn codeartifact_login(
+    matches: &ArgMatches,
+    config: &MfaConfig,
+    paths: &Paths,
+    offline: bool,
+) -> Result<()> {
+    let tokens = session_tokens(matches, config, paths, offline)?;
+    let timeout = timeout(&Options::new(matches, config, paths))?;
+
+    // Validated by clap as required
+    let codeartifact = CodeArtifact {
+        domain: matches.value_of(ARG_DOMAIN).unwrap(),
+        repository: matches.value_of(ARG_REPOSITORY).unwrap(),
+        domain_owner: matches.value_of(ARG_DOMAIN_OWNER),
+        region: matches.value_of(ARG_REGION),
+    };
+    let tool = matches.value_of(ARG_TOOL).unwrap().parse()?;
+
+    codeartifact.login(tool, &tokens, timeout)
+}
+
+fn export(matches: &ArgMatches, config: &MfaConfig, paths: &Paths, offline: bool) -> Result<()> {
+    let tokens = session_tokens(matches, config, paths, offline)?;
+    let options = Options::new(matches, config, paths);
+    let vars = match matches.value_of(ARG_TO) {
+        Some(to) => to
+            .parse::<ExportTarget>()?
+            .env_vars(&options.mfa_profile(), &tokens),
+        None => tokens.env_vars(),
+    };
+    emit(matches, &sink::export_lines(options.shell()?, &vars))
+}
+
+fn env(matches: &ArgMatches, config: &MfaConfig, paths: &Paths) -> Result<()> {
+    let mfa_profile = Options::new(matches, config, paths).mfa_profile();
+
+    if let Some(session) = Cache::read(paths)?.session(&mfa_profile) {
+        if !session.is_valid() {
+            eprintln!(
+                "Warning: session for mfa profile {} expired at {}",
+                mfa_profile, session.expiration
+            );
+        }
+    }
+
+    let creds = CredFile::from_path(&paths.credentials)?;
+    let cred = creds
+        .credential(&mfa_profile)
+        .ok_or_else(|| anyhow!("Not Found mfa profile in credentials: {}", mfa_profile))?;
+
+    let shell = Options::new(matches, config, paths).shell()?;
+    emit(
+        matches,
+        &sink::export_lines(shell, &credential_env_vars(cred)),
+    )
+}
+
+fn status(matches: &ArgMatches, config: &MfaConfig, paths: &Paths) -> Result<()> {
+    let mfa_profile = Options::new(matches, config, paths).mfa_profile();
+    let creds = CredFile::from_path(&paths.credentials)?;
+    let cred = creds
+        .credential(&mfa_profile)
+        .ok_or_else(|| anyhow!("Not Found mfa profile in credentials: {}", mfa_profile))?;
+
+    println!("mfa profile:    {}", mfa_profile);
+
+    let provenance = match cred.provenance() {
+        Some(p) => p,
+        None => {
+            println!("provenance:     not recorded");
+            return Ok(());
+        }
+    };
+
+    let remaining = provenance.expiration - Utc::now();
+    let state = if remaining > chrono::Duration::zero() {
+        format!("in {}", duration::humanize(remaining))
+    } else {
+        "expired".to_string()
+    };
+
+    println!("source profile: {}", provenance.source_profile);
+    println!("device:         {}", provenance.device);
+    println!("issued:         {}", local_time(provenance.issued));
+    // A relative time already says how long is left.
+    if duration::time_format() == Some(TimeFormat::Relative) && remaining > chrono::Duration::zero()
+    {
+        println!("expires:        {}", local_time(provenance.expiration));
+    } else {
+        println!(
+            "expires:        {} ({})",
+            local_time(provenance.expiration),
+            state
+        );
+    }
+    println!("written by:     aws-mfa {}", provenance.version);
+
+    if provenance.device == IMPORTED
+        || [SAML, WEB_IDENTITY].contains(&&*provenance.source_profile)
+        || matches!(
+            aws_config_sso(paths, &provenance.source_profile),
+            Ok(Some(_))
+        )
+    {
+        return Ok(());
+    }
+
+    match config::mfa::get_device_arn(&provenance.source_profile, config) {
+        Ok(arn) if arn != provenance.device => eprintln!(
+            "Warning: mfa.yml maps profile {} to device {}, but the session was issued with {}",
+            provenance.source_profile, arn, provenance.device
+        ),
+        Err(_) => eprintln!(
+            "Warning: profile {} is no longer configured in mfa.yml",
+            provenance.source_profile
+        ),
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn countdown(matches: &ArgMatches, config: &MfaConfig, paths: &Paths) -> Result<()> {
+    let mfa_profile = Options::new(matches, config, paths).mfa_profile();
+    let expiration = session_expiration(paths, &mfa_profile)?;
+    let watch = matches.is_present(ARG_WATCH);
+
+    loop {
+        let remaining = expiration - Utc::now();
+        if remaining <= chrono::Duration::zero() {
+            if watch {
+                println!();
+            }
+            return Err(anyhow!(
+                "Session for mfa profile {} expired at {}",
+                mfa_profile,
+                local_time(expiration)
+            ));
+        }
+
+        if !watch {
+            println!("{}", duration::humanize(remaining));
+            return Ok(());
+        }
+
+        // Rewrite the line in place.
+        print!("\r{}\x1b[K", duration::humanize(remaining));
+        std::io::stdout().flush()?;
+        std::thread::sleep(Duration::from_secs(1));
+    }
+}
+
+// Silent on success, for `aws-mfa check --min-remaining 30m || aws-mfa`.
+fn check(matches: &ArgMatches, config: &MfaConfig, paths: &Paths) -> Result<()> {
+    let mfa_profile = Options::new(matches, config, paths).mfa_profile();
+    // Validated by clap with a default value
+    let min_remaining = duration::parse_seconds(matches.value_of(ARG_MIN_REMAINING).unwrap())?;
+    let min_remaining = chrono::Duration::seconds(min_remaining.into());
+
+    let expiration = session_expiration(paths, &mfa_profile)?;
+    let remaining = expiration - Utc::now();
+
+    if remaining <= chrono::Duration::zero() {
+        return Err(anyhow!(
+            "Session for mfa profile {} expired at {}",
+            mfa_profile,
+            local_time(expiration)
+        ));
+    }
+    if remaining < min_remaining {
+        return Err(anyhow!(
+            "Session for mfa profile {} has {} left, less than {}",
+            mfa_profile,
+            duration::humanize(remaining),
+            duration::humanize(min_remaining)
+        ));
+    }
+    Ok(())
+}
+
+// From the cache, or the provenance in the credentials file when it isn't cached.
+fn session_expiration(paths: &Paths, mfa_profile: &str) -> Result<DateTime<Utc>> {
+    if let Some(session) = Cache::read(paths)?.session(mfa_profile) {
+        return Ok(session.expiration);
+    }
+
+    CredFile::from_path(&paths.credentials)?
+        .credential(mfa_profile)
+        .and_then(|cred| cred.provenance())
+        .map(|provenance| provenance.expiration)
+        .ok_or_else(|| anyhow!("Not Found session for mfa profile: {}", mfa_profile))
+}
+
+// Answers JSON-RPC requests read from stdin, one per line, until it is closed.
+// stdout only carries the responses, so sessions aren't written to env or http.
+fn serve_rpc(app: &App, config: &MfaConfig, paths: &Paths) -> Result<()> {
+    let mut stdout = std::io::stdout();
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match rpc::parse(&line) {
+            Ok(request) => {
+                let result = rpc_call(app, config, paths, &request);
+                let Some(id) = request.id else {
+                    continue;
+                };
+                match result {
+                    Ok(result) => rpc::response(&id, result),
+                    Err(e) => rpc::failure(&id, &e),
+                }
+            }
+            Err(response) => response,
+        };
+        writeln!(stdout, "{}", response)?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+fn rpc_call(
+    app: &App,
+    config: &MfaConfig,
+    paths: &Paths,
+    request: &rpc::Request,
+) -> Result<serde_json::Value> {
+    match request.method.as_str() {
+        "devices" => Ok(config
+            .devices()
+            .iter()
+            .map(|device| {
+                json!({
+                    "profile": device.profile,
+                    "arn": device.arn,
+                    "output_profile": device.output_profile,
+                })
+            })
+            .collect()),
+        "status" => rpc_status(app, config, paths, rpc::params(&request.params)?),
+        "refresh" => rpc_refresh(app, config, paths, rpc::params(&request.params)?),
+        method => Err(RpcError::method_not_found(method).into()),
+    }
+}
+
+// The command line of a request, so its options resolve like the CLI's.
+fn rpc_matches(
+    app: &App,
+    flags: &[(&str, Option<&String>)],
+    switches: &[(&str, bool)],
+    code: Option<&String>,
+) -> Result<ArgMatches> {
+    let mut args = vec![env!("CARGO_PKG_NAME").to_string()];
+    for (switch, on) in switches {
+        if *on {
+            args.push(switch.to_string());
+        }
+    }
+    for (flag, value) in flags {
+        if let Some(value) = value {
+            args.push(flag.to_string());
+            args.push(value.to_string());
+        }
+    }
+    args.extend(code.cloned());
+    app.clone().try_get_matches_from(args).map_err(|e| {
+        let message = e.to_string();
+        RpcError::invalid_params(message.lines().next().unwrap_or_default()).into()
+    })
+}
+
+fn rpc_status(
+    app: &App,
+    config: &MfaConfig,
+    paths: &Paths,
+    params: StatusParams,
+) -> Result<serde_json::Value> {
+    let matches = rpc_matches(
+        app,
+        &[("--mfa-profile", params.mfa_profile.as_ref())],
+        &[],
+        None,
+    )?;
+    let mfa_profile = Options::new(&matches, config, paths).mfa_profile();
+    let creds = CredFile::from_path(&paths.credentials)?;
+    let cred = creds
+        .credential(&mfa_profile)
+        .ok_or_else(|| anyhow!("Not Found mfa profile in credentials: {}", mfa_profile))?;
+
+    // Without provenance all but the name are null.
+    let provenance = cred.provenance();
+    Ok(json!({
+        "mfa_profile": mfa_profile,
+        "source_profile": provenance.as_ref().map(|p| &p.source_profile),
+        "device": provenance.as_ref().map(|p| &p.device),
+        "issued": provenance.as_ref().map(|p| p.issued.to_rfc3339()),
+        "expiration": provenance.as_ref().map(|p| p.expiration.to_rfc3339()),
+        "valid": provenance.as_ref().map(|p| p.expiration > Utc::now()),
+    }))
+}
+
+// Without a code the device's code_source gives one. A prompt would read stdin,
+// which carries the requests, so a device without one needs the code in params.
+fn rpc_refresh(
+    app: &App,
+    config: &MfaConfig,
+    paths: &Paths,
+    params: RefreshParams,
+) -> Result<serde_json::Value> {
+    // It would be read as a flag.
+    if params
+        .code
+        .as_deref()
+        .is_some_and(|code| code.starts_with('-'))
+    {
+        return Err(RpcError::invalid_params("code must be digits").into());
+    }
+    let matches = rpc_matches(
+        app,
+        &[
+            ("--profile", params.profile.as_ref()),
+            ("--mfa-profile", params.mfa_profile.as_ref()),
+            ("--duration-seconds", params.duration.as_ref()),
+        ],
+        &[("--force", params.force)],
+        params.code.as_ref(),
+    )?;
+    let options = Options::new(&matches, config, paths).no_prompt();
+    if sink::parse_outputs(&options.output())?
+        .iter()
+        .any(|output| matches!(output, Output::Env | Output::Http))
+    {
+        return Err(anyhow!(
+            "Outputs env and http can't be used with rpc, whose stdout carries the responses: set output without them"
+        ));
+    }
+
+    let tokens = refresh_session(&matches, config, &options, false)?;
+    Ok(json!({
+        "mfa_profile": options.mfa_profile(),
+        "expiration": tokens.expiration().to_rfc3339(),
+    }))
+}
+
+fn history(matches: &ArgMatches, paths: &Paths) -> Result<()> {
+    // Validated by clap with a default value
+    let limit = matches
+        .value_of(ARG_LIMIT)
+        .unwrap()
+        .parse::<usize>()
+        .map_err(|e| anyhow!("Parse error: cannot parse -n: {}", e))?;
+    let profile = arg_or_env(matches, ARG_PROFILE);
+
+    let entries: Vec<audit::Entry> = audit::read(paths)?
+        .into_iter()
+        .filter(|entry| profile.as_ref().is_none_or(|p| &entry.profile == p))
+        .collect();
+
+    for entry in entries.iter().skip(entries.len().saturating_sub(limit)) {
+        let result = match &entry.error {
+            None => "ok".to_string(),
+            Some(e) => format!("failed: {}", e.lines().next().unwrap_or_default()),
+        };
+        println!(
+            "{}  {}  {}  {}  {}  {}",
+            local_time(entry.time),
+            entry.profile,
+            entry.mfa_profile,
+            entry.device,
+            duration::humanize(chrono::Duration::seconds(entry.duration.into())),
+            result
+        );
+    }
+    Ok(())
+}
+
+// Reads any credentials file without changing it, and warns when others than
+// its owner can read it.
+fn inspect(matches: &ArgMatches, paths: &Paths) -> Result<()> {
+    let path = matches
+        .value_of(ARG_FILE)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| paths.credentials.clone());
+    let creds = CredFile::from_path(&path).map_err(|e| anyhow!("{}: {}", e, path.display()))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path)?.permissions().mode() & 0o777;
+        if mode & 0o077 != 0 {
+            eprintln!(
+                "Warning: {} has mode {:o}, so others can read it: chmod 600 it",
+                path.display(),
+                mode
+            );
+        }
+    }
+
+    let width = creds
+        .credentials()
+        .iter()
+        .map(|cred| cred.profile().len())
+        .max()
+        .unwrap_or_default();
+    for cred in creds.credentials() {
+        let expiration = match (cred.kind(), cred.expiration()) {
+            (_, Some(time)) if time <= Utc::now() => format!("expired {}", local_time(time)),
+            (_, Some(time)) => format!("expires {}", local_time(time)),
+            (CredentialKind::Session, None) => "expiration not recorded".to_string(),
+            _ => String::new(),
+        };
+        let line = format!(
+            "{:width$}  {:14}  {}",
+            cred.profile(),
+            cred.kind().to_string(),
+            expiration,
+            width = width
+        );
+        println!("{}", line.trim_end());
+    }
+    Ok(())
+}
+
+// Lists the GetSessionToken and AssumeRole calls CloudTrail recorded for the
+// IAM user of the profile, and fails when some of them weren't made by a
+// refresh on this machine.
+fn audit_remote(matches: &ArgMatches, config: &MfaConfig, paths: &Paths) -> Result<()> {
+    let options = Options::new(matches, config, paths);
+    let profile = options
+        .profile()
+        .ok_or_else(|| anyhow!("audit-remote needs the profile of the IAM user with -p"))?;
+    // Validated by clap with default values
+    let since = duration::parse_seconds(matches.value_of(ARG_SINCE).unwrap())?;
+    let region = matches.value_of(ARG_REGION).unwrap();
+    let since = Utc::now() - chrono::Duration::seconds(since.into());
+    let timeout = timeout(&options)?;
+
+    let (_, user) = iam_user(&profile, timeout)?;
+    let requests = aws::token_requests(&user, since, region, &profile, timeout)?;
+    let entries: Vec<audit::Entry> = audit::read(paths)?
+        .into_iter()
+        .filter(|entry| entry.profile == profile)
+        .collect();
+
+    let mut foreign = 0;
+    for request in &requests {
+        let here = audit::made_here(request.event_time, &entries);
+        if !here {
+            foreign += 1;
+        }
+        println!(
+            "{}  {:<15}  {:<15}  {:<12}  {}",
+            local_time(request.event_time),
+            request.event_name,
+            request.source_ip_address,
+            request.error_code.as_deref().unwrap_or("ok"),
+            if here {
+                "this machine"
+            } else {
+                "NOT FROM THIS MACHINE"
+            }
+        );
+    }
+
+    if foreign > 0 {
+        return Err(anyhow!(
+            "{} of {} token requests of {} since {} were not made by aws-mfa on this machine",
+            foreign,
+            requests.len(),
+            user,
+            local_time(since)
+        ));
+    }
+    eprintln!(
+        "All {} token requests of {} since {} were made on this machine",
+        requests.len(),
+        user,
+        local_time(since)
+    );
+    Ok(())
+}
+
+// In time_format of mfa.yml, else the local time.
+fn local_time(time: DateTime<Utc>) -> String {
+    duration::time_format()
+        .unwrap_or(TimeFormat::Local)
+        .format(time, Utc::now())
+}
+
+// Like local_time, but just the clock by default, for prompts and progress.
+fn clock_time(time: DateTime<Utc>) -> String {
+    match duration::time_format() {
+        Some(format) => format.format(time, Utc::now()),
+        None => time.with_timezone(&Local).format("%H:%M").to_string(),
+    }
+}
+
+fn snapshot(matches: &ArgMatches, paths: &Paths) -> Result<()> {
+    if matches.is_present(ARG_LIST) {
+        for id in config::snapshot::list(paths)? {
+            println!("{}", id);
+        }
+        return Ok(());
+    }
+
+    println!("{}", config::snapshot::create(paths)?);
+    Ok(())
+}
+
+fn rollback(matches: &ArgMatches, paths: &Paths) -> Result<()> {
+    let id = matches.value_of(ARG_SNAPSHOT_ID).unwrap();
+
+    // Keep the current state so that the rollback itself can be undone.
+    let current = config::snapshot::create(paths)?;
+    // Rolling back may be what fixes a broken mfa.yml, so it isn't required.
+    let lock = MfaConfig::read(paths)
+        .ok()
+        .and_then(|config| config.lock)
+        .unwrap_or_default();
+    let (restored, removed) = config::snapshot::restore(paths, id, lock)?;
+
+    eprintln!("Saved the current files as snapshot {}", current);
+    eprintln!("Restored {} from snapshot {}", restored.join(", "), id);
+    if !removed.is_empty() {
+        eprintln!("Removed {}, not in the snapshot", removed.join(", "));
+    }
+    Ok(())
+}
+
+fn restore(matches: &ArgMatches, config: &MfaConfig, paths: &Paths) -> Result<()> {
+    let options = Options::new(matches, config, paths);
+    let uri = match matches.value_of(ARG_FROM) {
+        Some(uri) => uri.to_string(),
+        None => options.remote_backup().ok_or_else(|| {
+            anyhow!("Not Found backup location: pass --from or set backup in mfa.yml")
+        })?,
+    };
+
+    let (backup, object) = S3Backup::parse(
+        paths,
+        &uri,
+        options.profile().as_deref(),
+        timeout(&options)?,
+    )?;
+    let object = match object {
+        Some(object) => object,
+        None => {
+            let names = backup.list()?;
+            if names.is_empty() {
+                return Err(anyhow!("Not Found backups in {}", uri));
+            }
+            let index = prompt::select("Backup to restore", &names)?;
+            names[index].clone()
+        }
+    };
+
+    let content = backup.download(&object, options.force())?;
+    let restored: CredFile = std::str::from_utf8(&content)
+        .map_err(anyhow::Error::new)
+        .and_then(str::parse)
+        .map_err(|e| {
+            anyhow!(
+                "Refusing to restore {}: not a credentials file\n{}",
+                object,
+                e
+            )
+        })?;
+
+    let dropped = dropped_profiles(&paths.credentials, &restored)?;
+    if !dropped.is_empty() && !options.force() {
+        let dropped = dropped.join(", ");
+        if !tty::is_interactive() {
+            return Err(anyhow!(
+                "Refusing to restore {} without profiles {}: pass --force to drop them",
+                object,
+                dropped
+            ));
+        }
+        if !prompt::confirm(&format!(
+            "Restore {} and drop profiles {}?",
+            object, dropped
+        ))? {
+            return Err(anyhow!("Canceled"));
+        }
+    }
+
+    // The current file is backed up locally first, like any other write.
+    let tx = Transaction::new(paths.credentials.clone(), options.backup_path())
+        .lock_with(options.lock());
+    tx.run(|path| {
+        config::replace(path, &content).map_err(|e| anyhow!("Error writing to credentials: {}", e))
+    })?;
+
+    eprintln!("Restored credentials from {}", object);
+    Ok(())
+}
+
+// Profiles of the current credentials file the restored one doesn't have.
+fn dropped_profiles(current: &Path, restored: &CredFile) -> Result<Vec<String>> {
+    if !current.exists() {
+        return Ok(Vec::new());
+    }
+    let current = CredFile::from_path(current).map_err(|e| {
+        anyhow!(
+            "Refusing to restore over {}: {}\npass --lenient or move it aside first",
+            current.display(),
+            e
+        )
+    })?;
+    Ok(current
+        .credentials()
+        .iter()
+        .map(|cred| cred.profile())
+        .filter(|profile| restored.credential(profile).is_none())
+        .map(String::from)
+        .collect())
+}
+
+fn secret(matches: &ArgMatches, paths: &Paths) -> Result<()> {
+    let new_store = !secrets::secrets_path(paths).exists();
+
+    match matches.subcommand() {
+        Some((CMD_ADD, sub_matches)) => {
+            // Validated by clap as required
+            let name = sub_matches.value_of(ARG_NAME).unwrap();
+            let passphrase = secrets::passphrase(paths, new_store)?;
+            let mut store = Secrets::read(paths, &passphrase)?;
+
+            // The URI of a QR code also tells the length of the codes.
+            let from_uri = |otp: OtpAuth| (Zeroizing::new(otp.secret.clone()), otp.digits);
+            let (seed, digits) = match sub_matches.value_of(ARG_QR) {
+                Some(image) => from_uri(OtpAuth::from_qr(Path::new(image))?),
+                None => {
+                    let input = Zeroizing::new(if tty::is_interactive() {
+                        prompt::secret("TOTP seed (base32) or otpauth:// URI")?
+                    } else {
+                        let mut line = String::new();
+                        std::io::stdin().read_line(&mut line)?;
+                        line
+                    });
+
+                    if input.trim_start().starts_with("otpauth://") {
+                        from_uri(OtpAuth::parse(&input)?)
+                    } else {
+                        let seed = Zeroizing::new(input.trim().replace(' ', "").to_uppercase());
+                        // Fails early on a seed that isn't base32.
+                        totp::generate(&seed, totp::now())?;
+                        (seed, totp::DIGITS)
+                    }
+                }
+            };
+
+            store.insert(name, &seed);
+            store.write(paths, &passphrase)?;
+            eprintln!("Stored seed {}", name);
+            if digits != totp::DIGITS {
+                eprintln!(
+                    "The device makes {} digit codes: set code_digits: {} on it in mfa.yml",
+                    digits, digits
+                );
+            }
+        }
+        Some((CMD_LIST, _)) => {
+            for name in Secrets::read(paths, &secrets::passphrase(paths, false)?)?.names() {
+                println!("{}", name);
+            }
+        }
+        Some((CMD_REMOVE, sub_matches)) => {
+            // Validated by clap as required
+            let name = sub_matches.value_of(ARG_NAME).unwrap();
+            let passphrase = secrets::passphrase(paths, false)?;
+            let mut store = Secrets::read(paths, &passphrase)?;
+
+            if !store.remove(name) {
+                return Err(anyhow!("Not Found secret: {}", name));
+            }
+            store.write(paths, &passphrase)?;
+            eprintln!("Removed seed {}", name);
+        }
+        Some((CMD_UNLOCK, sub_matches)) => {
+            let enrolled = Enrollment::read(paths)?;
+            // Validated by clap as required with possible values
+            match sub_matches.value_of(ARG_METHOD).unwrap() {
+                "key" => {
+                    if enrolled.is_some() {
+                        return Err(anyhow!("Secrets are already unlocked with a security key"));
+                    }
+                    let store = if new_store {
+                        Secrets::default()
+                    } else {
+                        Secrets::read(paths, &secrets::typed_passphrase(false)?)?
+                    };
+                    let enrollment = Enrollment::create()?;
+                    let secret = enrollment.secret()?;
+
+                    // Without the enrollment the store couldn't be opened again.
+                    enrollment.write(paths)?;
+                    if let Err(e) = store.write(paths, &secret) {
+                        Enrollment::remove(paths)?;
+                        return Err(e);
+                    }
+                    eprintln!("Secrets are now unlocked with the security key");
+                }
+                _ => {
+                    let enrollment = enrolled
+                        .ok_or_else(|| anyhow!("Secrets are already unlocked with a passphrase"))?;
+                    let store = Secrets::read(paths, &enrollment.secret()?)?;
+                    store.write(paths, &secrets::typed_passphrase(true)?)?;
+                    Enrollment::remove(paths)?;
+                    eprintln!("Secrets are now unlocked with a passphrase");
+                }
+            }
+        }
+        _ => unreachable!("clap requires a subcommand"),
+    }
+    Ok(())
+}
+
+fn key(matches: &ArgMatches, paths: &Paths) -> Result<()> {
+    // Like device import, these work before mfa.yml exists.
+    let config = MfaConfig::read(paths).unwrap_or_default();
+    let profile = |sub_matches: &ArgMatches| {
+        arg_or_env(sub_matches, ARG_PROFILE)
+            .ok_or_else(|| anyhow!("key commands need the profile of the keys with -p"))
+    };
+    let store = |sub_matches: &ArgMatches, profile: &str| -> Result<KeyStore> {
+        match sub_matches.value_of(ARG_STORE) {
+            Some(store) => store.parse(),
+            None => Ok(config::mfa::get_key_store(profile, &config).unwrap_or(KeyStore::Keychain)),
+        }
+    };
+
+    match matches.subcommand() {
+        Some((CMD_IMPORT, sub_matches)) => {
+            let profile = profile(sub_matches)?;
+            let store = store(sub_matches, &profile)?;
+
+            let (access_key_id, secret_access_key) = if tty::is_interactive() {
+                eprint!("Access key ID: ");
+                std::io::stderr().flush()?;
+                let mut line = String::new();
+                std::io::stdin().read_line(&mut line)?;
+                (line, Zeroizing::new(prompt::secret("Secret access key")?))
+            } else {
+                let mut lines = std::io::stdin().lines();
+                let mut next = || lines.next().transpose().map(Option::unwrap_or_default);
+                (next()?, Zeroizing::new(next()?))
+            };
+
+            LongTermKeys::new(&access_key_id, &secret_access_key)?.store(paths, store, &profile)?;
+            eprintln!("Stored the keys of profile {} in {}", profile, store);
+
+            let in_credentials = CredFile::from_path(&paths.credentials)
+                .ok()
+                .and_then(|creds| {
+                    creds
+                        .credential(&profile)
+                        .map(|c| c.get("aws_secret_access_key").is_some())
+                })
+                .unwrap_or(false);
+            if in_credentials {
+                eprintln!(
+                    "The keys of profile {} are still in {}, remove them from there",
+                    profile,
+                    paths.credentials.display()
+                );
+            }
+            if config::mfa::get_key_store(&profile, &config) != Some(store) {
+                eprintln!(
+                    "Use them with key_store: {} on the device of profile {} in mfa.yml",
+                    store, profile
+                );
+            }
+        }
+        Some((CMD_LIST, _)) => {
+            for device in config.devices() {
+                if let Some(store) = device.key_store {
+                    let access_key_id = LongTermKeys::load(paths, store, &device.profile)?
+                        .map(|keys| keys.access_key_id().to_string())
+                        .unwrap_or_else(|| "(not imported)".to_string());
+                    println!("{}\t{}\t{}", device.profile, store, access_key_id);
+                }
+            }
+        }
+        Some((CMD_REMOVE, sub_matches)) => {
+            let profile = profile(sub_matches)?;
+            let store = store(sub_matches, &profile)?;
+
+            if !LongTermKeys::remove(paths, store, &profile)? {
+                return Err(anyhow!(
+                    "Not Found keys of profile {} in {}",
+                    profile,
+                    store
+                ));
+            }
+            eprintln!("Removed the keys of profile {} from {}", profile, store);
+        }
+        _ => unreachable!("clap requires a subcommand"),
+    }
+    Ok(())
+}
+
+fn device(matches: &ArgMatches, paths: &Paths) -> Result<()> {
+    match matches.subcommand() {
+        Some((CMD_IMPORT, sub_matches)) => {
+            // Validated by clap as required
+            let path = sub_matches.value_of(ARG_FILE).unwrap();
+            let entries = inventory::read(Path::new(path))?;
+            let configured = match config::mfa::config_path(paths) {
+                Ok(path) => MfaConfig::from_path(path)?,
+                Err(_) => MfaConfig::default(),
+            };
+
+            let mut devices: Vec<(&str, &str)> = vec![];
+            for entry in &entries {
+                if !entry.is_totp() {
+                    eprintln!(
+                        "Skipping {}: {} devices can't be used with GetSessionToken",
+                        entry.profile,
+                        entry.kind.as_deref().unwrap_or_default()
+                    );
+                } else if let Err(e) = entry.arn.parse::<Arn>() {
+                    eprintln!("Skipping {}: {}", entry.profile, e);
+                } else if configured.device_for(&entry.profile).is_some()
+                    || devices.iter().any(|(p, _)| *p == entry.profile)
+                {
+                    eprintln!("Skipping {}: already configured", entry.profile);
+                } else {
+                    devices.push((&entry.profile, &entry.arn));
+                }
+            }
+
+            if !devices.is_empty() {
+                config::mfa::save_devices(paths, &devices)?;
+            }
+            eprintln!(
+                "Imported {} of {} devices into {}",
+                devices.len(),
+                entries.len(),
+                config::mfa::save_path(paths).display()
+            );
+        }
+        Some((CMD_PROVISION, sub_matches)) => provision_device(sub_matches, paths)?,
+        Some((CMD_RESYNC, sub_matches)) => resync_device(sub_matches, paths)?,
+        Some((CMD_DEACTIVATE, sub_matches)) => deactivate_device(sub_matches, paths)?,
+        _ => unreachable!("clap requires a subcommand"),
+    }
+    Ok(())
+}
+
+// Creates a virtual device for the IAM user of the profile, shows its seed as a
+// QR code for the authenticator app, and enables it with two codes of the app.
+// A device that can't be enabled is deleted again.
+fn provision_device(matches: &ArgMatches, paths: &Paths) -> Result<()> {
+    tty::require_interactive("show the QR code of a new device")?;
+    let profile = arg_or_env(matches, ARG_PROFILE)
+        .ok_or_else(|| anyhow!("device provision needs the profile of the IAM user with -p"))?;
+
+    let configured = match config::mfa::config_path(paths) {
+        Ok(path) => MfaConfig::from_path(path)?,
+        Err(_) => MfaConfig::default(),
+    };
+    if let Some(device) = configured.device_for(&profile) {
+        return Err(anyhow!(
+            "Profile {} already has MFA device {} in mfa.yml",
+            profile,
+            device.arn
+        ));
+    }
+    let timeout = timeout(&Options::new(matches, &configured, paths))?;
+
+    let (caller, user) = iam_user(&profile, timeout)?;
+    let name = matches.value_of(ARG_NAME).unwrap_or(&user);
+
+    let device = aws::create_virtual_mfa_device(name, &profile, timeout)?;
+    let label = format!("Amazon Web Services:{}@{}", user, caller.account_id);
+    eprintln!("{}", OtpAuth::new(&label, &device.seed).qr()?.as_str());
+    eprintln!("Scan the QR code with the authenticator app of {}", user);
+
+    let enabled = consecutive_codes("the app").and_then(|[first, second]| {
+        aws::enable_mfa_device(&user, &device.arn, [&first, &second], &profile, timeout)
+    });
+
+    if let Err(e) = enabled {
+        if let Err(delete) = aws::delete_virtual_mfa_device(&device.arn, &profile, timeout) {
+            eprintln!(
+                "Warning: could not delete device {}: {}",
+                device.arn, delete
+            );
+        }
+        return Err(e);
+    }
+
+    config::mfa::save_device(paths, &profile, &device.arn)?;
+    eprintln!(
+        "Enabled MFA device {} and saved it for profile {} in {}",
+        device.arn,
+        profile,
+        config::mfa::save_path(paths).display()
+    );
+    Ok(())
+}
+
+fn admin(matches: &ArgMatches, paths: &Paths) -> Result<()> {
+    match matches.subcommand() {
+        Some((CMD_BOOTSTRAP, sub_matches)) => bootstrap(sub_matches, paths),
+        _ => unreachable!("clap requires a subcommand"),
+    }
+}
+
+// Gives each user of users.yml an enabled virtual device, and writes what to
+// hand them: the devices: entry of their mfa.yml and the QR code to scan. The
+// device is enabled with codes of its seed, so the users need no IAM rights.
+fn bootstrap(matches: &ArgMatches, paths: &Paths) -> Result<()> {
+    let profile = arg_or_env(matches, ARG_PROFILE)
+        .ok_or_else(|| anyhow!("admin bootstrap needs the admin profile with -p"))?;
+    // Validated by clap as required and with a default value
+    let users = users::read(Path::new(matches.value_of(ARG_USERS).unwrap()))?;
+    let dir = Path::new(matches.value_of(ARG_OUT_DIR).unwrap());
+    let timeout = timeout(&Options::new(matches, &MfaConfig::default(), paths))?;
+
+    let admin: Arn = sts::get_caller_identity(Some(&profile), Partition::Aws, timeout)?.parse()?;
+    std::fs::create_dir_all(dir).map_err(|e| anyhow!("{}: {}", e, dir.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+
+    let mut failed = 0;
+    for user in &users {
+        match bootstrap_user(user, &admin, dir, &profile, timeout) {
+            Ok(arn) => eprintln!("Enabled MFA device {} of {}", arn, user.user),
+            Err(e) => {
+                failed += 1;
+                eprintln!("Error with user {}: {}", user.user, e);
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(anyhow!(
+            "{} of {} users got no MFA device, see above",
+            failed,
+            users.len()
+        ));
+    }
+    eprintln!(
+        "Hand each user their files in {}: the QR code is the seed, keep it private",
+        dir.display()
+    );
+    Ok(())
+}
+
+fn bootstrap_user(
+    user: &users::User,
+    admin: &Arn,
+    dir: &Path,
+    profile: &str,
+    timeout: Duration,
+) -> Result<String> {
+    let device = aws::create_virtual_mfa_device(&user.user, profile, timeout)?;
+
+    // Two consecutive codes, the latest being the current one.
+    let now = totp::now();
+    let enabled = totp::generate(&device.seed, now - totp::STEP)
+        .and_then(|first| Ok([first, totp::generate(&device.seed, now)?]))
+        .and_then(|[first, second]| {
+            aws::enable_mfa_device(&user.user, &device.arn, [&first, &second], profile, timeout)
+        });
+    if let Err(e) = enabled {
+        if let Err(delete) = aws::delete_virtual_mfa_device(&device.arn, profile, timeout) {
+            eprintln!(
+                "Warning: could not delete device {}: {}",
+                device.arn, delete
+            );
+        }
+        return Err(e);
+    }
+
+    let label = format!("Amazon Web Services:{}@{}", user.user, admin.account_id);
+    let qr = OtpAuth::new(&label, &device.seed).qr()?;
+    sink::write_private(
+        &dir.join(format!("{}.qr.txt", user.user)),
+        &qr,
+        false,
+        false,
+    )?;
+    sink::write_private(
+        &dir.join(format!("{}.mfa.yml", user.user)),
+        &config::mfa::device_snippet(&user.profile, &device.arn)?,
+        false,
+        false,
+    )?;
+    Ok(device.arn.clone())
+}
+
+// mfa.yml and the device of the profile in it, for resync and deactivate.
+fn configured_device(paths: &Paths, profile: &str) -> Result<(MfaConfig, String)> {
+    let config = MfaConfig::from_path(config::mfa::config_path(paths)?)?;
+    let arn = config::mfa::get_device_arn(profile, &config)?;
+    Ok((config, arn))
+}
+
+// Re-syncs the clock IAM keeps for the device with two codes it shows now.
+fn resync_device(matches: &ArgMatches, paths: &Paths) -> Result<()> {
+    tty::require_interactive("ask for the codes of the device")?;
+    let profile = arg_or_env(matches, ARG_PROFILE)
+        .ok_or_else(|| anyhow!("device resync needs the profile of the device with -p"))?;
+    let (config, arn) = configured_device(paths, &profile)?;
+    let timeout = timeout(&Options::new(matches, &config, paths))?;
+    let (_, user) = iam_user(&profile, timeout)?;
+
+    let [first, second] = consecutive_codes("the device")?;
+    aws::resync_mfa_device(&user, &arn, [&first, &second], &profile, timeout)?;
+
+    // The codes refused before the resync say nothing about the next ones.
+    let mut cache = Cache::read(paths)?;
+    cache.clear_failures(&arn);
+    cache.clear_sts_error(&arn);
+    cache.write(paths)?;
+
+    eprintln!("Resynchronized MFA device {}", arn);
+    Ok(())
+}
+
+// Detaches the device from the user, e.g. when it is lost, and removes it from
+// mfa.yml. With --delete a virtual device is deleted in IAM too.
+fn deactivate_device(matches: &ArgMatches, paths: &Paths) -> Result<()> {
+    let profile = arg_or_env(matches, ARG_PROFILE)
+        .ok_or_else(|| anyhow!("device deactivate needs the profile of the device with -p"))?;
+    let (config, arn) = configured_device(paths, &profile)?;
+    let timeout = timeout(&Options::new(matches, &config, paths))?;
+    let (_, user) = iam_user(&profile, timeout)?;
+
+    if !matches.is_present(ARG_FORCE) {
+        if !tty::is_interactive() {
+            return Err(anyhow!(
+                "Refusing to deactivate MFA device {} without confirmation: pass --force to run it from a script",
+                arn
+            ));
+        }
+        if !prompt::confirm(&format!("Deactivate MFA device {} of {}?", arn, user))? {
+            return Err(anyhow!("Canceled"));
+        }
+    }
+
+    aws::deactivate_mfa_device(&user, &arn, &profile, timeout)?;
+    eprintln!("Deactivated MFA device {}", arn);
+
+    if matches.is_present(ARG_DELETE) {
+        aws::delete_virtual_mfa_device(&arn, &profile, timeout)?;
+        eprintln!("Deleted MFA device {}", arn);
+    }
+
+    config::mfa::remove_device(paths, &profile)?;
+    eprintln!(
+        "Removed the device of profile {} from {}",
+        profile,
+        config::mfa::save_path(paths).display()
+    );
+    Ok(())
+}
+
+// The IAM user the profile authenticates as, by name.
+fn iam_user(profile: &str, timeout: Duration) -> Result<(Arn, String)> {
+    let caller: Arn = sts::get_caller_identity(Some(profile), Partition::Aws, timeout)?.parse()?;
+    let user = caller
+        .resource
+        .strip_prefix("user/")
+        .and_then(|path| path.rsplit('/').next())
+        .ok_or_else(|| {
+            anyhow!(
+                "Cannot manage MFA devices of {}: only IAM users have one",
+                caller
+            )
+        })?
+        .to_string();
+    Ok((caller, user))
+}
+
+// Two codes in a row of an authenticator, as IAM wants to enable or resync it.
+fn consecutive_codes(shown_by: &str) -> Result<[String; 2]> {
+    let read_code = |question: String| -> Result<String> {
+        eprint!("{}: ", question);
+        std::io::stderr().flush()?;
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line)?;
+        let code = line.trim().to_string();
+        code::validate(&code, totp::DIGITS)?;
+        Ok(code)
+    };
+
+    let first = read_code(format!("Code of {}", shown_by))?;
+    let second = read_code(format!("Next code of {}, once it changes", shown_by))?;
+    Ok([first, second])
+}
+
+fn show_config(matches: &ArgMatches, config: &MfaConfig, paths: &Paths) -> Result<()> {
+    match matches.subcommand() {
+        Some((CMD_EFFECTIVE, sub_matches)) => {
+            let settings = Options::new(sub_matches, config, paths).effective()?;
+            let width = settings
+                .iter()
+                .filter_map(|(_, value, _)| value.as_ref().map(String::len))
+                .max()
+                .unwrap_or(0);
+
+            for (name, value, source) in settings {
+                println!(
+                    "{:<14} {:<width$}  {}",
+                    name,
+                    value.as_deref().unwrap_or("-"),
+                    source,
+                    width = width
+                );
+            }
+        }
+        Some((CMD_EXPORT, sub_matches)) => {
+            let exported = Zeroizing::new(config.export(sub_matches.is_present(ARG_REDACT))?);
+            emit(sub_matches, &exported)?;
+        }
+        _ => unreachable!("clap requires a subcommand"),
+    }
+    Ok(())
+}
+
+fn manage_cache(matches: &ArgMatches, paths: &Paths) -> Result<()> {
+    match matches.subcommand() {
+        Some((CMD_CLEAR, _)) => {
+            cache::clear(paths)?;
+            eprintln!("Cleared {}", paths.cache.display());
+        }
+        Some((CMD_GC, _)) => {
+            let removed = cache::gc(paths, Utc::now())?;
+            eprintln!(
+                "Removed {} stale entries from {}",
+                removed,
+                paths.cache.display()
+            );
+        }
+        _ => unreachable!("clap requires a subcommand"),
+    }
+    Ok(())
+}
+
+fn unset(matches: &ArgMatches) -> Result<()> {
+    let shell = match arg_or_env(matches, ARG_SHELL) {
+        Some(s) => s.parse()?,
+        None => Shell::detect(),
+    };
+    let keys: Vec<&str> = CREDENTIAL_ENV_VARS.iter().map(|(_, var)| *var).collect();
+
+    println!("{}", shell.unset(&keys));
+    Ok(())
+}
+
+fn shell_init(matches: &ArgMatches) -> Result<()> {
+    let shell = match matches
+        .value_of(ARG_SHELL_NAME)
+        .map(str::to_string)
+        .or_else(|| arg_or_env(matches, ARG_SHELL))
+    {
+        Some(s) => s.parse()?,
+        None => Shell::detect(),
+    };
+    // Validated by clap with a default value
+    let function = matches.value_of(ARG_FUNCTION_NAME).unwrap();
+
+    println!("{}", shell.init(function));
+    Ok(())
+}
+
+// Refreshes the session when a code is given or the cached one is no longer valid.
+fn session_tokens(
+    matches: &ArgMatches,
+    config: &MfaConfig,
+    paths: &Paths,
+    offline: bool,
+) -> Result<SessionTokens> {
+    if matches.is_present(ARG_MFA_CODE) {
+        if offline {
+            return Err(anyhow!("--offline cannot be used with an MFA code"));
+        }
+        return refresh(matches, config, paths);
+    }
+
+    let mfa_profile = Options::new(matches, config, paths).mfa_profile();
+    match Cache::read(paths)?.session(&mfa_profile) {
+        Some(session) if session.is_valid() => return cached_tokens(paths, &mfa_profile, session),
+        Some(session) if offline => {
+            return Err(anyhow!(
+                "Session for mfa profile {} expired at {}: rerun without --offline to refresh it",
+                mfa_profile,
+                session.expiration
+            ))
+        }
+        None if offline => {
+            return Err(anyhow!(
+                "Not Found cached session for mfa profile: {}",
+                mfa_profile
+            ))
+        }
+        _ => {}
+    }
+
+    refresh(matches, config, paths)
+}
+
+fn cached_tokens(paths: &Paths, mfa_profile: &str, session: &Session) -> Result<SessionTokens> {
+    let creds = CredFile::from_path(&paths.credentials)?;
+    let cred = creds
+        .credential(mfa_profile)
+        .ok_or_else(|| anyhow!("Not Found mfa profile in credentials: {}", mfa_profile))?;
+
+    let tokens = SessionTokens::from_aws_credential(cred, session.expiration)?;
+    redact::register_tokens(&tokens);
+    Ok(tokens)
+}
+
+fn cache_session(
+    paths: &Paths,
+    mfa_profile: &str,
+    profile: &str,
+    tokens: &SessionTokens,
+) -> Result<()> {
+    let mut cache = Cache::read(paths)?;
+    cache.set_session(mfa_profile, Session::new(profile, tokens.expiration()));
+    cache.write(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod app {
+        use super::*;
+        use aws_mfa::option_env_var;
+        use std::ffi::OsStr;
+
+        #[test]
+        fn it_reads_every_option_from_env() {
+            let help = Help::new();
+            let app = app(&help);
+
+            // --force skips the safety checks, so it is never taken from the environment.
+            let options = app.get_arguments().filter(|arg| {
+                !arg.is_positional() && ![ARG_FORCE, "help", "version"].contains(&arg.get_id())
+            });
+            for arg in options {
+                let var = option_env_var(arg.get_id());
+                assert_eq!(
+                    arg.get_env(),
+                    Some(OsStr::new(&var)),
+                    "--{} has no {}",
+                    arg.get_long().unwrap_or_default(),
+                    var
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,111 @@
+use crate::Result;
+
+use anyhow::anyhow;
+use std::net::UdpSocket;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Seconds from the NTP epoch, 1900-01-01, to the Unix epoch.
+const NTP_UNIX_OFFSET: f64 = 2_208_988_800.0;
+const PACKET_LEN: usize = 48;
+
+// Seconds the local clock is behind the server (positive) or ahead of it
+// (negative), from one SNTP request. The server is host or host:port.
+pub fn offset(server: &str, timeout: Duration) -> Result<f64> {
+    let address = if server.contains(':') {
+        server.to_string()
+    } else {
+        format!("{}:123", server)
+    };
+
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket
+        .connect(&address)
+        .map_err(|e| anyhow!("{}: {}", e, address))?;
+
+    // Version 4, client mode
+    let mut request = [0u8; PACKET_LEN];
+    request[0] = 0x23;
+
+    let sent = unix_now();
+    socket.send(&request)?;
+    let mut response = [0u8; PACKET_LEN];
+    let len = socket
+        .recv(&mut response)
+        .map_err(|e| anyhow!("No answer from {}: {}", address, e))?;
+    let received = unix_now();
+
+    if len < PACKET_LEN {
+        return Err(anyhow!("Invalid NTP response from {}", address));
+    }
+    offset_of(&response, sent, received)
+}
+
+// (server receive - client send + server transmit - client receive) / 2
+fn offset_of(response: &[u8], sent: f64, received: f64) -> Result<f64> {
+    let mode = response[0] & 0x07;
+    let stratum = response[1];
+    // Mode 4 is a server reply, stratum 0 a "kiss of death" asking to back off.
+    if mode != 4 || stratum == 0 {
+        return Err(anyhow!("NTP server refused the request"));
+    }
+
+    let server_received = timestamp(&response[32..40]);
+    let server_sent = timestamp(&response[40..48]);
+    Ok(((server_received - sent) + (server_sent - received)) / 2.0)
+}
+
+// A 64 bit NTP timestamp as Unix seconds.
+fn timestamp(bytes: &[u8]) -> f64 {
+    let seconds = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64;
+    let fraction = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as f64;
+    seconds + fraction / 2f64.powi(32) - NTP_UNIX_OFFSET
+}
+
+fn unix_now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(mode: u8, stratum: u8, unix: u32) -> [u8; PACKET_LEN] {
+        let mut packet = [0u8; PACKET_LEN];
+        packet[0] = 0x20 | mode;
+        packet[1] = stratum;
+        let ntp = (unix as u64 + NTP_UNIX_OFFSET as u64) as u32;
+        packet[32..36].copy_from_slice(&ntp.to_be_bytes());
+        packet[40..44].copy_from_slice(&ntp.to_be_bytes());
+        // Half a second into both
+        packet[36] = 0x80;
+        packet[44] = 0x80;
+        packet
+    }
+
+    mod offset_of {
+        use super::*;
+
+        #[test]
+        fn it_computes_offset_of_local_clock() {
+            let packet = response(4, 2, 1_700_000_010);
+            let offset = offset_of(&packet, 1_700_000_000.0, 1_700_000_001.0).unwrap();
+            assert_eq!(offset, 10.0);
+        }
+
+        #[test]
+        fn it_fails_with_kiss_of_death() {
+            let packet = response(4, 0, 1_700_000_010);
+            assert!(offset_of(&packet, 1_700_000_000.0, 1_700_000_001.0).is_err());
+        }
+
+        #[test]
+        fn it_fails_with_client_packet() {
+            let packet = response(3, 2, 1_700_000_010);
+            assert!(offset_of(&packet, 1_700_000_000.0, 1_700_000_001.0).is_err());
+        }
+    }
+}
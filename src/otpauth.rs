@@ -0,0 +1,239 @@
+use crate::{totp, Result};
+
+use anyhow::anyhow;
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
+use std::path::Path;
+use std::process::Command;
+use zeroize::{Zeroize, Zeroizing};
+
+// A TOTP provisioning URI, as in the QR code IAM shows when a virtual device is
+// set up: otpauth://totp/LABEL?secret=BASE32&issuer=...&digits=6&period=30
+pub struct OtpAuth {
+    pub label: String,
+    pub secret: String,
+    pub digits: u32,
+}
+
+// The seed stays out of logs and panics.
+impl std::fmt::Debug for OtpAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OtpAuth")
+            .field("label", &self.label)
+            .field("digits", &self.digits)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for OtpAuth {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+    }
+}
+
+impl OtpAuth {
+    pub fn new(label: &str, secret: &str) -> Self {
+        Self {
+            label: label.to_string(),
+            secret: secret.to_string(),
+            digits: totp::DIGITS,
+        }
+    }
+
+    pub fn uri(&self) -> Zeroizing<String> {
+        let issuer = self.label.split_once(':').map(|(issuer, _)| issuer);
+        let mut uri = format!(
+            "otpauth://totp/{}?secret={}",
+            encode(&self.label),
+            self.secret
+        );
+        if let Some(issuer) = issuer {
+            uri.push_str(&format!("&issuer={}", encode(issuer)));
+        }
+        if self.digits != totp::DIGITS {
+            uri.push_str(&format!("&digits={}", self.digits));
+        }
+        Zeroizing::new(uri)
+    }
+
+    // The URI as a QR code of half-height blocks for a terminal, light on dark
+    // so that phones read it on the usual dark background.
+    pub fn qr(&self) -> Result<Zeroizing<String>> {
+        let code = QrCode::new(self.uri().as_bytes())
+            .map_err(|e| anyhow!("Error making QR code: {}", e))?;
+        Ok(Zeroizing::new(
+            code.render::<Dense1x2>()
+                .dark_color(Dense1x2::Light)
+                .light_color(Dense1x2::Dark)
+                .build(),
+        ))
+    }
+
+    pub fn parse(uri: &str) -> Result<Self> {
+        let rest = uri
+            .trim()
+            .strip_prefix("otpauth://")
+            .ok_or_else(|| anyhow!("Not an otpauth:// URI"))?;
+        let (kind, rest) = rest.split_once('/').unwrap_or((rest, ""));
+        if !kind.eq_ignore_ascii_case("totp") {
+            return Err(anyhow!("Unsupported OTP type, only totp is: {}", kind));
+        }
+        let (label, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+        let mut secret = None;
+        let mut digits = totp::DIGITS;
+        for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            let value = decode(value)?;
+            match key {
+                "secret" => secret = Some(value.replace(' ', "").to_uppercase()),
+                "digits" => {
+                    digits = value
+                        .parse()
+                        .map_err(|_| anyhow!("Invalid digits in otpauth URI: {}", value))?
+                }
+                "algorithm" if !value.eq_ignore_ascii_case("SHA1") => {
+                    return Err(anyhow!("Unsupported TOTP algorithm: {}", value))
+                }
+                "period" if value != totp::STEP.to_string() => {
+                    return Err(anyhow!("Unsupported TOTP period: {}", value))
+                }
+                _ => {}
+            }
+        }
+
+        let secret = secret.ok_or_else(|| anyhow!("No secret in otpauth URI"))?;
+        // Fails early on a seed that isn't base32.
+        totp::generate_digits(&secret, totp::now(), digits)?;
+
+        Ok(Self {
+            label: decode(label)?,
+            secret,
+            digits,
+        })
+    }
+
+    // The URI in a QR code image, decoded with zbarimg of the zbar tools.
+    pub fn from_qr(image: &Path) -> Result<Self> {
+        let output = Command::new("zbarimg")
+            .args(["--quiet", "--raw"])
+            .arg(image)
+            .output()
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => anyhow!(
+                    "Not Found zbarimg to read QR codes: install zbar, or pass the otpauth:// URI instead"
+                ),
+                _ => anyhow!("{}: zbarimg", e),
+            })?;
+
+        if !output.status.success() {
+            return Err(anyhow!("No QR code found in {}", image.display()));
+        }
+
+        let mut stdout = String::from_utf8(output.stdout)?;
+        let parsed = stdout
+            .lines()
+            .find(|line| line.starts_with("otpauth://"))
+            .map(Self::parse)
+            .unwrap_or_else(|| Err(anyhow!("No otpauth:// QR code in {}", image.display())));
+        stdout.zeroize();
+        parsed
+    }
+}
+
+// Percent-encoding of a URI component, keeping the : and @ of labels.
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b':' | b'@' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// Percent-decoding of a URI component.
+fn decode(value: &str) -> Result<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = value
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| anyhow!("Invalid escape in otpauth URI"))?;
+                decoded.push(hex);
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| anyhow!("Invalid escape in otpauth URI"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod parse {
+        use super::*;
+
+        #[test]
+        fn it_reads_iam_provisioning_uri() {
+            let otp = OtpAuth::parse(
+                "otpauth://totp/Amazon%20Web%20Services:tanaka@012345678901?secret=jbswy3dpehpk3pxp&issuer=Amazon%20Web%20Services",
+            )
+            .unwrap();
+            assert_eq!(otp.label, "Amazon Web Services:tanaka@012345678901");
+            assert_eq!(otp.secret, "JBSWY3DPEHPK3PXP");
+            assert_eq!(otp.digits, 6);
+        }
+
+        #[test]
+        fn it_reads_digits() {
+            let otp = OtpAuth::parse("otpauth://totp/x?digits=8&secret=JBSWY3DPEHPK3PXP").unwrap();
+            assert_eq!(otp.digits, 8);
+        }
+
+        #[test]
+        fn it_fails_with_unsupported_uri() {
+            assert!(OtpAuth::parse("https://example.com").is_err());
+            assert!(OtpAuth::parse("otpauth://hotp/x?secret=JBSWY3DPEHPK3PXP").is_err());
+            assert!(
+                OtpAuth::parse("otpauth://totp/x?secret=JBSWY3DPEHPK3PXP&algorithm=SHA256")
+                    .is_err()
+            );
+            assert!(OtpAuth::parse("otpauth://totp/x?issuer=AWS").is_err());
+        }
+
+        #[test]
+        fn it_reads_its_own_uri() {
+            let uri = OtpAuth::new(
+                "Amazon Web Services:tanaka@012345678901",
+                "JBSWY3DPEHPK3PXP",
+            )
+            .uri();
+            assert!(uri.ends_with("&issuer=Amazon%20Web%20Services"));
+
+            let otp = OtpAuth::parse(&uri).unwrap();
+            assert_eq!(otp.label, "Amazon Web Services:tanaka@012345678901");
+            assert_eq!(otp.secret, "JBSWY3DPEHPK3PXP");
+        }
+
+        #[test]
+        fn it_leaves_secret_out_of_debug_output() {
+            let otp = OtpAuth::parse("otpauth://totp/x?secret=JBSWY3DPEHPK3PXP").unwrap();
+            assert!(!format!("{:?}", otp).contains("JBSWY3DPEHPK3PXP"));
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use crate::Result;
+
+use anyhow::anyhow;
+use lazy_static::lazy_static;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+lazy_static! {
+    static ref CA_BUNDLE: Mutex<Option<PathBuf>> = Mutex::new(None);
+    static ref AWS_CLI_INSTALLED: bool =
+        std::env::var_os("PATH").is_some_and(|path| on_path("aws", &path));
+}
+
+// Returned when a subprocess doesn't finish in time.
+#[derive(Debug)]
+pub struct Timeout {
+    pub program: String,
+    pub after: Duration,
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Timed out: {} did not finish within {}s and was killed",
+            self.program,
+            self.after.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for Timeout {}
+
+// Set from --ca-bundle or ca_bundle in mfa.yml, for TLS intercepting proxies.
+// AWS_CA_BUNDLE needs nothing, the aws CLI reads it itself.
+pub fn set_ca_bundle(path: PathBuf) {
+    *CA_BUNDLE.lock().unwrap() = Some(path);
+}
+
+// The CA bundle the embedded STS clients verify TLS with, looked up like the aws
+// CLI does: the one set, else AWS_CA_BUNDLE, else ca_bundle of the profile.
+#[cfg(any(feature = "sdk", feature = "sigv4"))]
+pub(crate) fn ca_bundle(profile: Option<&str>) -> Option<PathBuf> {
+    CA_BUNDLE
+        .lock()
+        .unwrap()
+        .clone()
+        .or_else(|| {
+            std::env::var_os("AWS_CA_BUNDLE")
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from)
+        })
+        .or_else(|| {
+            crate::config::credentials::aws_config_value(
+                &crate::config::Paths::aws_cli(),
+                &crate::config::credentials::aws_cli_profile(profile),
+                "ca_bundle",
+            )
+            .map(PathBuf::from)
+        })
+}
+
+// The PEM of a CA bundle, checked to hold certificates before a TLS client is
+// built with it.
+#[cfg(any(feature = "sdk", feature = "sigv4"))]
+pub(crate) fn read_ca_bundle(path: &std::path::Path) -> Result<Vec<u8>> {
+    use rustls_pki_types::pem::PemObject;
+    use rustls_pki_types::CertificateDer;
+
+    let pem = std::fs::read(path)
+        .map_err(|e| anyhow!("Error reading CA bundle {}: {}", path.display(), e))?;
+    let certs = CertificateDer::pem_slice_iter(&pem)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| anyhow!("Invalid CA bundle {}: {:?}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificates in CA bundle {}", path.display()));
+    }
+    Ok(pem)
+}
+
+// The aws CLI, verifying TLS with the CA bundle if one is set.
+pub fn aws_command() -> Command {
+    let mut command = Command::new("aws");
+    if let Some(path) = CA_BUNDLE.lock().unwrap().as_ref() {
+        command.arg("--ca-bundle").arg(path);
+    }
+    command
+}
+
+// Whether the aws CLI is on PATH, looked up once.
+pub fn aws_cli_installed() -> bool {
+    *AWS_CLI_INSTALLED
+}
+
+fn on_path(program: &str, path: &OsStr) -> bool {
+    let extensions: &[&str] = if cfg!(windows) {
+        &[".exe", ".cmd"]
+    } else {
+        &[""]
+    };
+    std::env::split_paths(path).any(|dir| {
+        extensions
+            .iter()
+            .any(|ext| dir.join(format!("{}{}", program, ext)).is_file())
+    })
+}
+
+// What a command needing the aws CLI fails with where it isn't installed.
+pub fn aws_cli_not_found() -> anyhow::Error {
+    anyhow!("Not Found aws CLI: install it from https://aws.amazon.com/cli/")
+}
+
+// Readable by the user only, and removed when dropped.
+pub(crate) struct TempFile(pub PathBuf);
+
+impl TempFile {
+    pub fn write(name: &str, content: &[u8]) -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("aws-mfa-{}-{}", name, std::process::id()));
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+
+        options.open(&path)?.write_all(content)?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+// Like Command::output, but kills the process once the timeout elapses.
+pub fn output_with_timeout(command: &mut Command, timeout: Duration) -> Result<Output> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound if program == "aws" => aws_cli_not_found(),
+            _ => anyhow!("{}: {}", e, program),
+        })?;
+
+    // Drain the pipes while waiting so a chatty child can't block on a full pipe.
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let status = match wait(&mut child, timeout)? {
+        Some(status) => status,
+        None => {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(Timeout {
+                program,
+                after: timeout,
+            }
+            .into());
+        }
+    };
+
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+fn wait(child: &mut Child, timeout: Duration) -> Result<Option<std::process::ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn drain<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod on_path {
+        use super::*;
+
+        #[test]
+        fn it_finds_program_in_any_directory() {
+            let dir = std::env::temp_dir().join(format!("aws-mfa-path-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let name = if cfg!(windows) { "aws.exe" } else { "aws" };
+            std::fs::write(dir.join(name), "").unwrap();
+
+            let path = std::env::join_paths(["/nonexistent", dir.to_str().unwrap()]).unwrap();
+            assert!(on_path("aws", &path));
+            assert!(!on_path("aws", OsStr::new("/nonexistent")));
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[cfg(any(feature = "sdk", feature = "sigv4"))]
+    mod read_ca_bundle {
+        use super::*;
+
+        const CA: &str = "\
+-----BEGIN CERTIFICATE-----\n\
+MIIBjDCCATGgAwIBAgIUUhypgyTaNFTNIRjg3GISGNEsaPIwCgYIKoZIzj0EAwIw\n\
+GjEYMBYGA1UEAwwPYXdzLW1mYSB0ZXN0IENBMCAXDTI2MTAxNjE1MDIwM1oYDzIx\n\
+MjYwOTIyMTUwMjAzWjAaMRgwFgYDVQQDDA9hd3MtbWZhIHRlc3QgQ0EwWTATBgcq\n\
+hkjOPQIBBggqhkjOPQMBBwNCAAQa+GWwQcuwuwFEh+kwasFxhedax4X9WGltrpPb\n\
+c8PciPv4nAvube9Ik5uMeY6jY+yNC1Xy0aip3oOcRTAHHUf2o1MwUTAdBgNVHQ4E\n\
+FgQUhR+cf66f+nT1Cd0zBz3Cs8IKvBIwHwYDVR0jBBgwFoAUhR+cf66f+nT1Cd0z\n\
+Bz3Cs8IKvBIwDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNJADBGAiEAukvO\n\
+meaKF0hQLWqxpzZwoSBgLCWJtoz5iOPxlIg4dxUCIQCn5BMI7IRtr9ZBs24Q7R9n\n\
+1nmKVtpetoi8W1CoVdlZpg==\n\
+-----END CERTIFICATE-----\n";
+
+        #[test]
+        fn it_reads_certificates_and_rejects_others() {
+            let dir = std::env::temp_dir().join(format!("aws-mfa-ca-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let (valid, empty, broken) = (
+                dir.join("ca.pem"),
+                dir.join("empty.pem"),
+                dir.join("broken.pem"),
+            );
+            std::fs::write(&valid, CA).unwrap();
+            std::fs::write(&empty, "not a certificate\n").unwrap();
+            std::fs::write(&broken, CA.replace("MIIB", "M!IB")).unwrap();
+
+            assert_eq!(read_ca_bundle(&valid).unwrap(), CA.as_bytes());
+            assert!(read_ca_bundle(&empty)
+                .unwrap_err()
+                .to_string()
+                .starts_with("No certificates"));
+            assert!(read_ca_bundle(&broken).is_err());
+            assert!(read_ca_bundle(&dir.join("missing.pem")).is_err());
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    mod output_with_timeout {
+        use super::*;
+
+        #[test]
+        fn it_returns_output_of_finished_process() {
+            let mut command = Command::new("sh");
+            command.args(["-c", "echo out; echo err >&2"]);
+
+            let output = output_with_timeout(&mut command, Duration::from_secs(5)).unwrap();
+            assert!(output.status.success());
+            assert_eq!(output.stdout, b"out\n");
+            assert_eq!(output.stderr, b"err\n");
+        }
+
+        #[test]
+        fn it_kills_process_after_timeout() {
+            let mut command = Command::new("sleep");
+            command.arg("5");
+
+            let started = Instant::now();
+            let err = output_with_timeout(&mut command, Duration::from_millis(200)).unwrap_err();
+            assert!(err.is::<Timeout>());
+            assert!(started.elapsed() < Duration::from_secs(5));
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use crate::{tty, Result};
+
+use anyhow::anyhow;
+use std::io::{BufRead, Write};
+
+// Asks a yes/no question on the terminal. Anything but "y" or "yes" is a no.
+pub fn confirm(question: &str) -> Result<bool> {
+    tty::require_interactive(&format!("ask \"{}\"", question))?;
+
+    eprint!("{} [y/N]: ", question);
+    std::io::stderr().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    Ok(is_yes(&answer))
+}
+
+// Asks to pick one of the items by number and returns its index.
+pub fn select(question: &str, items: &[String]) -> Result<usize> {
+    tty::require_interactive(&format!("ask \"{}\"", question))?;
+
+    for (i, item) in items.iter().enumerate() {
+        eprintln!("{:>3}) {}", i + 1, item);
+    }
+    eprint!("{} [1-{}]: ", question, items.len());
+    std::io::stderr().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    parse_selection(&answer, items.len())
+        .ok_or_else(|| anyhow!("Invalid selection: {}", answer.trim()))
+}
+
+// Asks for a value that isn't echoed, like a passphrase.
+pub fn secret(question: &str) -> Result<String> {
+    tty::require_interactive(&format!("ask \"{}\"", question))?;
+    rpassword::prompt_password(format!("{}: ", question)).map_err(anyhow::Error::new)
+}
+
+fn parse_selection(answer: &str, len: usize) -> Option<usize> {
+    match answer.trim().parse::<usize>() {
+        Ok(n) if (1..=len).contains(&n) => Some(n - 1),
+        _ => None,
+    }
+}
+
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod is_yes {
+        use super::*;
+
+        #[test]
+        fn it_accepts_yes() {
+            assert!(is_yes("y\n"));
+            assert!(is_yes(" Yes "));
+        }
+
+        #[test]
+        fn it_rejects_others() {
+            assert!(!is_yes(""));
+            assert!(!is_yes("n"));
+            assert!(!is_yes("yep"));
+        }
+    }
+
+    mod parse_selection {
+        use super::*;
+
+        #[test]
+        fn it_returns_index_of_number() {
+            assert_eq!(parse_selection("1\n", 3), Some(0));
+            assert_eq!(parse_selection(" 3 ", 3), Some(2));
+        }
+
+        #[test]
+        fn it_rejects_out_of_range_or_non_numbers() {
+            assert_eq!(parse_selection("0", 3), None);
+            assert_eq!(parse_selection("4", 3), None);
+            assert_eq!(parse_selection("latest", 3), None);
+        }
+    }
+}
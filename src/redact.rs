@@ -0,0 +1,119 @@
+use crate::SessionTokens;
+
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use std::sync::{Mutex, PoisonError};
+
+pub const MASK: &str = "****";
+
+// Shorter values are too likely to appear by chance to be masked everywhere.
+const MIN_SECRET_LEN: usize = 8;
+
+lazy_static! {
+    // Values following the names of secrets, as in credentials files, STS JSON,
+    // shell exports, mfa.yml and HTTP headers.
+    static ref RE_SECRET_VALUE: Regex = Regex::new(
+        r#"(?i)((?:secret_?access_?key|session_?token|security_?token|seed)["']?(?:\s*[=:]\s*["']?|\s+["']))([^\s"',}]+)"#
+    )
+    .unwrap();
+    static ref SECRETS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+}
+
+// Remembers a secret so it is masked wherever it appears, not only after its name.
+pub fn register(secret: &str) {
+    if secret.len() < MIN_SECRET_LEN {
+        return;
+    }
+
+    let mut secrets = SECRETS.lock().unwrap_or_else(PoisonError::into_inner);
+    if !secrets.iter().any(|s| s == secret) {
+        secrets.push(secret.to_string());
+    }
+}
+
+pub fn register_tokens(tokens: &SessionTokens) {
+    let credentials = tokens.credentials();
+    register(&credentials.secret_access_key);
+    register(&credentials.session_token);
+}
+
+// Masks secret access keys, session tokens and TOTP seeds in diagnostics.
+pub fn redact(text: &str) -> String {
+    let secrets = SECRETS.lock().unwrap_or_else(PoisonError::into_inner);
+    let text = secrets
+        .iter()
+        .fold(text.to_string(), |text, secret| text.replace(secret, MASK));
+
+    RE_SECRET_VALUE
+        .replace_all(&text, |caps: &Captures| format!("{}{}", &caps[1], MASK))
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod redact {
+        use super::*;
+
+        #[test]
+        fn it_masks_credentials_file_values() {
+            let text = "aws_access_key_id=ASIAEXAMPLE\naws_secret_access_key=abc/def+ghi\naws_session_token = tokentoken";
+            assert_eq!(
+                redact(text),
+                "aws_access_key_id=ASIAEXAMPLE\naws_secret_access_key=****\naws_session_token = ****"
+            );
+        }
+
+        #[test]
+        fn it_masks_sts_json_values() {
+            let text = r#"{"AccessKeyId": "ASIAEXAMPLE", "SecretAccessKey": "abc/def", "SessionToken": "FwoG+z=="}"#;
+            assert_eq!(
+                redact(text),
+                r#"{"AccessKeyId": "ASIAEXAMPLE", "SecretAccessKey": "****", "SessionToken": "****"}"#
+            );
+        }
+
+        #[test]
+        fn it_masks_shell_exports() {
+            assert_eq!(
+                redact("export AWS_SESSION_TOKEN='token'"),
+                "export AWS_SESSION_TOKEN='****'"
+            );
+            assert_eq!(
+                redact("set -gx AWS_SECRET_ACCESS_KEY 'secret'"),
+                "set -gx AWS_SECRET_ACCESS_KEY '****'"
+            );
+        }
+
+        #[test]
+        fn it_masks_totp_seeds() {
+            assert_eq!(
+                redact("invalid type: found seed: JBSWY3DPEHPK3PXP"),
+                "invalid type: found seed: ****"
+            );
+        }
+
+        #[test]
+        fn it_masks_registered_secrets_anywhere() {
+            register("registered-secret-value");
+            assert_eq!(
+                redact("An error occurred near registered-secret-value."),
+                "An error occurred near ****."
+            );
+        }
+
+        #[test]
+        fn it_ignores_short_registered_values() {
+            register("abc");
+            assert_eq!(redact("abc"), "abc");
+        }
+
+        #[test]
+        fn it_keeps_text_without_secrets() {
+            let text =
+                "An error occurred (AccessDenied) when calling the GetSessionToken operation";
+            assert_eq!(redact(text), text);
+        }
+    }
+}
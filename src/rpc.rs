@@ -0,0 +1,233 @@
+use crate::{errors, redact};
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fmt;
+
+// JSON-RPC 2.0 of `aws-mfa rpc`, one message per line on stdin and stdout, so
+// tray apps and editor extensions don't have to read the CLI's output.
+
+pub const VERSION: &str = "2.0";
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+// A failure of aws-mfa itself, with the code and hint of --errors json as data.
+pub const SERVER_ERROR: i64 = -32000;
+
+#[derive(Debug, Deserialize)]
+pub struct Request {
+    jsonrpc: String,
+    // None for notifications, which get no response.
+    pub id: Option<Value>,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+// A failure of the protocol, answered with its own code instead of SERVER_ERROR.
+#[derive(Debug)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+impl RpcError {
+    pub fn method_not_found(method: &str) -> Self {
+        Self {
+            code: METHOD_NOT_FOUND,
+            message: format!("Method not found: {}", method),
+        }
+    }
+
+    pub fn invalid_params(message: impl fmt::Display) -> Self {
+        Self {
+            code: INVALID_PARAMS,
+            message: format!("Invalid params: {}", message),
+        }
+    }
+}
+
+// The request of a line, or the error response to write instead.
+pub fn parse(line: &str) -> std::result::Result<Request, Value> {
+    let value: Value = serde_json::from_str(line).map_err(|e| {
+        error(
+            &Value::Null,
+            PARSE_ERROR,
+            &format!("Parse error: {}", e),
+            None,
+        )
+    })?;
+    if value.is_array() {
+        return Err(error(
+            &Value::Null,
+            INVALID_REQUEST,
+            "Invalid Request: batches are not supported",
+            None,
+        ));
+    }
+
+    let id = value.get("id").cloned().unwrap_or(Value::Null);
+    match serde_json::from_value::<Request>(value) {
+        Ok(request) if request.jsonrpc == VERSION => Ok(request),
+        Ok(_) => Err(error(
+            &id,
+            INVALID_REQUEST,
+            "Invalid Request: jsonrpc must be \"2.0\"",
+            None,
+        )),
+        Err(e) => Err(error(
+            &id,
+            INVALID_REQUEST,
+            &format!("Invalid Request: {}", e),
+            None,
+        )),
+    }
+}
+
+// The params of a method, which may be left out when all of them are optional.
+pub fn params<T: DeserializeOwned + Default>(params: &Value) -> Result<T, RpcError> {
+    match params {
+        Value::Null => Ok(T::default()),
+        params => serde_json::from_value(params.clone()).map_err(RpcError::invalid_params),
+    }
+}
+
+pub fn response(id: &Value, result: Value) -> Value {
+    json!({"jsonrpc": VERSION, "id": id, "result": result})
+}
+
+pub fn error(id: &Value, code: i64, message: &str, data: Option<Value>) -> Value {
+    let mut error = json!({"code": code, "message": message});
+    if let Some(data) = data {
+        error["data"] = data;
+    }
+    json!({"jsonrpc": VERSION, "id": id, "error": error})
+}
+
+// The error response of a failed call, redacted like the CLI's errors.
+pub fn failure(id: &Value, err: &anyhow::Error) -> Value {
+    match err.downcast_ref::<RpcError>() {
+        Some(e) => error(id, e.code, &e.message, None),
+        None => {
+            let code = errors::code(err);
+            error(
+                id,
+                SERVER_ERROR,
+                &redact::redact(&err.to_string()),
+                Some(json!({"code": code, "hint": errors::hint(&code)})),
+            )
+        }
+    }
+}
+
+// Params of `refresh`, named like the options of the CLI.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RefreshParams {
+    pub profile: Option<String>,
+    pub code: Option<String>,
+    pub mfa_profile: Option<String>,
+    pub duration: Option<String>,
+    // Overwrites a session of another profile, like --force.
+    #[serde(default)]
+    pub force: bool,
+}
+
+// Params of `status`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatusParams {
+    pub mfa_profile: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    mod parse {
+        use super::*;
+
+        #[test]
+        fn it_reads_request() {
+            let request =
+                parse(r#"{"jsonrpc": "2.0", "id": 1, "method": "status", "params": {}}"#).unwrap();
+            assert_eq!(request.id, Some(json!(1)));
+            assert_eq!(request.method, "status");
+
+            let notification = parse(r#"{"jsonrpc": "2.0", "method": "devices"}"#).unwrap();
+            assert_eq!(notification.id, None);
+            assert_eq!(notification.params, Value::Null);
+        }
+
+        #[test]
+        fn it_answers_invalid_lines() {
+            let err = parse("{").unwrap_err();
+            assert_eq!(err["id"], Value::Null);
+            assert_eq!(err["error"]["code"], PARSE_ERROR);
+
+            let err = parse(r#"{"jsonrpc": "1.0", "id": "a", "method": "status"}"#).unwrap_err();
+            assert_eq!(err["id"], "a");
+            assert_eq!(err["error"]["code"], INVALID_REQUEST);
+
+            let err = parse(r#"[{"jsonrpc": "2.0", "id": 1, "method": "status"}]"#).unwrap_err();
+            assert_eq!(err["error"]["code"], INVALID_REQUEST);
+
+            let err = parse(r#"{"jsonrpc": "2.0", "id": 2}"#).unwrap_err();
+            assert_eq!(err["id"], 2);
+            assert_eq!(err["error"]["code"], INVALID_REQUEST);
+        }
+    }
+
+    mod params {
+        use super::*;
+
+        #[test]
+        fn it_reads_optional_params() {
+            let p: RefreshParams = params(&Value::Null).unwrap();
+            assert!(p.profile.is_none());
+
+            let p: RefreshParams = params(&json!({"profile": "work", "code": "123456"})).unwrap();
+            assert_eq!(p.profile.as_deref(), Some("work"));
+            assert_eq!(p.code.as_deref(), Some("123456"));
+        }
+
+        #[test]
+        fn it_rejects_unknown_params() {
+            let err = params::<StatusParams>(&json!({"profile": "work"})).unwrap_err();
+            assert_eq!(err.code, INVALID_PARAMS);
+        }
+    }
+
+    mod failure {
+        use super::*;
+
+        #[test]
+        fn it_answers_with_code_and_hint() {
+            let err = anyhow!("Not Found mfa profile in credentials: work-mfa");
+            let response = failure(&json!(3), &err);
+            assert_eq!(response["error"]["code"], SERVER_ERROR);
+            assert_eq!(response["error"]["data"]["code"], "SessionNotFound");
+            assert!(response["error"]["data"]["hint"].is_string());
+        }
+
+        #[test]
+        fn it_keeps_protocol_errors() {
+            let err = anyhow::Error::new(RpcError::method_not_found("nope"));
+            let response = failure(&json!(4), &err);
+            assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+            assert!(response["error"].get("data").is_none());
+        }
+    }
+}
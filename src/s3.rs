@@ -0,0 +1,354 @@
+use crate::config::Paths;
+use crate::process::{aws_command, output_with_timeout};
+use crate::{log, Result};
+
+use anyhow::anyhow;
+use chrono::Utc;
+use data_encoding::HEXLOWER;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::time::Duration;
+
+pub const S3_SCHEME: &str = "s3://";
+
+// Object name always holding the most recent backup
+pub const LATEST: &str = "latest";
+
+// The SHA-256 of each object uploaded from this machine, by URI
+const DIGESTS_FILE: &str = "s3-backups.json";
+
+// Copies of the credentials file in S3, encrypted with SSE-KMS and uploaded with
+// the long-term profile.
+#[derive(Debug, Clone)]
+pub struct S3Backup {
+    // Always ends with "/"
+    prefix: String,
+    profile: Option<String>,
+    timeout: Duration,
+    // Downloads are checked against these rather than the object's metadata,
+    // which anyone able to write to the bucket sets along with the content.
+    digests: PathBuf,
+}
+
+impl S3Backup {
+    pub fn new(paths: &Paths, uri: &str, profile: Option<&str>, timeout: Duration) -> Result<Self> {
+        if !uri.starts_with(S3_SCHEME) || uri.len() == S3_SCHEME.len() {
+            return Err(anyhow!("Invalid S3 backup location: {}", uri));
+        }
+
+        let prefix = match uri.ends_with('/') {
+            true => uri.to_string(),
+            false => format!("{}/", uri),
+        };
+
+        Ok(Self {
+            prefix,
+            profile: profile.map(str::to_string),
+            timeout,
+            digests: paths.data.join(DIGESTS_FILE),
+        })
+    }
+
+    // Uploads the file as a timestamped object and as "latest", returning the former.
+    pub fn upload(&self, path: &Path) -> Result<String> {
+        let digest = sha256_hex(&std::fs::read(path)?);
+        let name = format!("credentials-{}", Utc::now().format("%Y%m%dT%H%M%SZ"));
+
+        for object in [&name, LATEST] {
+            let uri = self.uri(object);
+            self.aws(
+                aws_command()
+                    .args(["s3", "cp"])
+                    .arg(path)
+                    .arg(&uri)
+                    .args(["--sse", "aws:kms"])
+                    .args(["--metadata", &format!("sha256={}", digest)]),
+            )
+            .map_err(|e| anyhow!("Error uploading credentials backup to {}: {}", uri, e))?;
+        }
+        self.record_digest(&[self.uri(&name), self.uri(LATEST)], &digest)?;
+
+        let uri = self.uri(&name);
+        log::event("s3_backup_uploaded", json!({ "uri": uri }));
+        Ok(uri)
+    }
+
+    // Splits a location into the backup prefix and the object, if it names one.
+    pub fn parse(
+        paths: &Paths,
+        uri: &str,
+        profile: Option<&str>,
+        timeout: Duration,
+    ) -> Result<(Self, Option<String>)> {
+        let bucket_only = uri[S3_SCHEME.len().min(uri.len())..].find('/').is_none();
+
+        match uri.rsplit_once('/') {
+            Some((prefix, object)) if !object.is_empty() && !bucket_only => Ok((
+                Self::new(paths, prefix, profile, timeout)?,
+                Some(object.to_string()),
+            )),
+            _ => Ok((Self::new(paths, uri, profile, timeout)?, None)),
+        }
+    }
+
+    // Names of the backups under the prefix, newest first with "latest" on top.
+    pub fn list(&self) -> Result<Vec<String>> {
+        let stdout = self
+            .aws(aws_command().args(["s3", "ls", &self.prefix]))
+            .map_err(|e| anyhow!("Error listing backups in {}: {}", self.prefix, e))?;
+
+        Ok(parse_listing(&String::from_utf8(stdout)?))
+    }
+
+    // Downloads the backup and checks it against the SHA-256 recorded when it was
+    // uploaded from this machine. One uploaded elsewhere is only taken `unchecked`.
+    pub fn download(&self, object: &str, unchecked: bool) -> Result<Vec<u8>> {
+        let uri = self.uri(object);
+        let stdout = self
+            .aws(aws_command().args(["s3", "cp", &uri, "-"]))
+            .map_err(|e| anyhow!("Error downloading {}: {}", uri, e))?;
+
+        self.check_digest(&uri, &stdout, unchecked)?;
+        Ok(stdout)
+    }
+
+    fn check_digest(&self, uri: &str, content: &[u8], unchecked: bool) -> Result<()> {
+        let actual = sha256_hex(content);
+        match read_digests(&self.digests)?.get(uri) {
+            Some(expected) if *expected != actual => Err(anyhow!(
+                "Integrity check failed for {}: expected SHA-256 {}, got {}",
+                uri,
+                expected,
+                actual
+            )),
+            Some(_) => Ok(()),
+            None if unchecked => Ok(()),
+            None => Err(anyhow!(
+                "Refusing to restore {}: it wasn't uploaded from this machine, so there is no SHA-256 to check it against: pass --force to restore it anyway",
+                uri
+            )),
+        }
+    }
+
+    fn record_digest(&self, uris: &[String], digest: &str) -> Result<()> {
+        let mut digests = read_digests(&self.digests)?;
+        for uri in uris {
+            digests.insert(uri.clone(), digest.to_string());
+        }
+
+        if let Some(dir) = self.digests.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&self.digests, serde_json::to_string_pretty(&digests)?)
+            .map_err(|e| anyhow!("Error writing to {}: {}", self.digests.display(), e))?;
+        log::file_written(&self.digests);
+        Ok(())
+    }
+
+    fn uri(&self, object: &str) -> String {
+        format!("{}{}", self.prefix, object)
+    }
+
+    fn aws(&self, command: &mut Command) -> Result<Vec<u8>> {
+        if let Some(profile) = &self.profile {
+            command.args(["--profile", profile]);
+        }
+
+        let Output {
+            status,
+            stdout,
+            stderr,
+        } = output_with_timeout(command, self.timeout)?;
+
+        if status.success() {
+            Ok(stdout)
+        } else {
+            Err(anyhow!("{}", String::from_utf8(stderr)?))
+        }
+    }
+}
+
+// Lines of `aws s3 ls` look like "2022-01-01 09:00:00        116 credentials-...".
+fn parse_listing(stdout: &str) -> Vec<String> {
+    let mut names: Vec<String> = stdout
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("PRE "))
+        .filter_map(|line| line.split_whitespace().nth(3))
+        .map(str::to_string)
+        .collect();
+
+    names.sort_by(|a, b| match (a.as_str(), b.as_str()) {
+        (LATEST, _) => std::cmp::Ordering::Less,
+        (_, LATEST) => std::cmp::Ordering::Greater,
+        _ => b.cmp(a),
+    });
+    names
+}
+
+fn read_digests(path: &Path) -> Result<BTreeMap<String, String>> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content).map_err(anyhow::Error::new),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(anyhow!("{}: {}", e, path.display())),
+    }
+}
+
+pub fn sha256_hex(content: &[u8]) -> String {
+    HEXLOWER.encode(&Sha256::digest(content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths() -> Paths {
+        Paths::under(&std::env::temp_dir().join(format!("aws-mfa-s3-{}", std::process::id())))
+    }
+
+    mod s3_backup {
+        use super::*;
+
+        #[test]
+        fn it_appends_slash_to_prefix() {
+            let backup = S3Backup::new(
+                &paths(),
+                "s3://my-bucket/aws-backups",
+                None,
+                Duration::from_secs(30),
+            )
+            .unwrap();
+            assert_eq!(backup.uri(LATEST), "s3://my-bucket/aws-backups/latest");
+
+            let backup = S3Backup::new(
+                &paths(),
+                "s3://my-bucket/aws-backups/",
+                None,
+                Duration::from_secs(30),
+            )
+            .unwrap();
+            assert_eq!(backup.uri(LATEST), "s3://my-bucket/aws-backups/latest");
+        }
+
+        #[test]
+        fn it_rejects_non_s3_location() {
+            assert!(
+                S3Backup::new(&paths(), "/tmp/backups", None, Duration::from_secs(30)).is_err()
+            );
+            assert!(S3Backup::new(&paths(), "s3://", None, Duration::from_secs(30)).is_err());
+        }
+    }
+
+    mod parse {
+        use super::*;
+
+        #[test]
+        fn it_splits_object_from_prefix() {
+            let (backup, object) = S3Backup::parse(
+                &paths(),
+                "s3://my-bucket/aws-backups/latest",
+                None,
+                Duration::from_secs(30),
+            )
+            .unwrap();
+            assert_eq!(backup.prefix, "s3://my-bucket/aws-backups/");
+            assert_eq!(object, Some(LATEST.to_string()));
+        }
+
+        #[test]
+        fn it_returns_prefix_without_object() {
+            let (backup, object) = S3Backup::parse(
+                &paths(),
+                "s3://my-bucket/aws-backups/",
+                None,
+                Duration::from_secs(30),
+            )
+            .unwrap();
+            assert_eq!(backup.prefix, "s3://my-bucket/aws-backups/");
+            assert!(object.is_none());
+
+            let (backup, object) =
+                S3Backup::parse(&paths(), "s3://my-bucket", None, Duration::from_secs(30)).unwrap();
+            assert_eq!(backup.prefix, "s3://my-bucket/");
+            assert!(object.is_none());
+        }
+    }
+
+    mod check_digest {
+        use super::*;
+
+        #[test]
+        fn it_checks_content_against_recorded_digest() {
+            let backup = S3Backup::new(
+                &paths(),
+                "s3://my-bucket/digests/",
+                None,
+                Duration::from_secs(30),
+            )
+            .unwrap();
+            let latest = backup.uri(LATEST);
+            backup
+                .record_digest(std::slice::from_ref(&latest), &sha256_hex(b"[default]\n"))
+                .unwrap();
+
+            assert!(backup.check_digest(&latest, b"[default]\n", false).is_ok());
+            let err = backup
+                .check_digest(&latest, b"[stolen]\n", true)
+                .unwrap_err();
+            assert!(err.to_string().starts_with("Integrity check failed"));
+        }
+
+        #[test]
+        fn it_refuses_object_uploaded_elsewhere_unless_unchecked() {
+            let backup = S3Backup::new(
+                &paths(),
+                "s3://my-bucket/elsewhere/",
+                None,
+                Duration::from_secs(30),
+            )
+            .unwrap();
+            let uri = backup.uri(LATEST);
+
+            let err = backup
+                .check_digest(&uri, b"[default]\n", false)
+                .unwrap_err();
+            assert!(err.to_string().starts_with("Refusing to restore"));
+            assert!(backup.check_digest(&uri, b"[default]\n", true).is_ok());
+        }
+    }
+
+    mod parse_listing {
+        use super::*;
+
+        #[test]
+        fn it_returns_names_newest_first() {
+            let stdout = "                           PRE old/
+2022-01-01 09:00:00        116 credentials-20220101T000000Z
+2022-01-02 09:00:00        116 latest
+2022-01-02 09:00:00        116 credentials-20220102T000000Z
+";
+            assert_eq!(
+                parse_listing(stdout),
+                vec![
+                    "latest",
+                    "credentials-20220102T000000Z",
+                    "credentials-20220101T000000Z",
+                ]
+            );
+        }
+    }
+
+    mod sha256_hex {
+        use super::*;
+
+        #[test]
+        fn it_returns_hex_digest() {
+            assert_eq!(
+                sha256_hex(b"abc"),
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+            );
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use crate::arn::Partition;
+use crate::keys::LongTermKeys;
+use crate::process::{self, Timeout};
+use crate::sts::RegionalEndpoints;
+use crate::{redact, Credentials, Result, SessionTokens};
+
+use anyhow::anyhow;
+use aws_config::default_provider::region::DefaultRegionChain;
+use aws_config::meta::region::RegionProviderChain;
+use aws_config::BehaviorVersion;
+use aws_sdk_sts::config::Credentials as SdkCredentials;
+use aws_sdk_sts::error::ProvideErrorMetadata;
+use aws_smithy_http_client::tls::{self, rustls_provider::CryptoMode, TlsContext, TrustStore};
+use aws_smithy_http_client::Builder as HttpClientBuilder;
+use chrono::{DateTime, Utc};
+use std::time::{Duration, SystemTime};
+
+// Without a configured region the CLI calls the global endpoint, which is in us-east-1.
+const GLOBAL_REGION: &str = "us-east-1";
+const GLOBAL_ENDPOINT: &str = "https://sts.amazonaws.com";
+
+// GetSessionToken through the AWS SDK, signed with the profile's keys, or the
+// given ones, like the aws CLI would.
+pub fn get_session_token(
+    device_arn: &str,
+    code: &str,
+    duration: u32,
+    profile: Option<&str>,
+    keys: Option<&LongTermKeys>,
+    endpoints: Option<RegionalEndpoints>,
+    timeout: Duration,
+) -> Result<SessionTokens> {
+    let partition = Partition::of_device(device_arn)?;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    // Checked here, since the SDK panics on certificates it can't read.
+    let ca_bundle = process::ca_bundle(profile)
+        .map(|path| process::read_ca_bundle(&path))
+        .transpose()?;
+
+    runtime.block_on(async {
+        // The region of the profile, not only of the default one.
+        let mut region_chain = DefaultRegionChain::builder();
+        if let Some(profile) = profile {
+            region_chain = region_chain.profile_name(profile);
+        }
+        let region = RegionProviderChain::first_try(region_chain.build())
+            .or_else(partition.default_region().unwrap_or(GLOBAL_REGION));
+        let mut loader = aws_config::defaults(BehaviorVersion::latest()).region(region);
+        if let Some(profile) = profile {
+            loader = loader.profile_name(profile);
+        }
+        if let Some(pem) = ca_bundle {
+            let context = TlsContext::builder()
+                .with_trust_store(TrustStore::empty().with_pem_certificate(pem))
+                .build()?;
+            loader = loader.http_client(
+                HttpClientBuilder::new()
+                    .tls_provider(tls::Provider::Rustls(CryptoMode::AwsLc))
+                    .tls_context(context)
+                    .build_https(),
+            );
+        }
+        if let Some(keys) = keys {
+            loader = loader.credentials_provider(SdkCredentials::new(
+                keys.access_key_id(),
+                keys.secret_access_key(),
+                None,
+                None,
+                "aws-mfa",
+            ));
+        }
+        let legacy = RegionalEndpoints::from_env().or(endpoints) == Some(RegionalEndpoints::Legacy);
+        if legacy && partition == Partition::Aws && !endpoint_set() {
+            loader = loader.endpoint_url(GLOBAL_ENDPOINT);
+        }
+        let config = loader.load().await;
+
+        let request = aws_sdk_sts::Client::new(&config)
+            .get_session_token()
+            .serial_number(device_arn)
+            .token_code(code)
+            .duration_seconds(duration as i32)
+            .send();
+        let output = tokio::time::timeout(timeout, request)
+            .await
+            .map_err(|_| Timeout {
+                program: "STS GetSessionToken".to_string(),
+                after: timeout,
+            })?
+            .map_err(|e| {
+                // Worded like the CLI, whose error codes are read elsewhere.
+                anyhow!(
+                    "An error occurred ({}) when calling the GetSessionToken operation: {}",
+                    e.code().unwrap_or("Unknown"),
+                    e.message().unwrap_or_default()
+                )
+            })?;
+
+        let credentials = output
+            .credentials
+            .ok_or_else(|| anyhow!("No credentials in GetSessionToken response"))?;
+        let tokens = SessionTokens {
+            credentials: Credentials {
+                access_key_id: credentials.access_key_id,
+                secret_access_key: credentials.secret_access_key,
+                session_token: credentials.session_token,
+                expiration: DateTime::<Utc>::from(SystemTime::try_from(credentials.expiration)?),
+            },
+        };
+        redact::register_tokens(&tokens);
+        Ok(tokens)
+    })
+}
+
+// The SDK reads AWS_ENDPOINT_URL_STS and AWS_ENDPOINT_URL itself.
+fn endpoint_set() -> bool {
+    ["AWS_ENDPOINT_URL_STS", "AWS_ENDPOINT_URL"]
+        .iter()
+        .any(|var| std::env::var(var).is_ok())
+}
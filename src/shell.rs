@@ -0,0 +1,181 @@
+use crate::Result;
+
+use anyhow::anyhow;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    // sh, bash and zsh
+    Posix,
+    Fish,
+    PowerShell,
+}
+
+impl Shell {
+    pub const VARIANTS: [&'static str; 6] = ["sh", "bash", "zsh", "fish", "powershell", "pwsh"];
+
+    // Guesses the flavor from $SHELL, falling back to posix.
+    pub fn detect() -> Self {
+        std::env::var("SHELL")
+            .ok()
+            .and_then(|path| {
+                Path::new(&path)
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .and_then(|name| name.parse().ok())
+            })
+            .unwrap_or(Self::Posix)
+    }
+
+    pub fn export(&self, key: &str, value: &str) -> String {
+        match self {
+            Self::Posix => format!("export {}={}", key, quote(value, "'\\''")),
+            Self::Fish => format!("set -gx {} {}", key, quote(value, "\\'")),
+            Self::PowerShell => format!("$Env:{} = {}", key, quote(value, "''")),
+        }
+    }
+
+    // Defines a shell function running `aws-mfa export` and evaluating its output in
+    // the current shell, which a child process can't change by itself.
+    pub fn init(&self, function: &str) -> String {
+        match self {
+            Self::Posix => format!(
+                r#"{}() {{
+    local exports
+    exports="$(command aws-mfa export --shell sh "$@")" || return
+    eval "$exports"
+}}"#,
+                function
+            ),
+            Self::Fish => format!(
+                r#"function {}
+    set -l exports (command aws-mfa export --shell fish $argv); or return
+    printf '%s\n' $exports | source
+end"#,
+                function
+            ),
+            Self::PowerShell => format!(
+                r#"function {} {{
+    $exports = & aws-mfa export --shell powershell @args
+    if ($LASTEXITCODE -ne 0) {{ return }}
+    $exports | Out-String | Invoke-Expression
+}}"#,
+                function
+            ),
+        }
+    }
+
+    pub fn unset(&self, keys: &[&str]) -> String {
+        match self {
+            Self::Posix => format!("unset {}", keys.join(" ")),
+            Self::Fish => keys
+                .iter()
+                .map(|key| format!("set -e {}", key))
+                .collect::<Vec<String>>()
+                .join("\n"),
+            Self::PowerShell => keys
+                .iter()
+                .map(|key| format!("Remove-Item Env:{} -ErrorAction SilentlyContinue", key))
+                .collect::<Vec<String>>()
+                .join("\n"),
+        }
+    }
+}
+
+impl FromStr for Shell {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sh" | "bash" | "zsh" => Ok(Self::Posix),
+            "fish" => Ok(Self::Fish),
+            "powershell" | "pwsh" => Ok(Self::PowerShell),
+            _ => Err(anyhow!("Unknown shell: {}", s)),
+        }
+    }
+}
+
+// Single-quotes the value, replacing embedded quotes with the shell's escape.
+fn quote(value: &str, escaped_quote: &str) -> String {
+    format!("'{}'", value.replace('\'', escaped_quote))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod export {
+        use super::*;
+
+        #[test]
+        fn it_returns_posix_export() {
+            assert_eq!(Shell::Posix.export("FOO", "b'ar"), "export FOO='b'\\''ar'");
+        }
+
+        #[test]
+        fn it_returns_fish_export() {
+            assert_eq!(Shell::Fish.export("FOO", "b'ar"), "set -gx FOO 'b\\'ar'");
+        }
+
+        #[test]
+        fn it_returns_powershell_export() {
+            assert_eq!(
+                Shell::PowerShell.export("FOO", "b'ar"),
+                "$Env:FOO = 'b''ar'"
+            );
+        }
+    }
+
+    mod init {
+        use super::*;
+
+        #[test]
+        fn it_defines_posix_function() {
+            let script = Shell::Posix.init("awsm");
+            assert!(script.starts_with("awsm() {"));
+            assert!(script.contains("aws-mfa export --shell sh \"$@\""));
+        }
+
+        #[test]
+        fn it_defines_fish_function() {
+            let script = Shell::Fish.init("awsm");
+            assert!(script.starts_with("function awsm\n"));
+            assert!(script.contains("aws-mfa export --shell fish $argv"));
+            assert!(script.ends_with("end"));
+        }
+
+        #[test]
+        fn it_defines_powershell_function() {
+            let script = Shell::PowerShell.init("awsm");
+            assert!(script.starts_with("function awsm {"));
+            assert!(script.contains("aws-mfa export --shell powershell @args"));
+        }
+    }
+
+    mod unset {
+        use super::*;
+
+        #[test]
+        fn it_returns_posix_unset() {
+            assert_eq!(Shell::Posix.unset(&["FOO", "BAR"]), "unset FOO BAR");
+        }
+
+        #[test]
+        fn it_returns_fish_unset() {
+            assert_eq!(Shell::Fish.unset(&["FOO", "BAR"]), "set -e FOO\nset -e BAR");
+        }
+    }
+
+    mod from_str {
+        use super::*;
+
+        #[test]
+        fn it_parses_shell_names() {
+            assert_eq!("zsh".parse::<Shell>().unwrap(), Shell::Posix);
+            assert_eq!("fish".parse::<Shell>().unwrap(), Shell::Fish);
+            assert_eq!("pwsh".parse::<Shell>().unwrap(), Shell::PowerShell);
+            assert!("tcsh".parse::<Shell>().is_err());
+        }
+    }
+}
@@ -0,0 +1,461 @@
+use crate::arn::Partition;
+use crate::config::credentials::{aws_cli_profile, aws_config_value, ConfigFile};
+use crate::config::Paths;
+use crate::keys::LongTermKeys;
+use crate::process::{self, output_with_timeout, Timeout};
+use crate::sts::{RegionalEndpoints, Unsigned};
+use crate::{redact, Credentials, Result, SessionTokens};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::CertificateDer;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+// GetSessionToken as one signed HTTPS request, without the AWS SDK or the aws
+// CLI. Keys are read from the environment, the credentials file or the
+// credential_process of the profile; others, like SSO, are left to the CLI.
+
+const API_VERSION: &str = "2011-06-15";
+// The global endpoint, which signs in us-east-1.
+const GLOBAL_REGION: &str = "us-east-1";
+
+// Signing keys, with the token of temporary ones.
+#[derive(Debug)]
+struct SigningKeys {
+    access_key_id: String,
+    secret_access_key: Zeroizing<String>,
+    session_token: Option<Zeroizing<String>>,
+}
+
+pub fn get_session_token(
+    device_arn: &str,
+    code: &str,
+    duration: u32,
+    profile: Option<&str>,
+    keys: Option<&LongTermKeys>,
+    endpoints: Option<RegionalEndpoints>,
+    timeout: Duration,
+) -> Result<SessionTokens> {
+    let partition = Partition::of_device(device_arn)?;
+    let keys = match keys {
+        Some(keys) => SigningKeys {
+            access_key_id: keys.access_key_id().to_string(),
+            secret_access_key: Zeroizing::new(keys.secret_access_key().to_string()),
+            session_token: None,
+        },
+        None => signing_keys(profile, timeout)?,
+    };
+
+    let configured_region = ["AWS_REGION", "AWS_DEFAULT_REGION"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok().filter(|v| !v.is_empty()))
+        .or_else(|| aws_config_value(&Paths::aws_cli(), &aws_cli_profile(profile), "region"));
+    let legacy = RegionalEndpoints::from_env().or(endpoints) == Some(RegionalEndpoints::Legacy);
+    let region = match configured_region {
+        Some(region) if !(legacy && partition == Partition::Aws) => region,
+        _ => partition
+            .default_region()
+            .unwrap_or(GLOBAL_REGION)
+            .to_string(),
+    };
+
+    let endpoint = ["AWS_ENDPOINT_URL_STS", "AWS_ENDPOINT_URL"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok().filter(|v| !v.is_empty()))
+        .unwrap_or_else(|| match partition {
+            Partition::Aws if legacy || region == GLOBAL_REGION => {
+                format!("https://sts.{}", partition.dns_suffix())
+            }
+            _ => format!("https://sts.{}.{}", region, partition.dns_suffix()),
+        });
+    let host = endpoint
+        .split_once("://")
+        .map_or(endpoint.as_str(), |(_, rest)| rest)
+        .trim_end_matches('/')
+        .to_string();
+
+    let body = [
+        ("Action", "GetSessionToken"),
+        ("Version", API_VERSION),
+        ("SerialNumber", device_arn),
+        ("TokenCode", code),
+        ("DurationSeconds", &duration.to_string()),
+    ]
+    .iter()
+    .map(|(key, value)| format!("{}={}", key, encode(value)))
+    .collect::<Vec<_>>()
+    .join("&");
+
+    let now = Utc::now();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let mut headers = vec![
+        (
+            "content-type",
+            "application/x-www-form-urlencoded; charset=utf-8".to_string(),
+        ),
+        ("host", host),
+        ("x-amz-date", amz_date),
+    ];
+    if let Some(token) = &keys.session_token {
+        headers.push(("x-amz-security-token", token.to_string()));
+    }
+    let authorization = authorization("POST", &headers, &body, &region, "sts", &keys, now);
+
+    let mut request = agent(profile)?.post(&endpoint).timeout(timeout);
+    for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+        request = request.set(name, value);
+    }
+    let response = request
+        .set("Authorization", &authorization)
+        .send_string(&body);
+
+    let xml = match response {
+        Ok(response) => response.into_string()?,
+        Err(ureq::Error::Status(_, response)) => {
+            let xml = response.into_string()?;
+            // Worded like the CLI, whose error codes are read elsewhere.
+            return Err(anyhow!(
+                "An error occurred ({}) when calling the GetSessionToken operation: {}",
+                tag(&xml, "Code").unwrap_or("Unknown"),
+                tag(&xml, "Message").unwrap_or_default()
+            ));
+        }
+        Err(ureq::Error::Transport(e))
+            if e.kind() == ureq::ErrorKind::Io && e.to_string().contains("timed out") =>
+        {
+            return Err(Timeout {
+                program: "STS GetSessionToken".to_string(),
+                after: timeout,
+            }
+            .into());
+        }
+        Err(e) => return Err(anyhow!("{}", e)),
+    };
+    let tokens = parse_tokens(&xml)?;
+    redact::register_tokens(&tokens);
+    Ok(tokens)
+}
+
+// Verifies TLS with the CA bundle the aws CLI would, if there is one, instead of
+// the roots built in.
+fn agent(profile: Option<&str>) -> Result<ureq::Agent> {
+    let Some(path) = process::ca_bundle(profile) else {
+        return Ok(ureq::Agent::new());
+    };
+    let pem = process::read_ca_bundle(&path)?;
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in CertificateDer::pem_slice_iter(&pem) {
+        let cert = cert.map_err(|e| anyhow!("Invalid CA bundle {}: {:?}", path.display(), e))?;
+        roots.add(cert)?;
+    }
+
+    let config = rustls::ClientConfig::builder_with_provider(
+        rustls::crypto::ring::default_provider().into(),
+    )
+    .with_safe_default_protocol_versions()?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    Ok(ureq::AgentBuilder::new()
+        .tls_config(Arc::new(config))
+        .build())
+}
+
+// From the environment without a profile, like the CLI, else the profile's
+// section of the credentials file, or its credential_process. Without a profile
+// that is AWS_PROFILE's, or default.
+fn signing_keys(profile: Option<&str>, timeout: Duration) -> Result<SigningKeys> {
+    let env = |var: &str| std::env::var(var).ok().filter(|v| !v.is_empty());
+    if let (None, Some(access_key_id), Some(secret_access_key)) = (
+        profile,
+        env("AWS_ACCESS_KEY_ID"),
+        env("AWS_SECRET_ACCESS_KEY"),
+    ) {
+        return Ok(SigningKeys {
+            access_key_id,
+            secret_access_key: Zeroizing::new(secret_access_key),
+            session_token: env("AWS_SESSION_TOKEN").map(Zeroizing::new),
+        });
+    }
+
+    let profile = &aws_cli_profile(profile);
+    let paths = Paths::aws_cli();
+    let creds = if paths.credentials.exists() {
+        Some(ConfigFile::from_path(&paths.credentials)?)
+    } else {
+        None
+    };
+    let cred = creds.as_ref().and_then(|creds| creds.credential(profile));
+    let value = |key: &str| cred.and_then(|c| c.get(key)).map(str::to_string);
+    if let (Some(access_key_id), Some(secret_access_key)) =
+        (value("aws_access_key_id"), value("aws_secret_access_key"))
+    {
+        return Ok(SigningKeys {
+            access_key_id,
+            secret_access_key: Zeroizing::new(secret_access_key),
+            session_token: value("aws_session_token").map(Zeroizing::new),
+        });
+    }
+
+    match value("credential_process")
+        .or_else(|| aws_config_value(&paths, profile, "credential_process"))
+    {
+        Some(command) => credential_process(&command, timeout),
+        None => Err(Unsigned {
+            profile: profile.to_string(),
+        }
+        .into()),
+    }
+}
+
+// What a credential_process prints, version 1 of the format.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ProcessCredentials {
+    version: u32,
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+// Runs the command through the shell, as the CLI does on its own with shlex.
+fn credential_process(command: &str, timeout: Duration) -> Result<SigningKeys> {
+    let output = output_with_timeout(Command::new("sh").args(["-c", command]), timeout)?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "credential_process failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    parse_process_credentials(&output.stdout)
+}
+
+fn parse_process_credentials(stdout: &[u8]) -> Result<SigningKeys> {
+    let creds: ProcessCredentials = serde_json::from_slice(stdout)
+        .map_err(|e| anyhow!("Invalid output of credential_process: {}", e))?;
+    if creds.version != 1 {
+        return Err(anyhow!(
+            "Unsupported Version {} of credential_process output",
+            creds.version
+        ));
+    }
+
+    redact::register(&creds.secret_access_key);
+    if let Some(token) = &creds.session_token {
+        redact::register(token);
+    }
+    Ok(SigningKeys {
+        access_key_id: creds.access_key_id,
+        secret_access_key: Zeroizing::new(creds.secret_access_key),
+        session_token: creds.session_token.map(Zeroizing::new),
+    })
+}
+
+// The Authorization header of AWS Signature Version 4, for headers given in
+// lower case and sorted, host among them.
+fn authorization(
+    method: &str,
+    headers: &[(&str, String)],
+    body: &str,
+    region: &str,
+    service: &str,
+    keys: &SigningKeys,
+    time: DateTime<Utc>,
+) -> String {
+    let date = time.format("%Y%m%d").to_string();
+    let amz_date = time.format("%Y%m%dT%H%M%SZ").to_string();
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n/\n\n{}\n{}\n{}",
+        method,
+        canonical_headers,
+        signed_headers,
+        hex(&Sha256::digest(body.as_bytes()))
+    );
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let secret = Zeroizing::new(format!("AWS4{}", *keys.secret_access_key));
+    let key = [date.as_str(), region, service, "aws4_request"]
+        .iter()
+        .fold(Zeroizing::new(secret.as_bytes().to_vec()), |key, part| {
+            Zeroizing::new(hmac(&key, part.as_bytes()))
+        });
+    let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        keys.access_key_id, scope, signed_headers, signature
+    )
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    // HMAC takes keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Percent-encoding of a form value, as SigV4 wants it.
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// The text of the first element of the name. The responses are flat enough not
+// to need an XML parser.
+fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(xml[start..end].trim())
+}
+
+fn parse_tokens(xml: &str) -> Result<SessionTokens> {
+    let value = |name: &str| {
+        tag(xml, name)
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("No {} in GetSessionToken response", name))
+    };
+
+    Ok(SessionTokens {
+        credentials: Credentials {
+            access_key_id: value("AccessKeyId")?,
+            secret_access_key: value("SecretAccessKey")?,
+            session_token: value("SessionToken")?,
+            expiration: value("Expiration")?.parse()?,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod authorization {
+        use super::*;
+
+        // get-vanilla of the AWS Signature Version 4 test suite
+        #[test]
+        fn it_signs_like_the_test_suite() {
+            let keys = SigningKeys {
+                access_key_id: "AKIDEXAMPLE".to_owned(),
+                secret_access_key: Zeroizing::new(
+                    "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_owned(),
+                ),
+                session_token: None,
+            };
+            let headers = [
+                ("host", "example.amazonaws.com".to_owned()),
+                ("x-amz-date", "20150830T123600Z".to_owned()),
+            ];
+            let time = "2015-08-30T12:36:00Z".parse().unwrap();
+
+            let authorization =
+                authorization("GET", &headers, "", "us-east-1", "service", &keys, time);
+            assert_eq!(
+                authorization,
+                "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+                 SignedHeaders=host;x-amz-date, \
+                 Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+            );
+        }
+    }
+
+    mod parse_tokens {
+        use super::*;
+
+        #[test]
+        fn it_reads_credentials_of_response() {
+            let xml = "<GetSessionTokenResponse><GetSessionTokenResult><Credentials>\
+                <AccessKeyId>ASIAEXAMPLE</AccessKeyId><SecretAccessKey>secret</SecretAccessKey>\
+                <SessionToken>token</SessionToken><Expiration>2030-01-01T00:00:00Z</Expiration>\
+                </Credentials></GetSessionTokenResult></GetSessionTokenResponse>";
+            let tokens = parse_tokens(xml).unwrap();
+            assert_eq!(tokens.credentials.access_key_id, "ASIAEXAMPLE");
+            assert_eq!(
+                tokens.expiration().to_rfc3339(),
+                "2030-01-01T00:00:00+00:00"
+            );
+        }
+
+        #[test]
+        fn it_fails_without_credentials() {
+            assert!(parse_tokens(
+                "<ErrorResponse><Error><Code>AccessDenied</Code></Error></ErrorResponse>"
+            )
+            .is_err());
+        }
+    }
+
+    mod parse_process_credentials {
+        use super::*;
+
+        #[test]
+        fn it_reads_keys_of_version_1() {
+            let keys = parse_process_credentials(
+                br#"{"Version": 1, "AccessKeyId": "AKIAEXAMPLE", "SecretAccessKey": "secret",
+                    "SessionToken": "token", "Expiration": "2030-01-01T00:00:00Z"}"#,
+            )
+            .unwrap();
+            assert_eq!(keys.access_key_id, "AKIAEXAMPLE");
+            assert_eq!(*keys.secret_access_key, "secret");
+            assert_eq!(
+                keys.session_token.as_deref().map(String::as_str),
+                Some("token")
+            );
+        }
+
+        #[test]
+        fn it_fails_with_other_versions() {
+            assert!(parse_process_credentials(
+                br#"{"Version": 2, "AccessKeyId": "AKIAEXAMPLE", "SecretAccessKey": "secret"}"#
+            )
+            .is_err());
+            assert!(parse_process_credentials(b"not json").is_err());
+        }
+    }
+
+    mod encode {
+        use super::*;
+
+        #[test]
+        fn it_encodes_arns() {
+            assert_eq!(
+                encode("arn:aws:iam::012345678901:mfa/tanaka"),
+                "arn%3Aaws%3Aiam%3A%3A012345678901%3Amfa%2Ftanaka"
+            );
+        }
+    }
+}
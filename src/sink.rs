@@ -0,0 +1,710 @@
+use crate::arn::Partition;
+use crate::config::credentials::{ConfigFile as CredFile, Provenance, Transaction};
+use crate::config::lock::LockStrategy;
+use crate::interrupt;
+use crate::keychain;
+use crate::log;
+use crate::s3::S3Backup;
+use crate::shell::Shell;
+use crate::sts;
+use crate::{credential_env_vars, Result, SessionTokens};
+
+use anyhow::anyhow;
+use chrono::SecondsFormat;
+use serde_json::json;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+pub const KEYCHAIN_SERVICE: &str = "aws-mfa";
+pub const DOTENV_FILE: &str = ".env";
+
+// How long a client of the http output has to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// A destination for the session credentials.
+pub trait CredentialSink {
+    fn write(&self, profile: &str, tokens: &SessionTokens) -> Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Output {
+    CredentialsFile,
+    Env,
+    Dotenv,
+    Keychain,
+    // Keeps serving after the command is done, see serve_until_interrupted.
+    Http,
+}
+
+impl Output {
+    pub const VARIANTS: [&'static str; 5] =
+        ["credentials-file", "env", "dotenv", "keychain", "http"];
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::CredentialsFile => "credentials-file",
+            Self::Env => "env",
+            Self::Dotenv => "dotenv",
+            Self::Keychain => "keychain",
+            Self::Http => "http",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Output {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "credentials-file" => Ok(Self::CredentialsFile),
+            "env" => Ok(Self::Env),
+            "dotenv" => Ok(Self::Dotenv),
+            "keychain" => Ok(Self::Keychain),
+            "http" => Ok(Self::Http),
+            _ => Err(anyhow!("Unknown output: {}", s)),
+        }
+    }
+}
+
+// Another tool `export --to` sets the variables of, so that what reads them,
+// like shell prompts, takes the session for one of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportTarget {
+    AwsVault,
+    Granted,
+}
+
+impl ExportTarget {
+    pub const VARIANTS: [&'static str; 2] = ["aws-vault", "granted"];
+
+    // The variables of `aws-vault exec`, or of granted's `assume`, for the profile.
+    pub fn env_vars(&self, profile: &str, tokens: &SessionTokens) -> Vec<(&'static str, String)> {
+        let mut vars = tokens.env_vars();
+        let expiration = tokens
+            .expiration()
+            .to_rfc3339_opts(SecondsFormat::Secs, true);
+
+        match self {
+            Self::AwsVault => {
+                let session_token = vars
+                    .iter()
+                    .find(|(var, _)| *var == "AWS_SESSION_TOKEN")
+                    .map(|(_, token)| token.clone())
+                    .unwrap_or_default();
+                vars.insert(0, ("AWS_VAULT", profile.to_string()));
+                vars.push(("AWS_SECURITY_TOKEN", session_token));
+                vars.push(("AWS_CREDENTIAL_EXPIRATION", expiration));
+            }
+            Self::Granted => {
+                vars.push(("AWS_PROFILE", profile.to_string()));
+                vars.push(("AWS_SESSION_EXPIRATION", expiration));
+            }
+        }
+        vars
+    }
+}
+
+impl FromStr for ExportTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "aws-vault" => Ok(Self::AwsVault),
+            "granted" => Ok(Self::Granted),
+            _ => Err(anyhow!("Unknown export target: {}", s)),
+        }
+    }
+}
+
+pub struct SinkOptions {
+    pub credentials: PathBuf,
+    pub backup: PathBuf,
+    pub lock: LockStrategy,
+    pub shell: Shell,
+    pub provenance: Option<Provenance>,
+    // Check the written profile against STS before keeping it
+    pub verify: bool,
+    // Write a .env others can read, which is refused otherwise
+    pub force: bool,
+    pub timeout: Duration,
+    pub s3_backup: Option<S3Backup>,
+}
+
+pub fn sink(output: Output, options: &SinkOptions) -> Box<dyn CredentialSink> {
+    let shell = options.shell;
+
+    match output {
+        Output::CredentialsFile => Box::new(credentials_file(options)),
+        Output::Env => Box::new(Env { shell }),
+        Output::Dotenv => Box::new(Dotenv {
+            path: PathBuf::from(DOTENV_FILE),
+            force: options.force,
+        }),
+        Output::Keychain => Box::new(Keychain),
+        Output::Http => Box::new(Http { shell }),
+    }
+}
+
+pub fn credentials_file(options: &SinkOptions) -> CredentialsFile {
+    CredentialsFile {
+        credentials: options.credentials.clone(),
+        backup: options.backup.clone(),
+        lock: options.lock,
+        provenance: options.provenance.clone(),
+        verify: options.verify.then_some(options.timeout),
+        s3_backup: options.s3_backup.clone(),
+    }
+}
+
+// Parses a comma separated list like "credentials-file,dotenv" in execution order.
+pub fn parse_outputs(value: &str) -> Result<Vec<Output>> {
+    let mut outputs = value
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(Output::from_str)
+        .collect::<Result<Vec<Output>>>()?;
+    outputs.sort();
+    outputs.dedup();
+    Ok(outputs)
+}
+
+pub struct CredentialsFile {
+    credentials: PathBuf,
+    backup: PathBuf,
+    lock: LockStrategy,
+    provenance: Option<Provenance>,
+    // Timeout for get-caller-identity when verifying
+    verify: Option<Duration>,
+    s3_backup: Option<S3Backup>,
+}
+
+impl CredentialSink for CredentialsFile {
+    fn write(&self, profile: &str, tokens: &SessionTokens) -> Result<()> {
+        self.write_profiles(&[(profile, tokens)])
+    }
+}
+
+impl CredentialsFile {
+    // Writes the profiles under one backup, so either all of them land or none.
+    pub fn write_profiles(&self, profiles: &[(&str, &SessionTokens)]) -> Result<()> {
+        let tx =
+            Transaction::new(self.credentials.clone(), self.backup.clone()).lock_with(self.lock);
+
+        // Put the backup back if Ctrl-C lands after it was taken.
+        interrupt::guarded(
+            || tx.rollback(),
+            || {
+                tx.run(|path| {
+                    // Under the lock, so the upload is the file about to be patched. One
+                    // created empty by the transaction has nothing to back up.
+                    if let Some(s3_backup) = &self.s3_backup {
+                        if std::fs::metadata(path)?.len() > 0 {
+                            let uri = s3_backup.upload(path)?;
+                            eprintln!("Backed up credentials to {}", uri);
+                        }
+                    }
+
+                    let creds = profiles
+                        .iter()
+                        .map(|(profile, tokens)| {
+                            let cred = tokens.to_aws_credential(profile);
+                            match &self.provenance {
+                                Some(provenance) => cred.with_provenance(&Provenance {
+                                    expiration: tokens.expiration(),
+                                    ..provenance.clone()
+                                }),
+                                None => cred,
+                            }
+                        })
+                        .collect();
+                    CredFile::patch(path, creds)?;
+
+                    for (profile, tokens) in profiles {
+                        verify_written(path, profile, tokens)?;
+
+                        if let Some(timeout) = self.verify {
+                            self.verify_identity(profile, timeout)?;
+                        }
+                    }
+                    Ok(())
+                })
+            },
+        )
+    }
+
+    fn verify_identity(&self, profile: &str, timeout: Duration) -> Result<()> {
+        let partition = match &self.provenance {
+            Some(p) => Partition::of_device(&p.device)?,
+            None => Partition::Aws,
+        };
+
+        let arn = sts::get_caller_identity(Some(profile), partition, timeout).map_err(|e| {
+            anyhow!(
+                "Error verifying credentials: the {} profile was rejected by STS\n{}",
+                profile,
+                e
+            )
+        })?;
+        eprintln!("Verified {} profile as {}", profile, arn);
+        Ok(())
+    }
+}
+
+// Reads the file back to catch a write that didn't land as expected.
+fn verify_written(path: &Path, profile: &str, tokens: &SessionTokens) -> Result<()> {
+    let written = CredFile::from_path(path)?
+        .credential(profile)
+        .map(credential_env_vars)
+        .unwrap_or_default();
+
+    if written != tokens.env_vars() {
+        return Err(anyhow!(
+            "Error verifying credentials: the {} profile was not written correctly",
+            profile
+        ));
+    }
+    Ok(())
+}
+
+pub struct Env {
+    pub shell: Shell,
+}
+
+impl CredentialSink for Env {
+    fn write(&self, _profile: &str, tokens: &SessionTokens) -> Result<()> {
+        print_exports(self.shell, &tokens.env_vars());
+        Ok(())
+    }
+}
+
+pub fn print_exports(shell: Shell, vars: &[(&str, String)]) {
+    print!("{}", export_lines(shell, vars).as_str());
+}
+
+pub fn export_lines(shell: Shell, vars: &[(&str, String)]) -> Zeroizing<String> {
+    Zeroizing::new(
+        vars.iter()
+            .map(|(key, value)| format!("{}\n", shell.export(key, value)))
+            .collect(),
+    )
+}
+
+// Writes printed credentials to a file only the user can read. An existing file
+// others can read or write is refused unless forced, since it already leaks
+// them, and is made the user's only before it is written either way.
+pub fn write_private(path: &Path, content: &str, append: bool, force: bool) -> Result<()> {
+    #[cfg(unix)]
+    if let Ok(metadata) = std::fs::metadata(path) {
+        if metadata.is_file() && metadata.permissions().mode() & 0o077 != 0 && !force {
+            return Err(anyhow!(
+                "Refusing to write credentials to {}, which others can access: chmod 600 it or pass --force",
+                path.display()
+            ));
+        }
+    }
+
+    let mut options = OpenOptions::new();
+    options.create(true);
+    if append {
+        options.append(true);
+    } else {
+        options.write(true).truncate(true);
+    }
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    options
+        .open(path)
+        .and_then(|mut file| {
+            // Not a device like /dev/stdout
+            #[cfg(unix)]
+            if file.metadata()?.is_file() {
+                file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+            }
+            file.write_all(content.as_bytes())
+        })
+        .map_err(|e| anyhow!("Error writing to {}: {}", path.display(), e))?;
+    log::file_written(path);
+    Ok(())
+}
+
+pub struct Dotenv {
+    path: PathBuf,
+    force: bool,
+}
+
+impl CredentialSink for Dotenv {
+    fn write(&self, _profile: &str, tokens: &SessionTokens) -> Result<()> {
+        let current = if self.path.exists() {
+            std::fs::read_to_string(&self.path)?
+        } else {
+            String::new()
+        };
+
+        // A new .env is created readable by the user only, and one everyone
+        // can read is refused unless forced.
+        let content = Zeroizing::new(dotenv(&current, &tokens.env_vars()));
+        write_private(&self.path, &content, false, self.force)
+    }
+}
+
+// Replaces the credential variables in a dotenv file and keeps the other lines.
+fn dotenv(current: &str, vars: &[(&str, String)]) -> String {
+    let mut lines: Vec<String> = current
+        .lines()
+        .filter(|line| {
+            !vars
+                .iter()
+                .any(|(key, _)| line.trim_start().starts_with(&format!("{}=", key)))
+        })
+        .map(str::to_string)
+        .collect();
+
+    lines.extend(vars.iter().map(|(key, value)| format!("{}={}", key, value)));
+    lines.join("\n") + "\n"
+}
+
+// Stores the session as JSON in the OS keychain
+// (macOS `security` or the freedesktop Secret Service via `secret-tool`).
+pub struct Keychain;
+
+impl CredentialSink for Keychain {
+    fn write(&self, profile: &str, tokens: &SessionTokens) -> Result<()> {
+        let secret = Zeroizing::new(serde_json::to_string(tokens)?);
+        keychain::store(KEYCHAIN_SERVICE, profile, &secret)
+    }
+}
+
+// Serves the session in the container credentials format on localhost,
+// which the AWS CLI and SDKs read through AWS_CONTAINER_CREDENTIALS_FULL_URI.
+pub struct Http {
+    shell: Shell,
+}
+
+impl CredentialSink for Http {
+    fn write(&self, _profile: &str, tokens: &SessionTokens) -> Result<()> {
+        // The exports name one endpoint, so there is one session to serve.
+        if SERVING.swap(true, Ordering::SeqCst) {
+            return Err(anyhow!("Output http serves a single session"));
+        }
+
+        let server = CredentialServer::start(tokens)?;
+        print_exports(self.shell, &server.env_vars());
+        std::io::stdout().flush().map_err(anyhow::Error::new)
+    }
+}
+
+// Set once the http output serves a session from its background thread.
+static SERVING: AtomicBool = AtomicBool::new(false);
+
+// Keeps the process alive for the http output, if any, once the command is
+// done with everything else it writes.
+pub fn serve_until_interrupted() {
+    if SERVING.load(Ordering::SeqCst) {
+        eprintln!("Serving the session until interrupted");
+        loop {
+            std::thread::park();
+        }
+    }
+}
+
+// A container credentials endpoint served from a background thread, whose
+// session can be replaced while clients keep polling it.
+pub struct CredentialServer {
+    uri: String,
+    token: String,
+    body: Arc<Mutex<String>>,
+}
+
+impl CredentialServer {
+    pub fn start(tokens: &SessionTokens) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let uri = format!("http://{}/", listener.local_addr()?);
+        let token = authorization_token()?;
+        let body = Arc::new(Mutex::new(container_credentials(tokens)));
+
+        let (served_token, served_body) = (token.clone(), Arc::clone(&body));
+        // A connection of its own thread each, so an idle one can't hold up the rest.
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let body = served_body.lock().unwrap().clone();
+                let token = served_token.clone();
+                std::thread::spawn(move || {
+                    if let Err(err) = respond(stream, &token, &body) {
+                        eprintln!("{}", err);
+                    }
+                });
+            }
+        });
+
+        Ok(Self { uri, token, body })
+    }
+
+    pub fn update(&self, tokens: &SessionTokens) {
+        *self.body.lock().unwrap() = container_credentials(tokens);
+    }
+
+    pub fn env_vars(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("AWS_CONTAINER_CREDENTIALS_FULL_URI", self.uri.clone()),
+            ("AWS_CONTAINER_AUTHORIZATION_TOKEN", self.token.clone()),
+        ]
+    }
+}
+
+fn container_credentials(tokens: &SessionTokens) -> String {
+    let creds = tokens.credentials();
+    json!({
+        "AccessKeyId": creds.access_key_id,
+        "SecretAccessKey": creds.secret_access_key,
+        "Token": creds.session_token,
+        "Expiration": creds.expiration,
+    })
+    .to_string()
+}
+
+fn respond(mut stream: TcpStream, token: &str, body: &str) -> Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut authorized = false;
+
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("authorization") && value.trim() == token {
+                authorized = true;
+            }
+        }
+    }
+
+    let response = if authorized {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+
+    stream.write_all(response.as_bytes())?;
+    stream.flush().map_err(anyhow::Error::new)
+}
+
+fn authorization_token() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).map_err(|e| anyhow!("{}", e))?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod parse_outputs {
+        use super::*;
+
+        #[test]
+        fn it_parses_comma_separated_outputs() {
+            let outputs = parse_outputs("http,credentials-file, dotenv").unwrap();
+            assert_eq!(
+                outputs,
+                vec![Output::CredentialsFile, Output::Dotenv, Output::Http]
+            );
+        }
+
+        #[test]
+        fn it_fails_with_unknown_output() {
+            assert!(parse_outputs("credentials-file,foo").is_err());
+        }
+
+        #[test]
+        fn it_prints_outputs_as_parsed() {
+            for name in Output::VARIANTS {
+                assert_eq!(name.parse::<Output>().unwrap().to_string(), name);
+            }
+        }
+    }
+
+    mod export_target {
+        use super::*;
+
+        fn tokens() -> SessionTokens {
+            serde_json::from_str(
+                r#"{"Credentials": {"AccessKeyId": "ASIAEXAMPLE", "SecretAccessKey": "secret",
+                    "SessionToken": "token", "Expiration": "2030-01-01T00:00:00Z"}}"#,
+            )
+            .unwrap()
+        }
+
+        #[test]
+        fn it_sets_variables_of_aws_vault_exec() {
+            let vars = ExportTarget::AwsVault.env_vars("mfa", &tokens());
+            assert_eq!(vars[0], ("AWS_VAULT", "mfa".to_owned()));
+            assert!(vars.contains(&("AWS_SECURITY_TOKEN", "token".to_owned())));
+            assert!(vars.contains(&(
+                "AWS_CREDENTIAL_EXPIRATION",
+                "2030-01-01T00:00:00Z".to_owned()
+            )));
+        }
+
+        #[test]
+        fn it_sets_variables_of_granted_assume() {
+            let vars = ExportTarget::Granted.env_vars("mfa", &tokens());
+            assert!(vars.contains(&("AWS_PROFILE", "mfa".to_owned())));
+            assert!(vars.contains(&("AWS_SESSION_TOKEN", "token".to_owned())));
+            assert!(vars.contains(&("AWS_SESSION_EXPIRATION", "2030-01-01T00:00:00Z".to_owned())));
+        }
+    }
+
+    mod write_private {
+        use super::*;
+
+        fn path(name: &str) -> PathBuf {
+            let dir = std::env::temp_dir().join(format!("aws-mfa-out-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            dir.join(name)
+        }
+
+        #[test]
+        fn it_creates_file_readable_by_user_only() {
+            let path = path("new.env");
+            write_private(&path, "A=1\n", false, false).unwrap();
+            write_private(&path, "B=2\n", true, false).unwrap();
+
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), "A=1\nB=2\n");
+            #[cfg(unix)]
+            assert_eq!(
+                std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+                0o600
+            );
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn it_refuses_file_others_can_access_unless_forced() {
+            for mode in [0o644, 0o640, 0o620] {
+                let path = path(&format!("shared-{:o}.env", mode));
+                std::fs::write(&path, "").unwrap();
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+
+                assert!(write_private(&path, "A=1\n", false, false).is_err());
+                write_private(&path, "A=1\n", false, true).unwrap();
+                assert_eq!(std::fs::read_to_string(&path).unwrap(), "A=1\n");
+                assert_eq!(
+                    std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+                    0o600
+                );
+            }
+        }
+    }
+
+    mod dotenv {
+        use super::*;
+
+        #[test]
+        fn it_replaces_credential_variables() {
+            let current = "FOO=bar\nAWS_ACCESS_KEY_ID=old\nAWS_SESSION_TOKEN=old\n";
+            let vars = vec![
+                ("AWS_ACCESS_KEY_ID", "new".to_owned()),
+                ("AWS_SESSION_TOKEN", "token".to_owned()),
+            ];
+            assert_eq!(
+                dotenv(current, &vars),
+                "FOO=bar\nAWS_ACCESS_KEY_ID=new\nAWS_SESSION_TOKEN=token\n"
+            );
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn it_refuses_world_readable_dotenv_unless_forced() {
+            let dir = std::env::temp_dir().join(format!("aws-mfa-dotenv-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join(".env");
+            std::fs::write(&path, "FOO=bar\n").unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+            let tokens: SessionTokens = serde_json::from_str(
+                r#"{"Credentials": {"AccessKeyId": "ASIAEXAMPLE", "SecretAccessKey": "secret",
+                    "SessionToken": "token", "Expiration": "2030-01-01T00:00:00Z"}}"#,
+            )
+            .unwrap();
+
+            let sink = Dotenv {
+                path: path.clone(),
+                force: false,
+            };
+            assert!(sink.write("mfa", &tokens).is_err());
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), "FOO=bar\n");
+
+            let sink = Dotenv {
+                path: path.clone(),
+                force: true,
+            };
+            sink.write("mfa", &tokens).unwrap();
+            assert!(std::fs::read_to_string(&path)
+                .unwrap()
+                .contains("AWS_SESSION_TOKEN=token"));
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn it_creates_new_content() {
+            let vars = vec![("AWS_ACCESS_KEY_ID", "new".to_owned())];
+            assert_eq!(dotenv("", &vars), "AWS_ACCESS_KEY_ID=new\n");
+        }
+    }
+
+    mod credential_server {
+        use super::*;
+        use std::io::Read;
+
+        #[test]
+        fn it_answers_while_another_connection_is_idle() {
+            let tokens: SessionTokens = serde_json::from_str(
+                r#"{"Credentials": {"AccessKeyId": "ASIAEXAMPLE", "SecretAccessKey": "secret",
+                    "SessionToken": "token", "Expiration": "2030-01-01T00:00:00Z"}}"#,
+            )
+            .unwrap();
+            let server = CredentialServer::start(&tokens).unwrap();
+            let addr = server
+                .uri
+                .trim_start_matches("http://")
+                .trim_end_matches('/');
+
+            let _idle = TcpStream::connect(addr).unwrap();
+
+            let mut client = TcpStream::connect(addr).unwrap();
+            client
+                .set_read_timeout(Some(Duration::from_secs(2)))
+                .unwrap();
+            write!(
+                client,
+                "GET / HTTP/1.1\r\nAuthorization: {}\r\n\r\n",
+                server.token
+            )
+            .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+            assert!(response.contains("ASIAEXAMPLE"));
+        }
+    }
+}
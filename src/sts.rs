@@ -0,0 +1,488 @@
+use crate::arn::{Arn, Partition};
+use crate::keys::LongTermKeys;
+use crate::log;
+use crate::process::{aws_command, output_with_timeout, TempFile};
+use crate::redact;
+#[cfg(feature = "sdk")]
+use crate::sdk as embedded;
+#[cfg(all(feature = "sigv4", not(feature = "sdk")))]
+use crate::sigv4 as embedded;
+use crate::trace;
+use crate::{Result, SessionTokens};
+
+use anyhow::anyhow;
+use data_encoding::BASE64;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::path::Path;
+use std::process::{Command, Output};
+use std::time::{Duration, Instant};
+
+// Which STS endpoint the CLI calls, as AWS_STS_REGIONAL_ENDPOINTS. v1 of the CLI
+// uses the global one unless told otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegionalEndpoints {
+    // sts.<region>.amazonaws.com of the configured region
+    Regional,
+    // The global sts.amazonaws.com
+    Legacy,
+}
+
+impl RegionalEndpoints {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Regional => "regional",
+            Self::Legacy => "legacy",
+        }
+    }
+
+    // AWS_STS_REGIONAL_ENDPOINTS, which the CLI reads itself.
+    #[cfg(any(feature = "sdk", feature = "sigv4"))]
+    pub(crate) fn from_env() -> Option<Self> {
+        match std::env::var("AWS_STS_REGIONAL_ENDPOINTS").ok()?.as_str() {
+            "legacy" => Some(Self::Legacy),
+            "regional" => Some(Self::Regional),
+            _ => None,
+        }
+    }
+}
+
+// A profile whose keys the embedded client can't sign with, like an SSO one,
+// which the aws CLI takes over when it is installed.
+#[cfg(any(feature = "sdk", feature = "sigv4"))]
+#[derive(Debug)]
+pub(crate) struct Unsigned {
+    pub profile: String,
+}
+
+#[cfg(any(feature = "sdk", feature = "sigv4"))]
+impl std::fmt::Display for Unsigned {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Not Found keys of profile {} to sign the request with: install the aws CLI, which resolves SSO and other credential sources",
+            self.profile
+        )
+    }
+}
+
+#[cfg(any(feature = "sdk", feature = "sigv4"))]
+impl std::error::Error for Unsigned {}
+
+// What calls STS in place of the aws CLI, when the build has something to.
+#[cfg(feature = "sdk")]
+const EMBEDDED_CLIENT: &str = "the AWS SDK";
+#[cfg(all(feature = "sigv4", not(feature = "sdk")))]
+const EMBEDDED_CLIENT: &str = "signed requests of its own";
+
+pub fn get_session_token(
+    device_arn: &str,
+    code: &str,
+    duration: u32,
+    profile: Option<&str>,
+    endpoints: Option<RegionalEndpoints>,
+    timeout: Duration,
+) -> Result<SessionTokens> {
+    #[cfg(any(feature = "sdk", feature = "sigv4"))]
+    if use_embedded_client() {
+        let result = traced("get-session-token", || {
+            embedded::get_session_token(
+                device_arn, code, duration, profile, None, endpoints, timeout,
+            )
+        });
+        match result {
+            Err(e) if e.is::<Unsigned>() && crate::process::aws_cli_installed() => {
+                log::event("sts_client_fallback", json!({ "reason": e.to_string() }));
+            }
+            result => return result,
+        }
+    }
+
+    let stdout = call(
+        "get-session-token",
+        session_token_command(device_arn, code, duration, endpoints)?.args(profile_args(profile)),
+        timeout,
+    )?;
+
+    parse_tokens(&stdout)
+}
+
+// Like get_session_token, signed with keys that are not in the credentials file.
+pub fn get_session_token_with_keys(
+    device_arn: &str,
+    code: &str,
+    duration: u32,
+    keys: &LongTermKeys,
+    endpoints: Option<RegionalEndpoints>,
+    timeout: Duration,
+) -> Result<SessionTokens> {
+    #[cfg(any(feature = "sdk", feature = "sigv4"))]
+    if use_embedded_client() {
+        return traced("get-session-token", || {
+            embedded::get_session_token(
+                device_arn,
+                code,
+                duration,
+                None,
+                Some(keys),
+                endpoints,
+                timeout,
+            )
+        });
+    }
+
+    let stdout = call(
+        "get-session-token",
+        session_token_command(device_arn, code, duration, endpoints)?
+            .envs(keys.env_vars())
+            .env_remove("AWS_SESSION_TOKEN")
+            .env_remove("AWS_PROFILE"),
+        timeout,
+    )?;
+
+    parse_tokens(&stdout)
+}
+
+fn session_token_command(
+    device_arn: &str,
+    code: &str,
+    duration: u32,
+    endpoints: Option<RegionalEndpoints>,
+) -> Result<Command> {
+    let partition = Partition::of_device(device_arn)?;
+
+    let mut command = aws_command();
+    command
+        .arg("sts")
+        .arg("get-session-token")
+        .args(["--serial-number", device_arn])
+        .args(["--token-code", code])
+        .args(["--duration-seconds", duration.to_string().as_ref()])
+        .args(partition_args(partition));
+
+    // The environment still wins, like for any other setting.
+    if let Some(endpoints) = endpoints {
+        if std::env::var_os("AWS_STS_REGIONAL_ENDPOINTS").is_none() {
+            command.env("AWS_STS_REGIONAL_ENDPOINTS", endpoints.as_str());
+        }
+    }
+    Ok(command)
+}
+
+// STS allows 2 to 64 of letters, digits and +=,.@_-
+const MAX_SESSION_NAME_LEN: usize = 64;
+
+fn is_session_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "+=,.@_-".contains(c)
+}
+
+pub fn validate_role_session_name(name: &str) -> Result<()> {
+    if name.len() < 2
+        || name.len() > MAX_SESSION_NAME_LEN
+        || !name.chars().all(is_session_name_char)
+    {
+        return Err(anyhow!(
+            "Invalid role session name: {}, expected 2 to 64 of letters, digits and +=,.@_-",
+            name
+        ));
+    }
+    Ok(())
+}
+
+// Like aws-mfa-tanaka-1700000000, so that CloudTrail shows who assumed the
+// role and when. Characters STS doesn't allow in the user name become -.
+pub fn default_role_session_name(prefix: &str, user: &str, timestamp: i64) -> String {
+    let user: String = user
+        .chars()
+        .map(|c| if is_session_name_char(c) { c } else { '-' })
+        .collect();
+    let mut name = format!("{}-{}-{}", prefix, user, timestamp);
+    if name.len() > MAX_SESSION_NAME_LEN {
+        // Keeps the timestamp, cutting the user name short
+        let suffix = format!("-{}", timestamp);
+        name.truncate(MAX_SESSION_NAME_LEN - suffix.len());
+        name.push_str(&suffix);
+    }
+    name
+}
+
+// Assumes the role with the credentials of an MFA session rather than a profile.
+pub fn assume_role(
+    role_arn: &str,
+    session_name: &str,
+    session: &SessionTokens,
+    duration: u32,
+    partition: Partition,
+    timeout: Duration,
+) -> Result<SessionTokens> {
+    let stdout = call(
+        "assume-role",
+        aws_command()
+            .arg("sts")
+            .arg("assume-role")
+            .args(["--role-arn", role_arn])
+            .args(["--role-session-name", session_name])
+            .args(["--duration-seconds", duration.to_string().as_ref()])
+            .args(partition_args(partition))
+            .envs(session.env_vars())
+            .env_remove("AWS_PROFILE"),
+        timeout,
+    )?;
+
+    parse_tokens(&stdout)
+}
+
+// Exchanges a SAML response of the IdP for role credentials. The assertion file
+// holds the SAMLResponse as posted to AWS, base64 encoded or as XML.
+pub fn assume_role_with_saml(
+    role_arn: &str,
+    principal_arn: &str,
+    assertion_file: &Path,
+    duration: u32,
+    timeout: Duration,
+) -> Result<SessionTokens> {
+    let content = std::fs::read_to_string(assertion_file)
+        .map_err(|e| anyhow!("{}: {}", e, assertion_file.display()))?;
+
+    // Given as a file, as the command line shows in the process list.
+    let encoded = if content.trim_start().starts_with('<') {
+        let encoded = BASE64.encode(content.trim().as_bytes());
+        Some(TempFile::write("saml", encoded.as_bytes())?)
+    } else {
+        None
+    };
+    let assertion = encoded.as_ref().map_or(assertion_file, |f| f.0.as_path());
+
+    let stdout = call(
+        "assume-role-with-saml",
+        aws_command()
+            .arg("sts")
+            .arg("assume-role-with-saml")
+            .args(["--role-arn", role_arn])
+            .args(["--principal-arn", principal_arn])
+            .arg("--saml-assertion")
+            .arg(format!("file://{}", assertion.display()))
+            .args(["--duration-seconds", duration.to_string().as_ref()])
+            .args(partition_args(role_arn.parse::<Arn>()?.partition))
+            .env_remove("AWS_PROFILE"),
+        timeout,
+    )?;
+
+    parse_tokens(&stdout)
+}
+
+// Exchanges an OIDC token, e.g. of a CI runner or a Kubernetes service account,
+// for role credentials.
+pub fn assume_role_with_web_identity(
+    role_arn: &str,
+    session_name: &str,
+    token_file: &Path,
+    duration: u32,
+    timeout: Duration,
+) -> Result<SessionTokens> {
+    let content = std::fs::read_to_string(token_file)
+        .map_err(|e| anyhow!("{}: {}", e, token_file.display()))?;
+
+    // STS rejects the trailing newline many token files end with.
+    let trimmed = if content.trim() != content {
+        Some(TempFile::write("token", content.trim().as_bytes())?)
+    } else {
+        None
+    };
+    let token = trimmed.as_ref().map_or(token_file, |f| f.0.as_path());
+
+    let stdout = call(
+        "assume-role-with-web-identity",
+        aws_command()
+            .arg("sts")
+            .arg("assume-role-with-web-identity")
+            .args(["--role-arn", role_arn])
+            .args(["--role-session-name", session_name])
+            .arg("--web-identity-token")
+            .arg(format!("file://{}", token.display()))
+            .args(["--duration-seconds", duration.to_string().as_ref()])
+            .args(partition_args(role_arn.parse::<Arn>()?.partition))
+            .env_remove("AWS_PROFILE"),
+        timeout,
+    )?;
+
+    parse_tokens(&stdout)
+}
+
+// Returns the ARN the profile's credentials, or the default ones, authenticate as.
+pub fn get_caller_identity(
+    profile: Option<&str>,
+    partition: Partition,
+    timeout: Duration,
+) -> Result<String> {
+    let stdout = call(
+        "get-caller-identity",
+        aws_command()
+            .arg("sts")
+            .arg("get-caller-identity")
+            .args(["--query", "Arn", "--output", "text"])
+            .args(profile_args(profile))
+            .args(partition_args(partition)),
+        timeout,
+    )?;
+
+    Ok(String::from_utf8(stdout)?.trim().to_string())
+}
+
+// The embedded client takes the place of an aws CLI that isn't installed. The
+// SDK is also preferred over an installed one unless AWS_MFA_STS_CLIENT=cli,
+// while the signed requests of sigv4 are only made with =embedded.
+#[cfg(any(feature = "sdk", feature = "sigv4"))]
+fn use_embedded_client() -> bool {
+    let client = std::env::var(crate::ENV_STS_CLIENT).unwrap_or_default();
+    if !crate::process::aws_cli_installed() {
+        if client == "cli" {
+            eprintln!(
+                "Warning: {}=cli, but the aws CLI is not installed: using {}",
+                crate::ENV_STS_CLIENT,
+                EMBEDDED_CLIENT
+            );
+        }
+        return true;
+    }
+    match client.as_str() {
+        "cli" => false,
+        "embedded" => true,
+        _ => cfg!(feature = "sdk"),
+    }
+}
+
+// Logs and traces a request made without the aws CLI, like call does.
+#[cfg(any(feature = "sdk", feature = "sigv4"))]
+fn traced<T>(action: &str, request: impl FnOnce() -> Result<T>) -> Result<T> {
+    log::event("sts_request_started", json!({ "action": action }));
+    let mut span = trace::span(&format!("sts.{}", action));
+    span.set("action", action);
+    let started = Instant::now();
+
+    let result = request();
+    span.set_status(result.is_ok());
+    log::event(
+        "sts_request_finished",
+        json!({
+            "action": action,
+            "success": result.is_ok(),
+            "elapsed_ms": started.elapsed().as_millis() as u64,
+        }),
+    );
+    result
+}
+
+fn parse_tokens(stdout: &[u8]) -> Result<SessionTokens> {
+    let tokens = serde_json::from_slice(stdout).map_err(anyhow::Error::new)?;
+    redact::register_tokens(&tokens);
+    Ok(tokens)
+}
+
+// Runs the aws command and returns its stdout, or its stderr as the error.
+fn call(action: &str, command: &mut Command, timeout: Duration) -> Result<Vec<u8>> {
+    log::event("sts_request_started", json!({ "action": action }));
+    let mut span = trace::span(&format!("sts.{}", action));
+    span.set("action", action);
+    let started = Instant::now();
+
+    let result = output_with_timeout(command, timeout);
+    let elapsed_ms = started.elapsed().as_millis() as u64;
+    span.set_status(matches!(&result, Ok(output) if output.status.success()));
+
+    let Output {
+        status,
+        stdout,
+        stderr,
+    } = match result {
+        Ok(output) => output,
+        Err(e) => {
+            log::event(
+                "sts_request_finished",
+                json!({ "action": action, "success": false, "elapsed_ms": elapsed_ms }),
+            );
+            return Err(e);
+        }
+    };
+
+    log::event(
+        "sts_request_finished",
+        json!({ "action": action, "success": status.success(), "elapsed_ms": elapsed_ms }),
+    );
+
+    if status.success() {
+        Ok(stdout)
+    } else {
+        Err(anyhow!("{}", String::from_utf8(stderr)?))
+    }
+}
+
+fn profile_args(profile: Option<&str>) -> Vec<&str> {
+    match profile {
+        Some(p) => vec!["--profile", p],
+        None => vec![],
+    }
+}
+
+// Outside the commercial partition the global STS endpoint can't be used, so point
+// the CLI at the partition's regional one unless the user already chose a region.
+fn partition_args(partition: Partition) -> Vec<String> {
+    let region_set = ["AWS_REGION", "AWS_DEFAULT_REGION"]
+        .iter()
+        .any(|var| std::env::var(var).is_ok());
+    let endpoint_set = ["AWS_ENDPOINT_URL_STS", "AWS_ENDPOINT_URL"]
+        .iter()
+        .any(|var| std::env::var(var).is_ok());
+
+    match partition.default_region() {
+        Some(region) if !region_set => {
+            let mut args = vec!["--region".to_string(), region.to_string()];
+            if !endpoint_set {
+                args.push("--endpoint-url".to_string());
+                args.extend(partition.sts_endpoint(region));
+            }
+            args
+        }
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod default_role_session_name {
+        use super::*;
+
+        #[test]
+        fn it_names_user_and_time() {
+            let name = default_role_session_name("aws-mfa", "tanaka", 1_700_000_000);
+            assert_eq!(name, "aws-mfa-tanaka-1700000000");
+            assert!(validate_role_session_name(&name).is_ok());
+        }
+
+        #[test]
+        fn it_fits_odd_user_names() {
+            let name = default_role_session_name("aws-mfa", "DOMAIN\\Tanaka Taro", 1_700_000_000);
+            assert_eq!(name, "aws-mfa-DOMAIN-Tanaka-Taro-1700000000");
+
+            let name = default_role_session_name("aws-mfa", &"x".repeat(80), 1_700_000_000);
+            assert_eq!(name.len(), 64);
+            assert!(name.ends_with("-1700000000"));
+            assert!(validate_role_session_name(&name).is_ok());
+        }
+    }
+
+    mod validate_role_session_name {
+        use super::*;
+
+        #[test]
+        fn it_rejects_what_sts_would() {
+            assert!(validate_role_session_name("deploy@ci").is_ok());
+            assert!(validate_role_session_name("a").is_err());
+            assert!(validate_role_session_name("has space").is_err());
+            assert!(validate_role_session_name(&"x".repeat(65)).is_err());
+        }
+    }
+}
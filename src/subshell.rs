@@ -0,0 +1,63 @@
+use crate::config::Paths;
+use crate::Result;
+
+use std::path::Path;
+use std::process::Command;
+
+pub const ENV_PROMPT: &str = "AWS_MFA_PROMPT";
+
+// Builds an interactive shell which prefixes its prompt with $AWS_MFA_PROMPT
+// after loading the user's own startup files.
+pub fn command(paths: &Paths, shell_path: &str, prompt: &str) -> Result<Command> {
+    let name = Path::new(shell_path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    let mut command = Command::new(shell_path);
+    command.env(ENV_PROMPT, prompt);
+
+    match name {
+        "bash" => {
+            let rcfile = paths.data.join("bashrc");
+            write_rcfile(&rcfile, BASHRC)?;
+            command.arg("--rcfile").arg(rcfile);
+        }
+        "zsh" => {
+            let zdotdir = paths.data.join("zsh");
+            write_rcfile(&zdotdir.join(".zshrc"), ZSHRC)?;
+            if let Ok(dir) = std::env::var("ZDOTDIR") {
+                command.env("AWS_MFA_ZDOTDIR", dir);
+            }
+            command.env("ZDOTDIR", zdotdir);
+        }
+        "fish" => {
+            command.args(["--init-command", FISH_INIT]);
+        }
+        _ => {
+            let ps1 = std::env::var("PS1").unwrap_or_else(|_| "$ ".to_string());
+            command.env("PS1", format!("{} {}", prompt, ps1));
+        }
+    }
+
+    Ok(command)
+}
+
+const BASHRC: &str = r#"[ -f ~/.bashrc ] && . ~/.bashrc
+PS1="$AWS_MFA_PROMPT $PS1"
+"#;
+
+const ZSHRC: &str = r#"ZDOTDIR="${AWS_MFA_ZDOTDIR:-$HOME}"
+unset AWS_MFA_ZDOTDIR
+[ -f "$ZDOTDIR/.zshrc" ] && . "$ZDOTDIR/.zshrc"
+PROMPT="$AWS_MFA_PROMPT $PROMPT"
+"#;
+
+const FISH_INIT: &str = "functions -c fish_prompt __aws_mfa_fish_prompt; \
+function fish_prompt; echo -n \"$AWS_MFA_PROMPT \"; __aws_mfa_fish_prompt; end";
+
+fn write_rcfile(path: &Path, content: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, content).map_err(anyhow::Error::new)
+}
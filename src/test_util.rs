@@ -0,0 +1,369 @@
+// Helpers for testing the whole refresh pipeline without AWS.
+//
+// `MockSts` speaks the STS query protocol, so the aws CLI can be pointed at it
+// with `AWS_ENDPOINT_URL_STS`, and `TestHome` provides a throwaway `$HOME`.
+
+use crate::Result;
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+pub mod fixtures {
+    pub const ACCESS_KEY_ID: &str = "ASIAMOCKACCESSKEYID0";
+    pub const SECRET_ACCESS_KEY: &str = "mock/secret+access/key";
+    pub const SESSION_TOKEN: &str = "mock-session-token";
+    pub const DEVICE_ARN: &str = "arn:aws:iam::012345678901:mfa/tanaka";
+    pub const ACCOUNT: &str = "012345678901";
+    pub const CALLER_ARN: &str = "arn:aws:iam::012345678901:user/tanaka";
+
+    pub const MFA_YML: &str = "devices:
+  - profile: default
+    arn: arn:aws:iam::012345678901:mfa/tanaka
+";
+
+    pub const CREDENTIALS: &str = "[default]
+aws_access_key_id=AKIAMOCKLONGTERMKEY0
+aws_secret_access_key=mock-long-term-secret
+";
+
+    // Body of a successful GetSessionToken in the query protocol.
+    pub fn session_token_xml(expiration: &str) -> String {
+        format!(
+            r#"<GetSessionTokenResponse xmlns="https://sts.amazonaws.com/doc/2011-06-15/">
+  <GetSessionTokenResult>
+    <Credentials>
+      <AccessKeyId>{}</AccessKeyId>
+      <SecretAccessKey>{}</SecretAccessKey>
+      <SessionToken>{}</SessionToken>
+      <Expiration>{}</Expiration>
+    </Credentials>
+  </GetSessionTokenResult>
+  <ResponseMetadata>
+    <RequestId>00000000-0000-0000-0000-000000000000</RequestId>
+  </ResponseMetadata>
+</GetSessionTokenResponse>"#,
+            ACCESS_KEY_ID, SECRET_ACCESS_KEY, SESSION_TOKEN, expiration
+        )
+    }
+
+    pub const ROLE_ARN: &str = "arn:aws:iam::012345678901:role/deploy";
+
+    // Body of a successful AssumeRole, with the same credentials as the session.
+    pub fn assume_role_xml(expiration: &str) -> String {
+        assumed_role_xml("AssumeRole", expiration)
+    }
+
+    pub fn assume_role_with_saml_xml(expiration: &str) -> String {
+        assumed_role_xml("AssumeRoleWithSAML", expiration)
+    }
+
+    pub fn assume_role_with_web_identity_xml(expiration: &str) -> String {
+        assumed_role_xml("AssumeRoleWithWebIdentity", expiration)
+    }
+
+    fn assumed_role_xml(action: &str, expiration: &str) -> String {
+        format!(
+            r#"<{action}Response xmlns="https://sts.amazonaws.com/doc/2011-06-15/">
+  <{action}Result>
+    <AssumedRoleUser>
+      <Arn>arn:aws:sts::012345678901:assumed-role/deploy/aws-mfa</Arn>
+      <AssumedRoleId>AROAMOCKROLEID000000:aws-mfa</AssumedRoleId>
+    </AssumedRoleUser>
+    <Credentials>
+      <AccessKeyId>{access_key_id}</AccessKeyId>
+      <SecretAccessKey>{secret_access_key}</SecretAccessKey>
+      <SessionToken>{session_token}</SessionToken>
+      <Expiration>{expiration}</Expiration>
+    </Credentials>
+  </{action}Result>
+  <ResponseMetadata>
+    <RequestId>00000000-0000-0000-0000-000000000000</RequestId>
+  </ResponseMetadata>
+</{action}Response>"#,
+            action = action,
+            access_key_id = ACCESS_KEY_ID,
+            secret_access_key = SECRET_ACCESS_KEY,
+            session_token = SESSION_TOKEN,
+            expiration = expiration
+        )
+    }
+
+    // What `aws sts get-session-token` prints for the same session.
+    pub fn session_token_json(expiration: &str) -> String {
+        format!(
+            r#"{{"Credentials":{{"AccessKeyId":"{}","SecretAccessKey":"{}","SessionToken":"{}","Expiration":"{}"}}}}"#,
+            ACCESS_KEY_ID, SECRET_ACCESS_KEY, SESSION_TOKEN, expiration
+        )
+    }
+
+    pub fn caller_identity_xml() -> String {
+        format!(
+            r#"<GetCallerIdentityResponse xmlns="https://sts.amazonaws.com/doc/2011-06-15/">
+  <GetCallerIdentityResult>
+    <Arn>{}</Arn>
+    <UserId>{}</UserId>
+    <Account>{}</Account>
+  </GetCallerIdentityResult>
+  <ResponseMetadata>
+    <RequestId>00000000-0000-0000-0000-000000000000</RequestId>
+  </ResponseMetadata>
+</GetCallerIdentityResponse>"#,
+            CALLER_ARN, ACCESS_KEY_ID, ACCOUNT
+        )
+    }
+
+    pub fn error_xml(code: &str, message: &str) -> String {
+        format!(
+            r#"<ErrorResponse xmlns="https://sts.amazonaws.com/doc/2011-06-15/">
+  <Error>
+    <Type>Sender</Type>
+    <Code>{}</Code>
+    <Message>{}</Message>
+  </Error>
+  <RequestId>00000000-0000-0000-0000-000000000000</RequestId>
+</ErrorResponse>"#,
+            code, message
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum MockResponse {
+    Session {
+        expiration: String,
+    },
+    Error {
+        status: u16,
+        code: String,
+        message: String,
+    },
+}
+
+impl MockResponse {
+    fn to_http(&self, action: &str) -> String {
+        let (status, body) = match self {
+            Self::Session { .. } if action == "GetCallerIdentity" => {
+                ("200 OK".to_string(), fixtures::caller_identity_xml())
+            }
+            Self::Session { expiration } if action == "AssumeRole" => {
+                ("200 OK".to_string(), fixtures::assume_role_xml(expiration))
+            }
+            Self::Session { expiration } if action == "AssumeRoleWithSAML" => (
+                "200 OK".to_string(),
+                fixtures::assume_role_with_saml_xml(expiration),
+            ),
+            Self::Session { expiration } if action == "AssumeRoleWithWebIdentity" => (
+                "200 OK".to_string(),
+                fixtures::assume_role_with_web_identity_xml(expiration),
+            ),
+            Self::Session { expiration } => (
+                "200 OK".to_string(),
+                fixtures::session_token_xml(expiration),
+            ),
+            Self::Error {
+                status,
+                code,
+                message,
+            } => (
+                format!("{} Error", status),
+                fixtures::error_xml(code, message),
+            ),
+        };
+
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/xml\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    }
+}
+
+// An STS endpoint on localhost answering every request with the same response,
+// except that a session response also answers GetCallerIdentity and AssumeRole.
+pub struct MockSts {
+    endpoint: String,
+    requests: Arc<Mutex<Vec<HashMap<String, String>>>>,
+}
+
+impl MockSts {
+    pub fn start() -> Result<Self> {
+        let expiration = (chrono::Utc::now() + chrono::Duration::hours(1))
+            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        Self::start_with(MockResponse::Session { expiration })
+    }
+
+    pub fn start_with(response: MockResponse) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let endpoint = format!("http://{}", listener.local_addr()?);
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = Arc::clone(&requests);
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Ok(params) = serve(stream, &response) {
+                    recorded.lock().unwrap().push(params);
+                }
+            }
+        });
+
+        Ok(Self { endpoint, requests })
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    // Form parameters of the requests received so far, e.g. "TokenCode", and
+    // the "Authorization" header of signed ones.
+    pub fn requests(&self) -> Vec<HashMap<String, String>> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn serve(stream: TcpStream, response: &MockResponse) -> Result<HashMap<String, String>> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut content_length = 0;
+    let mut authorization = None;
+
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or_default();
+            }
+            if name.eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    let mut params = parse_form(&String::from_utf8_lossy(&body));
+    if let Some(authorization) = authorization {
+        params.insert("Authorization".to_string(), authorization);
+    }
+    let action = params.get("Action").map(String::as_str).unwrap_or_default();
+
+    let mut stream = stream;
+    stream.write_all(response.to_http(action).as_bytes())?;
+    stream.flush()?;
+
+    Ok(params)
+}
+
+fn parse_form(body: &str) -> HashMap<String, String> {
+    body.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(k, v)| (decode(k), decode(v)))
+        .collect()
+}
+
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+                match u8::from_str_radix(hex, 16) {
+                    Ok(b) => {
+                        out.push(b);
+                        i += 2;
+                    }
+                    Err(_) => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// A temporary $HOME with an ~/.aws directory, removed on drop.
+pub struct TestHome {
+    root: PathBuf,
+}
+
+impl TestHome {
+    pub fn new() -> Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let root = std::env::temp_dir().join(format!(
+            "aws-mfa-test-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(root.join(".aws"))?;
+        Ok(Self { root })
+    }
+
+    // A home with fixtures::MFA_YML and fixtures::CREDENTIALS.
+    pub fn with_fixtures() -> Result<Self> {
+        let home = Self::new()?;
+        home.write("mfa.yml", fixtures::MFA_YML)?;
+        home.write("credentials", fixtures::CREDENTIALS)?;
+        Ok(home)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    // The paths of the home, to pass to config in an in-process test.
+    pub fn paths(&self) -> crate::config::Paths {
+        crate::config::Paths::under(&self.root.join(".aws"))
+    }
+
+    pub fn aws_file(&self, filename: &str) -> PathBuf {
+        self.root.join(".aws").join(filename)
+    }
+
+    pub fn write(&self, filename: &str, content: &str) -> Result<()> {
+        let path = self.aws_file(filename);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, content).map_err(anyhow::Error::new)
+    }
+
+    pub fn read(&self, filename: &str) -> Result<String> {
+        std::fs::read_to_string(self.aws_file(filename)).map_err(anyhow::Error::new)
+    }
+}
+
+impl Drop for TestHome {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod decode {
+        use super::*;
+
+        #[test]
+        fn it_decodes_form_values() {
+            assert_eq!(decode("arn%3Aaws%3Aiam"), "arn:aws:iam");
+            assert_eq!(decode("a+b"), "a b");
+            assert_eq!(decode("100%"), "100%");
+        }
+    }
+}
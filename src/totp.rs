@@ -0,0 +1,154 @@
+use crate::Result;
+
+use anyhow::anyhow;
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+pub const STEP: u64 = 30;
+pub const DIGITS: u32 = 6;
+// Drift of the local clock beyond which codes are generated on corrected time.
+pub const MAX_DRIFT: f64 = 5.0;
+
+// Seconds added to the local clock, set after a clock check found it off.
+static CLOCK_OFFSET: AtomicI64 = AtomicI64::new(0);
+
+// A code with less time than this left may expire before STS checks it.
+pub const MIN_REMAINING: u64 = 2;
+
+// RFC 6238 time-based one time password with HMAC-SHA1.
+pub fn generate(seed: &str, timestamp: u64) -> Result<String> {
+    generate_digits(seed, timestamp, DIGITS)
+}
+
+pub fn generate_digits(seed: &str, timestamp: u64, digits: u32) -> Result<String> {
+    let key = decode_seed(seed)?;
+    let counter = (timestamp / STEP).to_be_bytes();
+
+    let mut mac = Hmac::<Sha1>::new_from_slice(&key).map_err(anyhow::Error::new)?;
+    mac.update(&counter);
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    Ok(format!(
+        "{:0width$}",
+        binary % 10u32.pow(digits),
+        width = digits as usize
+    ))
+}
+
+pub fn now() -> u64 {
+    let local = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    local.saturating_add_signed(CLOCK_OFFSET.load(Ordering::Relaxed))
+}
+
+pub fn set_clock_offset(seconds: i64) {
+    CLOCK_OFFSET.store(seconds, Ordering::Relaxed);
+}
+
+// Seconds the code of the timestamp is still valid for.
+pub fn remaining(timestamp: u64) -> u64 {
+    STEP - timestamp % STEP
+}
+
+// The time to generate a code at: now, or the start of the next window when the
+// current one is about to roll over, waited for with a countdown.
+pub fn fresh_timestamp() -> u64 {
+    let left = remaining(now());
+    if left >= MIN_REMAINING {
+        return now();
+    }
+
+    let mut stderr = std::io::stderr();
+    let countdown = stderr.is_terminal();
+    if !countdown {
+        eprintln!("Waiting {}s for the next TOTP window", left);
+    }
+    for second in (1..=left).rev() {
+        if countdown {
+            eprint!("\rWaiting {}s for the next TOTP window", second);
+            let _ = stderr.flush();
+        }
+        std::thread::sleep(Duration::from_secs(1));
+    }
+    if countdown {
+        eprintln!();
+    }
+    now()
+}
+
+// The key is overwritten once the code is computed.
+fn decode_seed(seed: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let normalized: Zeroizing<String> = Zeroizing::new(
+        seed.chars()
+            .filter(|c| !c.is_whitespace() && *c != '=')
+            .map(|c| c.to_ascii_uppercase())
+            .collect(),
+    );
+
+    BASE32_NOPAD
+        .decode(normalized.as_bytes())
+        .map(Zeroizing::new)
+        .map_err(|e| anyhow!("Invalid TOTP seed: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // base32 of the RFC 6238 test secret "12345678901234567890"
+    const SEED: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    mod remaining {
+        use super::*;
+
+        #[test]
+        fn it_counts_down_to_the_next_window() {
+            assert_eq!(remaining(30), 30);
+            assert_eq!(remaining(59), 1);
+            assert_eq!(remaining(1111111109), 1);
+        }
+    }
+
+    mod generate {
+        use super::*;
+
+        #[test]
+        fn it_generates_rfc6238_codes() {
+            assert_eq!(generate(SEED, 59).unwrap(), "287082");
+            assert_eq!(generate(SEED, 1111111109).unwrap(), "081804");
+            assert_eq!(generate(SEED, 1234567890).unwrap(), "005924");
+        }
+
+        #[test]
+        fn it_accepts_lowercase_and_spaced_seeds() {
+            let seed = "gezd gnbv gy3t qojq gezd gnbv gy3t qojq";
+            assert_eq!(generate(seed, 59).unwrap(), "287082");
+        }
+
+        #[test]
+        fn it_fails_with_invalid_seed() {
+            assert!(generate("not base32!", 59).is_err());
+        }
+
+        #[test]
+        fn it_generates_rfc6238_eight_digit_codes() {
+            assert_eq!(generate_digits(SEED, 59, 8).unwrap(), "94287082");
+            assert_eq!(generate_digits(SEED, 1111111109, 8).unwrap(), "07081804");
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use crate::Result;
+
+use lazy_static::lazy_static;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const ENV_OTLP_ENDPOINT: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+pub const ENV_OTLP_TRACES_ENDPOINT: &str = "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT";
+pub const ENV_OTLP_HEADERS: &str = "OTEL_EXPORTER_OTLP_HEADERS";
+
+const SERVICE_NAME: &str = "aws-mfa";
+
+// OTLP status codes
+const STATUS_OK: u8 = 1;
+const STATUS_ERROR: u8 = 2;
+
+lazy_static! {
+    static ref ENDPOINT: Option<String> = endpoint();
+    static ref TRACE_ID: String = random_hex(16);
+    static ref FINISHED: Mutex<Vec<SpanData>> = Mutex::new(Vec::new());
+    // Ids of the open spans, the last one being the parent of a new span
+    static ref OPEN: Mutex<Vec<String>> = Mutex::new(Vec::new());
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct SpanData {
+    span_id: String,
+    parent_span_id: Option<String>,
+    name: String,
+    start: u128,
+    end: u128,
+    attributes: Map<String, Value>,
+    status: Option<u8>,
+}
+
+// A span recorded when dropped. Does nothing unless traces are exported.
+pub struct Span {
+    data: Option<SpanData>,
+}
+
+pub fn span(name: &str) -> Span {
+    if ENDPOINT.is_none() {
+        return Span { data: None };
+    }
+
+    let span_id = random_hex(8);
+    let mut open = OPEN.lock().unwrap();
+    let parent_span_id = open.last().cloned();
+    open.push(span_id.clone());
+
+    Span {
+        data: Some(SpanData {
+            span_id,
+            parent_span_id,
+            name: name.to_string(),
+            start: now_nanos(),
+            end: 0,
+            attributes: Map::new(),
+            status: None,
+        }),
+    }
+}
+
+impl Span {
+    pub fn set<V: Into<Value>>(&mut self, key: &str, value: V) {
+        if let Some(data) = self.data.as_mut() {
+            data.attributes.insert(key.to_string(), value.into());
+        }
+    }
+
+    pub fn set_status(&mut self, ok: bool) {
+        if let Some(data) = self.data.as_mut() {
+            data.status = Some(if ok { STATUS_OK } else { STATUS_ERROR });
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(mut data) = self.data.take() {
+            data.end = now_nanos();
+            OPEN.lock().unwrap().retain(|id| id != &data.span_id);
+            FINISHED.lock().unwrap().push(data);
+        }
+    }
+}
+
+// Sends the spans finished so far. Failing to export only warns, since the
+// refresh itself went through.
+pub fn flush() {
+    let endpoint = match ENDPOINT.as_ref() {
+        Some(e) => e,
+        None => return,
+    };
+
+    let spans: Vec<SpanData> = FINISHED.lock().unwrap().drain(..).collect();
+    if spans.is_empty() {
+        return;
+    }
+
+    if let Err(e) = export(endpoint, &payload(&TRACE_ID, &spans)) {
+        eprintln!("Warning: failed to export traces to {}: {}", endpoint, e);
+    }
+}
+
+#[cfg(feature = "otel")]
+fn endpoint() -> Option<String> {
+    let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+
+    var(ENV_OTLP_TRACES_ENDPOINT).or_else(|| {
+        var(ENV_OTLP_ENDPOINT).map(|e| format!("{}/v1/traces", e.trim_end_matches('/')))
+    })
+}
+
+#[cfg(not(feature = "otel"))]
+fn endpoint() -> Option<String> {
+    None
+}
+
+#[cfg(feature = "otel")]
+fn export(endpoint: &str, payload: &Value) -> Result<()> {
+    let mut request = ureq::post(endpoint)
+        .timeout(std::time::Duration::from_secs(5))
+        .set("Content-Type", "application/json");
+
+    if let Ok(headers) = std::env::var(ENV_OTLP_HEADERS) {
+        for (name, value) in headers.split(',').filter_map(|h| h.split_once('=')) {
+            request = request.set(name.trim(), value.trim());
+        }
+    }
+
+    request.send_string(&payload.to_string())?;
+    Ok(())
+}
+
+#[cfg(not(feature = "otel"))]
+fn export(_endpoint: &str, _payload: &Value) -> Result<()> {
+    Ok(())
+}
+
+// The OTLP/JSON body of an ExportTraceServiceRequest.
+fn payload(trace_id: &str, spans: &[SpanData]) -> Value {
+    let spans: Vec<Value> = spans
+        .iter()
+        .map(|span| {
+            let mut value = json!({
+                "traceId": trace_id,
+                "spanId": span.span_id,
+                "name": span.name,
+                // SPAN_KIND_INTERNAL
+                "kind": 1,
+                "startTimeUnixNano": span.start.to_string(),
+                "endTimeUnixNano": span.end.to_string(),
+                "attributes": attributes(&span.attributes),
+            });
+            if let Some(parent) = &span.parent_span_id {
+                value["parentSpanId"] = json!(parent);
+            }
+            if let Some(code) = span.status {
+                value["status"] = json!({ "code": code });
+            }
+            value
+        })
+        .collect();
+
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": SERVICE_NAME } }],
+            },
+            "scopeSpans": [{
+                "scope": { "name": SERVICE_NAME, "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+fn attributes(map: &Map<String, Value>) -> Vec<Value> {
+    map.iter()
+        .filter_map(|(key, value)| {
+            let value = match value {
+                Value::Bool(b) => json!({ "boolValue": b }),
+                // int64 is a string in OTLP/JSON
+                Value::Number(n) if n.is_i64() || n.is_u64() => {
+                    json!({ "intValue": n.to_string() })
+                }
+                Value::Number(n) => json!({ "doubleValue": n }),
+                Value::String(s) => json!({ "stringValue": s }),
+                Value::Null | Value::Array(_) | Value::Object(_) => return None,
+            };
+            Some(json!({ "key": key, "value": value }))
+        })
+        .collect()
+}
+
+fn now_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default()
+}
+
+// Ids only need to be unique, not secret.
+fn random_hex(bytes: usize) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut hasher = Sha256::new();
+    hasher.update(now_nanos().to_le_bytes());
+    hasher.update(std::process::id().to_le_bytes());
+    hasher.update(COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+
+    hasher.finalize()[..bytes]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod payload {
+        use super::*;
+
+        #[test]
+        fn it_returns_otlp_json() {
+            let mut attributes = Map::new();
+            attributes.insert("action".to_string(), json!("GetSessionToken"));
+            attributes.insert("elapsed_ms".to_string(), json!(120));
+            attributes.insert("success".to_string(), json!(true));
+            attributes.insert("role_arn".to_string(), Value::Null);
+
+            let span = SpanData {
+                span_id: "00f067aa0ba902b7".to_string(),
+                parent_span_id: Some("53995c3f42cd8ad8".to_string()),
+                name: "sts.GetSessionToken".to_string(),
+                start: 1,
+                end: 2,
+                attributes,
+                status: Some(STATUS_OK),
+            };
+
+            let value = payload("4bf92f3577b34da6a3ce929d0e0e4736", &[span]);
+            let span = &value["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+            assert_eq!(span["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+            assert_eq!(span["parentSpanId"], "53995c3f42cd8ad8");
+            assert_eq!(span["startTimeUnixNano"], "1");
+            assert_eq!(span["status"]["code"], 1);
+            assert_eq!(
+                span["attributes"],
+                json!([
+                    { "key": "action", "value": { "stringValue": "GetSessionToken" } },
+                    { "key": "elapsed_ms", "value": { "intValue": "120" } },
+                    { "key": "success", "value": { "boolValue": true } },
+                ])
+            );
+        }
+    }
+
+    mod random_hex {
+        use super::*;
+
+        #[test]
+        fn it_returns_distinct_ids() {
+            let (a, b) = (random_hex(8), random_hex(8));
+            assert_eq!(a.len(), 16);
+            assert_ne!(a, b);
+        }
+    }
+}
@@ -0,0 +1,31 @@
+// Terminal detection shared by every interactive feature, so that prompts and
+// confirmations turn into clear errors when aws-mfa runs in a pipe or script.
+
+use crate::Result;
+
+use anyhow::anyhow;
+use std::io::IsTerminal;
+
+// Set to any non-empty value to never prompt, even on a terminal.
+pub const ENV_NO_PROMPT: &str = "AWS_MFA_NO_PROMPT";
+
+pub fn stdin_is_tty() -> bool {
+    std::io::stdin().is_terminal()
+}
+
+// Questions are asked on stderr and answered on stdin.
+pub fn is_interactive() -> bool {
+    let disabled = std::env::var(ENV_NO_PROMPT).is_ok_and(|v| !v.is_empty());
+    !disabled && stdin_is_tty() && std::io::stderr().is_terminal()
+}
+
+pub fn require_interactive(action: &str) -> Result<()> {
+    if is_interactive() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Cannot {}: not running in an interactive terminal",
+            action
+        ))
+    }
+}
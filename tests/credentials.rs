@@ -0,0 +1,94 @@
+use aws_mfa::config::credentials::{ConfigFile, Credential};
+use proptest::prelude::*;
+use std::collections::BTreeMap;
+
+// Profile names as people write them, unicode included. Surrounding spaces
+// are trimmed on parsing, so there are none.
+fn profile() -> impl Strategy<Value = String> {
+    prop_oneof![
+        "[a-zA-Z0-9_.@-]{1,30}",
+        "profile [a-z0-9-]{1,20}",
+        "\\p{L}{1,10}",
+    ]
+}
+
+// A line of a section: a setting, a comment, a nested setting or blanks.
+fn line() -> impl Strategy<Value = String> {
+    prop_oneof![
+        ("[a-z_]{1,20}", "[ \t]{0,2}", "\\PC{0,40}")
+            .prop_map(|(key, space, value)| format!("{}{}={}{}", key, space, space, value)),
+        "[#;]\\PC{0,40}",
+        "[ \t]{2,4}[a-z_]{1,10} = \\PC{0,20}",
+        "[ \t]{1,4}",
+    ]
+}
+
+fn sections(max: usize) -> impl Strategy<Value = BTreeMap<String, Vec<String>>> {
+    prop::collection::btree_map(profile(), prop::collection::vec(line(), 0..8), 0..max)
+}
+
+fn render(sections: &BTreeMap<String, Vec<String>>) -> String {
+    sections
+        .iter()
+        .map(|(profile, lines)| Credential::new(profile, lines).to_string())
+        .collect::<Vec<String>>()
+        .join("\n\n")
+}
+
+// Rendering what was parsed gives a file that parses to the same.
+fn assert_stable(content: &str) -> Result<(), TestCaseError> {
+    if let Ok(file) = content.parse::<ConfigFile>() {
+        let rendered = file.to_string();
+        let reparsed = rendered.parse::<ConfigFile>();
+        prop_assert!(reparsed.is_ok(), "{:?} no longer parses", rendered);
+        prop_assert_eq!(reparsed.unwrap().to_string(), rendered);
+    }
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn it_parses_rendered_sections_back(sections in sections(20)) {
+        let content = render(&sections);
+        let file: ConfigFile = content.parse().unwrap();
+        prop_assert_eq!(file.to_string(), content);
+
+        for (profile, lines) in &sections {
+            let cred = file.credential(profile).unwrap();
+            prop_assert_eq!(cred.to_string(), Credential::new(profile, lines).to_string());
+        }
+    }
+
+    #[test]
+    fn it_renders_any_parsed_file_stably(content in any::<String>()) {
+        assert_stable(&content)?;
+    }
+
+    #[test]
+    fn it_renders_edited_files_stably(
+        sections in sections(10),
+        noise in prop::collection::vec((0usize..200, "\\PC{0,10}|\r|\n|\r\n|\\[|\\]|=|#"), 0..8),
+    ) {
+        // A valid file with text dropped in at random places
+        let mut content = render(&sections);
+        for (at, text) in noise {
+            let mut at = at.min(content.len());
+            while !content.is_char_boundary(at) {
+                at -= 1;
+            }
+            content.insert_str(at, &text);
+        }
+        assert_stable(&content)?;
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(4))]
+
+    #[test]
+    fn it_parses_huge_files_back(sections in sections(3000)) {
+        let content = render(&sections);
+        let file: ConfigFile = content.parse().unwrap();
+        prop_assert_eq!(file.to_string(), content);
+    }
+}
@@ -0,0 +1,51 @@
+use aws_mfa::test_util::{fixtures, TestHome};
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn import_session(home: &TestHome, args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_aws-mfa"))
+        .arg("import-session")
+        .args(args)
+        .env("HOME", home.path())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn it_writes_session_read_from_stdin() {
+    let home = TestHome::with_fixtures().unwrap();
+    let json = fixtures::session_token_json("2030-01-01T00:00:00Z");
+
+    let output = import_session(&home, &["-m", "bastion", "-p", "ops"], &json);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let credentials = home.read("credentials").unwrap();
+    assert!(credentials.contains("[bastion]"));
+    assert!(credentials.contains("# aws-mfa: source_profile=ops"));
+    assert!(credentials.contains(&format!("aws_session_token={}", fixtures::SESSION_TOKEN)));
+    assert_eq!(home.read("credentials_bk").unwrap(), fixtures::CREDENTIALS);
+}
+
+#[test]
+fn it_fails_with_invalid_json() {
+    let home = TestHome::with_fixtures().unwrap();
+
+    let output = import_session(&home, &[], "not json");
+    assert!(!output.status.success());
+    assert_eq!(home.read("credentials").unwrap(), fixtures::CREDENTIALS);
+}
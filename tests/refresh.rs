@@ -0,0 +1,539 @@
+use aws_mfa::rpc;
+use aws_mfa::test_util::{fixtures, MockResponse, MockSts, TestHome};
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+// Without the aws CLI these run against the embedded STS client, which only
+// makes GetSessionToken. Tests of the other actions are ignored, to be run with
+// `cargo test -- --ignored` where the aws CLI is installed.
+
+fn aws_mfa(home: &TestHome, sts: &MockSts, args: &[&str]) -> Output {
+    aws_mfa_with_env(home, sts, args, &[])
+}
+
+fn aws_mfa_with_env(
+    home: &TestHome,
+    sts: &MockSts,
+    args: &[&str],
+    envs: &[(&str, &str)],
+) -> Output {
+    command(home, sts, args, envs).output().unwrap()
+}
+
+// Runs aws-mfa with the lines of input on stdin.
+fn aws_mfa_with_input(home: &TestHome, sts: &MockSts, args: &[&str], input: &str) -> Output {
+    let mut child = command(home, sts, args, &[])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn command(home: &TestHome, sts: &MockSts, args: &[&str], envs: &[(&str, &str)]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_aws-mfa"));
+    for (var, _) in std::env::vars() {
+        if var.starts_with("AWS_MFA_") {
+            command.env_remove(var);
+        }
+    }
+
+    command
+        .args(args)
+        .env("HOME", home.path())
+        .env("AWS_ENDPOINT_URL_STS", sts.endpoint())
+        .env("AWS_DEFAULT_REGION", "us-east-1")
+        .env_remove("AWS_PROFILE")
+        .env_remove("AWS_ACCESS_KEY_ID")
+        .env_remove("AWS_SECRET_ACCESS_KEY")
+        .env_remove("AWS_SESSION_TOKEN")
+        .env_remove("AWS_SHARED_CREDENTIALS_FILE")
+        .env_remove("AWS_CONFIG_FILE")
+        .env_remove("AWS_MFA_CODE")
+        .envs(envs.iter().copied());
+    command
+}
+
+#[test]
+#[cfg_attr(
+    not(any(feature = "sdk", feature = "sigv4")),
+    ignore = "needs the aws CLI"
+)]
+fn it_writes_mfa_profile_from_sts_session() {
+    let home = TestHome::with_fixtures().unwrap();
+    let sts = MockSts::start().unwrap();
+
+    let output = aws_mfa(&home, &sts, &["-p", "default", "123456"]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let requests = sts.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0]["Action"], "GetSessionToken");
+    assert_eq!(requests[0]["SerialNumber"], fixtures::DEVICE_ARN);
+    assert_eq!(requests[0]["TokenCode"], "123456");
+    assert_eq!(requests[0]["DurationSeconds"], "900");
+
+    let credentials = home.read("credentials").unwrap();
+    assert!(credentials.contains("[mfa]"));
+    assert!(credentials.contains(&format!("aws_access_key_id={}", fixtures::ACCESS_KEY_ID)));
+    assert!(credentials.contains(&format!("aws_session_token={}", fixtures::SESSION_TOKEN)));
+    assert!(credentials.contains("# aws-mfa: source_profile=default"));
+    assert_eq!(home.read("credentials_bk").unwrap(), fixtures::CREDENTIALS);
+}
+
+#[cfg(any(feature = "sdk", feature = "sigv4"))]
+#[test]
+fn it_writes_mfa_profile_without_aws_cli() {
+    let home = TestHome::with_fixtures().unwrap();
+    let sts = MockSts::start().unwrap();
+
+    let output = aws_mfa_with_env(&home, &sts, &["-p", "default", "123456"], &[("PATH", "")]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let requests = sts.requests();
+    assert_eq!(requests[0]["Action"], "GetSessionToken");
+    assert_eq!(requests[0]["TokenCode"], "123456");
+
+    let credentials = home.read("credentials").unwrap();
+    assert!(credentials.contains(&format!("aws_session_token={}", fixtures::SESSION_TOKEN)));
+}
+
+#[cfg(any(feature = "sdk", feature = "sigv4"))]
+#[test]
+fn it_signs_with_keys_of_aws_profile_without_aws_cli() {
+    let home = TestHome::new().unwrap();
+    home.write(
+        "mfa.yml",
+        "devices:\n  - profile: work\n    arn: arn:aws:iam::012345678901:mfa/tanaka\n",
+    )
+    .unwrap();
+    home.write(
+        "credentials",
+        &format!(
+            "{}[work]\naws_access_key_id=AKIAMOCKWORKKEY00000\naws_secret_access_key=mock-work-secret\n",
+            fixtures::CREDENTIALS
+        ),
+    )
+    .unwrap();
+    let sts = MockSts::start().unwrap();
+
+    let output = aws_mfa_with_env(
+        &home,
+        &sts,
+        &["123456"],
+        &[("PATH", ""), ("AWS_PROFILE", "work")],
+    );
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let requests = sts.requests();
+    assert!(requests[0]["Authorization"].contains("Credential=AKIAMOCKWORKKEY00000/"));
+}
+
+#[cfg(feature = "sdk")]
+#[test]
+fn it_falls_back_to_sdk_without_aws_cli() {
+    let home = TestHome::with_fixtures().unwrap();
+    let sts = MockSts::start().unwrap();
+
+    let output = aws_mfa_with_env(
+        &home,
+        &sts,
+        &["-p", "default", "123456"],
+        &[("PATH", ""), ("AWS_MFA_STS_CLIENT", "cli")],
+    );
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("using the AWS SDK"));
+    assert_eq!(sts.requests().len(), 1);
+}
+
+#[test]
+#[cfg_attr(
+    not(any(feature = "sdk", feature = "sigv4")),
+    ignore = "needs the aws CLI"
+)]
+fn it_uses_exported_keys_with_serial_number() {
+    let home = TestHome::new().unwrap();
+    home.write("mfa.yml", "devices: []\n").unwrap();
+    let sts = MockSts::start().unwrap();
+    let device = "arn:aws:iam::012345678901:mfa/ci";
+
+    let output = aws_mfa_with_env(
+        &home,
+        &sts,
+        &["--serial-number", device, "123456"],
+        &[
+            ("AWS_ACCESS_KEY_ID", "AKIAMOCKLONGTERMKEY0"),
+            ("AWS_SECRET_ACCESS_KEY", "mock-long-term-secret"),
+        ],
+    );
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let requests = sts.requests();
+    assert_eq!(requests[0]["SerialNumber"], device);
+
+    let credentials = home.read("credentials").unwrap();
+    assert!(credentials.contains("# aws-mfa: source_profile=env"));
+}
+
+#[test]
+#[ignore = "needs the aws CLI for AssumeRole: run with --ignored"]
+fn it_writes_mfa_and_role_profiles_with_then_assume() {
+    let home = TestHome::with_fixtures().unwrap();
+    let sts = MockSts::start().unwrap();
+
+    let output = aws_mfa(
+        &home,
+        &sts,
+        &[
+            "-p",
+            "default",
+            "--then-assume",
+            fixtures::ROLE_ARN,
+            "123456",
+        ],
+    );
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let requests = sts.requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0]["Action"], "GetSessionToken");
+    assert_eq!(requests[1]["Action"], "AssumeRole");
+    assert_eq!(requests[1]["RoleArn"], fixtures::ROLE_ARN);
+
+    let credentials = home.read("credentials").unwrap();
+    assert!(credentials.contains("[mfa]"));
+    assert!(credentials.contains("[mfa-role]"));
+    assert_eq!(home.read("credentials_bk").unwrap(), fixtures::CREDENTIALS);
+}
+
+#[test]
+#[ignore = "needs the aws CLI for AssumeRole: run with --ignored"]
+fn it_assumes_role_of_device_with_assume_role() {
+    let home = TestHome::with_fixtures().unwrap();
+    home.write(
+        "mfa.yml",
+        &format!(
+            "devices:\n  - profile: default\n    arn: {}\n    role_arn: {}\n",
+            fixtures::DEVICE_ARN,
+            fixtures::ROLE_ARN
+        ),
+    )
+    .unwrap();
+    let sts = MockSts::start().unwrap();
+
+    let args = ["assume-role", "-p", "default", "--role-profile", "admin"];
+    let output = aws_mfa(&home, &sts, &[&args[..], &["123456"]].concat());
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    // The cached MFA session assumes the role again without a code.
+    let output = aws_mfa(&home, &sts, &args);
+    assert!(output.status.success());
+
+    let actions: Vec<_> = sts.requests().iter().map(|r| r["Action"].clone()).collect();
+    assert_eq!(actions, ["GetSessionToken", "AssumeRole", "AssumeRole"]);
+    assert_eq!(sts.requests()[1]["RoleArn"], fixtures::ROLE_ARN);
+
+    let credentials = home.read("credentials").unwrap();
+    assert!(credentials.contains("[mfa]"));
+    assert!(credentials.contains("[admin]"));
+}
+
+#[test]
+#[ignore = "needs the aws CLI for AssumeRole: run with --ignored"]
+fn it_chains_roles_through_via_of_preset() {
+    let jump = "arn:aws:iam::111111111111:role/jump";
+    let home = TestHome::with_fixtures().unwrap();
+    home.write(
+        "mfa.yml",
+        &format!(
+            "{}roles:\n  deploy:\n    arn: {}\n    via: [{}]\n",
+            fixtures::MFA_YML,
+            fixtures::ROLE_ARN,
+            jump
+        ),
+    )
+    .unwrap();
+    let sts = MockSts::start().unwrap();
+
+    let output = aws_mfa(
+        &home,
+        &sts,
+        &["-p", "default", "--role-arn", fixtures::ROLE_ARN, "123456"],
+    );
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let requests = sts.requests();
+    let actions: Vec<_> = requests.iter().map(|r| r["Action"].clone()).collect();
+    assert_eq!(actions, ["GetSessionToken", "AssumeRole", "AssumeRole"]);
+    assert_eq!(requests[1]["RoleArn"], jump);
+    assert_eq!(requests[2]["RoleArn"], fixtures::ROLE_ARN);
+    assert!(home.read("credentials").unwrap().contains("[mfa]"));
+}
+
+#[test]
+#[ignore = "needs the aws CLI for AssumeRole: run with --ignored"]
+fn it_assumes_role_of_aws_config_profile() {
+    let home = TestHome::with_fixtures().unwrap();
+    home.write(
+        "config",
+        &format!(
+            "[profile deploy]\nrole_arn = {}\nsource_profile = default\nmfa_serial = arn:aws:iam::012345678901:mfa/deployer\nrole_session_name = tanaka\n",
+            fixtures::ROLE_ARN
+        ),
+    )
+    .unwrap();
+    let sts = MockSts::start().unwrap();
+
+    let output = aws_mfa(&home, &sts, &["-p", "deploy", "123456"]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let requests = sts.requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0]["Action"], "GetSessionToken");
+    assert_eq!(
+        requests[0]["SerialNumber"],
+        "arn:aws:iam::012345678901:mfa/deployer"
+    );
+    assert_eq!(requests[1]["Action"], "AssumeRole");
+    assert_eq!(requests[1]["RoleArn"], fixtures::ROLE_ARN);
+    assert_eq!(requests[1]["RoleSessionName"], "tanaka");
+
+    let credentials = home.read("credentials").unwrap();
+    assert!(credentials.contains("# aws-mfa: source_profile=default"));
+}
+
+#[test]
+#[ignore = "needs the aws CLI for AssumeRoleWithSAML: run with --ignored"]
+fn it_writes_role_assumed_with_saml_assertion() {
+    let home = TestHome::with_fixtures().unwrap();
+    home.write(
+        "saml.xml",
+        "<samlp:Response xmlns:samlp=\"urn:oasis:names:tc:SAML:2.0:protocol\"/>\n",
+    )
+    .unwrap();
+    let sts = MockSts::start().unwrap();
+    let assertion = home.aws_file("saml.xml");
+    let principal_arn = "arn:aws:iam::012345678901:saml-provider/corp";
+
+    let output = aws_mfa(
+        &home,
+        &sts,
+        &[
+            "saml",
+            "--assertion-file",
+            assertion.to_str().unwrap(),
+            "--role-arn",
+            fixtures::ROLE_ARN,
+            "--principal-arn",
+            principal_arn,
+            "-m",
+            "corp",
+        ],
+    );
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let requests = sts.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0]["Action"], "AssumeRoleWithSAML");
+    assert_eq!(requests[0]["PrincipalArn"], principal_arn);
+    assert!(!requests[0]["SAMLAssertion"].contains('<'));
+
+    let credentials = home.read("credentials").unwrap();
+    assert!(credentials.contains("[corp]"));
+    assert!(credentials.contains("# aws-mfa: source_profile=saml"));
+    assert_eq!(home.read("credentials_bk").unwrap(), fixtures::CREDENTIALS);
+}
+
+#[test]
+#[ignore = "needs the aws CLI for AssumeRoleWithWebIdentity: run with --ignored"]
+fn it_writes_role_assumed_with_web_identity_token() {
+    let home = TestHome::with_fixtures().unwrap();
+    home.write("token", "eyJhbGciOiJSUzI1NiJ9.eyJzdWIiOiJjaSJ9.c2ln\n")
+        .unwrap();
+    let sts = MockSts::start().unwrap();
+    let token_file = home.aws_file("token");
+
+    let output = aws_mfa_with_env(
+        &home,
+        &sts,
+        &["oidc", "-m", "ci"],
+        &[
+            ("AWS_ROLE_ARN", fixtures::ROLE_ARN),
+            ("AWS_WEB_IDENTITY_TOKEN_FILE", token_file.to_str().unwrap()),
+        ],
+    );
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let requests = sts.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0]["Action"], "AssumeRoleWithWebIdentity");
+    assert_eq!(requests[0]["RoleArn"], fixtures::ROLE_ARN);
+    assert_eq!(
+        requests[0]["WebIdentityToken"],
+        "eyJhbGciOiJSUzI1NiJ9.eyJzdWIiOiJjaSJ9.c2ln"
+    );
+
+    let credentials = home.read("credentials").unwrap();
+    assert!(credentials.contains("[ci]"));
+    assert!(credentials.contains("# aws-mfa: source_profile=web-identity"));
+}
+
+#[test]
+#[cfg_attr(
+    not(any(feature = "sdk", feature = "sigv4")),
+    ignore = "needs the aws CLI"
+)]
+fn it_keeps_credentials_when_sts_fails() {
+    let home = TestHome::with_fixtures().unwrap();
+    let sts = MockSts::start_with(MockResponse::Error {
+        status: 403,
+        code: "AccessDenied".to_owned(),
+        message: "MultiFactorAuthentication failed with invalid MFA one time pass code.".to_owned(),
+    })
+    .unwrap();
+
+    let output = aws_mfa(&home, &sts, &["-p", "default", "000000"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("AccessDenied"));
+    assert_eq!(home.read("credentials").unwrap(), fixtures::CREDENTIALS);
+}
+
+#[test]
+#[ignore = "needs the aws CLI for GetCallerIdentity: run with --ignored"]
+fn it_verifies_written_profile() {
+    let home = TestHome::with_fixtures().unwrap();
+    let sts = MockSts::start().unwrap();
+
+    let output = aws_mfa(&home, &sts, &["-p", "default", "--verify", "123456"]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains(fixtures::CALLER_ARN));
+
+    let requests = sts.requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[1]["Action"], "GetCallerIdentity");
+}
+
+#[test]
+#[cfg_attr(
+    not(any(feature = "sdk", feature = "sigv4")),
+    ignore = "needs the aws CLI"
+)]
+fn it_stops_trying_codes_after_repeated_failures() {
+    let home = TestHome::with_fixtures().unwrap();
+    let sts = MockSts::start_with(MockResponse::Error {
+        status: 403,
+        code: "AccessDenied".to_owned(),
+        message: "MultiFactorAuthentication failed with invalid MFA one time pass code.".to_owned(),
+    })
+    .unwrap();
+
+    for _ in 0..3 {
+        let output = aws_mfa(&home, &sts, &["-p", "default", "000000"]);
+        assert!(!output.status.success());
+    }
+
+    let output = aws_mfa(&home, &sts, &["-p", "default", "000000"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Refusing to try another code"));
+    assert_eq!(sts.requests().len(), 3);
+}
+
+#[test]
+#[cfg_attr(
+    not(any(feature = "sdk", feature = "sigv4")),
+    ignore = "needs the aws CLI"
+)]
+fn it_answers_repeated_access_denied_from_cache() {
+    let home = TestHome::with_fixtures().unwrap();
+    let sts = MockSts::start_with(MockResponse::Error {
+        status: 403,
+        code: "AccessDenied".to_owned(),
+        message: "User is not authorized to perform: sts:GetSessionToken".to_owned(),
+    })
+    .unwrap();
+
+    for _ in 0..2 {
+        let output = aws_mfa(&home, &sts, &["-p", "default", "123456"]);
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("AccessDenied"));
+    }
+    assert_eq!(sts.requests().len(), 1);
+
+    let output = aws_mfa(&home, &sts, &["-p", "default", "--force", "123456"]);
+    assert!(!output.status.success());
+    assert_eq!(sts.requests().len(), 2);
+}
+
+#[test]
+fn it_rejects_rpc_refresh_that_would_prompt() {
+    let home = TestHome::with_fixtures().unwrap();
+    let sts = MockSts::start().unwrap();
+
+    let request =
+        r#"{"jsonrpc": "2.0", "id": 1, "method": "refresh", "params": {"profile": "default"}}"#;
+    let output = aws_mfa_with_input(&home, &sts, &["rpc"], &format!("{}\n", request));
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let response: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(response["id"], 1);
+    assert_eq!(response["error"]["code"], rpc::INVALID_PARAMS);
+    assert!(sts.requests().is_empty());
+}